@@ -0,0 +1,42 @@
+use criterion::{BatchSize, Criterion, Throughput};
+use payment_engine::transaction::{engine::PaymentEngine, Transaction};
+
+/// Parse a generated csv up front so the engine benchmarks don't measure parsing
+pub fn generate_transactions(
+    clients: u16,
+    transactions_per_client: u32,
+    dispute_ratio: f64,
+) -> Vec<Transaction> {
+    let csv = PaymentEngine::generate_test_csv(clients, transactions_per_client, dispute_ratio, 42);
+    Transaction::read_from_reader(csv.as_bytes())
+        .map(|transaction| transaction.unwrap())
+        .collect()
+}
+
+/// Time performing every transaction against a fresh engine, in transactions per second
+pub fn bench_transactions(c: &mut Criterion, name: &str, transactions: Vec<Transaction>) {
+    let mut group = c.benchmark_group("perform_transaction");
+    group.throughput(Throughput::Elements(transactions.len() as u64));
+    group.sample_size(10);
+    group.bench_function(name, |b| {
+        b.iter_batched(
+            || transactions.clone(),
+            |transactions| {
+                let mut engine = PaymentEngine::default();
+                for transaction in transactions {
+                    let _ = engine.perform_transaction(transaction);
+                }
+                engine
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+/// Write a generated csv to a file in the temp directory, the file path is what the binary reads
+pub fn write_test_file(name: &str, csv: &str) -> String {
+    let path = std::env::temp_dir().join(name);
+    std::fs::write(&path, csv).unwrap();
+    path.to_str().unwrap().to_string()
+}
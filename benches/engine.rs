@@ -0,0 +1,30 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use payment_engine::transaction::{engine::PaymentEngine, Transaction};
+
+mod common;
+
+use common::{bench_transactions, generate_transactions, write_test_file};
+
+fn mixed_workload(c: &mut Criterion) {
+    // deposits and withdrawals with a dispute cycle on a tenth of the deposits
+    bench_transactions(c, "mixed", generate_transactions(1_000, 100, 0.1));
+}
+
+fn csv_file_parsing(c: &mut Criterion) {
+    // the file path is what the binary uses, so include the file reads
+    let csv = PaymentEngine::generate_test_csv(1_000, 100, 0.1, 42);
+    let path = write_test_file("payment_engine_bench_mixed.csv", &csv);
+    let rows = Transaction::read_from_file(&path).unwrap().count();
+
+    let mut group = c.benchmark_group("read_from_file");
+    group.throughput(Throughput::Elements(rows as u64));
+    group.sample_size(10);
+    group.bench_function("mixed", |b| {
+        b.iter(|| Transaction::read_from_file(&path).unwrap().count())
+    });
+    group.finish();
+    std::fs::remove_file(path).ok();
+}
+
+criterion_group!(benches, mixed_workload, csv_file_parsing);
+criterion_main!(benches);
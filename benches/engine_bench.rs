@@ -0,0 +1,64 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use payment_engine::transaction::{engine::PaymentEngine, Transaction};
+
+mod common;
+
+use common::{bench_transactions, generate_transactions, write_test_file};
+
+fn unique_clients(c: &mut Criterion) {
+    // client ids are u16, so this is the widest spread of clients possible for 100k transactions
+    bench_transactions(c, "unique_clients", generate_transactions(50_000, 2, 0.0));
+}
+
+fn dispute_cycle(c: &mut Criterion) {
+    // every deposit is disputed and resolved
+    bench_transactions(c, "dispute_cycle", generate_transactions(1_000, 100, 1.0));
+}
+
+fn single_client(c: &mut Criterion) {
+    bench_transactions(c, "single_client", generate_transactions(1, 100_000, 0.0));
+}
+
+fn csv_parsing(c: &mut Criterion) {
+    let csv = PaymentEngine::generate_test_csv(1_000, 1_000, 0.0, 42);
+    let path = write_test_file("payment_engine_bench_1m.csv", &csv);
+    let mut group = c.benchmark_group("read_from_file");
+    group.throughput(Throughput::Bytes(csv.len() as u64));
+    group.sample_size(10);
+    group.bench_function("1m_rows", |b| {
+        b.iter(|| Transaction::read_from_file(&path).unwrap().count())
+    });
+    group.finish();
+    std::fs::remove_file(path).ok();
+}
+
+fn binary_parsing(c: &mut Criterion) {
+    // the same transactions as csv and as binary records, per transaction so they compare
+    let csv = PaymentEngine::generate_test_csv(1_000, 1_000, 0.0, 42);
+    let mut binary = Vec::new();
+    let mut rows = 0;
+    for transaction in Transaction::read_from_reader(csv.as_bytes()) {
+        transaction.unwrap().write_binary(&mut binary).unwrap();
+        rows += 1;
+    }
+    let mut group = c.benchmark_group("csv_vs_binary");
+    group.throughput(Throughput::Elements(rows));
+    group.sample_size(10);
+    group.bench_function("csv", |b| {
+        b.iter(|| Transaction::read_from_reader(csv.as_bytes()).count())
+    });
+    group.bench_function("binary", |b| {
+        b.iter(|| Transaction::read_binary(binary.as_slice()).count())
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    unique_clients,
+    dispute_cycle,
+    single_client,
+    csv_parsing,
+    binary_parsing
+);
+criterion_main!(benches);
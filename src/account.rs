@@ -1,12 +1,20 @@
+use hmac::{Hmac, Mac};
 use serde::{ser::SerializeStruct, Deserialize, Serialize};
+use sha2::Sha256;
+
+/// HMAC-SHA256 of an account's state, used to detect tampering when accounts are exchanged
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct AccountSignature(pub [u8; 32]);
 
 // a total is not maintained since it is always calculatable from available and held
-#[derive(Debug, Default, Deserialize, PartialEq)]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
 pub struct Account {
     client: u16,
     available: f64,
     held: f64,
     locked: bool,
+    #[serde(skip_deserializing)] // not part of the output, internal use for analytics
+    first_tx: Option<u32>, // None if the account wasn't opened by a transaction, like a seeded balance
 }
 
 // Account is used like a database entry, not a lot of complex logic happening in here
@@ -19,22 +27,64 @@ impl Account {
         }
     }
 
-    // some getters for black-box testing
+    /// The same account under another client id
+    pub(crate) fn with_client(mut self, client: u16) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Rebuild an account from previously exported state, no transaction history comes with it
+    #[cfg(feature = "parquet")]
+    pub(crate) fn restore(client: u16, available: f64, held: f64, locked: bool) -> Self {
+        Account {
+            client,
+            available,
+            held,
+            locked,
+            first_tx: None,
+        }
+    }
+
+    /// Compare the balances and the lock, but not the transaction which opened the account
+    #[cfg(any(test, feature = "testutil"))]
+    pub(crate) fn eq_ignoring_history(&self, other: &Account) -> bool {
+        let without_history = Account {
+            first_tx: other.first_tx,
+            ..*self
+        };
+        without_history == *other
+    }
+
+    /// Break the held funds on purpose, to test the consistency checks
     #[cfg(test)]
+    pub(crate) fn set_held(&mut self, held: f64) {
+        self.held = held;
+    }
+
+    /// Record the transaction which caused the account to be opened
+    pub(crate) fn with_first_tx(mut self, tx: u32) -> Self {
+        self.first_tx = Some(tx);
+        self
+    }
+
+    // getters, also used for black-box testing
     pub fn client(&self) -> u16 {
         self.client
     }
 
-    #[cfg(test)]
     pub fn available(&self) -> f64 {
         self.available
     }
 
-    #[cfg(test)]
     pub fn held(&self) -> f64 {
         self.held
     }
 
+    /// The transaction id that first referred to this client
+    pub fn first_tx(&self) -> Option<u32> {
+        self.first_tx
+    }
+
     // getters used in the program, total isn't actually stored in the struct
     pub fn total(&self) -> f64 {
         self.available + self.held
@@ -49,6 +99,11 @@ impl Account {
         self.available += amount;
     }
 
+    /// Credit or debit the available funds directly, the balance may go negative
+    pub fn adjust(&mut self, amount: f64) {
+        self.available += amount;
+    }
+
     pub fn withdrawal(&mut self, amount: f64) -> bool {
         let can_withdrawal = self.available >= amount;
         if can_withdrawal {
@@ -57,21 +112,92 @@ impl Account {
         can_withdrawal
     }
 
+    /// Withdraw all of the available funds, returns the amount withdrawn
+    pub fn withdraw_available(&mut self) -> f64 {
+        let withdrawn = self.available.max(0_f64);
+        self.available -= withdrawn;
+        withdrawn
+    }
+
+    /// Undo a deposit, the funds have to still be available
+    pub fn reverse_deposit(&mut self, amount: f64) -> bool {
+        self.withdrawal(amount)
+    }
+
+    /// Undo a withdrawal
+    pub fn reverse_withdrawal(&mut self, amount: f64) {
+        self.deposit(amount);
+    }
+
     pub fn dispute(&mut self, amount: f64) {
         self.available -= amount;
         self.held += amount;
     }
 
     pub fn resolve(&mut self, amount: f64) {
-        self.held -= amount;
+        self.release_held(amount);
         self.available += amount;
     }
 
     pub fn chargeback(&mut self, amount: f64) {
-        self.held -= amount;
+        self.release_held(amount);
+        self.locked = true;
+    }
+
+    /// Freeze the account without a chargeback
+    pub(crate) fn lock(&mut self) {
         self.locked = true;
     }
 
+    /// Do the held funds cover `amount`? Float error below `scale` decimal places is ignored.
+    pub fn holds(&self, amount: f64, scale: u32) -> bool {
+        round_amount(self.held - amount, scale) >= 0_f64
+    }
+
+    /// Take `amount` out of the held funds, float error can't take them below zero
+    fn release_held(&mut self, amount: f64) {
+        self.held = (self.held - amount).max(0_f64);
+    }
+
+    /// Directly adjust the balances, fails without changing anything if either would become negative
+    pub(crate) fn correct(&mut self, delta_available: f64, delta_held: f64) -> bool {
+        let available = self.available + delta_available;
+        let held = self.held + delta_held;
+        let can_correct = available >= 0_f64 && held >= 0_f64;
+        if can_correct {
+            self.available = available;
+            self.held = held;
+        }
+        can_correct
+    }
+
+    /// Canonical byte layout of the account that signatures are computed over
+    fn canonical_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(19);
+        bytes.extend_from_slice(&self.client.to_le_bytes());
+        bytes.extend_from_slice(&self.available.to_le_bytes());
+        bytes.extend_from_slice(&self.held.to_le_bytes());
+        bytes.push(u8::from(self.locked));
+        bytes
+    }
+
+    fn mac(&self, secret: &[u8]) -> Hmac<Sha256> {
+        // HMAC accepts keys of any length so this can't fail
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+        mac.update(&self.canonical_bytes());
+        mac
+    }
+
+    /// Sign the account state with a shared secret
+    pub fn sign(&self, secret: &[u8]) -> AccountSignature {
+        AccountSignature(self.mac(secret).finalize().into_bytes().into())
+    }
+
+    /// Check the account state against a signature, comparison is in constant time
+    pub fn verify(&self, sig: &AccountSignature, secret: &[u8]) -> bool {
+        self.mac(secret).verify_slice(&sig.0).is_ok()
+    }
+
     /// Used to deserialize byte strings in tests
     #[cfg(test)]
     fn read_from_bytes(bytes: &[u8]) -> impl Iterator<Item = Result<Account, csv::Error>> + '_ {
@@ -82,25 +208,178 @@ impl Account {
     }
 }
 
-// Implement serialize manually for two reasons:
-// 1. 'total' is injected and calculated at serialization time from available and held amounts
-// 2. to output rounded floats to 4 decimal places
+/// Number of decimal places amounts are rounded to when no scale is configured
+pub const DEFAULT_SCALE: u32 = 4;
+/// Most decimal places a scale can have, past it an f64 can't tell the decimal places apart
+pub const MAX_SCALE: u32 = 15;
+
+/// Round an amount to `scale` decimal places for output
+pub(crate) fn round_amount(val: f64, scale: u32) -> f64 {
+    let precision = 10_f64.powi(scale as i32); // 10000 means round to 4 decimal places
+    f64::round(val * precision) / precision
+}
+
+/// Does the amount have no more than `scale` decimal places?
+pub(crate) fn fits_scale(amount: f64, scale: u32) -> bool {
+    let scaled = amount * 10_f64.powi(scale as i32);
+    // allow for the representation error of the float itself
+    (scaled - scaled.round()).abs() <= scaled.abs().max(1_f64) * 1e-12
+}
+
+/// Order of the columns when accounts are written out
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ColumnOrder {
+    /// client, available, held, total, locked
+    #[default]
+    Standard,
+    /// client, total, available, held, locked, for consumers of the old output
+    Legacy,
+}
+
+/// What happens to the zeros at the end of the decimals of an amount when accounts are written out
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum TrailingZeros {
+    /// Only as many decimal places as the amount needs, at least one: `10.5`, `12.0`.
+    /// With a scale of 0 there's no decimal point at all: `12`
+    #[default]
+    Trim,
+    /// Always the number of decimal places of the scale: `10.5000`
+    Pad,
+}
+
+/// Settings for how accounts are written out
+#[derive(Clone, Debug)]
+pub struct OutputFormat {
+    pub column_order: ColumnOrder,
+    pub scale: u32, // decimal places of the smallest currency unit
+    pub trailing_zeros: TrailingZeros,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat {
+            column_order: ColumnOrder::default(),
+            scale: DEFAULT_SCALE,
+            trailing_zeros: TrailingZeros::default(),
+        }
+    }
+}
+
+/// Format an amount rounded to `scale` decimal places in fixed point notation, never like `1e-4`.
+/// A scale of 0 gives an integer, for currencies without minor units.
+pub fn format_amount(val: f64, scale: u32, trailing_zeros: TrailingZeros) -> String {
+    let mut formatted = format!("{:.*}", scale as usize, round_amount(val, scale));
+    if trailing_zeros == TrailingZeros::Trim && scale > 0 {
+        formatted.truncate(formatted.trim_end_matches('0').len());
+        if formatted.ends_with('.') {
+            formatted.push('0');
+        }
+    }
+    formatted
+}
+
+/// Plain copy of an account with the amounts already rounded, for serializers other than the CSV output
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AccountView {
+    pub client: u16,
+    pub available: f64,
+    pub held: f64,
+    pub total: f64,
+    pub locked: bool,
+}
+
+/// An account paired with the format it should be serialized in
+pub struct FormattedAccount<'a> {
+    account: &'a Account,
+    format: &'a OutputFormat,
+    fixed_point: bool, // amounts are fixed point strings, otherwise rounded numbers
+}
+
+impl Account {
+    /// Copy the account with the amounts rounded to `decimals` places, the total is computed before rounding
+    pub fn to_view(&self, decimals: u32) -> AccountView {
+        AccountView {
+            client: self.client,
+            available: round_amount(self.available, decimals),
+            held: round_amount(self.held, decimals),
+            total: round_amount(self.total(), decimals),
+            locked: self.locked,
+        }
+    }
+
+    /// Serialize the account in a specific format, serializing 'Account' directly uses the default format
+    pub fn formatted<'a>(&'a self, format: &'a OutputFormat) -> FormattedAccount<'a> {
+        FormattedAccount {
+            account: self,
+            format,
+            fixed_point: true,
+        }
+    }
+
+    /// Like `formatted` but the amounts are rounded numbers instead of fixed point strings,
+    /// for formats with a number type like JSON. The trailing zeros policy doesn't apply,
+    /// with a scale of 0 the amounts are integers.
+    pub fn formatted_numbers<'a>(&'a self, format: &'a OutputFormat) -> FormattedAccount<'a> {
+        FormattedAccount {
+            account: self,
+            format,
+            fixed_point: false,
+        }
+    }
+}
+
 impl Serialize for Account {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        let f64_round = |val: f64| -> f64 {
-            let precision = 10000_f64; // 10000 means round to 4 decimal places
-            f64::round(val * precision) / precision
-        };
+        self.formatted(&OutputFormat::default())
+            .serialize(serializer)
+    }
+}
+
+// Implement serialize manually for three reasons:
+// 1. 'total' is injected and calculated at serialization time from available and held amounts
+// 2. to output amounts rounded to the scale, 4 decimal places by default, in fixed point notation
+// 3. the column order depends on the format
+impl Serialize for FormattedAccount<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let scale = self.format.scale;
+        if self.fixed_point {
+            let trailing_zeros = self.format.trailing_zeros;
+            self.serialize_with(serializer, |val| format_amount(val, scale, trailing_zeros))
+        } else if scale == 0 {
+            // a float would be written with a decimal point
+            self.serialize_with(serializer, |val| val.round() as i64)
+        } else {
+            self.serialize_with(serializer, |val| round_amount(val, scale))
+        }
+    }
+}
 
+impl FormattedAccount<'_> {
+    /// Serialize the columns in the order of the format, with the amounts converted by `amount`
+    fn serialize_with<S, T, F>(&self, serializer: S, amount: F) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+        T: Serialize,
+        F: Fn(f64) -> T,
+    {
+        let total = amount(self.account.total());
         let mut state = serializer.serialize_struct("Account", 5)?;
-        state.serialize_field("client", &self.client)?;
-        state.serialize_field("available", &f64_round(self.available))?;
-        state.serialize_field("held", &f64_round(self.held))?;
-        state.serialize_field("total", &f64_round(self.total()))?;
-        state.serialize_field("locked", &self.locked)?;
+        state.serialize_field("client", &self.account.client)?;
+        if self.format.column_order == ColumnOrder::Legacy {
+            state.serialize_field("total", &total)?;
+        }
+        state.serialize_field("available", &amount(self.account.available))?;
+        state.serialize_field("held", &amount(self.account.held))?;
+        if self.format.column_order == ColumnOrder::Standard {
+            state.serialize_field("total", &total)?;
+        }
+        state.serialize_field("locked", &self.account.locked)?;
         state.end()
     }
 }
@@ -136,6 +415,146 @@ mod tests {
         assert_eq!(acc.locked(), false);
     }
 
+    #[test]
+    fn held_never_negative() {
+        let mut acc = Account::new(1);
+        acc.deposit(0.8);
+        acc.dispute(0.7);
+        acc.dispute(0.1);
+        assert!(acc.holds(0.7, DEFAULT_SCALE));
+        acc.resolve(0.7);
+        // the float error of the sums would leave a tiny negative amount
+        assert!(acc.holds(0.1, DEFAULT_SCALE));
+        acc.chargeback(0.1);
+        assert_eq!(acc.held(), 0.0);
+        assert!(!acc.holds(0.1, DEFAULT_SCALE));
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let secret = b"shared secret";
+        let mut acc = Account::new(1);
+        acc.deposit(50.0);
+        let sig = acc.sign(secret);
+        assert!(acc.verify(&sig, secret));
+        assert!(!acc.verify(&sig, b"wrong secret"));
+        // tampering with the balance invalidates the signature
+        acc.available += 0.0001;
+        assert!(!acc.verify(&sig, secret));
+    }
+
+    #[test]
+    fn serialize_legacy_column_order() {
+        let mut acc = Account::new(1);
+        acc.deposit(10.0);
+        acc.dispute(2.5);
+        let format = OutputFormat {
+            column_order: ColumnOrder::Legacy,
+            ..Default::default()
+        };
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        wtr.serialize(acc.formatted(&format)).unwrap();
+        let serialize_str = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            serialize_str,
+            "client,total,available,held,locked\n1,10.0,7.5,2.5,false\n"
+        );
+    }
+
+    #[test]
+    fn serialize_scales() {
+        let mut acc = Account::new(1);
+        acc.deposit(12.34567);
+        let serialize_with_scale = |scale| {
+            let format = OutputFormat {
+                scale,
+                ..Default::default()
+            };
+            let mut wtr = csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(vec![]);
+            wtr.serialize(acc.formatted(&format)).unwrap();
+            String::from_utf8(wtr.into_inner().unwrap()).unwrap()
+        };
+        assert_eq!(serialize_with_scale(0), "1,12,0,12,false\n");
+        assert_eq!(serialize_with_scale(2), "1,12.35,0.0,12.35,false\n");
+        assert_eq!(serialize_with_scale(4), "1,12.3457,0.0,12.3457,false\n");
+    }
+
+    #[test]
+    fn serialize_fixed_point() {
+        let serialize_with = |available, trailing_zeros| {
+            let mut acc = Account::new(1);
+            acc.deposit(available);
+            let format = OutputFormat {
+                trailing_zeros,
+                ..Default::default()
+            };
+            let mut wtr = csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(vec![]);
+            wtr.serialize(acc.formatted(&format)).unwrap();
+            String::from_utf8(wtr.into_inner().unwrap()).unwrap()
+        };
+        // tiny and huge amounts which would otherwise be in scientific notation
+        assert_eq!(
+            serialize_with(0.0001, TrailingZeros::Trim),
+            "1,0.0001,0.0,0.0001,false\n"
+        );
+        assert_eq!(
+            serialize_with(1e17, TrailingZeros::Trim),
+            "1,100000000000000000.0,0.0,100000000000000000.0,false\n"
+        );
+        // rounded away below the scale
+        assert_eq!(
+            serialize_with(0.00001, TrailingZeros::Trim),
+            "1,0.0,0.0,0.0,false\n"
+        );
+        assert_eq!(
+            serialize_with(0.0001, TrailingZeros::Pad),
+            "1,0.0001,0.0000,0.0001,false\n"
+        );
+        assert_eq!(
+            serialize_with(10.5, TrailingZeros::Pad),
+            "1,10.5000,0.0000,10.5000,false\n"
+        );
+        assert_eq!(
+            serialize_with(10.5, TrailingZeros::Trim),
+            "1,10.5,0.0,10.5,false\n"
+        );
+        assert_eq!(format_amount(1e-7, 8, TrailingZeros::Trim), "0.0000001");
+        assert_eq!(format_amount(12.4, 0, TrailingZeros::Pad), "12");
+        assert_eq!(format_amount(12.5, 0, TrailingZeros::Trim), "13");
+    }
+
+    #[test]
+    fn view_matches_csv() {
+        let mut acc = Account::new(7);
+        acc.deposit(12.34567);
+        acc.deposit(0.00004);
+        acc.dispute(2.22225);
+        // a scale of 0 writes integers, which the view can't
+        for scale in [2, 4] {
+            let view = acc.to_view(scale);
+            let format = OutputFormat {
+                scale,
+                ..Default::default()
+            };
+            let mut account_csv = csv::Writer::from_writer(vec![]);
+            account_csv.serialize(acc.formatted(&format)).unwrap();
+            let mut view_csv = csv::Writer::from_writer(vec![]);
+            view_csv.serialize(&view).unwrap();
+            assert_eq!(
+                account_csv.into_inner().unwrap(),
+                view_csv.into_inner().unwrap()
+            );
+        }
+        let view = acc.to_view(4);
+        assert_eq!(view.available, 10.1235);
+        assert_eq!(view.held, 2.2223);
+        assert_eq!(view.total, 12.3457);
+    }
+
     #[test]
     fn verify_serialize_and_decimal_precision() {
         // input float and its expected rounded output
@@ -0,0 +1,22 @@
+use crate::transaction::{engine::PaymentEngine, Transaction, TransactionType};
+
+impl PaymentEngine {
+    /// An engine with an account in each state, shared by the tests of the modules over the engine.
+    /// Client 1 has 100.0 available, client 2 holds a deposit of 50.0 under dispute and client 3 is
+    /// locked by the chargeback of a deposit of 7.5. Each deposit has the tx id of its client.
+    pub(crate) fn example() -> PaymentEngine {
+        let mut engine = PaymentEngine::default();
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(100.0)),
+            Transaction::new(TransactionType::Deposit, 2, 2, Some(50.0)),
+            Transaction::new(TransactionType::Dispute, 2, 2, None),
+            Transaction::new(TransactionType::Deposit, 3, 3, Some(7.5)),
+            Transaction::new(TransactionType::Dispute, 3, 3, None),
+            Transaction::new(TransactionType::Chargeback, 3, 3, None),
+        ];
+        for tx in txs {
+            assert!(engine.perform_transaction(tx).is_ok());
+        }
+        engine
+    }
+}
@@ -0,0 +1,8 @@
+//! Library interface of the payment engine, the binary in `main.rs` is a thin CSV frontend over it.
+pub mod account;
+#[cfg(test)]
+mod fixtures;
+pub mod serializer;
+#[cfg(any(test, feature = "testutil"))]
+pub mod testutil;
+pub mod transaction;
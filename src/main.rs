@@ -1,77 +1,654 @@
-use std::process;
+use std::{
+    error::Error,
+    fmt,
+    fs::File,
+    io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    path::Path,
+    process,
+};
 
-use crate::transaction::engine::PaymentEngine;
-use crate::transaction::Transaction;
+use clap::{error::ErrorKind, CommandFactory, Parser, ValueEnum};
+use payment_engine::account::{
+    Account, ColumnOrder, OutputFormat, TrailingZeros, DEFAULT_SCALE, MAX_SCALE,
+};
+use payment_engine::serializer::{
+    AccountSerializer, CsvAccountSerializer, HumanReadableAccountSerializer, JsonAccountSerializer,
+};
+use payment_engine::transaction::engine::{
+    run::EngineRunError, PaymentEngine, SeedError, TransactionError, TransactionOutcome,
+};
+use payment_engine::transaction::{DropCounts, InputFormat, Transaction, UTF8_BOM};
+use serde::Serialize;
 
-mod account;
 #[macro_use]
 mod macros;
-mod transaction;
-/// Reads a csv transaction file, builds the payment engine and outputs errors.
-fn read_csv_into_engine(file: &str) -> Result<PaymentEngine, csv::Error> {
-    // this structure does our accounting
-    let mut engine = PaymentEngine::default();
 
+/// Serializer of the account output, picked with `--format`
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+enum OutputKind {
+    #[default]
+    Csv,
+    Json,
+    /// A padded table for people
+    Table,
+}
+
+/// Calculates account balances from a list of transactions.
+/// Input files are processed in order into the same accounts.
+#[derive(Debug, Default, Parser, PartialEq)]
+#[command(version)]
+struct Options {
+    /// Transaction files, processed in order into the same accounts
+    #[arg(required = true, value_name = "INPUT")]
+    inputs: Vec<String>,
+    /// Preload accounts from a 'client,available' csv
+    #[arg(long, value_name = "BALANCES")]
+    opening: Option<String>,
+    /// Warn and continue when an input file doesn't exist
+    #[arg(long)]
+    skip_missing: bool,
+    /// Skip identical replays of a deposit or withdrawal
+    #[arg(long)]
+    ignore_duplicates: bool,
+    /// A withdrawal above the available funds withdraws all of them and reports the shortfall
+    #[arg(long)]
+    partial_withdrawal: bool,
+    /// Output the columns as 'client,total,available,held,locked'
+    #[arg(long)]
+    legacy_columns: bool,
+    /// Write every amount with all the decimal places of the scale, like '10.5000' instead of '10.5'
+    #[arg(long)]
+    pad_decimals: bool,
+    /// Perform the disputes, resolves and chargebacks after the deposits and withdrawals of each file
+    #[arg(long)]
+    two_pass: bool,
+    /// Amounts have at most N decimal places, more precise amounts are rejected and the output is
+    /// rounded to N
+    #[arg(long, value_name = "N", value_parser = parse_scale)]
+    scale: Option<u32>,
+    /// Print the count and held total of the disputes which are still open to stderr
+    #[arg(long)]
+    report_open_disputes: bool,
+    /// Print the clients which ended at a zero total after more than one transaction to stderr
+    #[arg(long)]
+    flag_zero_net: bool,
+    /// Write the balances to a file instead of stdout
+    #[arg(long, value_name = "FILE")]
+    output: Option<String>,
+    /// Read the input files through a buffer of this size
+    #[arg(long, value_name = "BYTES")]
+    buffer_size: Option<usize>,
+    /// Exit with 1 if any row had an error, after the output
+    #[arg(long)]
+    fail_on_error: bool,
+    /// The input files separate the fields with C
+    #[arg(long, value_name = "C", value_parser = parse_delimiter)]
+    delimiter: Option<u8>,
+    /// Amounts use a comma as the decimal point, like '10,50', the delimiter is a semicolon unless
+    /// it's given
+    #[arg(long)]
+    decimal_comma: bool,
+    /// The input files hold 15 byte binary records, see the README
+    #[arg(long, conflicts_with_all = ["delimiter", "decimal_comma"])]
+    binary: bool,
+    /// Write each account to its own DIR/<client>.csv instead of stdout
+    #[arg(long, value_name = "DIR", conflicts_with = "output")]
+    per_client_dir: Option<String>,
+    /// Write the transactions the engine rejected to a csv, with the error in an extra column
+    #[arg(long, value_name = "FILE")]
+    rejected: Option<String>,
+    /// Leave out the unlocked accounts with nothing in them
+    #[arg(long)]
+    hide_zero: bool,
+    /// Quote every field of the csv output, not only the ones which need it
+    #[arg(long)]
+    quote_all: bool,
+    /// Never quote the fields of the csv output
+    #[arg(long, conflicts_with = "quote_all")]
+    quote_never: bool,
+    /// How the balances are written
+    #[arg(long = "format", value_name = "KIND", value_enum, default_value_t)]
+    output_kind: OutputKind,
+}
+
+/// A delimiter is a single byte
+fn parse_delimiter(arg: &str) -> Result<u8, String> {
+    match arg.as_bytes() {
+        [delimiter] => Ok(*delimiter),
+        _ => Err(String::from("the delimiter has to be a single character")),
+    }
+}
+
+/// A scale is a number of decimal places up to `MAX_SCALE`
+fn parse_scale(arg: &str) -> Result<u32, String> {
+    match arg.parse() {
+        Ok(scale) if scale <= MAX_SCALE => Ok(scale),
+        Ok(_) => Err(format!("the scale can be at most {}", MAX_SCALE)),
+        Err(e) => Err(format!("the scale has to be a number: {}", e)),
+    }
+}
+
+/// Parses the command line arguments, the error prints the usage or the help when it's exited with
+fn parse_args(args: &[String]) -> Result<Options, clap::Error> {
+    let options = Options::try_parse_from(args)?;
+    // a comma can't be both the decimal point and the delimiter
+    if options.decimal_comma && options.delimiter == Some(b',') {
+        return Err(Options::command().error(
+            ErrorKind::ArgumentConflict,
+            "a comma can't be both the decimal point and the delimiter",
+        ));
+    }
+    Ok(options)
+}
+
+impl Options {
+    /// Quoting of the csv output
+    fn quote_style(&self) -> csv::QuoteStyle {
+        if self.quote_all {
+            csv::QuoteStyle::Always
+        } else if self.quote_never {
+            csv::QuoteStyle::Never
+        } else {
+            csv::QuoteStyle::Necessary
+        }
+    }
+
+    /// Layout of the input files, a decimal comma switches the delimiter to a semicolon by default
+    fn input_format(&self) -> InputFormat {
+        let default_delimiter = if self.decimal_comma { b';' } else { b',' };
+        InputFormat {
+            delimiter: self.delimiter.unwrap_or(default_delimiter),
+            decimal_comma: self.decimal_comma,
+        }
+    }
+}
+
+/// A transaction the engine rejected, in the input columns so the file can be fed back in,
+/// the extra column is ignored when it's read
+#[derive(Serialize)]
+struct RejectedRow {
+    #[serde(rename = "type")]
+    transaction_type: &'static str,
+    client: u16,
+    tx: u32,
+    amount: Option<f64>,
+    timestamp: Option<u64>,
+    error: String,
+}
+
+impl RejectedRow {
+    fn new(transaction: &Transaction, error: &TransactionError) -> Self {
+        RejectedRow {
+            transaction_type: transaction.transaction_type().name(),
+            client: transaction.client(),
+            tx: transaction.tx(),
+            amount: transaction.amount(),
+            timestamp: transaction.timestamp(),
+            error: error.to_string(),
+        }
+    }
+}
+
+/// The line of a file a deserialize error points at, so the diagnostic shows the row as it's
+/// written. The file is only read again when it's displayed, nothing is shown if the error has no
+/// position or the line can't be read.
+struct RawRow<'a> {
+    path: &'a str,
+    error: &'a csv::Error,
+}
+
+impl RawRow<'_> {
+    fn read(&self) -> Option<String> {
+        let position = self.error.position()?;
+        let mut file = BufReader::new(File::open(self.path).ok()?);
+        // the position was counted after the byte order mark the reader left out
+        let mut start = [0; UTF8_BOM.len()];
+        let bom = match file.read_exact(&mut start) {
+            Ok(()) if start == *UTF8_BOM => UTF8_BOM.len() as u64,
+            _ => 0,
+        };
+        file.seek(SeekFrom::Start(bom + position.byte())).ok()?;
+        let mut line = String::new();
+        file.read_line(&mut line).ok()?;
+        Some(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+}
+
+impl fmt::Display for RawRow<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.read() {
+            Some(line) => write!(f, ", the row was '{}'", line),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reads a csv of opening balances into the payment engine.
+fn read_opening_balances(file: &str, engine: &mut PaymentEngine) -> Result<(), SeedError> {
+    match File::open(file) {
+        Ok(f) => engine.seed_balances(f),
+        Err(e) => {
+            eprintln_featureflag!("failed to open file: {}", file);
+            Err(csv::Error::from(e).into())
+        }
+    }
+}
+
+/// Reads a csv transaction file into the payment engine and outputs errors.
+/// Returns the number of rows which failed to deserialize, were dropped as invalid or were rejected
+/// by the engine, or `EngineRunError::Io` if the file couldn't be opened or read.
+/// With the `two_pass` option the disputes, resolves and chargebacks are held back until every deposit and
+/// withdrawal in the file is performed, so they can refer to a transaction later in the file.
+/// The transactions the engine rejects are written to `rejected` if it's given.
+fn read_csv_into_engine(
+    file: &str,
+    engine: &mut PaymentEngine,
+    options: &Options,
+    mut rejected: Option<&mut csv::Writer<File>>,
+) -> Result<usize, EngineRunError> {
     // reading input
-    let reader = Transaction::read_from_file(file);
-    match reader {
-        Ok(iter) => {
-            let mut previous_error = false;
-
-            // check to see if there is at least one valid row
-            let mut peekable_iter = iter.peekable();
-            if peekable_iter.peek().is_none() {
+    let path = file;
+    let file = File::open(path)?;
+    // told apart from a file with only a header
+    let empty_file = file.metadata().is_ok_and(|metadata| metadata.len() == 0);
+    let reader: Box<dyn Read> = match options.buffer_size {
+        Some(capacity) => Box::new(BufReader::with_capacity(capacity, file)),
+        None => Box::new(file),
+    };
+    let format = options.input_format();
+    let iter: Box<dyn Iterator<Item = Result<Transaction, csv::Error>>> = if options.binary {
+        Box::new(Transaction::read_unvalidated_binary(reader))
+    } else if format == InputFormat::default() {
+        Box::new(Transaction::read_unvalidated_from_reader(reader))
+    } else {
+        Box::new(Transaction::read_unvalidated_from_reader_with_format(
+            reader, format,
+        ))
+    };
+    let mut transaction_errors = 0;
+    let mut perform = |row: usize, transaction: Transaction| {
+        // only copied when it has to be written out
+        let copy = rejected.is_some().then(|| transaction.clone());
+        let kind = match engine.perform_transaction(transaction) {
+            // performed, so it's neither an error nor rejected
+            Ok(TransactionOutcome::AppliedWithWarning(warning)) => {
+                eprintln_featureflag!("warning: row {}: {}", row, warning);
+                return;
+            }
+            Ok(_) => return,
+            Err(kind) => kind,
+        };
+        if let (Some(writer), Some(transaction)) = (rejected.as_deref_mut(), copy) {
+            if let Err(write_error) = writer.serialize(RejectedRow::new(&transaction, &kind)) {
+                eprintln_featureflag!("failed to write a rejected transaction: {}", write_error);
+            }
+        }
+        if transaction_errors == 0 {
+            eprintln_featureflag!("errors: ");
+        }
+        transaction_errors += 1;
+        eprintln_featureflag!("  {}", EngineRunError::Transaction { row, kind });
+    };
+    let mut row_errors = 0;
+    let mut deferred = Vec::new();
+    // counted to tell why a table turned out empty
+    let mut rows = 0;
+    let mut valid_rows = 0;
+    let mut dropped = DropCounts::default();
+
+    // perform each transaction as they are read into the program, line-by-line
+    for (row, result) in iter.enumerate() {
+        rows += 1;
+        match result {
+            // transactions with an amount that doesn't fit their type are dropped and counted
+            Ok(transaction) if !transaction.validate() => {
+                dropped.count(transaction.validation_failure().unwrap());
+            }
+            Ok(transaction) => {
+                valid_rows += 1;
+                if options.two_pass && !transaction.transaction_type().is_new_transaction() {
+                    deferred.push((row, transaction));
+                } else {
+                    perform(row, transaction);
+                }
+            }
+            // invalid line in csv, or the file couldn't be read any further
+            Err(e) => {
+                let error = EngineRunError::from_csv(row, e);
+                let EngineRunError::Deserialize {
+                    error: csv_error, ..
+                } = &error
+                else {
+                    return Err(error);
+                };
+                row_errors += 1;
                 eprintln_featureflag!(
-                    "csv error: table is empty, all rows had errors or columns don't match"
+                    "csv error: {}{}",
+                    error,
+                    RawRow {
+                        path,
+                        error: csv_error
+                    }
                 );
             }
+        }
+    }
 
-            // perform each transaction as they are read into the program, line-by-line
-            for (row, result) in peekable_iter.enumerate() {
-                if let Ok(transaction) = result {
-                    if let Err(e) = engine.perform_transaction(transaction) {
-                        if !previous_error {
-                            eprintln_featureflag!("errors: ");
-                            previous_error = true;
-                        }
-                        eprintln_featureflag!("  {}", e);
-                    }
+    if dropped.total() > 0 {
+        eprintln_featureflag!(
+            "warning: dropped {} invalid rows, {} disputes, resolves or chargebacks with an amount and {} deposits, withdrawals or adjustments without one",
+            dropped.total(),
+            dropped.amount_on_referring_transaction,
+            dropped.missing_amount
+        );
+    }
+
+    // check to see if there was at least one valid row
+    if let Some(diagnostic) =
+        empty_table_diagnostic(empty_file, rows, rows - row_errors, valid_rows)
+    {
+        eprintln_featureflag!("csv error: table is empty, {}", diagnostic);
+    }
+
+    // second pass, the order among the referring transactions is kept
+    for (row, transaction) in deferred {
+        perform(row, transaction);
+    }
+    Ok(transaction_errors + row_errors + dropped.total())
+}
+
+/// Explains why no transaction could be taken from a file, from whether the file has no bytes at
+/// all, the number of rows the csv reader produced, how many of them were deserialized and how many
+/// of those were valid. Returns `None` if there was a valid row.
+fn empty_table_diagnostic(
+    empty_file: bool,
+    rows: usize,
+    deserialized: usize,
+    valid: usize,
+) -> Option<String> {
+    if valid > 0 {
+        None
+    } else if empty_file {
+        Some("file is empty".to_string())
+    } else if rows == 0 {
+        Some("file has no data rows".to_string())
+    } else if deserialized == 0 {
+        // a header that doesn't match makes every row fail with a missing field
+        Some(format!(
+            "header columns unexpected, none of the {} rows could be read",
+            rows
+        ))
+    } else {
+        Some(format!("{} rows present but all failed validation", rows))
+    }
+}
+
+/// Reads every input file into the payment engine in the order given.
+/// A missing file aborts the run, unless `skip_missing` is set then it is skipped with a warning.
+/// Returns the number of rows with errors over all of the files.
+fn read_inputs_into_engine(
+    options: &Options,
+    engine: &mut PaymentEngine,
+    mut rejected: Option<&mut csv::Writer<File>>,
+) -> Result<usize, EngineRunError> {
+    let mut errors = 0;
+    for file in &options.inputs {
+        match read_csv_into_engine(file, engine, options, rejected.as_deref_mut()) {
+            Ok(file_errors) => errors += file_errors,
+            Err(e) => {
+                let not_found = matches!(
+                    &e,
+                    EngineRunError::Io(io_error) if io_error.kind() == std::io::ErrorKind::NotFound
+                );
+                if options.skip_missing && not_found {
+                    eprintln_featureflag!("warning: skipping missing file: {}", file);
                 } else {
-                    // invalid line in csv
-                    eprintln_featureflag!("csv error: deserialize of row {} failed", row);
+                    eprintln_featureflag!("failed to open file: {}", file);
+                    return Err(e);
                 }
             }
-            Ok(engine)
         }
-        Err(e) => {
-            eprintln_featureflag!("failed to open file: {}", file);
-            Err(e)
+    }
+    Ok(errors)
+}
+
+/// Seeds the accounts with the opening balances if there are any, then reads every input file and
+/// flushes the rejected transactions. Returns the number of rows with errors over all of the files.
+fn read_all(
+    options: &Options,
+    engine: &mut PaymentEngine,
+    mut rejected: Option<&mut csv::Writer<File>>,
+) -> Result<usize, Box<dyn Error>> {
+    if let Some(opening) = &options.opening {
+        read_opening_balances(opening, engine)?;
+    }
+    let errors = read_inputs_into_engine(options, engine, rejected.as_deref_mut())?;
+    if let Some(writer) = rejected {
+        writer.flush()?;
+    }
+    Ok(errors)
+}
+
+/// Counts the disputes which are still open and the amount they hold
+fn open_disputes_summary(engine: &PaymentEngine) -> (usize, f64) {
+    engine
+        .open_disputes()
+        .fold((0, 0_f64), |(count, held), transaction| {
+            (count + 1, held + transaction.held_amount())
+        })
+}
+
+/// The clients whose total is zero as it would be written even though they made more than one
+/// transaction, which can be wash activity, with their transaction counts, ordered by client id
+fn zero_net_clients(engine: &PaymentEngine, format: &OutputFormat) -> Vec<(u16, usize)> {
+    let counts = engine.transaction_counts();
+    let mut clients: Vec<(u16, usize)> = engine
+        .accounts_iter()
+        .filter(|(_, account)| account.to_view(format.scale).total == 0_f64)
+        .filter_map(|(client, _)| {
+            let count = counts.get(client).copied().unwrap_or_default();
+            (count > 1).then_some((*client, count))
+        })
+        .collect();
+    clients.sort_unstable();
+    clients
+}
+
+/// Does the account have nothing in it as it would be written, and isn't locked?
+fn is_zero_activity(account: &Account, format: &OutputFormat) -> bool {
+    let view = account.to_view(format.scale);
+    view.available == 0_f64 && view.held == 0_f64 && !view.locked
+}
+
+/// Writes the accounts with the serializer, an account which fails is reported and skipped.
+/// With `hide_zero` the zero activity accounts are left out.
+fn serialize_accounts<S>(
+    engine: &PaymentEngine,
+    serializer: &mut S,
+    format: &OutputFormat,
+    hide_zero: bool,
+) -> Result<(), Box<dyn Error>>
+where
+    S: AccountSerializer,
+    S::Error: Error + 'static,
+{
+    serializer.write_header()?;
+    for (_, account) in engine.accounts_iter() {
+        if hide_zero && is_zero_activity(account, format) {
+            continue;
+        }
+        if let Err(e) = serializer.write_account(account) {
+            eprintln_featureflag!("Failed to output an account record! {}", e);
         }
     }
+    serializer.finish()?;
+    Ok(())
 }
 
-fn usage(program: &str) {
-    println!("usage: {} [input.csv]", program);
-    println!("       Calculates account balances from a list of transactions.");
-    process::exit(0);
+/// Writes the accounts in the output kind to the output file, or to stdout if there is none.
+/// An existing output file is truncated. With `hide_zero` the zero activity accounts are left out.
+/// The csv output is quoted in `quote_style`.
+fn write_accounts(
+    engine: &PaymentEngine,
+    format: &OutputFormat,
+    output: Option<&str>,
+    hide_zero: bool,
+    kind: OutputKind,
+    quote_style: csv::QuoteStyle,
+) -> Result<(), Box<dyn Error>> {
+    let writer: Box<dyn Write> = match output {
+        Some(file) => Box::new(File::create(file)?),
+        None => Box::new(std::io::stdout()),
+    };
+    match kind {
+        OutputKind::Csv => serialize_accounts(
+            engine,
+            &mut CsvAccountSerializer::with_quote_style(writer, format.clone(), quote_style),
+            format,
+            hide_zero,
+        ),
+        OutputKind::Json => serialize_accounts(
+            engine,
+            &mut JsonAccountSerializer::new(writer, format.clone()),
+            format,
+            hide_zero,
+        ),
+        OutputKind::Table => serialize_accounts(
+            engine,
+            &mut HumanReadableAccountSerializer::new(writer, format.clone()),
+            format,
+            hide_zero,
+        ),
+    }
+}
+
+/// Writes each account as its own csv named `<client>.csv` in `dir`, in the order of the client ids.
+/// The directory is created if it doesn't exist. A file which can't be written is reported and
+/// the other files are still written.
+/// With `hide_zero` the zero activity accounts get no file. The files are quoted in `quote_style`.
+/// Returns the number of files which failed.
+fn write_per_client(
+    engine: &PaymentEngine,
+    format: &OutputFormat,
+    dir: &str,
+    hide_zero: bool,
+    quote_style: csv::QuoteStyle,
+) -> Result<usize, std::io::Error> {
+    std::fs::create_dir_all(dir)?;
+    let mut accounts: Vec<_> = engine
+        .accounts_iter()
+        .filter(|(_, account)| !(hide_zero && is_zero_activity(account, format)))
+        .collect();
+    accounts.sort_unstable_by_key(|(client, _)| **client);
+    let mut failed = 0;
+    for (client, account) in accounts {
+        let path = Path::new(dir).join(format!("{}.csv", client));
+        let result = File::create(&path)
+            .map_err(csv::Error::from)
+            .and_then(|file| {
+                let mut wtr = csv::WriterBuilder::new()
+                    .quote_style(quote_style)
+                    .from_writer(file);
+                wtr.serialize(account.formatted(format))?;
+                wtr.flush()?;
+                Ok(())
+            });
+        if let Err(e) = result {
+            failed += 1;
+            eprintln_featureflag!("failed to write {}: {}", path.display(), e);
+        }
+    }
+    Ok(failed)
 }
 
 fn main() {
     // argument validation
     let args: Vec<String> = std::env::args().collect();
-    if args.len() != 2 || args[1] == "--help" {
-        usage(&args[0]);
+    let options = parse_args(&args).unwrap_or_else(|e| e.exit());
+
+    // this structure does our accounting
+    let mut engine = PaymentEngine::default()
+        .with_ignore_duplicates(options.ignore_duplicates)
+        .with_partial_withdrawal(options.partial_withdrawal);
+    if let Some(scale) = options.scale {
+        engine = engine.with_scale(scale);
     }
 
-    // attempt to read the file
-    match read_csv_into_engine(&args[1]) {
-        Ok(engine) => {
-            let mut wtr = csv::WriterBuilder::new().from_writer(std::io::stdout());
+    // the rejected transactions of every input go to the same file
+    let mut rejected = match options.rejected.as_deref().map(csv::Writer::from_path) {
+        Some(Ok(writer)) => Some(writer),
+        Some(Err(e)) => {
+            eprintln_featureflag!("failed to create the rejected file: {}", e);
+            process::exit(-1);
+        }
+        None => None,
+    };
+
+    // seed the accounts, then attempt to read the files
+    let result = read_all(&options, &mut engine, rejected.as_mut());
+    match result {
+        Ok(errors) => {
+            let format = OutputFormat {
+                column_order: if options.legacy_columns {
+                    ColumnOrder::Legacy
+                } else {
+                    ColumnOrder::Standard
+                },
+                scale: options.scale.unwrap_or(DEFAULT_SCALE),
+                trailing_zeros: if options.pad_decimals {
+                    TrailingZeros::Pad
+                } else {
+                    TrailingZeros::Trim
+                },
+            };
+            if options.report_open_disputes {
+                let (count, held) = open_disputes_summary(&engine);
+                eprintln!(
+                    "open disputes: {} holding {:.*}",
+                    count, format.scale as usize, held
+                );
+            }
+            if options.flag_zero_net {
+                for (client, count) in zero_net_clients(&engine, &format) {
+                    eprintln!(
+                        "warning: client {} has a zero total after {} transactions",
+                        client, count
+                    );
+                }
+            }
             // write the output
-            for (_, account) in engine.accounts_iter() {
-                if let Err(e) = wtr.serialize(account) {
-                    eprintln_featureflag!("Failed to output an account record! {}", e);
+            if let Some(dir) = &options.per_client_dir {
+                match write_per_client(
+                    &engine,
+                    &format,
+                    dir,
+                    options.hide_zero,
+                    options.quote_style(),
+                ) {
+                    Ok(0) => {}
+                    Ok(failed) => {
+                        eprintln_featureflag!("failed to write {} of the account files", failed);
+                        process::exit(-1);
+                    }
+                    Err(e) => {
+                        eprintln_featureflag!("failed to create the directory {}: {}", dir, e);
+                        process::exit(-1);
+                    }
                 }
+            } else if let Err(e) = write_accounts(
+                &engine,
+                &format,
+                options.output.as_deref(),
+                options.hide_zero,
+                options.output_kind,
+                options.quote_style(),
+            ) {
+                eprintln_featureflag!("failed to write the output: {}", e);
+                process::exit(-1);
+            }
+            // the output is still written so the failure can be inspected
+            if options.fail_on_error && errors > 0 {
+                process::exit(1);
             }
         }
         Err(e) => {
@@ -80,3 +657,288 @@ fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use payment_engine::transaction::TransactionType;
+
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        std::iter::once("payment_engine")
+            .chain(args.iter().copied())
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn parse_input_only() {
+        let options = parse_args(&args(&["input.csv"])).unwrap();
+        assert_eq!(options.inputs, ["input.csv"]);
+        assert_eq!(options.opening, None);
+        assert!(!options.skip_missing);
+    }
+
+    #[test]
+    fn parse_many_inputs() {
+        let options = parse_args(&args(&["a.csv", "--skip-missing", "b.csv"])).unwrap();
+        assert_eq!(options.inputs, ["a.csv", "b.csv"]);
+        assert!(options.skip_missing);
+        assert!(!options.ignore_duplicates);
+        let options = parse_args(&args(&["--ignore-duplicates", "a.csv"])).unwrap();
+        assert!(options.ignore_duplicates);
+        let options = parse_args(&args(&["a.csv", "--partial-withdrawal"])).unwrap();
+        assert!(options.partial_withdrawal);
+        let options = parse_args(&args(&["a.csv", "--legacy-columns"])).unwrap();
+        assert!(options.legacy_columns);
+        let options = parse_args(&args(&["a.csv", "--pad-decimals"])).unwrap();
+        assert!(options.pad_decimals);
+        let options = parse_args(&args(&["a.csv", "--two-pass"])).unwrap();
+        assert!(options.two_pass);
+        let options = parse_args(&args(&["--hide-zero", "a.csv"])).unwrap();
+        assert!(options.hide_zero);
+        let options = parse_args(&args(&["--format", "json", "a.csv"])).unwrap();
+        assert_eq!(options.output_kind, OutputKind::Json);
+        assert!(parse_args(&args(&["--format", "xml", "a.csv"])).is_err());
+        let options = parse_args(&args(&["--scale", "2", "a.csv"])).unwrap();
+        assert_eq!(options.scale, Some(2));
+        let options = parse_args(&args(&["--buffer-size", "65536", "a.csv"])).unwrap();
+        assert_eq!(options.buffer_size, Some(65536));
+        let options = parse_args(&args(&["--delimiter", "|", "a.csv"])).unwrap();
+        assert_eq!(options.input_format().delimiter, b'|');
+        let options = parse_args(&args(&["--decimal-comma", "a.csv"])).unwrap();
+        assert_eq!(options.input_format().delimiter, b';');
+        assert!(options.input_format().decimal_comma);
+        let options = parse_args(&args(&["--quote-all", "a.csv"])).unwrap();
+        assert!(matches!(options.quote_style(), csv::QuoteStyle::Always));
+        let options = parse_args(&args(&["--binary", "a.bin"])).unwrap();
+        assert!(options.binary);
+    }
+
+    #[test]
+    fn parse_opening() {
+        let expected = Options {
+            inputs: vec![String::from("input.csv")],
+            opening: Some(String::from("balances.csv")),
+            ..Default::default()
+        };
+        let options = parse_args(&args(&["--opening", "balances.csv", "input.csv"])).unwrap();
+        assert_eq!(options, expected);
+        // order of the flags doesn't matter
+        let options = parse_args(&args(&["input.csv", "--opening", "balances.csv"])).unwrap();
+        assert_eq!(options, expected);
+    }
+
+    #[test]
+    fn command_is_consistent() {
+        Options::command().debug_assert();
+    }
+
+    #[test]
+    fn parse_invalid() {
+        let kind = |arguments: &[&str]| parse_args(&args(arguments)).unwrap_err().kind();
+        assert_eq!(kind(&[]), ErrorKind::MissingRequiredArgument);
+        assert_eq!(kind(&["--help"]), ErrorKind::DisplayHelp);
+        assert_eq!(kind(&["input.csv", "--opening"]), ErrorKind::InvalidValue);
+        assert_eq!(
+            kind(&["--skip-missing"]),
+            ErrorKind::MissingRequiredArgument
+        );
+        assert_eq!(
+            kind(&["input.csv", "--unknown"]),
+            ErrorKind::UnknownArgument
+        );
+        assert_eq!(
+            kind(&["input.csv", "--scale", "two"]),
+            ErrorKind::ValueValidation
+        );
+        assert_eq!(
+            kind(&["input.csv", "--scale", "16"]),
+            ErrorKind::ValueValidation
+        );
+        assert_eq!(
+            kind(&["input.csv", "--buffer-size", "-1"]),
+            ErrorKind::UnknownArgument
+        );
+        assert_eq!(
+            kind(&["input.csv", "--delimiter", ";;"]),
+            ErrorKind::ValueValidation
+        );
+        assert_eq!(
+            kind(&["a.csv", "--decimal-comma", "--delimiter", ","]),
+            ErrorKind::ArgumentConflict
+        );
+        assert_eq!(
+            kind(&["a.csv", "--quote-all", "--quote-never"]),
+            ErrorKind::ArgumentConflict
+        );
+        assert_eq!(
+            kind(&["a.bin", "--binary", "--decimal-comma"]),
+            ErrorKind::ArgumentConflict
+        );
+    }
+
+    #[test]
+    fn dispute_before_deposit() {
+        // the dispute refers to a deposit which is later in the file
+        let file = "tests/dispute_before_deposit.csv";
+        let two_pass = Options {
+            two_pass: true,
+            ..Default::default()
+        };
+        let mut engine = PaymentEngine::default();
+        assert!(read_csv_into_engine(file, &mut engine, &Options::default(), None).is_ok());
+        let (_, account) = engine.accounts_iter().next().unwrap();
+        assert_eq!(account.held(), 0.0);
+        assert_eq!(account.available(), 15.0);
+
+        let mut engine = PaymentEngine::default();
+        assert!(read_csv_into_engine(file, &mut engine, &two_pass, None).is_ok());
+        let (_, account) = engine.accounts_iter().next().unwrap();
+        assert_eq!(account.held(), 10.0);
+        assert_eq!(account.available(), 5.0);
+    }
+
+    #[test]
+    fn report_open_disputes() {
+        let options = parse_args(&args(&["--report-open-disputes", "input.csv"])).unwrap();
+        assert!(options.report_open_disputes);
+
+        // two disputes are left open, the third is resolved
+        let mut engine = PaymentEngine::default();
+        assert!(
+            read_csv_into_engine("tests/open_disputes.csv", &mut engine, &options, None).is_ok()
+        );
+        assert_eq!(open_disputes_summary(&engine), (2, 14.25));
+    }
+
+    #[test]
+    fn flag_zero_net() {
+        let options = parse_args(&args(&["--flag-zero-net", "input.csv"])).unwrap();
+        assert!(options.flag_zero_net);
+
+        let (engine, errors) = PaymentEngine::from_transactions([
+            // deposited and withdrew it all
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(10.0)),
+            Transaction::new(TransactionType::Withdrawal, 1, 2, Some(10.0)),
+            // still has money
+            Transaction::new(TransactionType::Deposit, 2, 3, Some(10.0)),
+            Transaction::new(TransactionType::Withdrawal, 2, 4, Some(5.0)),
+            // a chargeback changes the deposit, it isn't a transaction of its own
+            Transaction::new(TransactionType::Deposit, 3, 5, Some(10.0)),
+            Transaction::new(TransactionType::Dispute, 3, 5, None),
+            Transaction::new(TransactionType::Chargeback, 3, 5, None),
+            // rejected, so never had a transaction
+            Transaction::new(TransactionType::Withdrawal, 4, 6, Some(1.0)),
+        ]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            zero_net_clients(&engine, &OutputFormat::default()),
+            [(1, 2)]
+        );
+    }
+
+    #[test]
+    fn parse_per_client_dir() {
+        let options = parse_args(&args(&["--per-client-dir", "out", "input.csv"])).unwrap();
+        assert_eq!(options.per_client_dir.as_deref(), Some("out"));
+        assert_eq!(
+            parse_args(&args(&[
+                "--per-client-dir",
+                "out",
+                "--output",
+                "o.csv",
+                "a.csv"
+            ]))
+            .unwrap_err()
+            .kind(),
+            ErrorKind::ArgumentConflict
+        );
+    }
+
+    #[test]
+    fn decimal_comma() {
+        let options = parse_args(&args(&["--decimal-comma", "tests/decimal_comma.csv"])).unwrap();
+        let mut engine = PaymentEngine::default();
+        assert_eq!(
+            read_csv_into_engine(&options.inputs[0], &mut engine, &options, None).unwrap(),
+            0
+        );
+        let (_, account) = engine.accounts_iter().next().unwrap();
+        assert_eq!(account.available(), 7.25);
+    }
+
+    #[test]
+    fn raw_row_of_deserialize_error() {
+        let path = "tests/error_bad_row.csv";
+        let errors: Vec<csv::Error> =
+            Transaction::read_unvalidated_from_reader(File::open(path).unwrap())
+                .filter_map(Result::err)
+                .collect();
+        assert_eq!(errors.len(), 1);
+        let raw = RawRow {
+            path,
+            error: &errors[0],
+        };
+        assert_eq!(raw.to_string(), ", the row was 'withdrawal, 1.25, 2, 2.0'");
+        // an error without a position has no row to show
+        let error = csv::Error::from(std::io::Error::other("no position"));
+        assert_eq!(
+            RawRow {
+                path,
+                error: &error
+            }
+            .to_string(),
+            ""
+        );
+    }
+
+    #[test]
+    fn missing_file_aborts() {
+        let options = parse_args(&args(&["tests/missing.csv", "tests/a1.csv"])).unwrap();
+        let mut engine = PaymentEngine::default();
+        assert!(matches!(
+            read_inputs_into_engine(&options, &mut engine, None),
+            Err(EngineRunError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound
+        ));
+        // the run stopped before reading the second file
+        assert_eq!(engine.accounts_iter().count(), 0);
+    }
+
+    #[test]
+    fn missing_file_skipped() {
+        let options = parse_args(&args(&[
+            "--skip-missing",
+            "tests/missing.csv",
+            "tests/a1.csv",
+        ]))
+        .unwrap();
+        let mut engine = PaymentEngine::default();
+        assert!(read_inputs_into_engine(&options, &mut engine, None).is_ok());
+        // 'a1.csv' has a deposit of 6000 and a withdrawal of 3000 for client 1
+        let (client, account) = engine.accounts_iter().next().unwrap();
+        assert_eq!(*client, 1);
+        assert_eq!(account.total(), 3000.0);
+    }
+
+    #[test]
+    fn empty_table_diagnostics() {
+        assert_eq!(empty_table_diagnostic(false, 3, 2, 1), None);
+        assert_eq!(
+            empty_table_diagnostic(true, 0, 0, 0).unwrap(),
+            "file is empty"
+        );
+        assert_eq!(
+            empty_table_diagnostic(false, 0, 0, 0).unwrap(),
+            "file has no data rows"
+        );
+        assert_eq!(
+            empty_table_diagnostic(false, 2, 0, 0).unwrap(),
+            "header columns unexpected, none of the 2 rows could be read"
+        );
+        assert_eq!(
+            empty_table_diagnostic(false, 4, 3, 0).unwrap(),
+            "4 rows present but all failed validation"
+        );
+    }
+}
@@ -0,0 +1,332 @@
+use std::io::Write;
+
+use crate::{
+    account::{Account, ColumnOrder, OutputFormat},
+    transaction::engine::PaymentEngine,
+};
+
+/// Writes the account table in some output format, a header first and then one account at a time
+pub trait AccountSerializer {
+    type Error;
+
+    fn write_header(&mut self) -> Result<(), Self::Error>;
+
+    fn write_account(&mut self, account: &Account) -> Result<(), Self::Error>;
+
+    /// Called after the last account, to close the table and flush
+    fn finish(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl PaymentEngine {
+    /// Write every account with the serializer, in the order of `accounts_iter`
+    pub fn write_accounts_with<S: AccountSerializer>(
+        &self,
+        serializer: &mut S,
+    ) -> Result<(), S::Error> {
+        serializer.write_header()?;
+        for (_, account) in self.accounts_iter() {
+            serializer.write_account(account)?;
+        }
+        serializer.finish()
+    }
+}
+
+/// Names of the columns in the order of the format
+fn column_names(format: &OutputFormat) -> [&'static str; 5] {
+    match format.column_order {
+        ColumnOrder::Standard => ["client", "available", "held", "total", "locked"],
+        ColumnOrder::Legacy => ["client", "total", "available", "held", "locked"],
+    }
+}
+
+/// The CSV account table, the same as the default output of the binary
+pub struct CsvAccountSerializer<W: Write> {
+    writer: csv::Writer<W>,
+    format: OutputFormat,
+}
+
+impl<W: Write> CsvAccountSerializer<W> {
+    pub fn new(writer: W, format: OutputFormat) -> Self {
+        Self::with_quote_style(writer, format, csv::QuoteStyle::Necessary)
+    }
+
+    /// Quote the fields, header included, in the given style instead of only when they need it
+    pub fn with_quote_style(writer: W, format: OutputFormat, quote_style: csv::QuoteStyle) -> Self {
+        CsvAccountSerializer {
+            // the header is written by 'write_header' so it's there even without accounts
+            writer: csv::WriterBuilder::new()
+                .has_headers(false)
+                .quote_style(quote_style)
+                .from_writer(writer),
+            format,
+        }
+    }
+}
+
+impl<W: Write> AccountSerializer for CsvAccountSerializer<W> {
+    type Error = csv::Error;
+
+    fn write_header(&mut self) -> Result<(), csv::Error> {
+        self.writer.write_record(column_names(&self.format))
+    }
+
+    fn write_account(&mut self, account: &Account) -> Result<(), csv::Error> {
+        self.writer.serialize(account.formatted(&self.format))
+    }
+
+    fn finish(&mut self) -> Result<(), csv::Error> {
+        Ok(self.writer.flush()?)
+    }
+}
+
+/// A JSON array with an object per account, the keys are the CSV columns
+pub struct JsonAccountSerializer<W: Write> {
+    writer: W,
+    format: OutputFormat,
+    first: bool,
+}
+
+impl<W: Write> JsonAccountSerializer<W> {
+    pub fn new(writer: W, format: OutputFormat) -> Self {
+        JsonAccountSerializer {
+            writer,
+            format,
+            first: true,
+        }
+    }
+}
+
+impl<W: Write> AccountSerializer for JsonAccountSerializer<W> {
+    type Error = std::io::Error;
+
+    fn write_header(&mut self) -> Result<(), std::io::Error> {
+        self.writer.write_all(b"[")
+    }
+
+    fn write_account(&mut self, account: &Account) -> Result<(), std::io::Error> {
+        let separator: &[u8] = if self.first { b"\n" } else { b",\n" };
+        self.first = false;
+        self.writer.write_all(separator)?;
+        serde_json::to_writer(&mut self.writer, &account.formatted_numbers(&self.format))?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), std::io::Error> {
+        self.writer.write_all(b"\n]\n")?;
+        self.writer.flush()
+    }
+}
+
+/// A table for people, with the columns right aligned and the amounts padded to the scale
+pub struct HumanReadableAccountSerializer<W: Write> {
+    writer: W,
+    format: OutputFormat,
+}
+
+impl<W: Write> HumanReadableAccountSerializer<W> {
+    pub fn new(writer: W, format: OutputFormat) -> Self {
+        HumanReadableAccountSerializer { writer, format }
+    }
+}
+
+/// Width of the columns of the human readable table
+const CLIENT_WIDTH: usize = 6;
+const AMOUNT_WIDTH: usize = 16;
+const LOCKED_WIDTH: usize = 7;
+
+impl<W: Write> AccountSerializer for HumanReadableAccountSerializer<W> {
+    type Error = std::io::Error;
+
+    fn write_header(&mut self) -> Result<(), std::io::Error> {
+        let [client, a, b, c, locked] = column_names(&self.format);
+        writeln!(
+            self.writer,
+            "{:>CLIENT_WIDTH$}{:>AMOUNT_WIDTH$}{:>AMOUNT_WIDTH$}{:>AMOUNT_WIDTH$}{:>LOCKED_WIDTH$}",
+            client, a, b, c, locked
+        )
+    }
+
+    fn write_account(&mut self, account: &Account) -> Result<(), std::io::Error> {
+        let view = account.to_view(self.format.scale);
+        let amounts = match self.format.column_order {
+            ColumnOrder::Standard => [view.available, view.held, view.total],
+            ColumnOrder::Legacy => [view.total, view.available, view.held],
+        };
+        let scale = self.format.scale as usize;
+        writeln!(
+            self.writer,
+            "{:>CLIENT_WIDTH$}{:>AMOUNT_WIDTH$.scale$}{:>AMOUNT_WIDTH$.scale$}{:>AMOUNT_WIDTH$.scale$}{:>LOCKED_WIDTH$}",
+            view.client, amounts[0], amounts[1], amounts[2], view.locked
+        )
+    }
+
+    fn finish(&mut self) -> Result<(), std::io::Error> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{Transaction, TransactionType};
+
+    /// Records the calls it gets
+    #[derive(Default)]
+    struct MockSerializer {
+        calls: Vec<String>,
+    }
+
+    impl AccountSerializer for MockSerializer {
+        type Error = ();
+
+        fn write_header(&mut self) -> Result<(), ()> {
+            self.calls.push("header".to_string());
+            Ok(())
+        }
+
+        fn write_account(&mut self, account: &Account) -> Result<(), ()> {
+            self.calls.push(format!("account {}", account.client()));
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<(), ()> {
+            self.calls.push("finish".to_string());
+            Ok(())
+        }
+    }
+
+    /// A single account so the output has a fixed order, with funds available and held
+    fn one_account() -> PaymentEngine {
+        let (engine, errors) = PaymentEngine::from_transactions([
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(10.5)),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(2.0)),
+            Transaction::new(TransactionType::Dispute, 1, 2, None),
+        ]);
+        assert!(errors.is_empty());
+        engine
+    }
+
+    #[test]
+    fn serializer_calls() {
+        let mut engine = one_account();
+        engine
+            .perform_transaction(Transaction::new(TransactionType::Deposit, 2, 3, Some(1.0)))
+            .unwrap();
+        let mut mock = MockSerializer::default();
+        engine.write_accounts_with(&mut mock).unwrap();
+        assert_eq!(mock.calls.len(), 4);
+        assert_eq!(mock.calls[0], "header");
+        assert_eq!(mock.calls[3], "finish");
+        let mut accounts = mock.calls[1..3].to_vec();
+        accounts.sort();
+        assert_eq!(accounts, ["account 1", "account 2"]);
+
+        let mut mock = MockSerializer::default();
+        PaymentEngine::default()
+            .write_accounts_with(&mut mock)
+            .unwrap();
+        assert_eq!(mock.calls, ["header", "finish"]);
+    }
+
+    #[test]
+    fn csv_serializer() {
+        let mut buf = Vec::new();
+        let mut serializer = CsvAccountSerializer::new(&mut buf, OutputFormat::default());
+        one_account().write_accounts_with(&mut serializer).unwrap();
+        drop(serializer);
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "client,available,held,total,locked\n1,10.5,2.0,12.5,false\n"
+        );
+    }
+
+    #[test]
+    fn csv_serializer_quote_all() {
+        let mut buf = Vec::new();
+        let mut serializer = CsvAccountSerializer::with_quote_style(
+            &mut buf,
+            OutputFormat::default(),
+            csv::QuoteStyle::Always,
+        );
+        one_account().write_accounts_with(&mut serializer).unwrap();
+        drop(serializer);
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "\"client\",\"available\",\"held\",\"total\",\"locked\"\n\
+             \"1\",\"10.5\",\"2.0\",\"12.5\",\"false\"\n"
+        );
+    }
+
+    #[test]
+    fn json_serializer() {
+        let mut buf = Vec::new();
+        let format = OutputFormat {
+            column_order: ColumnOrder::Legacy,
+            ..Default::default()
+        };
+        one_account()
+            .write_accounts_with(&mut JsonAccountSerializer::new(&mut buf, format))
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "[\n{\"client\":1,\"total\":12.5,\"available\":10.5,\"held\":2.0,\"locked\":false}\n]\n"
+        );
+        let mut buf = Vec::new();
+        PaymentEngine::default()
+            .write_accounts_with(&mut JsonAccountSerializer::new(
+                &mut buf,
+                OutputFormat::default(),
+            ))
+            .unwrap();
+        assert_eq!(buf, b"[\n]\n");
+    }
+
+    #[test]
+    fn scale_zero_integers() {
+        let (engine, errors) = PaymentEngine::from_transactions([
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(100.0)),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(20.0)),
+            Transaction::new(TransactionType::Dispute, 1, 2, None),
+        ]);
+        assert!(errors.is_empty());
+        let format = OutputFormat {
+            scale: 0,
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        let mut serializer = CsvAccountSerializer::new(&mut buf, format.clone());
+        engine.write_accounts_with(&mut serializer).unwrap();
+        drop(serializer);
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "client,available,held,total,locked\n1,100,20,120,false\n"
+        );
+        let mut buf = Vec::new();
+        engine
+            .write_accounts_with(&mut JsonAccountSerializer::new(&mut buf, format))
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "[\n{\"client\":1,\"available\":100,\"held\":20,\"total\":120,\"locked\":false}\n]\n"
+        );
+    }
+
+    #[test]
+    fn human_readable_serializer() {
+        let mut buf = Vec::new();
+        let format = OutputFormat {
+            scale: 2,
+            ..Default::default()
+        };
+        one_account()
+            .write_accounts_with(&mut HumanReadableAccountSerializer::new(&mut buf, format))
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "client       available            held           total locked\n     \
+                  1           10.50            2.00           12.50  false\n"
+        );
+    }
+}
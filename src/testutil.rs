@@ -0,0 +1,287 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    account::{round_amount, Account, DEFAULT_SCALE},
+    transaction::{engine::PaymentEngine, DisputeStatus, TransactionType},
+};
+
+impl PaymentEngine {
+    /// Generate a deterministic transaction CSV for benchmarks and demos.
+    /// Clients take turns so their transactions are interleaved like a real feed.
+    /// Each client gets `transactions_per_client` deposits and withdrawals, a `dispute_ratio` fraction
+    /// of the deposits are followed by a dispute and a resolve on top of that.
+    /// The output is always valid input, running it through the engine produces no errors.
+    pub fn generate_test_csv(
+        clients: u16,
+        transactions_per_client: u32,
+        dispute_ratio: f64,
+        seed: u64,
+    ) -> String {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut balances: HashMap<u16, f64> = HashMap::new();
+        let mut csv = String::from("type,client,tx,amount\n");
+        let mut tx: u32 = 0;
+
+        for _ in 0..transactions_per_client {
+            for client in 1..=clients {
+                tx += 1;
+                let balance = balances.entry(client).or_default();
+                // only withdraw up to 90% of the balance so float error can't cause insufficient funds
+                let withdrawal =
+                    (*balance * 0.9 * rng.random::<f64>() * 10000_f64).floor() / 10000_f64;
+                if withdrawal > 0_f64 && rng.random_bool(0.5) {
+                    *balance -= withdrawal;
+                    writeln!(csv, "withdrawal,{},{},{:.4}", client, tx, withdrawal).unwrap();
+                } else {
+                    let deposit = rng.random_range(1..10_000_000) as f64 / 10000_f64;
+                    *balance += deposit;
+                    writeln!(csv, "deposit,{},{},{:.4}", client, tx, deposit).unwrap();
+                    if rng.random_bool(dispute_ratio) {
+                        writeln!(csv, "dispute,{},{}", client, tx).unwrap();
+                        writeln!(csv, "resolve,{},{}", client, tx).unwrap();
+                    }
+                }
+            }
+        }
+        csv
+    }
+
+    /// Panic with a readable list of the differences if the accounts in the engine aren't exactly `expected`.
+    /// The order of `expected` doesn't matter. The opening transaction isn't compared since expected
+    /// accounts are usually built without any history.
+    pub fn assert_accounts_eq(&self, expected: &[Account]) {
+        let accounts: HashMap<u16, &Account> = self
+            .accounts_iter()
+            .map(|(client, account)| (*client, account))
+            .collect();
+        let mut diff = String::new();
+        let mut seen = HashSet::new();
+        for expected in expected {
+            seen.insert(expected.client());
+            match accounts.get(&expected.client()) {
+                Some(actual) => {
+                    if !actual.eq_ignoring_history(expected) {
+                        writeln!(
+                            diff,
+                            "  client {}: expected {:?}, found {:?}",
+                            expected.client(),
+                            expected,
+                            actual
+                        )
+                        .unwrap();
+                    }
+                }
+                None => writeln!(
+                    diff,
+                    "  client {}: missing from the engine",
+                    expected.client()
+                )
+                .unwrap(),
+            }
+        }
+        let mut unexpected: Vec<&u16> = accounts
+            .keys()
+            .filter(|client| !seen.contains(client))
+            .collect();
+        unexpected.sort_unstable();
+        for client in unexpected {
+            writeln!(
+                diff,
+                "  client {}: not expected, found {:?}",
+                client, accounts[client]
+            )
+            .unwrap();
+        }
+        assert!(diff.is_empty(), "accounts differ:\n{}", diff);
+    }
+
+    /// Panic if the total of any account isn't what its history accounts for: the deposits and
+    /// adjustments less the withdrawals, less every charged back amount, with reversed transactions
+    /// undone and the corrections applied. Disputes and resolves only move funds between available
+    /// and held, so they never change the total. Compared at the output precision.
+    /// Only meaningful for engines whose every balance came from transactions, seeded balances,
+    /// a house account or records pruned by a duplicate window aren't in the history.
+    pub fn assert_conservation(&self) {
+        let mut expected: HashMap<u16, f64> = HashMap::new();
+        for transaction in self.transactions_in_order() {
+            let Some(amount) = transaction.amount() else {
+                continue;
+            };
+            let signed = match transaction.transaction_type() {
+                TransactionType::Withdrawal => -amount,
+                _ => amount,
+            };
+            let change = match transaction.dispute_status() {
+                // the chargeback takes the held amount whichever way the transaction went
+                Some(DisputeStatus::Chargeback) => signed - amount,
+                Some(DisputeStatus::Rolledback) => 0_f64,
+                _ => signed,
+            };
+            *expected.entry(transaction.client()).or_default() += change;
+        }
+        for correction in self.corrections() {
+            *expected.entry(correction.client).or_default() +=
+                correction.delta_available + correction.delta_held;
+        }
+        let mut diff = String::new();
+        let mut accounts: Vec<(&u16, &Account)> = self.accounts_iter().collect();
+        accounts.sort_unstable_by_key(|(client, _)| **client);
+        for (client, account) in accounts {
+            let expected = expected.get(client).copied().unwrap_or_default();
+            if round_amount(account.total(), DEFAULT_SCALE) != round_amount(expected, DEFAULT_SCALE)
+            {
+                writeln!(
+                    diff,
+                    "  client {}: total {} but the history accounts for {}",
+                    client,
+                    account.total(),
+                    expected
+                )
+                .unwrap();
+            }
+        }
+        assert!(diff.is_empty(), "money isn't conserved:\n{}", diff);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{engine::TransactionError, Transaction};
+
+    fn total(engine: &PaymentEngine, client: u16) -> f64 {
+        engine
+            .accounts_iter()
+            .find(|(id, _)| **id == client)
+            .map_or(0_f64, |(_, account)| account.total())
+    }
+
+    /// A random transaction, the referring ones mostly pick a tx id which exists
+    fn random_transaction(rng: &mut StdRng, tx: u32) -> Transaction {
+        let client = rng.random_range(1..=3);
+        let amount = rng.random_range(1..1_000_000) as f64 / 10000_f64;
+        let referred = rng.random_range(1..=tx);
+        match rng.random_range(0..5) {
+            0 => Transaction::new(TransactionType::Deposit, client, tx, Some(amount)),
+            1 => Transaction::new(TransactionType::Withdrawal, client, tx, Some(amount)),
+            2 => Transaction::new(TransactionType::Dispute, client, referred, None),
+            3 => Transaction::new(TransactionType::Resolve, client, referred, None),
+            _ => Transaction::new(TransactionType::Chargeback, client, referred, None),
+        }
+    }
+
+    #[test]
+    fn conservation_of_random_sequences() {
+        for seed in 0..50 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut engine = PaymentEngine::default();
+            for tx in 1..=200 {
+                let transaction = random_transaction(&mut rng, tx);
+                let (client, kind) = (transaction.client(), transaction.transaction_type());
+                let referred_amount = engine
+                    .transactions_in_order()
+                    .find(|t| t.tx() == transaction.tx())
+                    .and_then(|t| t.amount());
+                let before = total(&engine, client);
+                let result = engine.perform_transaction(transaction.clone());
+                let change = total(&engine, client) - before;
+                let allowed = match (&result, kind) {
+                    (Err(_), _) => 0_f64,
+                    (Ok(_), TransactionType::Deposit) => transaction.amount().unwrap(),
+                    (Ok(_), TransactionType::Withdrawal) => -transaction.amount().unwrap(),
+                    (Ok(_), TransactionType::Chargeback) => -referred_amount.unwrap(),
+                    (Ok(_), _) => 0_f64,
+                };
+                assert!(
+                    (change - allowed).abs() < 1e-9,
+                    "seed {} tx {}: {:?} changed the total by {} instead of {}",
+                    seed,
+                    tx,
+                    kind,
+                    change,
+                    allowed
+                );
+                if kind == TransactionType::Withdrawal {
+                    // a failed withdrawal is always for lack of funds or a locked account
+                    assert!(matches!(
+                        result,
+                        Ok(_)
+                            | Err(TransactionError::InsufficientFunds(_))
+                            | Err(TransactionError::AccountLocked(_))
+                    ));
+                }
+            }
+            engine.assert_conservation();
+        }
+    }
+
+    #[test]
+    fn conservation_violation() {
+        let mut engine = PaymentEngine::default();
+        let csv = "client,available\n1,10.0\n";
+        assert!(engine.seed_balances(csv.as_bytes()).is_ok());
+        // the seeded balance isn't in the history
+        let violation = std::panic::catch_unwind(|| engine.assert_conservation());
+        let message = *violation.unwrap_err().downcast::<String>().unwrap();
+        assert!(
+            message.contains("client 1: total 10 but the history accounts for 0"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn generated_csv_is_deterministic() {
+        let csv = PaymentEngine::generate_test_csv(5, 20, 0.25, 42);
+        assert_eq!(csv, PaymentEngine::generate_test_csv(5, 20, 0.25, 42));
+        assert_ne!(csv, PaymentEngine::generate_test_csv(5, 20, 0.25, 43));
+    }
+
+    #[test]
+    fn generated_csv_has_no_errors() {
+        let csv = PaymentEngine::generate_test_csv(10, 100, 0.5, 7);
+        let mut engine = PaymentEngine::default();
+        let mut rows = 0;
+        for transaction in Transaction::read_from_bytes(csv.as_bytes()) {
+            assert!(engine.perform_transaction(transaction.unwrap()).is_ok());
+            rows += 1;
+        }
+        let count_type = |ty: &str| csv.lines().filter(|line| line.starts_with(ty)).count();
+        assert_eq!(count_type("deposit") + count_type("withdrawal"), 10 * 100);
+        // every dispute comes with a resolve
+        assert!(count_type("dispute") > 0);
+        assert_eq!(count_type("dispute"), count_type("resolve"));
+        assert_eq!(rows, csv.lines().count() - 1);
+        assert_eq!(engine.accounts_iter().count(), 10);
+    }
+
+    #[test]
+    fn assert_accounts_eq_diff() {
+        let mut engine = PaymentEngine::default();
+        let csv = "client,available\n1,10.0\n2,5.0\n";
+        assert!(engine.seed_balances(csv.as_bytes()).is_ok());
+        let account = |client, amount| {
+            let mut account = Account::new(client);
+            account.deposit(amount);
+            account
+        };
+        engine.assert_accounts_eq(&[account(2, 5.0), account(1, 10.0)]);
+
+        let mismatch = std::panic::catch_unwind(|| {
+            engine.assert_accounts_eq(&[account(1, 11.0), account(3, 1.0)]);
+        });
+        let message = *mismatch.unwrap_err().downcast::<String>().unwrap();
+        assert!(message.contains("client 1: expected"), "{}", message);
+        assert!(message.contains("client 2: not expected"), "{}", message);
+        assert!(
+            message.contains("client 3: missing from the engine"),
+            "{}",
+            message
+        );
+    }
+}
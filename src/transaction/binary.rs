@@ -0,0 +1,209 @@
+//! Fixed width binary encoding of transactions, for input which is too big to parse as CSV.
+//!
+//! Every record is 15 bytes, little endian and without padding:
+//! - `type`: `u8`, 0 deposit, 1 withdrawal, 2 dispute, 3 resolve, 4 chargeback, 5 adjustment
+//! - `client`: `u16`
+//! - `tx`: `u32`
+//! - `amount`: `f64`, NaN for the transactions without an amount
+//!
+//! There is no header and no timestamp.
+
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+
+use crate::transaction::{Transaction, TransactionType};
+
+/// Size of an encoded transaction in bytes
+pub const BINARY_RECORD_SIZE: usize = 15;
+
+impl TransactionType {
+    /// Discriminant of the type in the binary encoding
+    const fn binary_code(self) -> u8 {
+        match self {
+            TransactionType::Deposit => 0,
+            TransactionType::Withdrawal => 1,
+            TransactionType::Dispute => 2,
+            TransactionType::Resolve => 3,
+            TransactionType::Chargeback => 4,
+            TransactionType::Adjustment => 5,
+        }
+    }
+
+    const fn from_binary_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(TransactionType::Deposit),
+            1 => Some(TransactionType::Withdrawal),
+            2 => Some(TransactionType::Dispute),
+            3 => Some(TransactionType::Resolve),
+            4 => Some(TransactionType::Chargeback),
+            5 => Some(TransactionType::Adjustment),
+            _ => None,
+        }
+    }
+}
+
+impl Transaction {
+    /// Encode the transaction as a binary record
+    pub fn write_binary<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut record = [0_u8; BINARY_RECORD_SIZE];
+        record[0] = self.transaction_type.binary_code();
+        record[1..3].copy_from_slice(&self.client.to_le_bytes());
+        record[3..7].copy_from_slice(&self.tx.to_le_bytes());
+        record[7..15].copy_from_slice(&self.amount.unwrap_or(f64::NAN).to_le_bytes());
+        writer.write_all(&record)
+    }
+
+    fn from_binary(record: &[u8; BINARY_RECORD_SIZE]) -> Result<Transaction, csv::Error> {
+        let transaction_type = TransactionType::from_binary_code(record[0]).ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown transaction type {}", record[0]),
+            )
+        })?;
+        let amount = f64::from_le_bytes(record[7..15].try_into().unwrap());
+        Ok(Transaction {
+            transaction_type,
+            client: u16::from_le_bytes(record[1..3].try_into().unwrap()),
+            tx: u32::from_le_bytes(record[3..7].try_into().unwrap()),
+            amount: (!amount.is_nan()).then_some(amount),
+            timestamp: None,
+            reason: None,
+            dispute_status: None,
+            disputed_at: None,
+            resolved_at: None,
+            chargeback_at: None,
+            dispute_reason: None,
+            held_fee: 0_f64,
+        })
+    }
+
+    /// Same as `read_from_reader` but for binary records, see the module documentation.
+    /// An unknown type is an error for that record, a truncated record at the end is an error
+    /// which ends the input.
+    pub fn read_binary<R: Read>(
+        reader: R,
+    ) -> impl Iterator<Item = Result<Transaction, csv::Error>> {
+        Self::validated(Self::read_unvalidated_binary(reader))
+    }
+
+    /// Same as `read_binary` but the transactions which fail `validate` are kept
+    pub fn read_unvalidated_binary<R: Read>(
+        reader: R,
+    ) -> impl Iterator<Item = Result<Transaction, csv::Error>> {
+        let mut reader = BufReader::new(reader);
+        let mut done = false;
+        std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            let mut record = [0_u8; BINARY_RECORD_SIZE];
+            let mut filled = 0;
+            while filled < BINARY_RECORD_SIZE {
+                match reader.read(&mut record[filled..]) {
+                    Ok(0) => break,
+                    Ok(n) => filled += n,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        done = true;
+                        return Some(Err(e.into()));
+                    }
+                }
+            }
+            match filled {
+                0 => None,
+                BINARY_RECORD_SIZE => Some(Self::from_binary(&record)),
+                _ => {
+                    done = true;
+                    Some(Err(io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        format!("truncated record of {} bytes", filled),
+                    )
+                    .into()))
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(transactions: &[Transaction]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for transaction in transactions {
+            transaction.write_binary(&mut buf).unwrap();
+        }
+        buf
+    }
+
+    #[test]
+    fn round_trip() {
+        let transactions = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(10.5)),
+            Transaction::new(
+                TransactionType::Withdrawal,
+                u16::MAX,
+                u32::MAX,
+                Some(0.0001),
+            ),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+            Transaction::new(TransactionType::Resolve, 1, 1, None),
+            Transaction::new(TransactionType::Chargeback, 1, 1, None),
+            Transaction::new(TransactionType::Adjustment, 2, 3, Some(-4.25)),
+        ];
+        let buf = encode(&transactions);
+        assert_eq!(buf.len(), transactions.len() * BINARY_RECORD_SIZE);
+        let read: Vec<Transaction> = Transaction::read_binary(buf.as_slice())
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(read.len(), transactions.len());
+        for (read, written) in read.iter().zip(&transactions) {
+            assert_eq!(read.transaction_type, written.transaction_type);
+            assert_eq!(read.client, written.client);
+            assert_eq!(read.tx, written.tx);
+            assert_eq!(read.amount, written.amount);
+        }
+    }
+
+    #[test]
+    fn binary_errors() {
+        let mut buf = encode(&[Transaction::new(TransactionType::Deposit, 1, 1, Some(1.0))]);
+        // an unknown type only fails its own record
+        let mut unknown = buf.clone();
+        unknown[0] = 9;
+        buf.extend_from_slice(&unknown);
+        // a dispute with an amount is dropped by the validation
+        buf.extend(encode(&[Transaction::new(
+            TransactionType::Dispute,
+            1,
+            1,
+            Some(1.0),
+        )]));
+        buf.extend(encode(&[Transaction::new(
+            TransactionType::Dispute,
+            1,
+            1,
+            None,
+        )]));
+        // the input ends in the middle of a record
+        buf.extend_from_slice(&[0, 1, 0]);
+        let results: Vec<_> = Transaction::read_binary(buf.as_slice()).collect();
+        assert_eq!(results.len(), 4);
+        assert!(results[0].is_ok());
+        assert!(results[1]
+            .as_ref()
+            .unwrap_err()
+            .to_string()
+            .contains("unknown transaction type 9"));
+        assert_eq!(
+            results[2].as_ref().unwrap().transaction_type,
+            TransactionType::Dispute
+        );
+        assert!(results[3]
+            .as_ref()
+            .unwrap_err()
+            .to_string()
+            .contains("truncated record of 3 bytes"));
+        assert_eq!(Transaction::read_binary(&[][..]).count(), 0);
+    }
+}
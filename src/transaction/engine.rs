@@ -1,11 +1,46 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    io::Read,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use indexmap::IndexMap;
+use serde::Deserialize;
+
+pub mod anonymize;
+pub mod corrections;
+pub mod estimate;
+pub mod events;
+pub mod fees;
+pub mod fraud;
+mod infer;
+#[cfg(feature = "iso8583")]
+pub mod iso8583;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod mt940;
+pub mod netting;
+pub mod ofx;
+#[cfg(feature = "parquet")]
+mod parquet;
+pub mod reconcile;
+pub mod recurring;
+pub mod replay;
+pub mod report;
+pub mod reversals;
+pub mod run;
+pub mod shard;
+mod validate;
 
 use crate::{
-    account::Account,
-    transaction::{Transaction, TransactionType},
+    account::{fits_scale, Account, AccountSignature, DEFAULT_SCALE, MAX_SCALE},
+    transaction::engine::corrections::Correction,
+    transaction::{skip_bom, DisputeStatus, Transaction, TransactionType},
 };
 
 /// Error type for invalid transactions
+#[derive(Debug)]
 pub enum TransactionError {
     InvalidTransaction(u32),
     DuplicateTransaction(u32),
@@ -13,10 +48,25 @@ pub enum TransactionError {
     NonPositiveAmount(u16, u32, f64),
     InsufficientFunds(u16),
     NonExistingDisputeResolveOrChargeback(u16, u32),
-    ClientMismatch(u16, u32, u16),
     InvalidDispute(u16, u32),
     InvalidResolve(u16, u32),
     InvalidChargeback(u16, u32),
+    ClientNotAllowed(u16),
+    AccountLimitReached(u16),
+    AmountExceedsLimit(u16, u32, f64, f64),
+    ExcessPrecision(u16, u32, f64),
+    NonExistingReversal(u32),
+    InvalidReversal(u16, u32),
+    DuplicateAccount(u16),
+    AggregateLimitExceeded(u16),
+    DailyWithdrawalLimitExceeded(u16, f64),
+    DisputeAlreadyResolved(u16, u32),
+    InsufficientHeld(u16, u32),
+    UnknownClient(u16),
+    MalformedMessage(String),
+    TooManyOpenDisputes(u16),
+    InvalidFeeRate(f64),
+    InvalidDepositHookResult(u16, u32, f64),
 }
 
 impl std::fmt::Display for TransactionError {
@@ -41,18 +91,22 @@ impl std::fmt::Display for TransactionError {
             TransactionError::InsufficientFunds(client) => {
                 write!(f, "client '{}' has insufficient funds", client)
             }
+            TransactionError::MalformedMessage(reason) => {
+                write!(f, "the message can't be performed: {}", reason)
+            }
+            TransactionError::InvalidFeeRate(rate) => {
+                write!(f, "the fee rate '{}' isn't a number", rate)
+            }
+            TransactionError::InvalidDepositHookResult(client, tx, credited) => write!(
+                f,
+                "client '{}' deposit '{}' would be credited '{}' by the deposit hook, which isn't between zero and the amount",
+                client, tx, credited
+            ),
             TransactionError::NonExistingDisputeResolveOrChargeback(client, tx) => write!(
                 f,
                 "client '{}' referred to transaction '{}' which doesn't exist",
                 client, tx
             ),
-            TransactionError::ClientMismatch(client, tx, tx_client) => {
-                write!(
-                    f,
-                    "client '{}' referred to transaction '{}' which belongs to client '{}'",
-                    client, tx, tx_client
-                )
-            }
             TransactionError::InvalidDispute(client, tx) => {
                 write!(f, "client '{}' can't dispute transaction '{}'", client, tx)
             }
@@ -66,77 +120,559 @@ impl std::fmt::Display for TransactionError {
                     client, tx
                 )
             }
+            TransactionError::InsufficientHeld(client, tx) => write!(
+                f,
+                "client '{}' holds less than disputed transaction '{}' would release",
+                client, tx
+            ),
+            TransactionError::TooManyOpenDisputes(client) => write!(
+                f,
+                "client '{}' has as many disputes open as it's allowed",
+                client
+            ),
+            TransactionError::DisputeAlreadyResolved(client, tx) => write!(
+                f,
+                "client '{}' already resolved the dispute of transaction '{}'",
+                client, tx
+            ),
+            TransactionError::ClientNotAllowed(client) => {
+                write!(f, "client '{}' is not on the allowlist", client)
+            }
+            TransactionError::AccountLimitReached(client) => write!(
+                f,
+                "client '{}' can't open an account, the account limit is reached",
+                client
+            ),
+            TransactionError::UnknownClient(client) => {
+                write!(f, "client '{}' has no registered account", client)
+            }
+            TransactionError::AmountExceedsLimit(client, tx, amount, ceiling) => write!(
+                f,
+                "client '{}' tried to deposit/withdraw '{}' in transaction '{}' which is over the limit of '{}'",
+                client, amount, tx, ceiling
+            ),
+            TransactionError::ExcessPrecision(client, tx, amount) => write!(
+                f,
+                "client '{}' used amount '{}' in transaction '{}' which is more precise than the currency",
+                client, amount, tx
+            ),
+            TransactionError::NonExistingReversal(tx) => {
+                write!(f, "can't reverse transaction '{}' which doesn't exist", tx)
+            }
+            TransactionError::InvalidReversal(client, tx) => {
+                write!(f, "client '{}' can't reverse transaction '{}'", client, tx)
+            }
+            TransactionError::DuplicateAccount(client) => {
+                write!(f, "account '{}' exists in more than one engine", client)
+            }
+            TransactionError::DailyWithdrawalLimitExceeded(client, limit) => write!(
+                f,
+                "client '{}' withdrew more than the daily limit of '{}'",
+                client, limit
+            ),
+            TransactionError::AggregateLimitExceeded(client) => write!(
+                f,
+                "client '{}' deposited more than the aggregate limit in total",
+                client
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
+/// What a successful transaction did to the engine
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransactionOutcome {
+    /// The transaction changed the accounts or the transaction record
+    Applied,
+    /// The transaction was applied like 'Applied' but something about it should be looked at
+    AppliedWithWarning(TransactionWarning),
+    /// Nothing changed, like an ignored replay of a duplicate transaction
+    NoOp,
+}
+
+/// Notice about a transaction which was still performed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransactionWarning {
+    /// The deposit took the total deposits of the client over the aggregate limit, see 'LimitAction::Warn'
+    AggregateLimitExceeded(u16),
+    /// The withdrawal was above the available funds and withdrew all of them instead, see
+    /// `with_partial_withdrawal`. Holds the client, the amount requested and the amount withdrawn.
+    PartialWithdrawal(u16, f64, f64),
+}
+
+impl std::fmt::Display for TransactionWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransactionWarning::AggregateLimitExceeded(client) => write!(
+                f,
+                "client '{}' deposited more than the aggregate limit",
+                client
+            ),
+            TransactionWarning::PartialWithdrawal(client, requested, fulfilled) => write!(
+                f,
+                "client '{}' requested a withdrawal of '{}' but only '{}' was available and withdrawn",
+                client, requested, fulfilled
+            ),
+        }
+    }
+}
+
+/// A row of the opening balances file
+#[derive(Debug, Deserialize)]
+struct OpeningBalance {
+    client: u16,
+    available: f64,
+}
+
+/// Error type for an opening balances file which can't be seeded
+#[derive(Debug)]
+pub enum SeedError {
+    Csv(csv::Error),
+    InvalidBalance(u16, f64),
+    ExcessPrecision(u16, f64),
+    DuplicateAccount(u16),
+    Rejected(TransactionError),
+}
+
+impl std::fmt::Display for SeedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SeedError::Csv(e) => write!(f, "csv error: {}", e),
+            SeedError::InvalidBalance(client, available) => write!(
+                f,
+                "client '{}' has an opening balance of '{}' which isn't a non-negative number",
+                client, available
+            ),
+            SeedError::ExcessPrecision(client, available) => write!(
+                f,
+                "client '{}' has an opening balance of '{}' which is more precise than the currency",
+                client, available
+            ),
+            SeedError::DuplicateAccount(client) => write!(
+                f,
+                "client '{}' has more than one opening balance or already has an account",
+                client
+            ),
+            SeedError::Rejected(e) => write!(f, "{}", e),
         }
     }
 }
 
-#[derive(Default)]
+impl std::error::Error for SeedError {}
+
+impl From<csv::Error> for SeedError {
+    fn from(e: csv::Error) -> Self {
+        SeedError::Csv(e)
+    }
+}
+
+/// What happens to a deposit which takes a client over the aggregate limit
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LimitAction {
+    /// The deposit is performed with 'TransactionWarning::AggregateLimitExceeded'
+    Warn,
+    /// The deposit isn't performed
+    Reject,
+}
+
+/// Takes the amount of a deposit and gives the amount to credit, shared by clones of the engine
+type DepositHook = Arc<Mutex<dyn FnMut(f64) -> f64 + Send>>;
+
+/// Takes the client of an account which became locked, shared by clones of the engine
+type LockHook = Arc<Mutex<dyn FnMut(u16) + Send>>;
+
+/// Checks made on every deposit and withdrawal
+#[derive(Clone, Debug, Default)]
+struct NewTransactionRules {
+    ignore_duplicates: bool, // identical replays of a deposit or withdrawal are a no-op instead of an error
+    max_transaction_amount: Option<f64>, // ceiling for a single deposit or withdrawal
+    scale: Option<u32>, // decimal places of the smallest currency unit, amounts aren't checked if 'None'
+    aggregate_limit: Option<(f64, LimitAction)>, // ceiling for the total deposits of a client
+    daily_withdrawal_limit: Option<f64>, // ceiling for the withdrawals of a client in a day
+    partial_withdrawal: bool, // a withdrawal above the available funds drains them instead of failing
+}
+
+/// Length of the days the daily withdrawal limit counts in, days start at midnight UTC
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Would the transaction open a dispute while its client has `limit` of them open already?
+/// The open disputes are counted over the whole transaction record, so it's only done with a limit.
+fn too_many_open_disputes(
+    transactions: &IndexMap<(u16, u32), Transaction>,
+    open_disputes: &HashMap<u16, usize>,
+    limit: Option<usize>,
+    transaction: &Transaction,
+) -> bool {
+    let Some(limit) = limit else {
+        return false;
+    };
+    let opens_dispute = transaction.transaction_type == TransactionType::Dispute
+        && transactions
+            .get(&transaction.key())
+            .is_some_and(|t| t.dispute_possible() && t.dispute_status.is_none());
+    opens_dispute && open_disputes.get(&transaction.client).copied().unwrap_or(0) >= limit
+}
+
+/// The running totals of a client that the new transaction counts towards
+struct RunningTotals<'a> {
+    deposits: &'a mut f64,
+    withdrawn_today: &'a mut f64,
+}
+
+#[derive(Clone, Default)]
 pub struct PaymentEngine {
     accounts: HashMap<u16, Account>,
-    transactions: HashMap<u32, Transaction>, // acceptable because transactions are globally unique, but could be under the client id
+    // an IndexMap so the transaction record keeps the order the transactions were applied in
+    transactions: IndexMap<(u16, u32), Transaction>, // keyed by client and tx, tx ids only have to be unique per client
+    allowlist: Option<HashSet<u16>>, // only these clients may transact, everyone may if 'None'
+    max_accounts: Option<usize>,     // no new accounts are opened past this many
+    corrections: Vec<Correction>,    // audit log of direct balance corrections
+    rules: NewTransactionRules,
+    deposit_totals: HashMap<u16, f64>, // sum of every deposit of a client, for the aggregate limit
+    round_trip_threshold: Option<Duration>, // chargebacks sooner than this after the deposit are suspicious
+    deduplicate: bool, // drop repeated transactions of a batch before performing it
+    duplicate_window: Option<u32>, // only tx ids this close to the newest one are kept for duplicate checks
+    newest_tx: Option<u32>, // newest tx id of the record for the duplicate window, None until it's looked up
+    inserts_since_prune: u32, // new transactions since the record was last pruned to the duplicate window
+    deposit_hook: Option<DepositHook>, // turns the amount of a deposit into the amount credited
+    house_account: Option<u16>, // receives what the deposit hook took off the deposits
+    daily_withdrawals: HashMap<(u16, Option<u64>), f64>, // withdrawn per client and day, for the daily limit
+    withdrawal_day: Option<u64>, // newest day of a dated withdrawal, the days before it are dropped
+    lock_hook: Option<LockHook>, // called with the client when an account becomes locked
+    require_registered: bool,    // clients without an account get 'UnknownClient' instead of one
+    max_open_disputes: Option<usize>, // disputes of a client past this many open ones get 'TooManyOpenDisputes'
+    open_disputes: HashMap<u16, usize>, // number of open disputes of each client, without the clients with none
 }
 
 impl PaymentEngine {
+    /// Only allow the given clients to transact, everyone else gets 'ClientNotAllowed'
+    pub fn with_client_allowlist(mut self, allowlist: HashSet<u16>) -> Self {
+        self.allowlist = Some(allowlist);
+        self
+    }
+
+    /// Treat a deposit or withdrawal identical to an earlier one with the same client and tx id as a no-op.
+    /// A reused tx id with different content is still a 'DuplicateTransaction'.
+    pub fn with_ignore_duplicates(mut self, ignore_duplicates: bool) -> Self {
+        self.rules.ignore_duplicates = ignore_duplicates;
+        self
+    }
+
+    /// Limit how many disputes a client can have open at once, a dispute past the limit gets
+    /// 'TooManyOpenDisputes'. Resolving or charging back a dispute frees its slot.
+    pub fn with_max_open_disputes_per_client(mut self, limit: usize) -> Self {
+        self.max_open_disputes = Some(limit);
+        self
+    }
+
+    /// Limit how many accounts can be opened, clients without an account past the limit get
+    /// 'AccountLimitReached' while existing accounts keep transacting
+    pub fn with_max_accounts(mut self, limit: usize) -> Self {
+        self.max_accounts = Some(limit);
+        self
+    }
+
+    /// Only let the clients with an account transact, the rest get 'UnknownClient' instead of
+    /// having one opened for them. Accounts are registered with `seed_balances` before this is
+    /// turned on, the house account is still opened when it's first credited.
+    pub fn with_require_registered(mut self, require_registered: bool) -> Self {
+        self.require_registered = require_registered;
+        self
+    }
+
+    /// Reject any single deposit or withdrawal above the ceiling with 'AmountExceedsLimit'
+    pub fn with_max_transaction_amount(mut self, ceiling: f64) -> Self {
+        self.rules.max_transaction_amount = Some(ceiling);
+        self
+    }
+
+    /// Reject amounts with more decimal places than `scale` with 'ExcessPrecision',
+    /// for example a scale of 0 for JPY or 3 for BHD.
+    /// Panics if `scale` is above `MAX_SCALE`.
+    pub fn with_scale(mut self, scale: u32) -> Self {
+        assert!(
+            scale <= MAX_SCALE,
+            "a scale can't be above {} decimal places",
+            MAX_SCALE
+        );
+        self.rules.scale = Some(scale);
+        self
+    }
+
+    /// Decimal places the amounts are checked, compared and reported at, `DEFAULT_SCALE` unless
+    /// `with_scale` set one
+    pub fn scale(&self) -> u32 {
+        self.rules.scale.unwrap_or(DEFAULT_SCALE)
+    }
+
+    /// Let a withdrawal above the available funds withdraw all of them instead of failing.
+    /// It's recorded with the amount withdrawn and its outcome carries 'TransactionWarning::PartialWithdrawal'.
+    pub fn with_partial_withdrawal(mut self, partial_withdrawal: bool) -> Self {
+        self.rules.partial_withdrawal = partial_withdrawal;
+        self
+    }
+
+    /// Watch the total a client deposits during the run, a deposit which takes it over `limit` gets
+    /// 'AggregateLimitExceeded'. With 'LimitAction::Warn' the deposit is still performed and its
+    /// outcome carries the warning instead.
+    pub fn with_aggregate_limit(mut self, limit: f64, action: LimitAction) -> Self {
+        self.rules.aggregate_limit = Some((limit, action));
+        self
+    }
+
+    /// Only check duplicates against the `window` tx ids before the newest one, older records are
+    /// pruned to bound the memory of long streams. This assumes the tx ids are roughly sequential:
+    /// a duplicate of a pruned tx id slips through and pruned transactions can't be disputed.
+    /// Transactions under dispute are always kept so they can still be resolved or charged back.
+    pub fn with_duplicate_window(mut self, window: u32) -> Self {
+        self.duplicate_window = Some(window);
+        self
+    }
+
+    /// Note the new transaction `tx` and drop the records outside the duplicate window, only once
+    /// every `window` new transactions so the cost of a prune is spread over them
+    fn prune_outside_duplicate_window(&mut self, tx: u32) {
+        let Some(window) = self.duplicate_window else {
+            return;
+        };
+        let newest = match self.newest_tx {
+            Some(newest) => newest.max(tx),
+            // the record was rebuilt since the last transaction, look it up once
+            None => self
+                .transactions
+                .keys()
+                .map(|&(_, tx)| tx)
+                .fold(tx, u32::max),
+        };
+        self.newest_tx = Some(newest);
+        self.inserts_since_prune += 1;
+        if self.inserts_since_prune < window {
+            return;
+        }
+        self.inserts_since_prune = 0;
+        self.transactions
+            .retain(|&(_, tx), t| tx.saturating_add(window) >= newest || t.in_dispute());
+    }
+
+    /// Transform the amount of every deposit before it's credited, for example to take a fee.
+    /// The checks are made on the amount as given, while the account and the transaction record
+    /// get the amount the hook returns, so a dispute holds what was actually credited.
+    /// Replays of a deposit are compared to the recorded amount, so `with_ignore_duplicates` won't
+    /// recognise them if the hook changed it. Clones of the engine share the hook.
+    /// A deposit the hook returns NaN, a negative amount or more than the amount for gets
+    /// 'InvalidDepositHookResult' and isn't credited.
+    pub fn set_deposit_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(f64) -> f64 + Send + 'static,
+    {
+        self.deposit_hook = Some(Arc::new(Mutex::new(hook)));
+    }
+
+    /// Call `hook` with the client whenever an account becomes locked, by a chargeback or by
+    /// `freeze_accounts_above_chargeback_ratio`. It's called once per account, transactions
+    /// rejected because the account is locked don't call it again. Clones of the engine share
+    /// the hook.
+    pub fn on_lock<F>(&mut self, hook: F)
+    where
+        F: FnMut(u16) + Send + 'static,
+    {
+        self.lock_hook = Some(Arc::new(Mutex::new(hook)));
+    }
+
+    /// Credit the difference the deposit hook takes off each deposit to the account of `client`.
+    /// The house account is opened if needed and is credited even if it's locked or over the account limit.
+    pub fn with_house_account(mut self, client: u16) -> Self {
+        self.house_account = Some(client);
+        self
+    }
+
+    /// Limit what a client can withdraw in a day, a withdrawal which takes the total of its day over
+    /// `limit` gets 'DailyWithdrawalLimitExceeded'. The day comes from the timestamp of the
+    /// withdrawal, the withdrawals without a timestamp all count towards one undated day.
+    /// Only the newest day is remembered, a withdrawal dated before it starts that day over.
+    pub fn with_daily_withdrawal_limit(mut self, limit: f64) -> Self {
+        self.rules.daily_withdrawal_limit = Some(limit);
+        self
+    }
+
+    /// Allow a client to transact, starts an allowlist if the engine didn't have one
+    pub fn add_to_allowlist(&mut self, client: u16) {
+        self.allowlist
+            .get_or_insert_with(HashSet::new)
+            .insert(client);
+    }
+
+    /// Stop a client from transacting, does nothing if the engine has no allowlist
+    pub fn remove_from_allowlist(&mut self, client: u16) {
+        if let Some(allowlist) = &mut self.allowlist {
+            allowlist.remove(&client);
+        }
+    }
+
     pub fn perform_transaction(
         &mut self,
         transaction: Transaction,
-    ) -> Result<(), TransactionError> {
+    ) -> Result<TransactionOutcome, TransactionError> {
         // Reading the function body will make these helpers easier to understand
 
-        /// Withdrawals and Deposits create new transactions in the transaction record
+        /// Withdrawals, Deposits and Adjustments create new transactions in the transaction record
         fn new_transaction(
-            transactions: &mut HashMap<u32, Transaction>,
+            transactions: &mut IndexMap<(u16, u32), Transaction>,
             account: &mut Account,
-            transaction: Transaction,
-        ) -> Result<(), TransactionError> {
+            mut transaction: Transaction,
+            rules: &NewTransactionRules,
+            totals: RunningTotals,
+            deposit_hook: Option<&DepositHook>,
+            fee: &mut f64,
+        ) -> Result<TransactionOutcome, TransactionError> {
             // assume that the transaction is a valid format before this function is called
             let amount = transaction.amount.unwrap();
-            // check for duplicate transactions
-            if transactions.contains_key(&transaction.tx) {
+            // check for duplicate transactions, an identical replay may be let through as a no-op
+            if let Some(previous_transaction) = transactions.get(&transaction.key()) {
+                let identical = previous_transaction.transaction_type
+                    == transaction.transaction_type
+                    && previous_transaction.amount == transaction.amount;
+                if rules.ignore_duplicates && identical {
+                    return Ok(TransactionOutcome::NoOp);
+                }
                 return Err(TransactionError::DuplicateTransaction(transaction.tx));
             }
+            // adjustments are made by an operator, they're signed and not held to the limits
+            let is_adjustment = transaction.transaction_type == TransactionType::Adjustment;
             // check for non-positive amounts
-            if amount <= 0_f64 {
+            if !is_adjustment && amount <= 0_f64 {
                 return Err(TransactionError::NonPositiveAmount(
                     transaction.client,
                     transaction.tx,
                     amount,
                 ));
             }
+            // check the amount is representable in the currency
+            if rules
+                .scale
+                .is_some_and(|scale| !transaction.fits_scale(scale))
+            {
+                return Err(TransactionError::ExcessPrecision(
+                    transaction.client,
+                    transaction.tx,
+                    amount,
+                ));
+            }
+            // check the amount against the ceiling, only after it's known to be positive
+            if let Some(ceiling) = rules.max_transaction_amount {
+                if !is_adjustment && amount > ceiling {
+                    return Err(TransactionError::AmountExceedsLimit(
+                        transaction.client,
+                        transaction.tx,
+                        amount,
+                        ceiling,
+                    ));
+                }
+            }
+            let is_deposit = transaction.transaction_type == TransactionType::Deposit;
+            // check the total deposits, a warning only comes with the outcome once the deposit is performed
+            let mut exceeds_aggregate = false;
+            // a withdrawal which could only be partly fulfilled, reported once it's performed
+            let mut shortfall = None;
+            if let Some((limit, action)) = rules.aggregate_limit {
+                if is_deposit && *totals.deposits + amount > limit {
+                    if action == LimitAction::Reject {
+                        return Err(TransactionError::AggregateLimitExceeded(transaction.client));
+                    }
+                    exceeds_aggregate = true;
+                }
+            }
+            // check what the client has withdrawn on the day of the withdrawal
+            if let Some(limit) = rules.daily_withdrawal_limit {
+                let is_withdrawal = transaction.transaction_type == TransactionType::Withdrawal;
+                if is_withdrawal && *totals.withdrawn_today + amount > limit {
+                    return Err(TransactionError::DailyWithdrawalLimitExceeded(
+                        transaction.client,
+                        limit,
+                    ));
+                }
+            }
             match transaction.transaction_type {
-                TransactionType::Deposit => account.deposit(amount),
+                TransactionType::Deposit => {
+                    let credited = match deposit_hook {
+                        Some(hook) => (hook.lock().unwrap())(amount),
+                        None => amount,
+                    };
+                    if !(0_f64..=amount).contains(&credited) {
+                        return Err(TransactionError::InvalidDepositHookResult(
+                            transaction.client,
+                            transaction.tx,
+                            credited,
+                        ));
+                    }
+                    account.deposit(credited);
+                    *totals.deposits += amount;
+                    *fee = amount - credited;
+                    // the record keeps what was credited, that's what a dispute can take back
+                    transaction.amount = Some(credited);
+                }
                 TransactionType::Withdrawal => {
                     if !account.withdrawal(amount) {
-                        return Err(TransactionError::InsufficientFunds(transaction.client));
+                        if !rules.partial_withdrawal || account.available() <= 0_f64 {
+                            return Err(TransactionError::InsufficientFunds(transaction.client));
+                        }
+                        // the record keeps what was withdrawn, that's what a dispute can give back
+                        let fulfilled = account.withdraw_available();
+                        transaction.amount = Some(fulfilled);
+                        shortfall = Some(fulfilled);
                     }
+                    *totals.withdrawn_today += transaction.amount.unwrap();
                 }
+                TransactionType::Adjustment => account.adjust(amount),
                 _ => unreachable!(),
             }
-            transactions.insert(transaction.tx, transaction);
-            Ok(())
+            let client = transaction.client;
+            transactions.insert(transaction.key(), transaction);
+            if exceeds_aggregate {
+                return Ok(TransactionOutcome::AppliedWithWarning(
+                    TransactionWarning::AggregateLimitExceeded(client),
+                ));
+            }
+            if let Some(fulfilled) = shortfall {
+                return Ok(TransactionOutcome::AppliedWithWarning(
+                    TransactionWarning::PartialWithdrawal(client, amount, fulfilled),
+                ));
+            }
+            Ok(TransactionOutcome::Applied)
         }
         /// Disputes, Resolves and Chargebacks refer to older transactions
         fn referring_transaction(
-            transactions: &mut HashMap<u32, Transaction>,
+            transactions: &mut IndexMap<(u16, u32), Transaction>,
             account: &mut Account,
             transaction: Transaction,
-        ) -> Result<(), TransactionError> {
-            // transaction refers to an old transaction
-            let tx = transactions.get_mut(&transaction.tx);
+            scale: u32,
+        ) -> Result<TransactionOutcome, TransactionError> {
+            // transaction refers to an old transaction of the same client
+            let tx = transactions.get_mut(&transaction.key());
             // make sure the old transaction exists
             if let Some(previous_transaction) = tx {
-                if transaction.client != previous_transaction.client {
-                    return Err(TransactionError::ClientMismatch(
+                // the held funds have to cover what a resolve or chargeback releases, they can't
+                // go negative whichever way the balances got here
+                let releases_held = matches!(
+                    transaction.transaction_type,
+                    TransactionType::Resolve | TransactionType::Chargeback
+                ) && previous_transaction.dispute_status
+                    == Some(DisputeStatus::Disputed);
+                if releases_held && !account.holds(previous_transaction.held_amount(), scale) {
+                    return Err(TransactionError::InsufficientHeld(
                         transaction.client,
                         transaction.tx,
-                        previous_transaction.client,
                     ));
                 }
                 // try the transaction action, if it succeeds apply the action on the account too
                 match transaction.transaction_type {
                     TransactionType::Dispute => {
                         if previous_transaction.dispute() {
-                            account.dispute(previous_transaction.amount.unwrap());
+                            previous_transaction.disputed_at = transaction.timestamp;
+                            previous_transaction.dispute_reason = transaction.reason;
+                            account.dispute(previous_transaction.disputed_amount());
                         } else {
                             return Err(TransactionError::InvalidDispute(
                                 transaction.client,
@@ -146,7 +682,15 @@ impl PaymentEngine {
                     }
                     TransactionType::Resolve => {
                         if previous_transaction.resolve() {
-                            account.resolve(previous_transaction.amount.unwrap());
+                            previous_transaction.resolved_at = transaction.timestamp;
+                            account.resolve(previous_transaction.held_amount());
+                        } else if previous_transaction.dispute_status
+                            == Some(DisputeStatus::Resolved)
+                        {
+                            return Err(TransactionError::DisputeAlreadyResolved(
+                                transaction.client,
+                                transaction.tx,
+                            ));
                         } else {
                             return Err(TransactionError::InvalidResolve(
                                 transaction.client,
@@ -156,7 +700,15 @@ impl PaymentEngine {
                     }
                     TransactionType::Chargeback => {
                         if previous_transaction.chargeback() {
-                            account.chargeback(previous_transaction.amount.unwrap());
+                            previous_transaction.chargeback_at = transaction.timestamp;
+                            account.chargeback(previous_transaction.held_amount());
+                        } else if previous_transaction.dispute_status
+                            == Some(DisputeStatus::Resolved)
+                        {
+                            return Err(TransactionError::DisputeAlreadyResolved(
+                                transaction.client,
+                                transaction.tx,
+                            ));
                         } else {
                             return Err(TransactionError::InvalidChargeback(
                                 transaction.client,
@@ -173,13 +725,14 @@ impl PaymentEngine {
                     transaction.tx,
                 ));
             }
-            Ok(())
+            Ok(TransactionOutcome::Applied)
         }
+        self.check_client(transaction.client, 0)?;
         // get customer account or create it if we've never seen it before
         let account = self
             .accounts
             .entry(transaction.client)
-            .or_insert_with(|| Account::new(transaction.client));
+            .or_insert_with(|| Account::new(transaction.client).with_first_tx(transaction.tx));
 
         // attempt the transaction if the account is not locked
         if !account.locked() {
@@ -191,19 +744,316 @@ impl PaymentEngine {
             // perform the transaction on the account
             // transactions are grouped into making a new entry OR referring/modifying an old one
             if transaction.transaction_type.is_new_transaction() {
-                new_transaction(&mut self.transactions, account, transaction)
+                let tx = transaction.tx;
+                // only withdrawals get an entry for their day, and only when there is a limit
+                let mut not_tracked = 0_f64;
+                let withdrawn_today = if transaction.transaction_type == TransactionType::Withdrawal
+                    && self.rules.daily_withdrawal_limit.is_some()
+                {
+                    let day = transaction.timestamp.map(|t| t / SECONDS_PER_DAY);
+                    // a newer day ends the older ones, drop them so the map stays a day long
+                    if day > self.withdrawal_day {
+                        self.withdrawal_day = day;
+                        self.daily_withdrawals
+                            .retain(|&(_, past), _| past.is_none() || past >= day);
+                    }
+                    self.daily_withdrawals
+                        .entry((transaction.client, day))
+                        .or_default()
+                } else {
+                    &mut not_tracked
+                };
+                let totals = RunningTotals {
+                    deposits: self.deposit_totals.entry(transaction.client).or_default(),
+                    withdrawn_today,
+                };
+                let mut fee = 0_f64;
+                let outcome = new_transaction(
+                    &mut self.transactions,
+                    account,
+                    transaction,
+                    &self.rules,
+                    totals,
+                    self.deposit_hook.as_ref(),
+                    &mut fee,
+                );
+                if let Some(house) = self.house_account.filter(|_| fee != 0_f64) {
+                    self.accounts
+                        .entry(house)
+                        .or_insert_with(|| Account::new(house))
+                        .deposit(fee);
+                }
+                if outcome.is_ok() {
+                    self.prune_outside_duplicate_window(tx);
+                }
+                outcome
             } else {
-                referring_transaction(&mut self.transactions, account, transaction)
+                let client = transaction.client;
+                if too_many_open_disputes(
+                    &self.transactions,
+                    &self.open_disputes,
+                    self.max_open_disputes,
+                    &transaction,
+                ) {
+                    return Err(TransactionError::TooManyOpenDisputes(client));
+                }
+                let transaction_type = transaction.transaction_type;
+                let outcome = referring_transaction(
+                    &mut self.transactions,
+                    account,
+                    transaction,
+                    self.rules.scale.unwrap_or(DEFAULT_SCALE),
+                );
+                if outcome.is_ok() {
+                    // every successful dispute opens one, every resolve or chargeback closes one
+                    let open = self.open_disputes.entry(client).or_default();
+                    if transaction_type == TransactionType::Dispute {
+                        *open += 1;
+                    } else {
+                        *open = open.saturating_sub(1);
+                    }
+                    if *open == 0 {
+                        self.open_disputes.remove(&client);
+                    }
+                }
+                // the account was unlocked before, so a lock now is a transition
+                if let Some(hook) = self.lock_hook.as_ref().filter(|_| account.locked()) {
+                    (hook.lock().unwrap())(client);
+                }
+                outcome
             }
         } else {
             Err(TransactionError::AccountLocked(transaction.client))
         }
     }
 
+    /// Drop the repeated transactions of a batch given to `perform_transactions` before it's performed
+    pub fn with_deduplication(mut self, deduplicate: bool) -> Self {
+        self.deduplicate = deduplicate;
+        self
+    }
+
+    /// Keep the first occurrence of each transaction and drop the repeats, the order is kept.
+    /// Transactions are the same if they have the same client, tx id and type, since disputes,
+    /// resolves and chargebacks share the tx id of the transaction they refer to.
+    /// Returns the remaining transactions and the client and tx id of the dropped ones.
+    pub fn merge_duplicate_transactions<I>(txs: I) -> (Vec<Transaction>, Vec<(u16, u32)>)
+    where
+        I: IntoIterator<Item = Transaction>,
+    {
+        let mut seen = HashSet::new();
+        let mut dropped = Vec::new();
+        let merged = txs
+            .into_iter()
+            .filter(|t| {
+                let first = seen.insert((t.client, t.tx, t.transaction_type));
+                if !first {
+                    dropped.push(t.key());
+                }
+                first
+            })
+            .collect();
+        (merged, dropped)
+    }
+
+    /// A default engine with every transaction performed in order, and the errors they raised
+    pub fn from_transactions<I>(txs: I) -> (PaymentEngine, Vec<TransactionError>)
+    where
+        I: IntoIterator<Item = Transaction>,
+    {
+        let mut engine = PaymentEngine::default();
+        let errors = engine.perform_transactions(txs);
+        (engine, errors)
+    }
+
+    /// Perform every transaction in order and collect the errors.
+    /// With deduplication the whole batch is read first to drop the repeats.
+    pub fn perform_transactions<I>(&mut self, txs: I) -> Vec<TransactionError>
+    where
+        I: IntoIterator<Item = Transaction>,
+    {
+        let txs: Box<dyn Iterator<Item = Transaction>> = if self.deduplicate {
+            Box::new(Self::merge_duplicate_transactions(txs).0.into_iter())
+        } else {
+            Box::new(txs.into_iter())
+        };
+        txs.filter_map(|transaction| self.perform_transaction(transaction).err())
+            .collect()
+    }
+
+    /// Perform every transaction of the batch or none of them, the batch runs against a copy of the
+    /// engine which only replaces `self` if all transactions succeed. On failure the number of
+    /// transactions which succeeded is returned with all of the errors and `self` is unchanged.
+    /// The lock hook only hears of the accounts a batch locked once the batch replaced `self`.
+    pub fn apply_batch_atomically<I>(
+        &mut self,
+        txs: I,
+    ) -> Result<usize, (usize, Vec<TransactionError>)>
+    where
+        I: IntoIterator<Item = Transaction>,
+    {
+        let mut batch = self.clone();
+        // the copy shares the hook, a batch which is thrown away mustn't call it
+        batch.lock_hook = None;
+        let mut applied = 0;
+        let mut errors = Vec::new();
+        for transaction in txs {
+            match batch.perform_transaction(transaction) {
+                Ok(_) => applied += 1,
+                Err(e) => errors.push(e),
+            }
+        }
+        if !errors.is_empty() {
+            return Err((applied, errors));
+        }
+        let mut locked: Vec<u16> = batch
+            .accounts
+            .values()
+            .filter(|account| {
+                account.locked()
+                    && !self
+                        .accounts
+                        .get(&account.client())
+                        .is_some_and(Account::locked)
+            })
+            .map(Account::client)
+            .collect();
+        locked.sort_unstable();
+        batch.lock_hook = self.lock_hook.take();
+        *self = batch;
+        if let Some(hook) = &self.lock_hook {
+            for client in locked {
+                (hook.lock().unwrap())(client);
+            }
+        }
+        Ok(applied)
+    }
+
+    /// The checks on a client before it can transact: the allowlist, and for a client without an
+    /// account the account limit and the registration. The limit counts the `opening` accounts
+    /// which are about to be opened too.
+    fn check_client(&self, client: u16, opening: usize) -> Result<(), TransactionError> {
+        // clients that aren't allowed don't get an account
+        if let Some(allowlist) = &self.allowlist {
+            if !allowlist.contains(&client) {
+                return Err(TransactionError::ClientNotAllowed(client));
+            }
+        }
+        let known = self.accounts.contains_key(&client);
+        // check the limit before a new account is inserted
+        if let Some(limit) = self.max_accounts {
+            if self.accounts.len() + opening >= limit && !known {
+                return Err(TransactionError::AccountLimitReached(client));
+            }
+        }
+        if self.require_registered && !known {
+            return Err(TransactionError::UnknownClient(client));
+        }
+        Ok(())
+    }
+
+    /// Preload accounts from a `client,available` CSV before any transactions are performed.
+    /// The seeded amount has no originating transaction, so it can never be disputed.
+    /// A row that fails to deserialize, a negative, NaN or infinite balance, a balance with more
+    /// decimal places than the scale and a client listed twice or which already has an account
+    /// abort the seeding, and then no account is seeded. A client the engine wouldn't open an
+    /// account for is rejected with the error of `perform_transaction`.
+    pub fn seed_balances<R: Read>(&mut self, reader: R) -> Result<(), SeedError> {
+        let opening_balances = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All) // allow whitespace
+            .from_reader(skip_bom(reader))
+            .into_deserialize::<OpeningBalance>();
+        let mut seeded = HashMap::new();
+        for opening in opening_balances {
+            let opening = opening?;
+            let client = opening.client;
+            if !opening.available.is_finite() || opening.available < 0_f64 {
+                return Err(SeedError::InvalidBalance(client, opening.available));
+            }
+            if seeded.contains_key(&client) || self.accounts.contains_key(&client) {
+                return Err(SeedError::DuplicateAccount(client));
+            }
+            self.check_client(client, seeded.len())
+                .map_err(SeedError::Rejected)?;
+            if self
+                .rules
+                .scale
+                .is_some_and(|scale| !fits_scale(opening.available, scale))
+            {
+                return Err(SeedError::ExcessPrecision(client, opening.available));
+            }
+            let mut account = Account::new(client);
+            account.deposit(opening.available);
+            seeded.insert(client, account);
+        }
+        self.accounts.extend(seeded);
+        Ok(())
+    }
+
+    /// Sign every account with a shared secret, keyed by client id
+    pub fn sign_all_accounts(&self, secret: &[u8]) -> HashMap<u16, AccountSignature> {
+        self.accounts
+            .iter()
+            .map(|(client, account)| (*client, account.sign(secret)))
+            .collect()
+    }
+
+    /// Check that every account has a valid signature and that no signed account is missing
+    pub fn verify_all_accounts(
+        &self,
+        signatures: &HashMap<u16, AccountSignature>,
+        secret: &[u8],
+    ) -> bool {
+        signatures.len() == self.accounts.len()
+            && self.accounts.iter().all(|(client, account)| {
+                signatures
+                    .get(client)
+                    .is_some_and(|sig| account.verify(sig, secret))
+            })
+    }
+
+    /// Iterate over the recorded deposits and withdrawals in the order they were applied
+    pub fn transactions_in_order(&self) -> impl Iterator<Item = &Transaction> {
+        self.transactions.values()
+    }
+
+    /// Iterate over the transactions which are currently disputed, in the order they were applied
+    pub fn open_disputes(&self) -> impl Iterator<Item = &Transaction> {
+        self.transactions
+            .values()
+            .filter(|t| t.dispute_status == Some(DisputeStatus::Disputed))
+    }
+
+    /// Count the deposits, withdrawals and adjustments in the transaction record of each client.
+    /// Disputes, resolves and chargebacks change a recorded transaction so they aren't counted,
+    /// and neither are records pruned by `with_duplicate_window`.
+    pub fn transaction_counts(&self) -> HashMap<u16, usize> {
+        let mut counts = HashMap::new();
+        for transaction in self.transactions.values() {
+            *counts.entry(transaction.client).or_default() += 1;
+        }
+        counts
+    }
+
     /// Iterate over all of the accounts in the engine
     pub fn accounts_iter(&self) -> impl Iterator<Item = (&u16, &Account)> {
         self.accounts.iter()
     }
+
+    /// Iterate over the accounts in chunks of `chunk_size`, the last one can be smaller.
+    /// Lets a caller write and flush a chunk at a time without collecting every account.
+    /// Panics if `chunk_size` is zero.
+    pub fn accounts_iter_by_chunk(
+        &self,
+        chunk_size: usize,
+    ) -> impl Iterator<Item = Vec<(&u16, &Account)>> {
+        assert!(chunk_size > 0, "chunks need at least one account");
+        let mut accounts = self.accounts.iter();
+        std::iter::from_fn(move || {
+            let chunk: Vec<_> = accounts.by_ref().take(chunk_size).collect();
+            (!chunk.is_empty()).then_some(chunk)
+        })
+    }
 }
 
 #[cfg(test)]
@@ -243,6 +1093,97 @@ mod tests {
         });
     }
 
+    #[test]
+    fn ignore_identical_duplicate() {
+        let mut engine = PaymentEngine::default().with_ignore_duplicates(true);
+        let transaction = Transaction::new(TransactionType::Deposit, 1, 1, Some(1.0));
+        assert_eq!(
+            engine.perform_transaction(transaction.clone()).unwrap(),
+            TransactionOutcome::Applied
+        );
+        // replaying the same transaction does nothing
+        assert_eq!(
+            engine.perform_transaction(transaction).unwrap(),
+            TransactionOutcome::NoOp
+        );
+        assert_eq!(engine.accounts[&1].available(), 1.0);
+    }
+
+    #[test]
+    fn duplicate_window() {
+        let deposit = |tx| Transaction::new(TransactionType::Deposit, 1, tx, Some(1.0));
+        let mut engine = PaymentEngine::default().with_duplicate_window(10);
+        for tx in 1..=5 {
+            engine.perform_transaction(deposit(tx)).unwrap();
+        }
+        engine
+            .perform_transaction(Transaction::new(TransactionType::Dispute, 1, 2, None))
+            .unwrap();
+        // inside the window
+        assert!(matches!(
+            engine.perform_transaction(deposit(3)).unwrap_err(),
+            TransactionError::DuplicateTransaction(3)
+        ));
+        for tx in 6..=100 {
+            engine.perform_transaction(deposit(tx)).unwrap();
+        }
+        assert!(engine.transactions.len() <= 2 * 10 + 1);
+        assert!(matches!(
+            engine.perform_transaction(deposit(95)).unwrap_err(),
+            TransactionError::DuplicateTransaction(95)
+        ));
+        // far outside the window the old record is gone and the duplicate slips through
+        assert_eq!(
+            engine.perform_transaction(deposit(3)).unwrap(),
+            TransactionOutcome::Applied
+        );
+        // the open dispute was kept and can still be resolved
+        assert!(engine.transactions.contains_key(&(1, 2)));
+        engine
+            .perform_transaction(Transaction::new(TransactionType::Resolve, 1, 2, None))
+            .unwrap();
+    }
+
+    #[test]
+    fn duplicate_window_with_open_disputes() {
+        let deposit = |tx| Transaction::new(TransactionType::Deposit, 1, tx, Some(1.0));
+        let mut engine = PaymentEngine::default().with_duplicate_window(10);
+        for tx in 1..=50 {
+            engine.perform_transaction(deposit(tx)).unwrap();
+            engine
+                .perform_transaction(Transaction::new(TransactionType::Dispute, 1, tx, None))
+                .unwrap();
+        }
+        // the open disputes don't make every new transaction prune
+        for tx in 51..=200 {
+            engine.perform_transaction(deposit(tx)).unwrap();
+            assert!(engine.inserts_since_prune < 10);
+        }
+        assert_eq!(engine.newest_tx, Some(200));
+        assert!(engine.transactions.len() <= 50 + 2 * 10);
+        // a record rebuilt without the newest tx id looks it up again
+        engine.rekey_transaction_ids(|tx| tx + 1000).unwrap();
+        assert_eq!(engine.newest_tx, None);
+        engine.perform_transaction(deposit(1150)).unwrap();
+        assert_eq!(engine.newest_tx, Some(1200));
+    }
+
+    #[test]
+    fn conflicting_duplicate_with_ignore() {
+        let mut engine = PaymentEngine::default().with_ignore_duplicates(true);
+        let res =
+            engine.perform_transaction(Transaction::new(TransactionType::Deposit, 1, 1, Some(1.0)));
+        assert!(res.is_ok());
+        // same tx id with a different amount is a real conflict
+        let res =
+            engine.perform_transaction(Transaction::new(TransactionType::Deposit, 1, 1, Some(2.0)));
+        assert!(match res.unwrap_err() {
+            TransactionError::DuplicateTransaction(tx) => tx == 1,
+            _ => false,
+        });
+        assert_eq!(engine.accounts[&1].available(), 1.0);
+    }
+
     #[test]
     fn account_locked() {
         let mut engine = PaymentEngine::default();
@@ -288,27 +1229,110 @@ mod tests {
     }
 
     #[test]
-    fn insufficient_funds() {
-        let mut engine = PaymentEngine::default();
+    fn amount_exceeds_limit() {
+        let mut engine = PaymentEngine::default().with_max_transaction_amount(10_000.0);
         let res = engine.perform_transaction(Transaction::new(
-            TransactionType::Withdrawal,
+            TransactionType::Deposit,
             1,
             1,
-            Some(20.5),
+            Some(10_000.0),
+        ));
+        assert!(res.is_ok());
+        let res = engine.perform_transaction(Transaction::new(
+            TransactionType::Deposit,
+            1,
+            2,
+            Some(10_000.01),
         ));
-        // can't withdrawal from an empty account!
-        assert!(res.is_err());
         assert!(match res.unwrap_err() {
-            TransactionError::InsufficientFunds(client) => client == 1,
+            TransactionError::AmountExceedsLimit(client, tx, amount, ceiling) =>
+                client == 1 && tx == 2 && amount == 10_000.01 && ceiling == 10_000.0,
             _ => false,
         });
+        // the balance is unchanged and the limit isn't cumulative
+        assert_eq!(engine.accounts[&1].available(), 10_000.0);
+        let res = engine.perform_transaction(Transaction::new(
+            TransactionType::Deposit,
+            1,
+            3,
+            Some(10_000.0),
+        ));
+        assert!(res.is_ok());
+        // the limit is checked before the funds
+        let res = engine.perform_transaction(Transaction::new(
+            TransactionType::Withdrawal,
+            2,
+            4,
+            Some(20_000.0),
+        ));
+        assert!(matches!(
+            res.unwrap_err(),
+            TransactionError::AmountExceedsLimit(..)
+        ));
     }
 
     #[test]
-    fn non_existing_tx_for_dispute_resolve_chargeback() {
-        let mut engine = PaymentEngine::default();
-        // dispute
-        let res =
+    fn excess_precision() {
+        let deposit = |tx, amount| Transaction::new(TransactionType::Deposit, 1, tx, Some(amount));
+        // scale 0
+        let mut engine = PaymentEngine::default().with_scale(0);
+        assert!(engine.perform_transaction(deposit(1, 100.0)).is_ok());
+        let res = engine.perform_transaction(deposit(2, 1.5));
+        assert!(match res.unwrap_err() {
+            TransactionError::ExcessPrecision(client, tx, amount) =>
+                client == 1 && tx == 2 && amount == 1.5,
+            _ => false,
+        });
+        // scale 2
+        let mut engine = PaymentEngine::default().with_scale(2);
+        assert!(engine.perform_transaction(deposit(1, 1.25)).is_ok());
+        let res = engine.perform_transaction(deposit(2, 1.255));
+        assert!(matches!(
+            res.unwrap_err(),
+            TransactionError::ExcessPrecision(..)
+        ));
+        // scale 4
+        let mut engine = PaymentEngine::default().with_scale(4);
+        assert!(engine.perform_transaction(deposit(1, 1.2345)).is_ok());
+        let res = engine.perform_transaction(deposit(2, 1.23456));
+        assert!(matches!(
+            res.unwrap_err(),
+            TransactionError::ExcessPrecision(..)
+        ));
+        assert_eq!(engine.accounts[&1].available(), 1.2345);
+        // without a scale nothing is checked
+        let mut engine = PaymentEngine::default();
+        assert!(engine.perform_transaction(deposit(1, 1.23456)).is_ok());
+    }
+
+    #[test]
+    #[should_panic(expected = "a scale can't be above 15 decimal places")]
+    fn scale_above_max() {
+        let _ = PaymentEngine::default().with_scale(MAX_SCALE + 1);
+    }
+
+    #[test]
+    fn insufficient_funds() {
+        let mut engine = PaymentEngine::default();
+        let res = engine.perform_transaction(Transaction::new(
+            TransactionType::Withdrawal,
+            1,
+            1,
+            Some(20.5),
+        ));
+        // can't withdrawal from an empty account!
+        assert!(res.is_err());
+        assert!(match res.unwrap_err() {
+            TransactionError::InsufficientFunds(client) => client == 1,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn non_existing_tx_for_dispute_resolve_chargeback() {
+        let mut engine = PaymentEngine::default();
+        // dispute
+        let res =
             engine.perform_transaction(Transaction::new(TransactionType::Dispute, 1, 1, None));
         assert!(res.is_err());
         assert!(match res.unwrap_err() {
@@ -337,7 +1361,7 @@ mod tests {
     }
 
     #[test]
-    fn client_mismatch() {
+    fn other_clients_transaction() {
         let mut engine = PaymentEngine::default();
         // first deposit with client '1'
         let res = engine.perform_transaction(Transaction::new(
@@ -348,33 +1372,42 @@ mod tests {
         ));
         assert!(res.is_ok());
 
-        // then try various dispute actions with client '2', all should fail
-        let res =
-            engine.perform_transaction(Transaction::new(TransactionType::Dispute, 2, 1, None));
-        assert!(res.is_err());
-        assert!(match res.unwrap_err() {
-            TransactionError::ClientMismatch(client, tx, owner) =>
-                client == 2 && tx == 1 && owner == 1,
-            _ => false,
-        });
-
-        let res =
-            engine.perform_transaction(Transaction::new(TransactionType::Resolve, 2, 1, None));
-        assert!(res.is_err());
-        assert!(match res.unwrap_err() {
-            TransactionError::ClientMismatch(client, tx, owner) =>
-                client == 2 && tx == 1 && owner == 1,
-            _ => false,
-        });
+        // client '2' has no tx '1' of their own, so every dispute action fails
+        for kind in [
+            TransactionType::Dispute,
+            TransactionType::Resolve,
+            TransactionType::Chargeback,
+        ] {
+            let res = engine.perform_transaction(Transaction::new(kind, 2, 1, None));
+            assert!(matches!(
+                res.unwrap_err(),
+                TransactionError::NonExistingDisputeResolveOrChargeback(2, 1)
+            ));
+        }
+        assert_eq!(engine.accounts[&1].held(), 0.0);
+    }
 
+    #[test]
+    fn tx_ids_unique_per_client() {
+        let mut engine = PaymentEngine::default();
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(10.0)),
+            Transaction::new(TransactionType::Deposit, 2, 1, Some(25.0)),
+            Transaction::new(TransactionType::Dispute, 2, 1, None),
+        ];
+        assert!(engine.perform_transactions(txs).is_empty());
+        // the dispute only holds the funds of client '2'
+        assert_eq!(engine.accounts[&1].available(), 10.0);
+        assert_eq!(engine.accounts[&1].held(), 0.0);
+        assert_eq!(engine.accounts[&2].available(), 0.0);
+        assert_eq!(engine.accounts[&2].held(), 25.0);
+        // a reused tx id within one client is still a duplicate
         let res =
-            engine.perform_transaction(Transaction::new(TransactionType::Chargeback, 2, 1, None));
-        assert!(res.is_err());
-        assert!(match res.unwrap_err() {
-            TransactionError::ClientMismatch(client, tx, owner) =>
-                client == 2 && tx == 1 && owner == 1,
-            _ => false,
-        });
+            engine.perform_transaction(Transaction::new(TransactionType::Deposit, 1, 1, Some(5.0)));
+        assert!(matches!(
+            res.unwrap_err(),
+            TransactionError::DuplicateTransaction(1)
+        ));
     }
 
     #[test]
@@ -443,4 +1476,829 @@ mod tests {
             _ => false,
         })
     }
+
+    #[test]
+    fn duplicate_resolve_and_chargeback() {
+        let mut engine = PaymentEngine::default();
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(10.0)),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(5.0)),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+            Transaction::new(TransactionType::Dispute, 1, 2, None),
+            Transaction::new(TransactionType::Resolve, 1, 1, None),
+        ];
+        assert!(engine.perform_transactions(txs).is_empty());
+        // a second resolve doesn't take the amount out of held again
+        let res =
+            engine.perform_transaction(Transaction::new(TransactionType::Resolve, 1, 1, None));
+        assert!(matches!(
+            res.unwrap_err(),
+            TransactionError::DisputeAlreadyResolved(1, 1)
+        ));
+        // neither does a chargeback of the resolved dispute
+        let res =
+            engine.perform_transaction(Transaction::new(TransactionType::Chargeback, 1, 1, None));
+        assert!(matches!(
+            res.unwrap_err(),
+            TransactionError::DisputeAlreadyResolved(1, 1)
+        ));
+        assert_eq!(engine.accounts[&1].held(), 5.0);
+        assert_eq!(engine.accounts[&1].available(), 10.0);
+
+        // the chargeback locks the account so a second one is stopped before the dispute
+        engine
+            .perform_transaction(Transaction::new(TransactionType::Chargeback, 1, 2, None))
+            .unwrap();
+        let res =
+            engine.perform_transaction(Transaction::new(TransactionType::Chargeback, 1, 2, None));
+        assert!(matches!(
+            res.unwrap_err(),
+            TransactionError::AccountLocked(1)
+        ));
+        assert_eq!(engine.accounts[&1].held(), 0.0);
+        assert_eq!(engine.accounts[&1].total(), 10.0);
+    }
+
+    #[test]
+    fn withdrawal_dispute_held() {
+        let sequences = [
+            [TransactionType::Dispute, TransactionType::Resolve],
+            [TransactionType::Dispute, TransactionType::Chargeback],
+        ];
+        for sequence in sequences {
+            let mut engine = PaymentEngine::default();
+            let txs = [
+                Transaction::new(TransactionType::Deposit, 1, 1, Some(10.0)),
+                Transaction::new(TransactionType::Withdrawal, 1, 2, Some(4.0)),
+            ];
+            assert!(engine.perform_transactions(txs).is_empty());
+            for transaction_type in sequence {
+                engine
+                    .perform_transaction(Transaction::new(transaction_type, 1, 2, None))
+                    .unwrap();
+                assert!(engine.accounts[&1].held() >= 0.0);
+            }
+            // a repeat of the last step is refused and held doesn't move
+            assert!(engine
+                .perform_transaction(Transaction::new(sequence[1], 1, 2, None))
+                .is_err());
+            assert_eq!(engine.accounts[&1].held(), 0.0);
+        }
+    }
+
+    #[test]
+    fn insufficient_held() {
+        let mut engine = PaymentEngine::default();
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(10.0)),
+            Transaction::new(TransactionType::Withdrawal, 1, 2, Some(4.0)),
+            Transaction::new(TransactionType::Dispute, 1, 2, None),
+        ];
+        assert!(engine.perform_transactions(txs).is_empty());
+        // the held funds were lost some other way, releasing the dispute would take them negative
+        engine.accounts.get_mut(&1).unwrap().set_held(1.0);
+        for transaction_type in [TransactionType::Resolve, TransactionType::Chargeback] {
+            let res = engine.perform_transaction(Transaction::new(transaction_type, 1, 2, None));
+            assert!(matches!(
+                res.unwrap_err(),
+                TransactionError::InsufficientHeld(1, 2)
+            ));
+        }
+        assert_eq!(engine.accounts[&1].held(), 1.0);
+        assert!(!engine.accounts[&1].locked());
+        // the dispute is still open
+        assert_eq!(engine.open_disputes().count(), 1);
+    }
+
+    #[test]
+    fn client_not_allowed() {
+        let mut engine = PaymentEngine::default().with_client_allowlist(HashSet::from([1]));
+        let res = engine.perform_transaction(Transaction::new(
+            TransactionType::Deposit,
+            1,
+            1,
+            Some(10.0),
+        ));
+        assert!(res.is_ok());
+        let res = engine.perform_transaction(Transaction::new(
+            TransactionType::Deposit,
+            2,
+            2,
+            Some(10.0),
+        ));
+        assert!(match res.unwrap_err() {
+            TransactionError::ClientNotAllowed(client) => client == 2,
+            _ => false,
+        });
+        // no account is opened for a rejected client
+        assert!(!engine.accounts.contains_key(&2));
+
+        // allow client 2 and remove client 1
+        engine.add_to_allowlist(2);
+        engine.remove_from_allowlist(1);
+        let res = engine.perform_transaction(Transaction::new(
+            TransactionType::Deposit,
+            2,
+            3,
+            Some(10.0),
+        ));
+        assert!(res.is_ok());
+        let res = engine.perform_transaction(Transaction::new(
+            TransactionType::Deposit,
+            1,
+            4,
+            Some(10.0),
+        ));
+        assert!(match res.unwrap_err() {
+            TransactionError::ClientNotAllowed(client) => client == 1,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn account_limit_reached() {
+        let mut engine = PaymentEngine::default().with_max_accounts(2);
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(10.0)),
+            Transaction::new(TransactionType::Deposit, 2, 2, Some(10.0)),
+        ];
+        for tx in txs {
+            assert!(engine.perform_transaction(tx).is_ok());
+        }
+        // a third client can't open an account
+        let res = engine.perform_transaction(Transaction::new(
+            TransactionType::Deposit,
+            3,
+            3,
+            Some(10.0),
+        ));
+        assert!(match res.unwrap_err() {
+            TransactionError::AccountLimitReached(client) => client == 3,
+            _ => false,
+        });
+        assert_eq!(engine.accounts.len(), 2);
+        // existing accounts are unaffected
+        let res = engine.perform_transaction(Transaction::new(
+            TransactionType::Deposit,
+            1,
+            4,
+            Some(10.0),
+        ));
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn max_open_disputes() {
+        let mut engine = PaymentEngine::default().with_max_open_disputes_per_client(2);
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(10.0)),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(10.0)),
+            Transaction::new(TransactionType::Deposit, 1, 3, Some(10.0)),
+            Transaction::new(TransactionType::Deposit, 2, 4, Some(10.0)),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+            Transaction::new(TransactionType::Dispute, 1, 2, None),
+            // the limit is per client
+            Transaction::new(TransactionType::Dispute, 2, 4, None),
+        ];
+        assert!(engine.perform_transactions(txs).is_empty());
+        let third = Transaction::new(TransactionType::Dispute, 1, 3, None);
+        assert!(matches!(
+            third.is_valid_for_engine(&engine)[..],
+            [TransactionError::TooManyOpenDisputes(1)]
+        ));
+        let res = engine.perform_transaction(third.clone());
+        assert!(matches!(
+            res.unwrap_err(),
+            TransactionError::TooManyOpenDisputes(1)
+        ));
+        assert_eq!(engine.accounts[&1].held(), 20.0);
+        // disputing an open dispute again is still invalid rather than over the limit
+        let res =
+            engine.perform_transaction(Transaction::new(TransactionType::Dispute, 1, 1, None));
+        assert!(matches!(
+            res.unwrap_err(),
+            TransactionError::InvalidDispute(1, 1)
+        ));
+        // resolving one frees a slot
+        engine
+            .perform_transaction(Transaction::new(TransactionType::Resolve, 1, 1, None))
+            .unwrap();
+        assert!(engine.perform_transaction(third).is_ok());
+        assert_eq!(engine.accounts[&1].held(), 20.0);
+        assert_eq!(engine.open_disputes, HashMap::from([(1, 2), (2, 1)]));
+        engine
+            .perform_transaction(Transaction::new(TransactionType::Chargeback, 2, 4, None))
+            .unwrap();
+        assert_eq!(engine.open_disputes, HashMap::from([(1, 2)]));
+    }
+
+    #[test]
+    fn first_tx() {
+        let mut engine = PaymentEngine::default();
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 7, Some(10.0)),
+            Transaction::new(TransactionType::Deposit, 1, 3, Some(20.0)),
+            Transaction::new(TransactionType::Dispute, 1, 7, None),
+        ];
+        for tx in txs {
+            assert!(engine.perform_transaction(tx).is_ok());
+        }
+        // later transactions don't overwrite the first one
+        assert_eq!(engine.accounts[&1].first_tx(), Some(7));
+    }
+
+    #[test]
+    fn sign_and_verify_all_accounts() {
+        let secret = b"shared secret";
+        let mut engine = PaymentEngine::default();
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(10.0)),
+            Transaction::new(TransactionType::Deposit, 2, 2, Some(20.0)),
+        ];
+        for tx in txs {
+            assert!(engine.perform_transaction(tx).is_ok());
+        }
+        let signatures = engine.sign_all_accounts(secret);
+        assert_eq!(signatures.len(), 2);
+        assert!(engine.verify_all_accounts(&signatures, secret));
+        // any change to an account after signing fails verification
+        let res =
+            engine.perform_transaction(Transaction::new(TransactionType::Deposit, 2, 3, Some(1.0)));
+        assert!(res.is_ok());
+        assert!(!engine.verify_all_accounts(&signatures, secret));
+    }
+
+    #[test]
+    fn transactions_in_order() {
+        let mut engine = PaymentEngine::default();
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 2, 30, Some(10.0)),
+            Transaction::new(TransactionType::Deposit, 1, 5, Some(10.0)),
+            Transaction::new(TransactionType::Dispute, 2, 30, None),
+            Transaction::new(TransactionType::Withdrawal, 1, 12, Some(1.0)),
+            Transaction::new(TransactionType::Deposit, 3, 1, Some(10.0)),
+            Transaction::new(TransactionType::Resolve, 2, 30, None),
+        ];
+        for tx in txs {
+            assert!(engine.perform_transaction(tx).is_ok());
+        }
+        // failed transactions aren't recorded
+        assert!(engine
+            .perform_transaction(Transaction::new(
+                TransactionType::Withdrawal,
+                3,
+                2,
+                Some(100.0)
+            ))
+            .is_err());
+        let order: Vec<u32> = engine.transactions_in_order().map(|t| t.tx).collect();
+        assert_eq!(order, [30, 5, 12, 1]);
+    }
+
+    #[test]
+    fn require_registered() {
+        let csv = "client,available\n1,10.0\n";
+        let deposit =
+            |client, tx| Transaction::new(TransactionType::Deposit, client, tx, Some(5.0));
+
+        // by default an unknown client gets an account
+        let mut engine = PaymentEngine::default();
+        engine.seed_balances(csv.as_bytes()).unwrap();
+        assert!(engine.perform_transaction(deposit(2, 1)).is_ok());
+        assert_eq!(engine.accounts.len(), 2);
+
+        let mut engine = PaymentEngine::default();
+        engine.seed_balances(csv.as_bytes()).unwrap();
+        let mut engine = engine.with_require_registered(true);
+        assert!(engine.perform_transaction(deposit(1, 1)).is_ok());
+        let res = engine.perform_transaction(deposit(2, 2));
+        assert!(matches!(
+            res.unwrap_err(),
+            TransactionError::UnknownClient(2)
+        ));
+        assert_eq!(engine.accounts.len(), 1);
+        assert_eq!(engine.accounts[&1].available(), 15.0);
+        // the tx id wasn't taken
+        assert!(!engine.transactions.contains_key(&(2, 2)));
+    }
+
+    #[test]
+    fn seed_balances() {
+        let mut engine = PaymentEngine::default();
+        let csv = r#"
+        client, available
+        1, 100.0
+        2, 25.5"#;
+        assert!(engine.seed_balances(csv.as_bytes()).is_ok());
+        let account = |client, amount| {
+            let mut account = Account::new(client);
+            account.deposit(amount);
+            account
+        };
+        engine.assert_accounts_eq(&[account(1, 100.0), account(2, 25.5)]);
+        // seeded accounts weren't opened by a transaction
+        assert_eq!(engine.accounts[&2].first_tx(), None);
+        // seeded funds can be withdrawn like any other
+        let res = engine.perform_transaction(Transaction::new(
+            TransactionType::Withdrawal,
+            2,
+            1,
+            Some(20.5),
+        ));
+        assert!(res.is_ok());
+        engine.assert_accounts_eq(&[account(1, 100.0), account(2, 5.0)]);
+        // but there is no transaction behind the seeded balance to dispute
+        let res =
+            engine.perform_transaction(Transaction::new(TransactionType::Dispute, 1, 0, None));
+        assert!(match res.unwrap_err() {
+            TransactionError::NonExistingDisputeResolveOrChargeback(client, tx) =>
+                client == 1 && tx == 0,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn aggregate_limit_reject() {
+        let mut engine = PaymentEngine::default().with_aggregate_limit(100.0, LimitAction::Reject);
+        let deposit = |tx, amount| Transaction::new(TransactionType::Deposit, 1, tx, Some(amount));
+        assert!(engine.perform_transaction(deposit(1, 60.0)).is_ok());
+        // withdrawals don't lower the total deposited
+        assert!(engine
+            .perform_transaction(Transaction::new(
+                TransactionType::Withdrawal,
+                1,
+                2,
+                Some(50.0)
+            ))
+            .is_ok());
+        // reaching the limit exactly is allowed
+        assert!(engine.perform_transaction(deposit(3, 40.0)).is_ok());
+        let res = engine.perform_transaction(deposit(4, 0.01));
+        assert!(matches!(
+            res.unwrap_err(),
+            TransactionError::AggregateLimitExceeded(1)
+        ));
+        assert_eq!(engine.accounts[&1].available(), 50.0);
+        assert!(!engine.transactions.contains_key(&(1, 4)));
+        // other clients have their own total
+        assert!(engine
+            .perform_transaction(Transaction::new(
+                TransactionType::Deposit,
+                2,
+                5,
+                Some(100.0)
+            ))
+            .is_ok());
+    }
+
+    #[test]
+    fn aggregate_limit_warn() {
+        let mut engine = PaymentEngine::default().with_aggregate_limit(100.0, LimitAction::Warn);
+        let deposit = |tx, amount| Transaction::new(TransactionType::Deposit, 1, tx, Some(amount));
+        assert!(engine.perform_transaction(deposit(1, 100.0)).is_ok());
+        let res = engine.perform_transaction(deposit(2, 0.01));
+        assert_eq!(
+            res.unwrap(),
+            TransactionOutcome::AppliedWithWarning(TransactionWarning::AggregateLimitExceeded(1))
+        );
+        // a warning isn't an error, the batch goes through as a whole
+        assert!(engine.apply_batch_atomically([deposit(3, 1.0)]).is_ok());
+        assert_eq!(engine.accounts[&1].available(), 101.01);
+        // the deposit still went through and can be disputed
+        assert!(engine
+            .perform_transaction(Transaction::new(TransactionType::Dispute, 1, 2, None))
+            .is_ok());
+    }
+
+    #[test]
+    fn partial_withdrawal() {
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(30.0)),
+            Transaction::new(TransactionType::Withdrawal, 1, 2, Some(100.0)),
+        ];
+        // by default the withdrawal is rejected and nothing is withdrawn
+        let (engine, errors) = PaymentEngine::from_transactions(txs.clone());
+        assert!(matches!(
+            errors[..],
+            [TransactionError::InsufficientFunds(1)]
+        ));
+        assert_eq!(engine.accounts[&1].available(), 30.0);
+        assert!(!engine.transactions.contains_key(&(1, 2)));
+
+        // in partial mode the balance is drained and the shortfall is a warning, not an error
+        let mut engine = PaymentEngine::default().with_partial_withdrawal(true);
+        let [deposit, withdrawal] = txs;
+        assert!(engine.perform_transaction(deposit).is_ok());
+        assert_eq!(
+            engine.perform_transaction(withdrawal).unwrap(),
+            TransactionOutcome::AppliedWithWarning(TransactionWarning::PartialWithdrawal(
+                1, 100.0, 30.0
+            ))
+        );
+        assert_eq!(engine.accounts[&1].available(), 0.0);
+        assert_eq!(engine.transactions[&(1, 2)].amount, Some(30.0));
+        // with nothing left the next one is rejected as before
+        let res = engine.perform_transaction(Transaction::new(
+            TransactionType::Withdrawal,
+            1,
+            3,
+            Some(5.0),
+        ));
+        assert!(matches!(
+            res.unwrap_err(),
+            TransactionError::InsufficientFunds(1)
+        ));
+    }
+
+    #[test]
+    fn batch_atomically() {
+        let mut engine = PaymentEngine::default().with_max_transaction_amount(1000.0);
+        let payroll = |last_amount| {
+            vec![
+                Transaction::new(TransactionType::Deposit, 1, 1, Some(500.0)),
+                Transaction::new(TransactionType::Deposit, 2, 2, Some(750.0)),
+                Transaction::new(TransactionType::Deposit, 3, 3, Some(last_amount)),
+            ]
+        };
+        let res = engine.apply_batch_atomically(payroll(1000.01));
+        let (applied, errors) = res.unwrap_err();
+        assert_eq!(applied, 2);
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(
+            errors[0],
+            TransactionError::AmountExceedsLimit(3, 3, ..)
+        ));
+        // nothing from the failed batch was applied
+        assert_eq!(engine.accounts.len(), 0);
+        assert!(engine.transactions.is_empty());
+
+        assert_eq!(engine.apply_batch_atomically(payroll(1000.0)).unwrap(), 3);
+        assert_eq!(engine.accounts.len(), 3);
+        assert_eq!(engine.accounts[&3].available(), 1000.0);
+    }
+
+    #[test]
+    fn batch_atomically_lock_hook() {
+        let locked = Arc::new(Mutex::new(Vec::new()));
+        let mut engine = PaymentEngine::default();
+        let sink = Arc::clone(&locked);
+        engine.on_lock(move |client| sink.lock().unwrap().push(client));
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(10.0)),
+            Transaction::new(TransactionType::Deposit, 2, 2, Some(5.0)),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+            Transaction::new(TransactionType::Dispute, 2, 2, None),
+        ];
+        assert!(engine.perform_transactions(txs).is_empty());
+        let chargebacks = |withdrawal| {
+            vec![
+                Transaction::new(TransactionType::Chargeback, 2, 2, None),
+                Transaction::new(TransactionType::Chargeback, 1, 1, None),
+                Transaction::new(TransactionType::Withdrawal, 3, 3, Some(withdrawal)),
+            ]
+        };
+        // the chargebacks of a failed batch don't lock anything
+        assert!(engine.apply_batch_atomically(chargebacks(1.0)).is_err());
+        assert!(locked.lock().unwrap().is_empty());
+        assert!(!engine.accounts[&1].locked());
+
+        engine
+            .perform_transaction(Transaction::new(TransactionType::Deposit, 3, 4, Some(1.0)))
+            .unwrap();
+        assert_eq!(engine.apply_batch_atomically(chargebacks(1.0)).unwrap(), 3);
+        assert_eq!(*locked.lock().unwrap(), [1, 2]);
+    }
+
+    #[test]
+    fn adjustments() {
+        let mut engine = PaymentEngine::default().with_max_transaction_amount(100.0);
+        let adjustment =
+            |tx, amount| Transaction::new(TransactionType::Adjustment, 1, tx, Some(amount));
+        assert!(engine
+            .perform_transaction(Transaction::new(TransactionType::Deposit, 1, 1, Some(10.0)))
+            .is_ok());
+        // credits aren't held to the ceiling of deposits
+        assert!(engine.perform_transaction(adjustment(2, 150.0)).is_ok());
+        assert_eq!(engine.accounts[&1].available(), 160.0);
+        // debits can take the balance below zero
+        assert!(engine.perform_transaction(adjustment(3, -200.0)).is_ok());
+        assert_eq!(engine.accounts[&1].available(), -40.0);
+        assert_eq!(engine.accounts[&1].held(), 0.0);
+        // adjustments are recorded but can't be disputed
+        assert!(engine.transactions.contains_key(&(1, 3)));
+        let res =
+            engine.perform_transaction(Transaction::new(TransactionType::Dispute, 1, 3, None));
+        assert!(matches!(
+            res.unwrap_err(),
+            TransactionError::InvalidDispute(1, 3)
+        ));
+        // the tx id is taken like any other
+        let res = engine.perform_transaction(adjustment(1, 5.0));
+        assert!(matches!(
+            res.unwrap_err(),
+            TransactionError::DuplicateTransaction(1)
+        ));
+    }
+
+    #[test]
+    fn deposit_hook_fee() {
+        let mut engine = PaymentEngine::default().with_house_account(0);
+        engine.set_deposit_hook(|amount| amount * 0.98);
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(100.0)),
+            Transaction::new(TransactionType::Deposit, 2, 2, Some(50.0)),
+            Transaction::new(TransactionType::Withdrawal, 2, 3, Some(10.0)),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+        ];
+        assert!(engine.perform_transactions(txs).is_empty());
+        // the dispute holds the net amount
+        assert_eq!(engine.accounts[&1].available(), 0.0);
+        assert_eq!(engine.accounts[&1].held(), 98.0);
+        assert_eq!(engine.transactions[&(1, 1)].amount, Some(98.0));
+        // withdrawals aren't charged
+        assert_eq!(engine.accounts[&2].available(), 39.0);
+        assert_eq!(engine.accounts[&0].available(), 3.0);
+
+        // without a house account the fee just isn't credited anywhere
+        let mut engine = PaymentEngine::default();
+        engine.set_deposit_hook(|amount| amount * 0.98);
+        engine
+            .perform_transaction(Transaction::new(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(100.0),
+            ))
+            .unwrap();
+        assert_eq!(engine.accounts.len(), 1);
+        assert_eq!(engine.accounts[&1].available(), 98.0);
+    }
+
+    #[test]
+    fn deposit_hook_result_is_checked() {
+        for credited in [f64::NAN, f64::INFINITY, -1.0, 101.0] {
+            let mut engine = PaymentEngine::default();
+            engine.set_deposit_hook(move |_| credited);
+            let res = engine.perform_transaction(Transaction::new(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(100.0),
+            ));
+            assert!(matches!(
+                res.unwrap_err(),
+                TransactionError::InvalidDepositHookResult(1, 1, _)
+            ));
+            assert!(engine.accounts.values().all(|acc| acc.total() == 0.0));
+        }
+        // taking everything is allowed
+        let mut engine = PaymentEngine::default();
+        engine.set_deposit_hook(|_| 0.0);
+        engine
+            .perform_transaction(Transaction::new(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(100.0),
+            ))
+            .unwrap();
+    }
+
+    #[test]
+    fn lock_hook_fires_once() {
+        let locked = Arc::new(Mutex::new(Vec::new()));
+        let mut engine = PaymentEngine::default();
+        let sink = Arc::clone(&locked);
+        engine.on_lock(move |client| sink.lock().unwrap().push(client));
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(10.0)),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(5.0)),
+            Transaction::new(TransactionType::Deposit, 2, 3, Some(5.0)),
+            Transaction::new(TransactionType::Dispute, 2, 3, None),
+            Transaction::new(TransactionType::Resolve, 2, 3, None),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+            Transaction::new(TransactionType::Chargeback, 1, 1, None),
+        ];
+        assert!(engine.perform_transactions(txs).is_empty());
+        assert_eq!(*locked.lock().unwrap(), [1]);
+        // the locked account rejects everything after, without calling the hook again
+        let txs = [
+            Transaction::new(TransactionType::Dispute, 1, 2, None),
+            Transaction::new(TransactionType::Chargeback, 1, 2, None),
+            Transaction::new(TransactionType::Deposit, 1, 4, Some(1.0)),
+        ];
+        assert_eq!(engine.perform_transactions(txs).len(), 3);
+        assert_eq!(*locked.lock().unwrap(), [1]);
+    }
+
+    #[test]
+    fn daily_withdrawal_limit() {
+        let withdrawal = |tx, amount, timestamp| {
+            Transaction::new(TransactionType::Withdrawal, 1, tx, Some(amount))
+                .with_timestamp(timestamp)
+        };
+        let mut engine = PaymentEngine::default().with_daily_withdrawal_limit(100.0);
+        engine
+            .perform_transaction(Transaction::new(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(500.0),
+            ))
+            .unwrap();
+        let day = 20_000 * SECONDS_PER_DAY;
+        engine
+            .perform_transaction(withdrawal(2, 60.0, day + 10))
+            .unwrap();
+        let res = engine.perform_transaction(withdrawal(3, 50.0, day + 3600));
+        assert!(matches!(
+            res.unwrap_err(),
+            TransactionError::DailyWithdrawalLimitExceeded(1, limit) if limit == 100.0
+        ));
+        assert_eq!(engine.accounts[&1].available(), 440.0);
+        // up to the limit is fine
+        engine
+            .perform_transaction(withdrawal(4, 40.0, day + 7200))
+            .unwrap();
+        // the next day starts over
+        engine
+            .perform_transaction(withdrawal(5, 50.0, day + SECONDS_PER_DAY))
+            .unwrap();
+        assert_eq!(engine.accounts[&1].available(), 350.0);
+        // the day before is dropped once the next one starts
+        assert_eq!(engine.daily_withdrawals.len(), 1);
+        assert_eq!(engine.withdrawal_day, Some(day / SECONDS_PER_DAY + 1));
+        // without a limit nothing is tracked
+        let mut engine = PaymentEngine::default();
+        engine
+            .perform_transaction(Transaction::new(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(500.0),
+            ))
+            .unwrap();
+        engine
+            .perform_transaction(withdrawal(2, 60.0, day + 10))
+            .unwrap();
+        assert!(engine.daily_withdrawals.is_empty());
+    }
+
+    #[test]
+    fn from_transactions() {
+        let (engine, errors) = PaymentEngine::from_transactions(vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(10.0)),
+            Transaction::new(TransactionType::Withdrawal, 1, 2, Some(20.0)),
+            Transaction::new(TransactionType::Deposit, 2, 3, Some(5.0)),
+        ]);
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0], TransactionError::InsufficientFunds(1)));
+        assert_eq!(engine.accounts[&1].available(), 10.0);
+        assert_eq!(engine.accounts[&2].available(), 5.0);
+    }
+
+    #[test]
+    fn accounts_by_chunk() {
+        let mut engine = PaymentEngine::default();
+        for client in 1..=10 {
+            let tx = Transaction::new(TransactionType::Deposit, client, client.into(), Some(1.0));
+            engine.perform_transaction(tx).unwrap();
+        }
+        let chunks: Vec<_> = engine.accounts_iter_by_chunk(3).collect();
+        let sizes: Vec<usize> = chunks.iter().map(Vec::len).collect();
+        assert_eq!(sizes, [3, 3, 3, 1]);
+        let mut clients: Vec<u16> = chunks
+            .iter()
+            .flatten()
+            .map(|(client, _)| **client)
+            .collect();
+        clients.sort_unstable();
+        assert_eq!(clients, (1..=10).collect::<Vec<_>>());
+        assert_eq!(
+            PaymentEngine::default().accounts_iter_by_chunk(3).count(),
+            0
+        );
+    }
+
+    #[test]
+    fn merge_duplicates() {
+        let txs = vec![
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(1.0)),
+            Transaction::new(TransactionType::Deposit, 2, 2, Some(2.0)),
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(1.0)),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+            Transaction::new(TransactionType::Deposit, 3, 3, Some(3.0)),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+            Transaction::new(TransactionType::Deposit, 2, 2, Some(2.0)),
+        ];
+        let (merged, dropped) = PaymentEngine::merge_duplicate_transactions(txs.clone());
+        let order: Vec<(TransactionType, u32)> =
+            merged.iter().map(|t| (t.transaction_type, t.tx)).collect();
+        assert_eq!(
+            order,
+            [
+                (TransactionType::Deposit, 1),
+                (TransactionType::Deposit, 2),
+                (TransactionType::Dispute, 1),
+                (TransactionType::Deposit, 3),
+            ]
+        );
+        assert_eq!(dropped, [(1, 1), (1, 1), (2, 2)]);
+
+        let mut engine = PaymentEngine::default().with_deduplication(true);
+        assert!(engine.perform_transactions(txs.clone()).is_empty());
+        assert_eq!(engine.accounts[&1].held(), 1.0);
+        // without deduplication the repeats are errors
+        let mut engine = PaymentEngine::default();
+        assert_eq!(engine.perform_transactions(txs).len(), 3);
+    }
+
+    #[test]
+    fn merge_duplicates_shared_tx_id() {
+        // tx ids are unique per client, so these are two deposits
+        let txs = vec![
+            Transaction::new(TransactionType::Deposit, 1, 7, Some(1.0)),
+            Transaction::new(TransactionType::Deposit, 2, 7, Some(2.0)),
+            Transaction::new(TransactionType::Deposit, 2, 7, Some(2.0)),
+        ];
+        let (merged, dropped) = PaymentEngine::merge_duplicate_transactions(txs.clone());
+        assert_eq!(merged.len(), 2);
+        assert_eq!(dropped, [(2, 7)]);
+
+        let mut engine = PaymentEngine::default().with_deduplication(true);
+        assert!(engine.perform_transactions(txs).is_empty());
+        assert_eq!(engine.accounts[&1].available(), 1.0);
+        assert_eq!(engine.accounts[&2].available(), 2.0);
+    }
+
+    #[test]
+    fn seed_balances_bad_row() {
+        let mut engine = PaymentEngine::default();
+        let csv = r#"
+        client, available
+        1, hello"#;
+        assert!(matches!(
+            engine.seed_balances(csv.as_bytes()).unwrap_err(),
+            SeedError::Csv(_)
+        ));
+    }
+
+    #[test]
+    fn seed_balances_invalid() {
+        let mut engine = PaymentEngine::default();
+        for balance in ["-1.0", "NaN", "inf"] {
+            let csv = format!("client,available\n1,10.0\n2,{}", balance);
+            assert!(matches!(
+                engine.seed_balances(csv.as_bytes()).unwrap_err(),
+                SeedError::InvalidBalance(2, _)
+            ));
+        }
+        let csv = "client,available\n1,10.0\n1,20.0";
+        assert!(matches!(
+            engine.seed_balances(csv.as_bytes()).unwrap_err(),
+            SeedError::DuplicateAccount(1)
+        ));
+        // nothing was seeded from the rows before the bad one
+        assert!(engine.accounts.is_empty());
+    }
+
+    #[test]
+    fn seed_balances_checks_clients() {
+        let csv = "client,available
+1,10.0
+2,20.5";
+        let seed = |mut engine: PaymentEngine| engine.seed_balances(csv.as_bytes());
+
+        // a client with an account isn't seeded over
+        let mut engine = PaymentEngine::default();
+        engine
+            .perform_transaction(Transaction::new(TransactionType::Deposit, 2, 1, Some(5.0)))
+            .unwrap();
+        assert!(matches!(
+            seed(engine).unwrap_err(),
+            SeedError::DuplicateAccount(2)
+        ));
+
+        let engine = PaymentEngine::default().with_client_allowlist(HashSet::from([1]));
+        assert!(matches!(
+            seed(engine).unwrap_err(),
+            SeedError::Rejected(TransactionError::ClientNotAllowed(2))
+        ));
+        let engine = PaymentEngine::default().with_max_accounts(1);
+        assert!(matches!(
+            seed(engine).unwrap_err(),
+            SeedError::Rejected(TransactionError::AccountLimitReached(2))
+        ));
+        let engine = PaymentEngine::default().with_require_registered(true);
+        assert!(matches!(
+            seed(engine).unwrap_err(),
+            SeedError::Rejected(TransactionError::UnknownClient(1))
+        ));
+        let engine = PaymentEngine::default().with_scale(0);
+        assert!(matches!(
+            seed(engine).unwrap_err(),
+            SeedError::ExcessPrecision(2, available) if available == 20.5
+        ));
+        assert!(seed(PaymentEngine::default().with_scale(1).with_max_accounts(2)).is_ok());
+    }
 }
@@ -0,0 +1,251 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::transaction::engine::{PaymentEngine, TransactionError};
+
+impl PaymentEngine {
+    /// Copy of the engine with every client id replaced by a salted hash of it, for analytics
+    /// which shouldn't see the real ids. Balances, transactions, corrections and the
+    /// configuration are kept under the new ids. Client ids are 16 bits so hashes can collide,
+    /// a collision takes the next free id in the order of the original ids. The same salt always
+    /// gives the same ids for the same set of clients.
+    pub fn anonymize_clients(&self, salt: &[u8]) -> PaymentEngine {
+        let mut clients: Vec<u16> = self.accounts.keys().copied().collect();
+        clients.sort_unstable();
+        let mut mapping = HashMap::with_capacity(clients.len());
+        let mut taken = HashSet::with_capacity(clients.len());
+        for client in clients {
+            let mut id = anonymous_id(client, salt);
+            while !taken.insert(id) {
+                id = id.wrapping_add(1);
+            }
+            mapping.insert(client, id);
+        }
+        // every transaction, correction and deposit total belongs to a client with an account
+        let map = |client: u16| mapping[&client];
+
+        let mut anonymized = self.empty_like();
+        anonymized.allowlist = self.allowlist.as_ref().map(|allowlist| {
+            allowlist
+                .iter()
+                .map(|&client| {
+                    mapping
+                        .get(&client)
+                        .copied()
+                        .unwrap_or_else(|| anonymous_id(client, salt))
+                })
+                .collect()
+        });
+        anonymized.accounts = self
+            .accounts
+            .values()
+            .map(|account| {
+                let client = map(account.client());
+                (client, account.clone().with_client(client))
+            })
+            .collect();
+        anonymized.transactions = self
+            .transactions
+            .iter()
+            .map(|(_, transaction)| {
+                let mut transaction = transaction.clone();
+                transaction.client = map(transaction.client);
+                (transaction.key(), transaction)
+            })
+            .collect();
+        anonymized.corrections = self
+            .corrections
+            .iter()
+            .map(|correction| {
+                let mut correction = correction.clone();
+                correction.client = map(correction.client);
+                correction
+            })
+            .collect();
+        anonymized.open_disputes = self
+            .open_disputes
+            .iter()
+            .map(|(&client, &open)| (map(client), open))
+            .collect();
+        anonymized.deposit_totals = self
+            .deposit_totals
+            .iter()
+            .map(|(&client, &total)| (map(client), total))
+            .collect();
+        anonymized.daily_withdrawals = self
+            .daily_withdrawals
+            .iter()
+            .map(|(&(client, day), &withdrawn)| ((map(client), day), withdrawn))
+            .collect();
+        anonymized.withdrawal_day = self.withdrawal_day;
+        anonymized
+    }
+
+    /// Replace every tx id with `mapper` of it, to move the engine to another id space. The
+    /// transaction record keeps its order, disputes, resolves and chargebacks refer to the new ids
+    /// from then on, and so do the first transactions of the accounts. If two transactions of a
+    /// client would get the same id nothing changes and the id is a 'DuplicateTransaction'.
+    pub fn rekey_transaction_ids<F>(&mut self, mapper: F) -> Result<(), TransactionError>
+    where
+        F: Fn(u32) -> u32,
+    {
+        // check every new key before anything is changed
+        let mut keys = HashSet::with_capacity(self.transactions.len());
+        for &(client, tx) in self.transactions.keys() {
+            let tx = mapper(tx);
+            if !keys.insert((client, tx)) {
+                return Err(TransactionError::DuplicateTransaction(tx));
+            }
+        }
+        self.transactions = std::mem::take(&mut self.transactions)
+            .into_values()
+            .map(|mut transaction| {
+                transaction.tx = mapper(transaction.tx);
+                (transaction.key(), transaction)
+            })
+            .collect();
+        self.newest_tx = None;
+        for account in self.accounts.values_mut() {
+            if let Some(tx) = account.first_tx() {
+                *account = account.clone().with_first_tx(mapper(tx));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Salted hash of a client id, seahash is used since its output is stable across platforms
+fn anonymous_id(client: u16, salt: &[u8]) -> u16 {
+    let mut bytes = client.to_le_bytes().to_vec();
+    bytes.extend_from_slice(salt);
+    (seahash::hash(&bytes) % u64::from(u16::MAX)) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{Transaction, TransactionType};
+
+    /// The anonymous client of a tx id which only one client used
+    fn opened_by(engine: &PaymentEngine, tx: u32) -> u16 {
+        engine
+            .transactions
+            .values()
+            .find(|transaction| transaction.tx == tx)
+            .unwrap()
+            .client
+    }
+
+    #[test]
+    fn same_salt_same_ids() {
+        let engine = PaymentEngine::example();
+        let a = engine.anonymize_clients(b"salt");
+        let b = engine.anonymize_clients(b"salt");
+        let mut ids_a: Vec<u16> = a.accounts.keys().copied().collect();
+        let mut ids_b: Vec<u16> = b.accounts.keys().copied().collect();
+        ids_a.sort_unstable();
+        ids_b.sort_unstable();
+        assert_eq!(ids_a, ids_b);
+        assert_eq!(ids_a.len(), 3);
+    }
+
+    #[test]
+    fn different_salt_different_ids() {
+        let engine = PaymentEngine::example();
+        let a = engine.anonymize_clients(b"salt");
+        let b = engine.anonymize_clients(b"pepper");
+        // the deposits which opened clients 1, 2 and 3
+        for tx in [1, 2, 3] {
+            let id_a = opened_by(&a, tx);
+            let id_b = opened_by(&b, tx);
+            assert_ne!(id_a, id_b);
+        }
+    }
+
+    #[test]
+    fn balances_unchanged() {
+        let engine = PaymentEngine::example();
+        let anonymized = engine.anonymize_clients(b"salt");
+        for (client, account) in engine.accounts_iter() {
+            // the transaction which opened the account leads to its new id
+            let id = opened_by(&anonymized, account.first_tx().unwrap());
+            let anonymous = &anonymized.accounts[&id];
+            assert_eq!(anonymous.client(), id);
+            assert_eq!(anonymous.available(), account.available());
+            assert_eq!(anonymous.held(), account.held());
+            assert_eq!(anonymous.locked(), account.locked());
+            assert_ne!(id, *client);
+        }
+        // the dispute still refers to the transaction of the same (anonymized) client
+        assert_eq!(anonymized.open_disputes().count(), 1);
+        let disputed = anonymized.open_disputes().next().unwrap();
+        assert_eq!(anonymized.accounts[&disputed.client].held(), 50.0);
+    }
+
+    #[test]
+    fn rekey_transaction_ids() {
+        let mut engine = PaymentEngine::example();
+        let shift = |tx: u32| tx + 1_000_000;
+        assert!(engine.rekey_transaction_ids(shift).is_ok());
+        assert!(engine.transactions.keys().all(|&(_, tx)| tx > 1_000_000));
+        assert_eq!(
+            engine
+                .transactions
+                .values()
+                .map(|t| t.tx)
+                .collect::<Vec<_>>(),
+            [1_000_001, 1_000_002, 1_000_003]
+        );
+        assert_eq!(engine.accounts[&1].first_tx(), Some(1_000_001));
+        // duplicates are found under the new ids, the old ones are free
+        let res = engine.perform_transaction(Transaction::new(
+            TransactionType::Deposit,
+            1,
+            shift(1),
+            Some(10.0),
+        ));
+        assert!(matches!(
+            res.unwrap_err(),
+            TransactionError::DuplicateTransaction(1_000_001)
+        ));
+        assert!(engine
+            .perform_transaction(Transaction::new(TransactionType::Deposit, 1, 1, Some(10.0)))
+            .is_ok());
+        // the open dispute is resolved under its new id
+        assert!(engine
+            .perform_transaction(Transaction::new(
+                TransactionType::Resolve,
+                2,
+                shift(2),
+                None
+            ))
+            .is_ok());
+        assert_eq!(engine.accounts[&2].held(), 0.0);
+    }
+
+    #[test]
+    fn rekey_collision() {
+        let mut engine = PaymentEngine::example();
+        engine
+            .perform_transaction(Transaction::new(
+                TransactionType::Withdrawal,
+                1,
+                5,
+                Some(4.0),
+            ))
+            .unwrap();
+        // client 1 has tx 1 and 5
+        let res = engine.rekey_transaction_ids(|tx| tx % 2);
+        assert!(matches!(
+            res.unwrap_err(),
+            TransactionError::DuplicateTransaction(1)
+        ));
+        assert!(engine.transactions.contains_key(&(1, 5)));
+        // ids shared across clients are fine
+        let len = engine.transactions.len();
+        assert!(engine
+            .rekey_transaction_ids(|tx| if tx == 5 { 5 } else { 9 })
+            .is_ok());
+        assert_eq!(engine.transactions.len(), len);
+        assert!(engine.transactions.contains_key(&(2, 9)));
+    }
+}
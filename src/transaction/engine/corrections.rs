@@ -0,0 +1,138 @@
+use std::io::Read;
+
+use serde::Deserialize;
+
+use crate::transaction::engine::PaymentEngine;
+
+/// A row of a corrections file
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Correction {
+    pub client: u16,
+    pub delta_available: f64,
+    pub delta_held: f64,
+    pub reason: String,
+}
+
+/// Error type for corrections which can't be applied
+#[derive(Debug)]
+pub enum CorrectionError {
+    Csv(csv::Error),
+    NonExistingAccount(u16),
+    WouldCreateNegativeBalance(u16),
+}
+
+impl std::fmt::Display for CorrectionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CorrectionError::Csv(e) => write!(f, "csv error: {}", e),
+            CorrectionError::NonExistingAccount(client) => {
+                write!(f, "correction for client '{}' who has no account", client)
+            }
+            CorrectionError::WouldCreateNegativeBalance(client) => write!(
+                f,
+                "correction would leave client '{}' with a negative balance",
+                client
+            ),
+        }
+    }
+}
+
+impl From<csv::Error> for CorrectionError {
+    fn from(e: csv::Error) -> Self {
+        CorrectionError::Csv(e)
+    }
+}
+
+impl PaymentEngine {
+    /// Apply a `client,delta_available,delta_held,reason` CSV of corrections directly to the accounts.
+    /// Corrections bypass the transaction rules, they apply to locked accounts and can't be disputed.
+    /// Every applied correction is kept in the audit log with its reason.
+    /// Processing stops at the first bad correction, the ones before it stay applied.
+    /// Returns the number of corrections applied.
+    pub fn process_corrections_csv<R: Read>(
+        &mut self,
+        reader: R,
+    ) -> Result<usize, CorrectionError> {
+        let corrections = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All) // allow whitespace
+            .from_reader(reader)
+            .into_deserialize::<Correction>();
+        let mut applied = 0;
+        for correction in corrections {
+            let correction = correction?;
+            let account = self
+                .accounts
+                .get_mut(&correction.client)
+                .ok_or(CorrectionError::NonExistingAccount(correction.client))?;
+            if !account.correct(correction.delta_available, correction.delta_held) {
+                return Err(CorrectionError::WouldCreateNegativeBalance(
+                    correction.client,
+                ));
+            }
+            self.corrections.push(correction);
+            applied += 1;
+        }
+        Ok(applied)
+    }
+
+    /// Audit log of every correction applied, in order
+    pub fn corrections(&self) -> &[Correction] {
+        &self.corrections
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_corrections() {
+        let mut engine = PaymentEngine::example();
+        let csv = r#"
+        client, delta_available, delta_held, reason
+        1, 25.0, 0.0, system error rectification
+        2, 5.0, -20.0, regulatory penalty"#;
+        assert_eq!(engine.process_corrections_csv(csv.as_bytes()).unwrap(), 2);
+        assert_eq!(engine.accounts[&1].available(), 125.0);
+        assert_eq!(engine.accounts[&2].available(), 5.0);
+        assert_eq!(engine.accounts[&2].held(), 30.0);
+        let reasons: Vec<&str> = engine
+            .corrections()
+            .iter()
+            .map(|c| c.reason.as_str())
+            .collect();
+        assert_eq!(
+            reasons,
+            ["system error rectification", "regulatory penalty"]
+        );
+    }
+
+    #[test]
+    fn negative_balance_correction() {
+        let mut engine = PaymentEngine::example();
+        let csv = r#"
+        client, delta_available, delta_held, reason
+        1, -100.01, 0.0, overdrawn"#;
+        let res = engine.process_corrections_csv(csv.as_bytes());
+        assert!(match res.unwrap_err() {
+            CorrectionError::WouldCreateNegativeBalance(client) => client == 1,
+            _ => false,
+        });
+        // nothing was changed or logged
+        assert_eq!(engine.accounts[&1].available(), 100.0);
+        assert!(engine.corrections().is_empty());
+    }
+
+    #[test]
+    fn correction_without_account() {
+        let mut engine = PaymentEngine::example();
+        let csv = r#"
+        client, delta_available, delta_held, reason
+        4, 1.0, 0.0, typo"#;
+        let res = engine.process_corrections_csv(csv.as_bytes());
+        assert!(matches!(
+            res.unwrap_err(),
+            CorrectionError::NonExistingAccount(4)
+        ));
+    }
+}
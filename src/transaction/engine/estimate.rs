@@ -0,0 +1,130 @@
+use std::time::{Duration, Instant};
+
+use crate::transaction::{engine::PaymentEngine, Transaction};
+
+/// Rows read and performed per second by a release build. It's a guess, not a measurement, use
+/// `Calibration::measure` for the throughput of a machine.
+pub const DEFAULT_ROWS_PER_SEC: f64 = 1_500_000.0;
+/// Size of an average row, like `deposit,1234,123456,1234.5678`
+pub const DEFAULT_BYTES_PER_ROW: f64 = 30.0;
+
+/// Throughput of the engine on a machine, for `PaymentEngine::estimate_processing_time`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Calibration {
+    pub rows_per_sec: f64,
+    pub bytes_per_row: f64,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Calibration {
+            rows_per_sec: DEFAULT_ROWS_PER_SEC,
+            bytes_per_row: DEFAULT_BYTES_PER_ROW,
+        }
+    }
+}
+
+impl Calibration {
+    /// Time reading and performing a sample transaction CSV on this machine, after an untimed
+    /// warm-up run. The sample should look like the real input and be big enough to take a few
+    /// milliseconds. An empty sample gives the default calibration.
+    pub fn measure(sample_csv: &[u8]) -> Calibration {
+        let run = || {
+            let mut engine = PaymentEngine::default();
+            let mut rows = 0_usize;
+            for transaction in Transaction::read_from_reader(sample_csv) {
+                rows += 1;
+                if let Ok(transaction) = transaction {
+                    let _ = engine.perform_transaction(transaction);
+                }
+            }
+            rows
+        };
+        run();
+        let start = Instant::now();
+        let rows = run();
+        let elapsed = start.elapsed();
+        if rows == 0 {
+            return Calibration::default();
+        }
+        Calibration {
+            // an instant run would divide by zero
+            rows_per_sec: rows as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+            bytes_per_row: sample_csv.len() as f64 / rows as f64,
+        }
+    }
+}
+
+impl PaymentEngine {
+    /// Project how long a transaction CSV of `file_size_bytes` takes to process, from the
+    /// calibration of this machine or the default throughput if there is none.
+    /// It's a rough guess, the real time depends on the mix of transactions and the storage.
+    /// `None` if the calibration gives no time, like with a zero or negative throughput or row size.
+    pub fn estimate_processing_time(
+        file_size_bytes: u64,
+        calibration: Option<&Calibration>,
+    ) -> Option<Duration> {
+        let calibration = calibration.copied().unwrap_or_default();
+        let rows = file_size_bytes as f64 / calibration.bytes_per_row;
+        Duration::try_from_secs_f64(rows / calibration.rows_per_sec).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_estimate() {
+        let estimate = PaymentEngine::estimate_processing_time(45_000_000, None);
+        assert_eq!(estimate, Some(Duration::from_secs(1)));
+        assert_eq!(
+            PaymentEngine::estimate_processing_time(0, None),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(Calibration::measure(b""), Calibration::default());
+    }
+
+    #[test]
+    fn calibrated_estimate() {
+        let calibration = Calibration {
+            rows_per_sec: 1_000.0,
+            bytes_per_row: 20.0,
+        };
+        // 2000 rows at 1000 a second
+        assert_eq!(
+            PaymentEngine::estimate_processing_time(40_000, Some(&calibration)),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            PaymentEngine::estimate_processing_time(2_000_000, Some(&calibration)),
+            Some(Duration::from_secs(100))
+        );
+    }
+
+    #[test]
+    fn invalid_calibration() {
+        let estimate = |rows_per_sec, bytes_per_row| {
+            let calibration = Calibration {
+                rows_per_sec,
+                bytes_per_row,
+            };
+            PaymentEngine::estimate_processing_time(1_000, Some(&calibration))
+        };
+        assert_eq!(estimate(0.0, 20.0), None);
+        assert_eq!(estimate(1_000.0, 0.0), None);
+        assert_eq!(estimate(0.0, 0.0), None);
+        assert_eq!(estimate(-1_000.0, 20.0), None);
+        assert_eq!(estimate(f64::NAN, 20.0), None);
+    }
+
+    #[test]
+    fn measured_bytes_per_row() {
+        let sample = PaymentEngine::generate_test_csv(50, 100, 0.1, 1);
+        let rows = sample.lines().count() - 1;
+        let calibration = Calibration::measure(sample.as_bytes());
+        assert_eq!(calibration.bytes_per_row, sample.len() as f64 / rows as f64);
+        // the speed depends on the machine, only that it's a usable one
+        assert!(calibration.rows_per_sec.is_finite() && calibration.rows_per_sec > 0.0);
+    }
+}
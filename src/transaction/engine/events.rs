@@ -0,0 +1,220 @@
+use crate::{
+    account::Account,
+    transaction::{
+        engine::{PaymentEngine, TransactionError},
+        DisputeStatus, Transaction, TransactionType,
+    },
+};
+
+/// A change to the engine state, for event sourced consumers
+#[derive(Clone, Debug, PartialEq)]
+pub enum DomainEvent {
+    AccountCreated(u16),
+    Deposited { client: u16, tx: u32, amount: f64 },
+    Withdrawn { client: u16, tx: u32, amount: f64 },
+    DisputeOpened { client: u16, tx: u32 },
+    DisputeResolved { client: u16, tx: u32 },
+    ChargebackProcessed { client: u16, tx: u32 },
+}
+
+impl DomainEvent {
+    /// The transaction which performs the event, `None` for an account being created
+    pub fn to_transaction(&self) -> Option<Transaction> {
+        let transaction = match *self {
+            DomainEvent::AccountCreated(_) => return None,
+            DomainEvent::Deposited { client, tx, amount } => {
+                Transaction::new(TransactionType::Deposit, client, tx, Some(amount))
+            }
+            DomainEvent::Withdrawn { client, tx, amount } => {
+                Transaction::new(TransactionType::Withdrawal, client, tx, Some(amount))
+            }
+            DomainEvent::DisputeOpened { client, tx } => {
+                Transaction::new(TransactionType::Dispute, client, tx, None)
+            }
+            DomainEvent::DisputeResolved { client, tx } => {
+                Transaction::new(TransactionType::Resolve, client, tx, None)
+            }
+            DomainEvent::ChargebackProcessed { client, tx } => {
+                Transaction::new(TransactionType::Chargeback, client, tx, None)
+            }
+        };
+        Some(transaction)
+    }
+}
+
+impl PaymentEngine {
+    /// Describe the state of the engine as a log of events, which `from_event_log` turns back
+    /// into the same accounts and transaction record. The order the transactions came in isn't
+    /// kept for disputes: every account is created first, then the deposits and withdrawals follow
+    /// in the order they were applied, then the disputes are opened, resolved and charged back.
+    /// Only what the transaction record explains is in the log, so adjustments, corrections,
+    /// opening balances, fees paid into the house account and pruned records don't round trip.
+    /// A reversed transaction is left out along with its reversal.
+    pub fn convert_to_event_sourced_log(&self) -> Vec<DomainEvent> {
+        let mut clients: Vec<u16> = self.accounts.keys().copied().collect();
+        clients.sort_unstable();
+        let mut events: Vec<DomainEvent> = clients
+            .into_iter()
+            .map(DomainEvent::AccountCreated)
+            .collect();
+        let mut opened = Vec::new();
+        let mut resolved = Vec::new();
+        let mut charged_back = Vec::new();
+        for t in self.transactions.values() {
+            // a rolled back transaction was reversed, so together they never happened
+            if t.dispute_status == Some(DisputeStatus::Rolledback) {
+                continue;
+            }
+            let (client, tx) = (t.client, t.tx);
+            let amount = t.amount.unwrap_or_default();
+            match t.transaction_type {
+                TransactionType::Deposit => {
+                    events.push(DomainEvent::Deposited { client, tx, amount })
+                }
+                TransactionType::Withdrawal => {
+                    events.push(DomainEvent::Withdrawn { client, tx, amount })
+                }
+                _ => continue,
+            }
+            match t.dispute_status {
+                None | Some(DisputeStatus::Rolledback) => continue,
+                Some(DisputeStatus::Disputed) => {}
+                Some(DisputeStatus::Resolved) => {
+                    resolved.push(DomainEvent::DisputeResolved { client, tx })
+                }
+                Some(DisputeStatus::Chargeback) => {
+                    charged_back.push(DomainEvent::ChargebackProcessed { client, tx })
+                }
+            }
+            opened.push(DomainEvent::DisputeOpened { client, tx });
+        }
+        // a chargeback locks the account, so it comes after everything else of the client
+        events.extend(opened);
+        events.extend(resolved);
+        events.extend(charged_back);
+        events
+    }
+
+    /// Build an engine from an event log like the one `convert_to_event_sourced_log` writes,
+    /// the errors of the events which couldn't be performed are returned.
+    pub fn from_event_log<I>(events: I) -> (PaymentEngine, Vec<TransactionError>)
+    where
+        I: IntoIterator<Item = DomainEvent>,
+    {
+        let mut engine = PaymentEngine::default();
+        let mut errors = Vec::new();
+        for event in events {
+            match event.to_transaction() {
+                Some(transaction) => {
+                    if let Err(e) = engine.perform_transaction(transaction) {
+                        errors.push(e);
+                    }
+                }
+                None => {
+                    if let DomainEvent::AccountCreated(client) = event {
+                        engine
+                            .accounts
+                            .entry(client)
+                            .or_insert_with(|| Account::new(client));
+                    }
+                }
+            }
+        }
+        (engine, errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::account::DEFAULT_SCALE;
+
+    /// Panic unless both engines have the same accounts and transaction record
+    fn assert_same_state(a: &PaymentEngine, b: &PaymentEngine) {
+        assert_eq!(a.accounts.len(), b.accounts.len());
+        for (client, account) in &a.accounts {
+            assert_eq!(
+                account.to_view(DEFAULT_SCALE),
+                b.accounts[client].to_view(DEFAULT_SCALE)
+            );
+        }
+        assert_eq!(a.transactions.len(), b.transactions.len());
+        for (key, transaction) in &a.transactions {
+            let other = &b.transactions[key];
+            assert_eq!(other.transaction_type, transaction.transaction_type);
+            assert_eq!(other.amount, transaction.amount);
+            assert_eq!(other.dispute_status, transaction.dispute_status);
+        }
+    }
+
+    #[test]
+    fn event_log() {
+        let mut engine = PaymentEngine::default();
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(100.0)),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+            Transaction::new(TransactionType::Withdrawal, 2, 2, Some(5.0)),
+            Transaction::new(TransactionType::Deposit, 2, 3, Some(20.0)),
+            Transaction::new(TransactionType::Dispute, 2, 3, None),
+            Transaction::new(TransactionType::Chargeback, 2, 3, None),
+        ];
+        let errors = engine.perform_transactions(txs);
+        // the failed withdrawal still opened the account of client '2'
+        assert!(matches!(
+            errors[..],
+            [TransactionError::InsufficientFunds(2)]
+        ));
+        assert_eq!(
+            engine.convert_to_event_sourced_log(),
+            [
+                DomainEvent::AccountCreated(1),
+                DomainEvent::AccountCreated(2),
+                DomainEvent::Deposited {
+                    client: 1,
+                    tx: 1,
+                    amount: 100.0
+                },
+                DomainEvent::Deposited {
+                    client: 2,
+                    tx: 3,
+                    amount: 20.0
+                },
+                DomainEvent::DisputeOpened { client: 1, tx: 1 },
+                DomainEvent::DisputeOpened { client: 2, tx: 3 },
+                DomainEvent::ChargebackProcessed { client: 2, tx: 3 },
+            ]
+        );
+    }
+
+    #[test]
+    fn event_log_round_trip() {
+        for seed in 0..20 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut engine = PaymentEngine::default();
+            for tx in 1..=200 {
+                let client = rng.random_range(1..=4);
+                let amount = rng.random_range(1..1_000_000) as f64 / 10000_f64;
+                let referred = rng.random_range(1..=tx);
+                let transaction = match rng.random_range(0..6) {
+                    0 | 1 => Transaction::new(TransactionType::Deposit, client, tx, Some(amount)),
+                    2 => Transaction::new(TransactionType::Withdrawal, client, tx, Some(amount)),
+                    3 => Transaction::new(TransactionType::Dispute, client, referred, None),
+                    4 => Transaction::new(TransactionType::Resolve, client, referred, None),
+                    _ => Transaction::new(TransactionType::Chargeback, client, referred, None),
+                };
+                let _ = engine.perform_transaction(transaction);
+            }
+            let (replayed, errors) =
+                PaymentEngine::from_event_log(engine.convert_to_event_sourced_log());
+            assert!(errors.is_empty(), "seed {}: {:?}", seed, errors);
+            assert_same_state(&engine, &replayed);
+            // the log of the replayed engine is the same log again
+            assert_eq!(
+                replayed.convert_to_event_sourced_log(),
+                engine.convert_to_event_sourced_log()
+            );
+        }
+    }
+}
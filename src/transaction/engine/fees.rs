@@ -0,0 +1,152 @@
+use crate::{
+    account::Account,
+    transaction::{
+        engine::{PaymentEngine, TransactionError},
+        DisputeStatus,
+    },
+};
+
+impl PaymentEngine {
+    /// Charge every account a fee of `rate` times its held funds, taken out of the held funds and
+    /// credited to the house account, see `with_house_account`. Without a house account the fee
+    /// isn't credited anywhere. It's a batch operation to call once per accrual period, `rate` is
+    /// clamped to between 0 and 1 and the total of the fees is returned, a NaN or infinite rate
+    /// is an error. The fee is also charged against each open dispute, so resolving or charging
+    /// one back releases what's left of it and the held funds stay what the disputes hold, see
+    /// `Transaction::held_amount`. The amounts of the transactions stay as they were performed.
+    /// Locked accounts are charged too, the house account itself isn't.
+    pub fn apply_held_fee(&mut self, rate: f64) -> Result<f64, TransactionError> {
+        if !rate.is_finite() {
+            return Err(TransactionError::InvalidFeeRate(rate));
+        }
+        let rate = rate.clamp(0_f64, 1_f64);
+        let mut total_fee = 0_f64;
+        for account in self.accounts.values_mut() {
+            if Some(account.client()) == self.house_account || account.held() == 0_f64 {
+                continue;
+            }
+            let fee = rate * account.held();
+            // the fee is never more than the held funds
+            account.correct(0_f64, -fee);
+            total_fee += fee;
+        }
+        for transaction in self.transactions.values_mut() {
+            if transaction.dispute_status == Some(DisputeStatus::Disputed)
+                && Some(transaction.client) != self.house_account
+            {
+                transaction.held_fee += rate * transaction.held_amount();
+            }
+        }
+        if let Some(house) = self.house_account.filter(|_| total_fee != 0_f64) {
+            self.accounts
+                .entry(house)
+                .or_insert_with(|| Account::new(house))
+                .deposit(total_fee);
+        }
+        Ok(total_fee)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{Transaction, TransactionType};
+
+    fn disputed_engine() -> PaymentEngine {
+        let mut engine = PaymentEngine::default().with_house_account(0);
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(100.0)),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(50.0)),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+            Transaction::new(TransactionType::Deposit, 2, 3, Some(40.0)),
+            Transaction::new(TransactionType::Dispute, 2, 3, None),
+            Transaction::new(TransactionType::Deposit, 3, 4, Some(10.0)),
+        ];
+        assert!(engine.perform_transactions(txs).is_empty());
+        engine
+    }
+
+    #[test]
+    fn held_fee() {
+        let mut engine = disputed_engine();
+        let totals_before: f64 = engine.accounts.values().map(Account::total).sum();
+        assert_eq!(engine.apply_held_fee(0.01).unwrap(), 1.4);
+        assert_eq!(engine.accounts[&1].held(), 99.0);
+        assert_eq!(engine.accounts[&1].available(), 50.0);
+        assert_eq!(engine.accounts[&2].held(), 39.6);
+        // nothing held, nothing charged
+        assert_eq!(engine.accounts[&3].total(), 10.0);
+        assert_eq!(engine.accounts[&0].available(), 1.4);
+        // the fees only move money into the house account
+        let totals_after: f64 = engine.accounts.values().map(Account::total).sum();
+        assert!((totals_after - totals_before).abs() < 1e-9);
+        assert!(engine.find_accounts_with_inconsistent_held().is_empty());
+
+        // resolving releases what's left of the dispute
+        assert!(engine
+            .perform_transaction(Transaction::new(TransactionType::Resolve, 1, 1, None))
+            .is_ok());
+        assert_eq!(engine.accounts[&1].held(), 0.0);
+        assert_eq!(engine.accounts[&1].available(), 149.0);
+        // the record keeps the amount which was deposited
+        assert_eq!(engine.transactions[&(1, 1)].amount, Some(100.0));
+        assert_eq!(engine.transactions[&(2, 3)].amount, Some(40.0));
+        assert_eq!(engine.transactions[&(2, 3)].held_amount(), 39.6);
+    }
+
+    #[test]
+    fn held_fee_lowers_dispute_exposure() {
+        let mut engine = disputed_engine();
+        assert!(engine.apply_held_fee(0.01).is_ok());
+        let exposure = engine.compute_dispute_exposure();
+        let held: f64 = engine.accounts.values().map(Account::held).sum();
+        assert_eq!(held, 138.6);
+        assert_eq!(exposure.total_disputed_amount, held);
+        assert_eq!(exposure.max_single_dispute, 99.0);
+    }
+
+    #[test]
+    fn held_fee_keeps_replays_identical() {
+        let mut engine = disputed_engine().with_ignore_duplicates(true);
+        assert!(engine.apply_held_fee(0.5).is_ok());
+        // an identical replay of the disputed deposit is still a no-op
+        assert!(engine
+            .perform_transaction(Transaction::new(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(100.0)
+            ))
+            .is_ok());
+        assert_eq!(engine.accounts[&1].held(), 50.0);
+        // the charge back takes what's left of the dispute
+        assert!(engine
+            .perform_transaction(Transaction::new(TransactionType::Chargeback, 1, 1, None))
+            .is_ok());
+        assert_eq!(engine.accounts[&1].held(), 0.0);
+        assert_eq!(engine.accounts[&1].total(), 50.0);
+    }
+
+    #[test]
+    fn held_fee_rejects_non_finite_rates() {
+        let mut engine = disputed_engine();
+        for rate in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert!(matches!(
+                engine.apply_held_fee(rate),
+                Err(TransactionError::InvalidFeeRate(_))
+            ));
+        }
+        assert_eq!(engine.accounts[&1].held(), 100.0);
+        assert!(!engine.accounts.contains_key(&0));
+    }
+
+    #[test]
+    fn held_fee_without_house_account() {
+        let mut engine = disputed_engine();
+        engine.house_account = None;
+        assert_eq!(engine.apply_held_fee(2.0).unwrap(), 140.0);
+        assert!(!engine.accounts.contains_key(&0));
+        assert_eq!(engine.accounts[&1].held(), 0.0);
+        assert_eq!(engine.apply_held_fee(0.5).unwrap(), 0.0);
+    }
+}
@@ -0,0 +1,607 @@
+use std::{collections::HashMap, time::Duration};
+
+use crate::transaction::{engine::PaymentEngine, DisputeStatus, Transaction, TransactionType};
+
+/// Chargebacks sooner than this after the deposit are suspicious, unless the engine is configured otherwise
+pub const DEFAULT_ROUND_TRIP_THRESHOLD: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Length of the window of `AmlRule::HighVelocity`
+const HOUR_SECS: u64 = 60 * 60;
+
+/// A deposit which was charged back shortly after it was made.
+/// The chargeback refers to the deposit so both have the id `tx`.
+#[derive(Debug, PartialEq)]
+pub struct FraudSuspect {
+    pub client: u16,
+    pub tx: u32,
+    pub elapsed: Duration,
+}
+
+/// The accounts `freeze_accounts_above_chargeback_ratio` froze, sorted by client id,
+/// and the sum of their totals
+#[derive(Debug, Default, PartialEq)]
+pub struct FreezeReport {
+    pub frozen_clients: Vec<u16>,
+    pub total_frozen_assets: f64,
+}
+
+/// Thresholds of `detect_account_takeover`. Transactions have no counterparty, so a change of
+/// payees can't be told, the share of withdrawals stands in for where the money goes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccountTakeoverRules {
+    /// The last this many deposits and withdrawals of a client are compared against the ones before
+    pub recent_transactions: usize,
+    /// A recent amount more than this many times the mean amount before is a spike
+    pub max_amount_spike_ratio: f64,
+    /// The share of withdrawals among the recent transactions may grow by this much, 0.5 is 50 points
+    pub max_withdrawal_share_increase: f64,
+}
+
+/// A client whose recent transactions don't look like its history. The risk score adds up how
+/// far over its threshold each triggered rule is, so it's above 1, the triggers describe them.
+#[derive(Debug, PartialEq)]
+pub struct AccountTakeoverSuspect {
+    pub client: u16,
+    pub risk_score: f64,
+    pub triggers: Vec<String>,
+}
+
+/// Anti-money-laundering heuristics checked against the transaction history of each client
+#[derive(Clone, Debug, PartialEq)]
+pub enum AmlRule {
+    /// More than this many transactions within an hour, by their timestamps
+    HighVelocity { transactions_per_hour: usize },
+    /// A deposit or withdrawal above the threshold
+    LargeTransaction { threshold: f64 },
+    /// A withdrawal within the window after a deposit, by their timestamps
+    RapidDepositWithdrawal { window: Duration },
+    /// At least this many charged back transactions. The first chargeback locks an account,
+    /// so more than one only comes from state that was imported or joined.
+    MultipleChargebacks { count: u32 },
+}
+
+/// A client which triggered an AML rule, the evidence describes the transactions which did
+#[derive(Debug, PartialEq)]
+pub struct SuspiciousActivityReport {
+    pub client: u16,
+    pub rule_triggered: AmlRule,
+    pub evidence: String,
+}
+
+impl AmlRule {
+    /// Describes why the history triggers the rule, `None` if it doesn't.
+    /// The history is in the order the transactions were performed.
+    fn check(&self, history: &[&Transaction]) -> Option<String> {
+        match *self {
+            AmlRule::HighVelocity {
+                transactions_per_hour,
+            } => {
+                let mut times: Vec<u64> = history.iter().filter_map(|t| t.timestamp).collect();
+                times.sort_unstable();
+                // slide an hour long window over the sorted times
+                let mut start = 0;
+                for end in 0..times.len() {
+                    while times[end] - times[start] >= HOUR_SECS {
+                        start += 1;
+                    }
+                    let count = end - start + 1;
+                    if count > transactions_per_hour {
+                        return Some(format!(
+                            "{} transactions within an hour starting at {}",
+                            count, times[start]
+                        ));
+                    }
+                }
+                None
+            }
+            AmlRule::LargeTransaction { threshold } => {
+                let large: Vec<String> = history
+                    .iter()
+                    .filter(|t| t.dispute_possible() && t.amount.is_some_and(|a| a > threshold))
+                    .map(|t| t.tx.to_string())
+                    .collect();
+                (!large.is_empty())
+                    .then(|| format!("transactions {} above {}", large.join(", "), threshold))
+            }
+            AmlRule::RapidDepositWithdrawal { window } => {
+                let mut last_deposit: Option<(u32, u64)> = None;
+                for t in history {
+                    let Some(timestamp) = t.timestamp else {
+                        continue;
+                    };
+                    match t.transaction_type {
+                        TransactionType::Deposit => last_deposit = Some((t.tx, timestamp)),
+                        TransactionType::Withdrawal => {
+                            if let Some((deposit, deposited_at)) = last_deposit {
+                                let elapsed = timestamp.saturating_sub(deposited_at);
+                                if Duration::from_secs(elapsed) <= window {
+                                    return Some(format!(
+                                        "deposit {} withdrawn by {} after {}s",
+                                        deposit, t.tx, elapsed
+                                    ));
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                None
+            }
+            AmlRule::MultipleChargebacks { count } => {
+                let charged_back: Vec<String> = history
+                    .iter()
+                    .filter(|t| t.dispute_status == Some(DisputeStatus::Chargeback))
+                    .map(|t| t.tx.to_string())
+                    .collect();
+                (charged_back.len() >= count as usize && count > 0).then(|| {
+                    format!(
+                        "{} chargebacks of transactions {}",
+                        charged_back.len(),
+                        charged_back.join(", ")
+                    )
+                })
+            }
+        }
+    }
+}
+
+impl PaymentEngine {
+    /// Check every rule against the transaction history of each client, only transactions which
+    /// were performed are in the history. A client gets a report for every rule it triggers,
+    /// the reports are ordered by client id and then by the order of the rules.
+    pub fn report_suspicious_activity(&self, rules: &[AmlRule]) -> Vec<SuspiciousActivityReport> {
+        let mut histories: HashMap<u16, Vec<&Transaction>> = HashMap::new();
+        for transaction in self.transactions.values() {
+            histories
+                .entry(transaction.client)
+                .or_default()
+                .push(transaction);
+        }
+        let mut clients: Vec<u16> = histories.keys().copied().collect();
+        clients.sort_unstable();
+        let mut reports = Vec::new();
+        for client in clients {
+            for rule in rules {
+                if let Some(evidence) = rule.check(&histories[&client]) {
+                    reports.push(SuspiciousActivityReport {
+                        client,
+                        rule_triggered: rule.clone(),
+                        evidence,
+                    });
+                }
+            }
+        }
+        reports
+    }
+
+    /// The amount of the deposits of a client which were charged back over the amount of all
+    /// their deposits, from the recorded transactions. `None` if the client has no deposits.
+    pub fn compute_chargeback_to_deposit_ratio(&self, client: u16) -> Option<f64> {
+        let mut deposited = 0_f64;
+        let mut charged_back = 0_f64;
+        for t in self
+            .transactions
+            .values()
+            .filter(|t| t.client == client && t.transaction_type == TransactionType::Deposit)
+        {
+            let amount = t.amount.unwrap_or_default();
+            deposited += amount;
+            if t.dispute_status == Some(DisputeStatus::Chargeback) {
+                charged_back += amount;
+            }
+        }
+        (deposited > 0_f64).then(|| charged_back / deposited)
+    }
+
+    /// The clients whose chargeback to deposit ratio is above the threshold, sorted by client id
+    pub fn clients_above_chargeback_ratio(&self, threshold: f64) -> Vec<u16> {
+        let mut clients: Vec<u16> = self
+            .accounts
+            .keys()
+            .copied()
+            .filter(|&client| {
+                self.compute_chargeback_to_deposit_ratio(client)
+                    .is_some_and(|ratio| ratio > threshold)
+            })
+            .collect();
+        clients.sort_unstable();
+        clients
+    }
+
+    /// Lock every account whose chargeback to deposit ratio is above the threshold.
+    /// A chargeback locks its account already, so this mostly catches state which was imported or
+    /// joined, but every account above the threshold is in the report whether it was locked before
+    /// or not. The lock hook is only called for the accounts which weren't.
+    pub fn freeze_accounts_above_chargeback_ratio(&mut self, threshold: f64) -> FreezeReport {
+        let mut report = FreezeReport {
+            frozen_clients: self.clients_above_chargeback_ratio(threshold),
+            ..Default::default()
+        };
+        for client in &report.frozen_clients {
+            // the clients come from the accounts
+            let account = self.accounts.get_mut(client).unwrap();
+            report.total_frozen_assets += account.total();
+            if !account.locked() {
+                account.lock();
+                if let Some(hook) = &self.lock_hook {
+                    (hook.lock().unwrap())(*client);
+                }
+            }
+        }
+        report
+    }
+
+    /// Compare the recent deposits and withdrawals of every client against the baseline of the ones
+    /// before them, in the order they were performed, and report the clients which changed
+    /// behaviour. Clients with no more transactions than the recent window have no baseline and
+    /// aren't checked. The result is ordered by client id.
+    pub fn detect_account_takeover(
+        &self,
+        rules: &AccountTakeoverRules,
+    ) -> Vec<AccountTakeoverSuspect> {
+        let mut histories: HashMap<u16, Vec<&Transaction>> = HashMap::new();
+        for transaction in self.transactions.values().filter(|t| t.dispute_possible()) {
+            histories
+                .entry(transaction.client)
+                .or_default()
+                .push(transaction);
+        }
+        let withdrawal_share = |transactions: &[&Transaction]| {
+            let withdrawals = transactions
+                .iter()
+                .filter(|t| t.transaction_type == TransactionType::Withdrawal)
+                .count();
+            withdrawals as f64 / transactions.len() as f64
+        };
+        let mut suspects: Vec<AccountTakeoverSuspect> = histories
+            .into_iter()
+            .filter(|(_, history)| history.len() > rules.recent_transactions)
+            .filter_map(|(client, history)| {
+                let (baseline, recent) =
+                    history.split_at(history.len() - rules.recent_transactions);
+                let mut risk_score = 0_f64;
+                let mut triggers = Vec::new();
+
+                let amount = |t: &&Transaction| t.amount.unwrap_or_default();
+                let mean = baseline.iter().map(amount).sum::<f64>() / baseline.len() as f64;
+                let largest = recent.iter().map(amount).fold(0_f64, f64::max);
+                if mean > 0_f64 && largest / mean > rules.max_amount_spike_ratio {
+                    risk_score += largest / mean / rules.max_amount_spike_ratio;
+                    triggers.push(format!(
+                        "amount {} is {:.1} times the mean {:.4} before",
+                        largest,
+                        largest / mean,
+                        mean
+                    ));
+                }
+
+                if !recent.is_empty() {
+                    let (before, now) = (withdrawal_share(baseline), withdrawal_share(recent));
+                    let increase = now - before;
+                    if increase > rules.max_withdrawal_share_increase {
+                        // a zero threshold still gives a finite score
+                        risk_score +=
+                            increase / rules.max_withdrawal_share_increase.max(f64::EPSILON);
+                        triggers.push(format!(
+                            "withdrawals went from {:.0}% to {:.0}% of the transactions",
+                            before * 100_f64,
+                            now * 100_f64
+                        ));
+                    }
+                }
+
+                (!triggers.is_empty()).then_some(AccountTakeoverSuspect {
+                    client,
+                    risk_score,
+                    triggers,
+                })
+            })
+            .collect();
+        suspects.sort_by_key(|suspect| suspect.client);
+        suspects
+    }
+
+    /// Flag deposits charged back within the round trip threshold instead of `DEFAULT_ROUND_TRIP_THRESHOLD`
+    pub fn with_round_trip_threshold(mut self, threshold: Duration) -> Self {
+        self.round_trip_threshold = Some(threshold);
+        self
+    }
+
+    /// Find the deposits which were disputed and charged back within the round trip threshold,
+    /// in the order the deposits were made. Both the deposit and the chargeback need a timestamp.
+    pub fn detect_round_trip_fraud(&self) -> Vec<FraudSuspect> {
+        let threshold = self
+            .round_trip_threshold
+            .unwrap_or(DEFAULT_ROUND_TRIP_THRESHOLD);
+        self.transactions
+            .values()
+            .filter(|t| {
+                t.transaction_type == TransactionType::Deposit
+                    && t.dispute_status == Some(DisputeStatus::Chargeback)
+            })
+            .filter_map(|t| {
+                let elapsed = t.chargeback_at?.saturating_sub(t.timestamp?);
+                Some(FraudSuspect {
+                    client: t.client,
+                    tx: t.tx,
+                    elapsed: Duration::from_secs(elapsed),
+                })
+            })
+            .filter(|suspect| suspect.elapsed < threshold)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 60 * 60;
+
+    fn charged_back_deposit(
+        engine: &mut PaymentEngine,
+        client: u16,
+        tx: u32,
+        deposited_at: u64,
+        charged_back_at: u64,
+    ) {
+        let txs = [
+            Transaction::new(TransactionType::Deposit, client, tx, Some(50.0))
+                .with_timestamp(deposited_at),
+            Transaction::new(TransactionType::Dispute, client, tx, None)
+                .with_timestamp(deposited_at),
+            Transaction::new(TransactionType::Chargeback, client, tx, None)
+                .with_timestamp(charged_back_at),
+        ];
+        for tx in txs {
+            assert!(engine.perform_transaction(tx).is_ok());
+        }
+    }
+
+    #[test]
+    fn round_trip_fraud() {
+        let mut engine = PaymentEngine::default();
+        charged_back_deposit(&mut engine, 1, 1, 1000, 1000);
+        charged_back_deposit(&mut engine, 2, 2, 1000, 1000 + 48 * HOUR);
+        charged_back_deposit(&mut engine, 3, 3, 1000, 1000 + 23 * HOUR);
+        assert_eq!(
+            engine.detect_round_trip_fraud(),
+            [
+                FraudSuspect {
+                    client: 1,
+                    tx: 1,
+                    elapsed: Duration::ZERO,
+                },
+                FraudSuspect {
+                    client: 3,
+                    tx: 3,
+                    elapsed: Duration::from_secs(23 * HOUR),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn round_trip_threshold() {
+        let mut engine =
+            PaymentEngine::default().with_round_trip_threshold(Duration::from_secs(HOUR));
+        charged_back_deposit(&mut engine, 1, 1, 1000, 1000 + 2 * HOUR);
+        assert!(engine.detect_round_trip_fraud().is_empty());
+        // without timestamps the elapsed time isn't known
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 2, 2, Some(50.0)),
+            Transaction::new(TransactionType::Dispute, 2, 2, None),
+            Transaction::new(TransactionType::Chargeback, 2, 2, None),
+        ];
+        for tx in txs {
+            assert!(engine.perform_transaction(tx).is_ok());
+        }
+        assert!(engine.detect_round_trip_fraud().is_empty());
+    }
+
+    #[test]
+    fn chargeback_ratio() {
+        let mut engine = PaymentEngine::default();
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(300.0)),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(100.0)),
+            Transaction::new(TransactionType::Dispute, 1, 2, None),
+            Transaction::new(TransactionType::Chargeback, 1, 2, None),
+            Transaction::new(TransactionType::Deposit, 2, 3, Some(80.0)),
+            Transaction::new(TransactionType::Dispute, 2, 3, None),
+            Transaction::new(TransactionType::Resolve, 2, 3, None),
+            Transaction::new(TransactionType::Deposit, 3, 4, Some(10.0)),
+            Transaction::new(TransactionType::Dispute, 3, 4, None),
+            Transaction::new(TransactionType::Chargeback, 3, 4, None),
+        ];
+        assert!(engine.perform_transactions(txs).is_empty());
+        assert_eq!(engine.compute_chargeback_to_deposit_ratio(1), Some(0.25));
+        // a resolved dispute isn't a chargeback
+        assert_eq!(engine.compute_chargeback_to_deposit_ratio(2), Some(0.0));
+        assert_eq!(engine.compute_chargeback_to_deposit_ratio(3), Some(1.0));
+        // no deposits at all
+        assert_eq!(engine.compute_chargeback_to_deposit_ratio(4), None);
+
+        assert_eq!(engine.clients_above_chargeback_ratio(0.2), [1, 3]);
+        assert_eq!(engine.clients_above_chargeback_ratio(0.25), [3]);
+    }
+
+    #[test]
+    fn freeze_above_chargeback_ratio() {
+        let mut engine = PaymentEngine::default();
+        let txs = [
+            // 50 of 150 charged back
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(100.0)),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(50.0)),
+            Transaction::new(TransactionType::Dispute, 1, 2, None),
+            Transaction::new(TransactionType::Chargeback, 1, 2, None),
+            // 20 of 220 charged back
+            Transaction::new(TransactionType::Deposit, 2, 3, Some(200.0)),
+            Transaction::new(TransactionType::Deposit, 2, 4, Some(20.0)),
+            Transaction::new(TransactionType::Dispute, 2, 4, None),
+            Transaction::new(TransactionType::Chargeback, 2, 4, None),
+            // 40 of 65 charged back
+            Transaction::new(TransactionType::Deposit, 3, 5, Some(25.0)),
+            Transaction::new(TransactionType::Deposit, 3, 6, Some(40.0)),
+            Transaction::new(TransactionType::Dispute, 3, 6, None),
+            Transaction::new(TransactionType::Chargeback, 3, 6, None),
+        ];
+        assert!(engine.perform_transactions(txs).is_empty());
+        let report = engine.freeze_accounts_above_chargeback_ratio(0.25);
+        assert_eq!(
+            report,
+            FreezeReport {
+                frozen_clients: vec![1, 3],
+                total_frozen_assets: 125.0,
+            }
+        );
+        assert!(engine.accounts[&1].locked());
+        assert!(engine.accounts[&3].locked());
+    }
+
+    fn timed(transaction_type: TransactionType, tx: u32, amount: f64, at: u64) -> Transaction {
+        Transaction::new(transaction_type, 1, tx, Some(amount)).with_timestamp(at)
+    }
+
+    #[test]
+    fn aml_high_velocity() {
+        let rules = [AmlRule::HighVelocity {
+            transactions_per_hour: 3,
+        }];
+        let (engine, _) = PaymentEngine::from_transactions(
+            (1..=4).map(|tx| timed(TransactionType::Deposit, tx, 1.0, u64::from(tx) * 1200)),
+        );
+        // three fit in any hour, the fourth is an hour after the first
+        assert!(engine.report_suspicious_activity(&rules).is_empty());
+
+        let (engine, _) = PaymentEngine::from_transactions(
+            (1..=4).map(|tx| timed(TransactionType::Deposit, tx, 1.0, u64::from(tx) * 900)),
+        );
+        assert_eq!(
+            engine.report_suspicious_activity(&rules),
+            [SuspiciousActivityReport {
+                client: 1,
+                rule_triggered: rules[0].clone(),
+                evidence: "4 transactions within an hour starting at 900".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn aml_large_transaction() {
+        let rules = [AmlRule::LargeTransaction { threshold: 1000.0 }];
+        let (engine, _) = PaymentEngine::from_transactions([
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(5000.0)),
+            Transaction::new(TransactionType::Withdrawal, 1, 2, Some(1000.0)),
+            Transaction::new(TransactionType::Withdrawal, 1, 3, Some(1500.0)),
+            Transaction::new(TransactionType::Deposit, 2, 4, Some(999.0)),
+        ]);
+        assert_eq!(
+            engine.report_suspicious_activity(&rules),
+            [SuspiciousActivityReport {
+                client: 1,
+                rule_triggered: rules[0].clone(),
+                evidence: "transactions 1, 3 above 1000".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn aml_rapid_deposit_withdrawal() {
+        let rules = [AmlRule::RapidDepositWithdrawal {
+            window: Duration::from_secs(10 * 60),
+        }];
+        let (engine, _) = PaymentEngine::from_transactions([
+            timed(TransactionType::Deposit, 1, 100.0, 1000),
+            timed(TransactionType::Withdrawal, 2, 10.0, 1000 + HOUR),
+            timed(TransactionType::Deposit, 3, 100.0, 1000 + 2 * HOUR),
+            timed(TransactionType::Withdrawal, 4, 150.0, 1000 + 2 * HOUR + 120),
+        ]);
+        assert_eq!(
+            engine.report_suspicious_activity(&rules),
+            [SuspiciousActivityReport {
+                client: 1,
+                rule_triggered: rules[0].clone(),
+                evidence: "deposit 3 withdrawn by 4 after 120s".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn aml_multiple_chargebacks() {
+        let rules = [
+            AmlRule::MultipleChargebacks { count: 2 },
+            AmlRule::LargeTransaction { threshold: 100.0 },
+        ];
+        let mut engine = PaymentEngine::default();
+        charged_back_deposit(&mut engine, 1, 1, 1000, 1000);
+        assert!(engine.report_suspicious_activity(&rules).is_empty());
+        // the account is locked after the first chargeback, a second one comes from joined state
+        let mut other = PaymentEngine::default();
+        charged_back_deposit(&mut other, 1, 2, 1000, 1000);
+        engine.transactions.extend(other.transactions);
+        assert_eq!(
+            engine.report_suspicious_activity(&rules),
+            [SuspiciousActivityReport {
+                client: 1,
+                rule_triggered: rules[0].clone(),
+                evidence: "2 chargebacks of transactions 1, 2".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn account_takeover() {
+        let mut engine = PaymentEngine::default();
+        let mut tx = 0;
+        let mut perform = |transaction_type, client, amount| {
+            tx += 1;
+            let transaction = Transaction::new(transaction_type, client, tx, Some(amount));
+            assert!(engine.perform_transaction(transaction).is_ok());
+        };
+        // small deposits, then a large one
+        for amount in [10.0, 12.0, 8.0, 10.0, 1000.0] {
+            perform(TransactionType::Deposit, 1, amount);
+        }
+        // steady
+        for _ in 0..5 {
+            perform(TransactionType::Deposit, 2, 10.0);
+        }
+        // only deposits, then the money is taken out
+        for _ in 0..4 {
+            perform(TransactionType::Deposit, 3, 10.0);
+        }
+        perform(TransactionType::Withdrawal, 3, 10.0);
+        // too short a history for a baseline
+        perform(TransactionType::Deposit, 4, 5000.0);
+
+        let rules = AccountTakeoverRules {
+            recent_transactions: 1,
+            max_amount_spike_ratio: 5.0,
+            max_withdrawal_share_increase: 0.5,
+        };
+        let suspects = engine.detect_account_takeover(&rules);
+        assert_eq!(
+            suspects.iter().map(|s| s.client).collect::<Vec<_>>(),
+            [1, 3]
+        );
+        // 1000 is 100 times the mean of 10
+        assert_eq!(suspects[0].risk_score, 20.0);
+        assert_eq!(
+            suspects[0].triggers,
+            ["amount 1000 is 100.0 times the mean 10.0000 before"]
+        );
+        assert_eq!(suspects[1].risk_score, 2.0);
+        assert_eq!(
+            suspects[1].triggers,
+            ["withdrawals went from 0% to 100% of the transactions"]
+        );
+
+        // with a window as long as the histories there is no baseline to compare against
+        let rules = AccountTakeoverRules {
+            recent_transactions: 5,
+            ..rules
+        };
+        assert!(engine.detect_account_takeover(&rules).is_empty());
+    }
+}
@@ -0,0 +1,131 @@
+use crate::{
+    account::round_amount,
+    transaction::{engine::PaymentEngine, DisputeStatus, Transaction, TransactionType},
+};
+
+impl Transaction {
+    /// Guess the amount of a deposit or withdrawal which is missing it, from the part of the account
+    /// balance that the recorded history of the client doesn't explain.
+    ///
+    /// This is a heuristic and unreliable. It only gives the right amount when the balances in the
+    /// engine come from an authoritative source which includes this transaction, like an imported
+    /// snapshot, and it is the only transaction missing from the history. Opening balances, or any
+    /// other money which isn't in the history, ends up in the guess.
+    /// Returns `None` if the transaction already has an amount or is in the history, the client has
+    /// no account, or the unexplained part doesn't fit the type of transaction.
+    pub fn infer_missing_amount_from_context(&self, engine: &PaymentEngine) -> Option<f64> {
+        if self.amount.is_some()
+            || !matches!(
+                self.transaction_type,
+                TransactionType::Deposit | TransactionType::Withdrawal
+            )
+            || engine.transactions.contains_key(&self.key())
+        {
+            return None;
+        }
+        let account = engine.accounts.get(&self.client)?;
+
+        // what the recorded history did to the total of the account
+        let history: f64 = engine
+            .transactions
+            .values()
+            .filter(|t| t.client == self.client)
+            .map(|t| {
+                let amount = t.amount.unwrap_or_default();
+                match (t.transaction_type, t.dispute_status) {
+                    (_, Some(DisputeStatus::Rolledback)) => 0_f64,
+                    // the deposit is taken back out of the account
+                    (TransactionType::Deposit, Some(DisputeStatus::Chargeback)) => 0_f64,
+                    // disputes of a withdrawal hold the amount, a chargeback removes it a second time
+                    (TransactionType::Withdrawal, Some(DisputeStatus::Chargeback)) => {
+                        -2_f64 * amount
+                    }
+                    (TransactionType::Withdrawal, _) => -amount,
+                    _ => amount,
+                }
+            })
+            .sum();
+        let corrections: f64 = engine
+            .corrections
+            .iter()
+            .filter(|c| c.client == self.client)
+            .map(|c| c.delta_available + c.delta_held)
+            .sum();
+
+        let unexplained = round_amount(account.total() - history - corrections, engine.scale());
+        let amount = match self.transaction_type {
+            TransactionType::Deposit => unexplained,
+            _ => -unexplained,
+        };
+        (amount > 0_f64).then_some(amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An engine whose balances include tx 3, but whose history lost it
+    fn engine_missing(missing: Transaction) -> PaymentEngine {
+        let mut engine = PaymentEngine::default();
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(100.0)),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(20.0)),
+            missing,
+            Transaction::new(TransactionType::Withdrawal, 1, 4, Some(30.0)),
+            Transaction::new(TransactionType::Dispute, 1, 2, None),
+            Transaction::new(TransactionType::Deposit, 2, 5, Some(5.0)),
+        ];
+        for tx in txs {
+            assert!(engine.perform_transaction(tx).is_ok());
+        }
+        engine.transactions.shift_remove(&(1, 3));
+        engine
+    }
+
+    #[test]
+    fn infer_deposit() {
+        let engine = engine_missing(Transaction::new(TransactionType::Deposit, 1, 3, Some(42.5)));
+        let missing = Transaction::new(TransactionType::Deposit, 1, 3, None);
+        assert_eq!(
+            missing.infer_missing_amount_from_context(&engine),
+            Some(42.5)
+        );
+        // the history of client 2 explains its whole balance
+        let other = Transaction::new(TransactionType::Deposit, 2, 3, None);
+        assert_eq!(other.infer_missing_amount_from_context(&engine), None);
+    }
+
+    #[test]
+    fn infer_withdrawal() {
+        let engine = engine_missing(Transaction::new(
+            TransactionType::Withdrawal,
+            1,
+            3,
+            Some(12.25),
+        ));
+        let missing = Transaction::new(TransactionType::Withdrawal, 1, 3, None);
+        assert_eq!(
+            missing.infer_missing_amount_from_context(&engine),
+            Some(12.25)
+        );
+        // money went out of the account, so it can't have been a deposit
+        let wrong_type = Transaction::new(TransactionType::Deposit, 1, 3, None);
+        assert_eq!(wrong_type.infer_missing_amount_from_context(&engine), None);
+    }
+
+    #[test]
+    fn nothing_to_infer() {
+        let engine = engine_missing(Transaction::new(TransactionType::Deposit, 1, 3, Some(1.0)));
+        // already has an amount, is already recorded, isn't a deposit or withdrawal, or no account
+        let cases = [
+            Transaction::new(TransactionType::Deposit, 1, 3, Some(1.0)),
+            Transaction::new(TransactionType::Deposit, 1, 1, None),
+            Transaction::new(TransactionType::Dispute, 1, 3, None),
+            Transaction::new(TransactionType::Deposit, 9, 3, None),
+        ];
+        for transaction in cases {
+            assert_eq!(transaction.infer_missing_amount_from_context(&engine), None);
+        }
+    }
+}
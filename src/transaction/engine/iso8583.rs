@@ -0,0 +1,276 @@
+use crate::transaction::{
+    engine::{PaymentEngine, TransactionError},
+    Transaction, TransactionType,
+};
+
+/// DE4 is in minor units, every currency is assumed to have two decimal places
+const AMOUNT_EXPONENT: i32 = 2;
+
+/// How a data element is laid out
+#[derive(Clone, Copy)]
+enum Format {
+    Fixed(usize),
+    /// Prefixed by its length in two ASCII digits
+    LlVar,
+    /// Prefixed by its length in three ASCII digits
+    LllVar,
+}
+
+/// Layouts of DE2 to DE37, the elements up to the retrieval reference number
+const FORMATS: [Format; 36] = [
+    Format::LlVar,     // 2 primary account number
+    Format::Fixed(6),  // 3 processing code
+    Format::Fixed(12), // 4 amount, transaction
+    Format::Fixed(12), // 5 amount, settlement
+    Format::Fixed(12), // 6 amount, cardholder billing
+    Format::Fixed(10), // 7 transmission date and time
+    Format::Fixed(8),  // 8 amount, cardholder billing fee
+    Format::Fixed(8),  // 9 conversion rate, settlement
+    Format::Fixed(8),  // 10 conversion rate, cardholder billing
+    Format::Fixed(6),  // 11 system trace audit number
+    Format::Fixed(6),  // 12 local transaction time
+    Format::Fixed(4),  // 13 local transaction date
+    Format::Fixed(4),  // 14 expiration date
+    Format::Fixed(4),  // 15 settlement date
+    Format::Fixed(4),  // 16 conversion date
+    Format::Fixed(4),  // 17 capture date
+    Format::Fixed(4),  // 18 merchant type
+    Format::Fixed(3),  // 19 acquiring institution country code
+    Format::Fixed(3),  // 20 PAN extended country code
+    Format::Fixed(3),  // 21 forwarding institution country code
+    Format::Fixed(3),  // 22 point of service entry mode
+    Format::Fixed(3),  // 23 card sequence number
+    Format::Fixed(3),  // 24 function code
+    Format::Fixed(2),  // 25 point of service condition code
+    Format::Fixed(2),  // 26 point of service capture code
+    Format::Fixed(1),  // 27 authorizing identification response length
+    Format::Fixed(9),  // 28 amount, transaction fee
+    Format::Fixed(9),  // 29 amount, settlement fee
+    Format::Fixed(9),  // 30 amount, transaction processing fee
+    Format::Fixed(9),  // 31 amount, settlement processing fee
+    Format::LlVar,     // 32 acquiring institution identification code
+    Format::LlVar,     // 33 forwarding institution identification code
+    Format::LlVar,     // 34 primary account number, extended
+    Format::LlVar,     // 35 track 2 data
+    Format::LllVar,    // 36 track 3 data
+    Format::Fixed(12), // 37 retrieval reference number
+];
+
+/// The data elements of a message the engine needs
+#[derive(Debug, Default, PartialEq)]
+struct Iso8583Message {
+    mti: String,
+    pan: Option<String>,
+    processing_code: Option<String>,
+    amount: Option<String>,
+    retrieval_reference: Option<String>,
+}
+
+/// Reads `len` bytes of ASCII from the start of `raw`, advancing it
+fn take<'a>(raw: &mut &'a [u8], len: usize, what: &str) -> Result<&'a str, String> {
+    if raw.len() < len {
+        return Err(format!("the message ends inside the {}", what));
+    }
+    let (field, rest) = raw.split_at(len);
+    *raw = rest;
+    if !field.is_ascii() {
+        return Err(format!("the {} isn't ASCII", what));
+    }
+    // ASCII is always valid UTF-8
+    Ok(std::str::from_utf8(field).unwrap())
+}
+
+/// Parse an ASCII message with a binary bitmap: the 4 digit MTI, the primary bitmap, the secondary
+/// bitmap if bit 1 is set, then the present data elements. Only the elements up to DE37 are read.
+fn parse(mut raw: &[u8]) -> Result<Iso8583Message, String> {
+    let mti = take(&mut raw, 4, "MTI")?.to_string();
+    if raw.len() < 8 {
+        return Err(String::from("the message ends inside the bitmap"));
+    }
+    let bitmap = u64::from_be_bytes(raw[..8].try_into().unwrap());
+    raw = &raw[8..];
+    let present = |element: usize| bitmap & (1 << (64 - element)) != 0;
+    if present(1) {
+        // the secondary bitmap is only about DE65 and up
+        take(&mut raw, 8, "secondary bitmap")?;
+    }
+    let mut message = Iso8583Message {
+        mti,
+        ..Default::default()
+    };
+    for (element, format) in (2..).zip(FORMATS) {
+        if !present(element) {
+            continue;
+        }
+        let what = format!("DE{}", element);
+        let len = match format {
+            Format::Fixed(len) => len,
+            Format::LlVar | Format::LllVar => {
+                let digits = if matches!(format, Format::LlVar) {
+                    2
+                } else {
+                    3
+                };
+                let prefix = take(&mut raw, digits, &what)?;
+                prefix
+                    .parse()
+                    .map_err(|_| format!("the length of {} isn't a number", what))?
+            }
+        };
+        let value = take(&mut raw, len, &what)?.to_string();
+        match element {
+            2 => message.pan = Some(value),
+            3 => message.processing_code = Some(value),
+            4 => message.amount = Some(value),
+            37 => message.retrieval_reference = Some(value),
+            _ => {}
+        }
+    }
+    Ok(message)
+}
+
+impl PaymentEngine {
+    /// Perform a card network message. The client is the PAN in DE2 and the tx id is the retrieval
+    /// reference number in DE37, both have to fit the ids of the engine. A purchase, processing code
+    /// `00` in DE3, is a withdrawal and a refund, code `20`, is a deposit, of the amount in DE4 in
+    /// minor units. A reversal, MTI `0400` or `0420`, reverses the transaction with the same
+    /// retrieval reference number like `process_reversals`.
+    pub fn process_iso8583_message(&mut self, raw: &[u8]) -> Result<(), TransactionError> {
+        let malformed = |reason: String| TransactionError::MalformedMessage(reason);
+        let message = parse(raw).map_err(malformed)?;
+        let required = |value: Option<String>, element: &str| {
+            value.ok_or_else(|| malformed(format!("{} is missing", element)))
+        };
+        let pan = required(message.pan, "DE2")?;
+        let client: u16 = pan
+            .parse()
+            .map_err(|_| malformed(format!("the PAN '{}' isn't a client id", pan)))?;
+        let reference = required(message.retrieval_reference, "DE37")?;
+        let tx: u32 = reference.trim().parse().map_err(|_| {
+            malformed(format!(
+                "the retrieval reference number '{}' isn't a tx id",
+                reference
+            ))
+        })?;
+        if matches!(&message.mti[..], "0400" | "0420") {
+            return self.reverse_transaction(client, tx);
+        }
+        let code = required(message.processing_code, "DE3")?;
+        let transaction_type = match code.get(..2) {
+            Some("00") => TransactionType::Withdrawal,
+            Some("20") => TransactionType::Deposit,
+            _ => {
+                return Err(malformed(format!(
+                    "the processing code '{}' isn't a purchase or a refund",
+                    code
+                )))
+            }
+        };
+        let minor_units: u64 = required(message.amount, "DE4")?
+            .parse()
+            .map_err(|_| malformed(String::from("the amount isn't a number")))?;
+        let amount = minor_units as f64 / 10_f64.powi(AMOUNT_EXPONENT);
+        self.perform_transaction(Transaction::new(transaction_type, client, tx, Some(amount)))
+            .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::DisputeStatus;
+
+    /// A message with DE2, DE3, DE4, DE11 and DE37
+    fn message(mti: &str, pan: &str, code: &str, minor_units: u64, reference: &str) -> Vec<u8> {
+        let mut bitmap = 0_u64;
+        for element in [2, 3, 4, 11, 37] {
+            bitmap |= 1 << (64 - element);
+        }
+        let mut raw = mti.as_bytes().to_vec();
+        raw.extend_from_slice(&bitmap.to_be_bytes());
+        raw.extend_from_slice(format!("{:02}{}", pan.len(), pan).as_bytes());
+        raw.extend_from_slice(code.as_bytes());
+        raw.extend_from_slice(format!("{:012}", minor_units).as_bytes());
+        raw.extend_from_slice(b"000123");
+        raw.extend_from_slice(format!("{:>12}", reference).as_bytes());
+        raw
+    }
+
+    #[test]
+    fn parse_message() {
+        // MTI 0200, DE2 '42', DE3 '000000', DE4 '000000001250', DE37 '000000000007'
+        let raw = b"0200\x70\x00\x00\x00\x08\x00\x00\x000242000000000000001250000000000007";
+        assert_eq!(
+            parse(raw).unwrap(),
+            Iso8583Message {
+                mti: String::from("0200"),
+                pan: Some(String::from("42")),
+                processing_code: Some(String::from("000000")),
+                amount: Some(String::from("000000001250")),
+                retrieval_reference: Some(String::from("000000000007")),
+            }
+        );
+        // a message cut short
+        assert!(parse(&raw[..20]).is_err());
+        assert!(parse(b"02").is_err());
+    }
+
+    #[test]
+    fn purchase_refund_and_reversal() {
+        let mut engine = PaymentEngine::default();
+        // a refund credits the account
+        assert!(engine
+            .process_iso8583_message(&message("0200", "42", "200000", 10000, "1"))
+            .is_ok());
+        assert_eq!(engine.accounts[&42].available(), 100.0);
+        // a purchase debits it
+        assert!(engine
+            .process_iso8583_message(&message("0200", "42", "000000", 1250, "2"))
+            .is_ok());
+        assert_eq!(engine.accounts[&42].available(), 87.5);
+        // the reversal of the purchase pays it back
+        assert!(engine
+            .process_iso8583_message(&message("0400", "42", "000000", 1250, "2"))
+            .is_ok());
+        assert_eq!(engine.accounts[&42].available(), 100.0);
+        assert_eq!(
+            engine.transactions[&(42, 2)].dispute_status,
+            Some(DisputeStatus::Rolledback)
+        );
+        // the engine still checks the funds
+        let res = engine.process_iso8583_message(&message("0200", "42", "000000", 20000, "3"));
+        assert!(matches!(
+            res.unwrap_err(),
+            TransactionError::InsufficientFunds(42)
+        ));
+    }
+
+    #[test]
+    fn unsupported_messages() {
+        let mut engine = PaymentEngine::default();
+        let malformed = |raw: Vec<u8>, engine: &mut PaymentEngine| {
+            matches!(
+                engine.process_iso8583_message(&raw),
+                Err(TransactionError::MalformedMessage(_))
+            )
+        };
+        // a balance inquiry
+        assert!(malformed(
+            message("0200", "42", "310000", 0, "1"),
+            &mut engine
+        ));
+        // a PAN too long for a client id
+        assert!(malformed(
+            message("0200", "4111111111111111", "000000", 100, "1"),
+            &mut engine
+        ));
+        assert!(malformed(
+            message("0200", "42", "000000", 100, "ABC"),
+            &mut engine
+        ));
+        // a processing code which is valid UTF-8 but not ASCII
+        let raw = message("0200", "42", "a\u{e9}000", 100, "1");
+        assert!(malformed(raw, &mut engine));
+        assert!(engine.accounts.is_empty());
+    }
+}
@@ -0,0 +1,287 @@
+use std::{
+    collections::HashSet,
+    io::Read,
+    time::{Duration, Instant},
+};
+
+use rdkafka::{
+    consumer::{BaseConsumer, Consumer},
+    error::{KafkaError, RDKafkaErrorCode},
+    producer::{BaseProducer, BaseRecord, Producer},
+    ClientConfig, Message, Offset, TopicPartitionList,
+};
+use serde::Serialize;
+
+use crate::transaction::{
+    engine::{PaymentEngine, TransactionError},
+    Transaction,
+};
+
+/// Header for the rows replayed from Kafka, which come without one
+const REPLAY_HEADER: &[u8] = b"type,client,tx,amount,timestamp\n";
+
+/// How long to wait for the queued messages to be delivered at the end of a stream
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Counts of a finished stream
+#[derive(Debug, Default, PartialEq)]
+pub struct KafkaStreamReport {
+    /// Messages produced, one per transaction
+    pub produced: usize,
+    /// Rows which couldn't be read plus transactions the engine rejected
+    pub errors: usize,
+}
+
+/// The JSON message produced for each transaction
+#[derive(Serialize)]
+struct TransactionEvent {
+    #[serde(rename = "type")]
+    transaction_type: &'static str,
+    client: u16,
+    tx: u32,
+    amount: Option<f64>,
+    error: Option<String>, // 'None' if the transaction was performed
+}
+
+impl PaymentEngine {
+    /// Perform every transaction of the CSV and produce the outcome as a JSON message to `topic`,
+    /// keyed by the client id so the events of a client stay in order. Rows which can't be read
+    /// are counted as errors but not produced. Fails if the producer fails, the transactions
+    /// performed until then stay performed.
+    pub fn stream_to_kafka(
+        &mut self,
+        broker: &str,
+        topic: &str,
+        csv_reader: impl Read,
+    ) -> Result<KafkaStreamReport, KafkaError> {
+        let producer: BaseProducer = ClientConfig::new()
+            .set("bootstrap.servers", broker)
+            .create()?;
+        let mut report = KafkaStreamReport::default();
+        for transaction in Transaction::read_from_reader(csv_reader) {
+            let transaction = match transaction {
+                Ok(transaction) => transaction,
+                Err(_) => {
+                    report.errors += 1;
+                    continue;
+                }
+            };
+            let mut event = TransactionEvent {
+                transaction_type: transaction.transaction_type.name(),
+                client: transaction.client,
+                tx: transaction.tx,
+                amount: transaction.amount,
+                error: None,
+            };
+            if let Err(e) = self.perform_transaction(transaction) {
+                report.errors += 1;
+                event.error = Some(e.to_string());
+            }
+            let key = event.client.to_string();
+            let payload = serde_json::to_vec(&event).expect("events always serialize");
+            let mut record = BaseRecord::to(topic).key(&key).payload(&payload);
+            loop {
+                match producer.send(record) {
+                    Ok(()) => break,
+                    // wait for the queue to drain and try again
+                    Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), rejected)) => {
+                        producer.poll(Duration::from_millis(100));
+                        record = rejected;
+                    }
+                    Err((e, _)) => return Err(e),
+                }
+            }
+            report.produced += 1;
+            producer.poll(Duration::ZERO);
+        }
+        producer.flush(FLUSH_TIMEOUT)?;
+        Ok(report)
+    }
+
+    /// Rebuild an engine from a topic whose message payloads are CSV rows without a header, like
+    /// `deposit,1,1,10.0`. Every partition is read from the beginning, whatever `group_id` has
+    /// committed, until the end of each partition is reached or `timeout` runs out, and nothing
+    /// is committed. Returns the engine and the errors of the transactions it rejected, payloads
+    /// which aren't a row are skipped. The order of the transactions is only kept within a
+    /// partition.
+    pub fn replay_from_kafka(
+        broker: &str,
+        topic: &str,
+        group_id: &str,
+        timeout: Duration,
+    ) -> Result<(PaymentEngine, Vec<TransactionError>), KafkaError> {
+        let deadline = Instant::now() + timeout;
+        let consumer: BaseConsumer = ClientConfig::new()
+            .set("bootstrap.servers", broker)
+            .set("group.id", group_id)
+            .set("auto.offset.reset", "earliest")
+            .set("enable.partition.eof", "true")
+            .set("enable.auto.commit", "false")
+            .create()?;
+        let metadata = consumer.fetch_metadata(Some(topic), timeout)?;
+        let mut partitions = TopicPartitionList::new();
+        for topic_metadata in metadata.topics() {
+            if let Some(error) = topic_metadata.error() {
+                return Err(KafkaError::MetadataFetch(error.into()));
+            }
+            for partition in topic_metadata.partitions() {
+                partitions.add_partition_offset(topic, partition.id(), Offset::Beginning)?;
+            }
+        }
+        consumer.assign(&partitions)?;
+
+        let mut engine = PaymentEngine::default();
+        let mut errors = Vec::new();
+        // a partition can reach its end again after more messages arrive, so count each once
+        let mut finished = HashSet::new();
+        while finished.len() < partitions.count() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            match consumer.poll(remaining) {
+                None => break,
+                Some(Err(KafkaError::PartitionEOF(partition))) => {
+                    finished.insert(partition);
+                }
+                Some(Err(e)) => return Err(e),
+                Some(Ok(message)) => {
+                    let transaction = message.payload().and_then(|row| {
+                        Transaction::read_unvalidated_from_reader(REPLAY_HEADER.chain(row))
+                            .next()?
+                            .ok()
+                    });
+                    if let Some(Err(e)) = transaction.map(|t| engine.perform_transaction(t)) {
+                        errors.push(e);
+                    }
+                }
+            }
+        }
+        Ok((engine, errors))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rdkafka::mocking::MockCluster;
+
+    use super::*;
+
+    const TOPIC: &str = "transactions";
+
+    /// Read up to `count` messages from the start of the topic as (key, JSON payload)
+    fn consume(brokers: &str, count: usize) -> Vec<(String, serde_json::Value)> {
+        let consumer: BaseConsumer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("group.id", "payment_engine_test")
+            .create()
+            .unwrap();
+        let mut partitions = TopicPartitionList::new();
+        partitions
+            .add_partition_offset(TOPIC, 0, Offset::Beginning)
+            .unwrap();
+        consumer.assign(&partitions).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(30);
+        let mut messages = Vec::new();
+        while messages.len() < count && Instant::now() < deadline {
+            if let Some(message) = consumer.poll(Duration::from_millis(100)) {
+                let message = message.unwrap();
+                let key = String::from_utf8(message.key().unwrap().to_vec()).unwrap();
+                let payload = serde_json::from_slice(message.payload().unwrap()).unwrap();
+                messages.push((key, payload));
+            }
+        }
+        messages
+    }
+
+    /// Produce each row as a message to partition 0 of the topic
+    fn produce(brokers: &str, rows: &[&str]) {
+        let producer: BaseProducer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .create()
+            .unwrap();
+        for row in rows {
+            producer
+                .send(BaseRecord::<(), _>::to(TOPIC).partition(0).payload(*row))
+                .unwrap();
+        }
+        producer.flush(FLUSH_TIMEOUT).unwrap();
+    }
+
+    #[test]
+    fn replay_from_mock_cluster() {
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic(TOPIC, 2, 1).unwrap();
+        let brokers = cluster.bootstrap_servers();
+        produce(
+            &brokers,
+            &[
+                "deposit,1,1,10.0",
+                "deposit, 2, 2, 4.0",
+                "not a row",
+                "withdrawal,1,3,2.5",
+                "dispute,2,2",
+                "withdrawal,2,4,1.0",
+                "dispute,1,1,5.0",
+            ],
+        );
+
+        let (engine, errors) =
+            PaymentEngine::replay_from_kafka(&brokers, TOPIC, "replay", Duration::from_secs(30))
+                .unwrap();
+        assert_eq!(engine.accounts.len(), 2);
+        assert_eq!(engine.accounts[&1].available(), 7.5);
+        assert_eq!(engine.accounts[&1].held(), 0.0);
+        assert_eq!(engine.accounts[&2].available(), 0.0);
+        assert_eq!(engine.accounts[&2].held(), 4.0);
+        // the withdrawal from the disputed funds and the dispute with an amount
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(errors[0], TransactionError::InsufficientFunds(2)));
+        assert!(matches!(errors[1], TransactionError::InvalidTransaction(1)));
+
+        // an empty topic ends at once
+        cluster.create_topic("empty", 1, 1).unwrap();
+        let (engine, errors) =
+            PaymentEngine::replay_from_kafka(&brokers, "empty", "replay", Duration::from_secs(30))
+                .unwrap();
+        assert!(engine.accounts.is_empty());
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn stream_to_mock_cluster() {
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic(TOPIC, 1, 1).unwrap();
+        let brokers = cluster.bootstrap_servers();
+
+        let csv = r#"
+        type, client, tx, amount
+        deposit, 1, 1, 10.0
+        withdrawal, 2, 2, 5.0
+        not a row, 1, 3, 1.0
+        withdrawal, 1, 4, 2.5"#;
+        let mut engine = PaymentEngine::default();
+        let report = engine
+            .stream_to_kafka(&brokers, TOPIC, csv.as_bytes())
+            .unwrap();
+        assert_eq!(
+            report,
+            KafkaStreamReport {
+                produced: 3,
+                errors: 2,
+            }
+        );
+        assert_eq!(engine.accounts[&1].available(), 7.5);
+
+        let messages = consume(&brokers, 3);
+        assert_eq!(messages.len(), 3);
+        let keys: Vec<&str> = messages.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(keys, ["1", "2", "1"]);
+        assert_eq!(messages[0].1["type"], "deposit");
+        assert_eq!(messages[0].1["amount"], 10.0);
+        assert!(messages[0].1["error"].is_null());
+        // client 2 had nothing to withdraw
+        assert_eq!(messages[1].1["error"], "client '2' has insufficient funds");
+    }
+}
@@ -0,0 +1,224 @@
+use std::fmt::Write;
+
+use chrono::{DateTime, NaiveDate};
+
+use crate::{
+    account::{format_amount, round_amount, TrailingZeros},
+    transaction::{engine::PaymentEngine, DisputeStatus, TransactionType},
+};
+
+/// The engine has no currency, so statements use the ISO 4217 code for transactions without one
+pub const MT940_CURRENCY: &str = "XXX";
+
+/// A line of the statement, `amount` is signed
+struct StatementLine {
+    tx: u32,
+    amount: f64,
+    timestamp: Option<u64>,
+}
+
+impl PaymentEngine {
+    /// Write the history of `client` as a SWIFT MT940 statement, `None` if the client has no account.
+    /// The statement lines are the deposits, withdrawals and adjustments in the order they were
+    /// performed, then a debit for every chargeback and the opposite entry for every reversal, like
+    /// `cross_validate_with_account_statement`. A line is dated by its timestamp, or by
+    /// `statement_date` without one. The closing balance is the total of the account and the
+    /// opening balance is whatever the lines don't explain, like opening balances and corrections.
+    /// The account number is the client id padded to 10 digits, the currency is `MT940_CURRENCY`
+    /// and the amounts have the decimal places of the scale, with a decimal comma.
+    pub fn generate_swift_mt940(&self, client: u16, statement_date: NaiveDate) -> Option<String> {
+        let account = self.accounts.get(&client)?;
+        let history = || self.transactions.values().filter(|t| t.client == client);
+        let signed = |transaction_type, amount: f64| match transaction_type {
+            TransactionType::Withdrawal => -amount,
+            _ => amount,
+        };
+        let mut lines: Vec<StatementLine> = history()
+            .map(|t| StatementLine {
+                tx: t.tx,
+                amount: signed(t.transaction_type, t.amount.unwrap()),
+                timestamp: t.timestamp,
+            })
+            .collect();
+        for t in history() {
+            let amount = t.amount.unwrap();
+            match t.dispute_status {
+                Some(DisputeStatus::Chargeback) => lines.push(StatementLine {
+                    tx: t.tx,
+                    amount: -amount,
+                    timestamp: t.chargeback_at,
+                }),
+                Some(DisputeStatus::Rolledback) => lines.push(StatementLine {
+                    tx: t.tx,
+                    amount: -signed(t.transaction_type, amount),
+                    timestamp: None,
+                }),
+                _ => {}
+            }
+        }
+
+        let scale = self.scale();
+        // a mark for the direction and the unsigned amount with a decimal comma
+        let money = |amount: f64| {
+            // float error can't make a zero balance a debit
+            let amount = round_amount(amount, scale);
+            let mark = if amount < 0_f64 { 'D' } else { 'C' };
+            let mut formatted = format_amount(amount.abs(), scale, TrailingZeros::Pad);
+            // the decimal comma is required even without decimal places
+            if scale == 0 {
+                formatted.push('.');
+            }
+            (mark, formatted.replace('.', ","))
+        };
+        let date_of = |timestamp: Option<u64>| {
+            timestamp
+                .and_then(|t| DateTime::from_timestamp(i64::try_from(t).ok()?, 0))
+                .map_or(statement_date, |time| time.date_naive())
+        };
+        let closing = account.total();
+        let opening = closing - lines.iter().map(|line| line.amount).sum::<f64>();
+        let date = statement_date.format("%y%m%d");
+
+        let mut statement = String::new();
+        // writing to a String can't fail
+        writeln!(statement, ":20:PE{}{:05}", date, client).unwrap();
+        writeln!(statement, ":25:{:010}", client).unwrap();
+        writeln!(statement, ":28C:1/1").unwrap();
+        let (mark, amount) = money(opening);
+        writeln!(
+            statement,
+            ":60F:{}{}{}{}",
+            mark, date, MT940_CURRENCY, amount
+        )
+        .unwrap();
+        for line in &lines {
+            let (mark, amount) = money(line.amount);
+            let value_date = date_of(line.timestamp);
+            writeln!(
+                statement,
+                ":61:{}{}{}NTRF{}",
+                value_date.format("%y%m%d"),
+                mark,
+                amount,
+                line.tx
+            )
+            .unwrap();
+        }
+        let (mark, amount) = money(closing);
+        writeln!(
+            statement,
+            ":62F:{}{}{}{}",
+            mark, date, MT940_CURRENCY, amount
+        )
+        .unwrap();
+        Some(statement)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+
+    use super::*;
+    use crate::transaction::Transaction;
+
+    fn statement_date() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 3, 31).unwrap()
+    }
+
+    #[test]
+    fn mt940_fields() {
+        let mut engine = PaymentEngine::default();
+        engine
+            .seed_balances("client,available\n7,10.0\n".as_bytes())
+            .unwrap();
+        let txs = [
+            // 2024-03-01
+            Transaction::new(TransactionType::Deposit, 7, 1, Some(100.0))
+                .with_timestamp(1709251200),
+            Transaction::new(TransactionType::Withdrawal, 7, 2, Some(25.5)),
+            Transaction::new(TransactionType::Deposit, 7, 3, Some(40.0)),
+            Transaction::new(TransactionType::Dispute, 7, 3, None),
+            Transaction::new(TransactionType::Chargeback, 7, 3, None),
+        ];
+        assert!(engine.perform_transactions(txs).is_empty());
+        let statement = engine.generate_swift_mt940(7, statement_date()).unwrap();
+
+        let field = |pattern: &str| {
+            let regex = Regex::new(pattern).unwrap();
+            regex
+                .captures_iter(&statement)
+                .map(|captures| {
+                    captures
+                        .iter()
+                        .skip(1)
+                        .map(|m| m.unwrap().as_str().to_string())
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(field(r"(?m)^:20:(PE\d{6}\d{5})$"), [["PE24033100007"]]);
+        assert_eq!(field(r"(?m)^:25:(\d{10})$"), [["0000000007"]]);
+        assert_eq!(field(r"(?m)^:28C:(\d+/\d+)$"), [["1/1"]]);
+        // the opening balance is the seeded one
+        assert_eq!(
+            field(r"(?m)^:60F:([CD])(\d{6})([A-Z]{3})(\d+,\d{4})$"),
+            [["C", "240331", "XXX", "10,0000"]]
+        );
+        assert_eq!(
+            field(r"(?m)^:61:(\d{6})([CD])(\d+,\d{4})NTRF(\d+)$"),
+            [
+                ["240301", "C", "100,0000", "1"],
+                ["240331", "D", "25,5000", "2"],
+                ["240331", "C", "40,0000", "3"],
+                ["240331", "D", "40,0000", "3"],
+            ]
+        );
+        let closing = field(r"(?m)^:62F:([CD])(\d{6})([A-Z]{3})(\d+),(\d{4})$");
+        assert_eq!(closing.len(), 1);
+        let [mark, date, currency, units, decimals] = &closing[0][..] else {
+            panic!("the closing balance has five parts");
+        };
+        assert_eq!(
+            (&mark[..], &date[..], &currency[..]),
+            ("C", "240331", "XXX")
+        );
+        let closing_balance: f64 = format!("{}.{}", units, decimals).parse().unwrap();
+        assert_eq!(closing_balance, engine.accounts[&7].total());
+        assert_eq!(closing_balance, 84.5);
+    }
+
+    #[test]
+    fn mt940_unknown_client() {
+        let engine = PaymentEngine::default();
+        assert_eq!(engine.generate_swift_mt940(1, statement_date()), None);
+    }
+
+    #[test]
+    fn mt940_debit_balance() {
+        let mut engine = PaymentEngine::default().with_scale(2);
+        let txs = [Transaction::new(
+            TransactionType::Adjustment,
+            3,
+            1,
+            Some(-12.5),
+        )];
+        assert!(engine.perform_transactions(txs).is_empty());
+        let statement = engine.generate_swift_mt940(3, statement_date()).unwrap();
+        assert!(statement.contains(":60F:C240331XXX0,00\n"));
+        assert!(statement.contains(":61:240331D12,50NTRF1\n"));
+        assert!(statement.ends_with(":62F:D240331XXX12,50\n"));
+
+        // a currency without minor units still has the decimal comma
+        let mut engine = PaymentEngine::default().with_scale(0);
+        let txs = [Transaction::new(
+            TransactionType::Deposit,
+            3,
+            1,
+            Some(100.0),
+        )];
+        assert!(engine.perform_transactions(txs).is_empty());
+        let statement = engine.generate_swift_mt940(3, statement_date()).unwrap();
+        assert!(statement.ends_with(":62F:C240331XXX100,\n"));
+    }
+}
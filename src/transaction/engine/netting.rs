@@ -0,0 +1,213 @@
+use crate::{
+    account::{round_amount, Account},
+    transaction::{engine::PaymentEngine, Transaction, TransactionType},
+};
+
+/// Which way the net payment of `apply_netting` goes
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NetDirection {
+    /// The first client pays the second
+    AToB,
+    /// The second client pays the first
+    BToA,
+    /// The payments cancel out, there is nothing left to pay
+    Even,
+}
+
+/// Outcome of netting the payments between two clients
+#[derive(Debug, PartialEq)]
+pub struct NettingResult {
+    pub net_direction: NetDirection,
+    pub net_amount: f64,
+    pub transactions_netted: usize,
+}
+
+impl PaymentEngine {
+    /// Replace the payments between two clients with a single net payment, like a clearing house.
+    /// A payment is a withdrawal of the payer and a deposit of the payee with the same tx id and
+    /// amount, neither of them ever disputed or reversed. The net payment takes the tx id of the
+    /// first payment netted and is recorded last, the balances don't change. `transactions_netted`
+    /// counts the payments, `None` if there are fewer than two or either client is locked or has
+    /// no account.
+    pub fn apply_netting(&mut self, client_a: u16, client_b: u16) -> Option<NettingResult> {
+        let locked = |client| self.accounts.get(&client).is_none_or(Account::locked);
+        if client_a == client_b || locked(client_a) || locked(client_b) {
+            return None;
+        }
+        // the tx id and amount of each payment, positive from A to B
+        let payments: Vec<(u32, f64)> = self
+            .transactions
+            .values()
+            .filter(|withdrawal| {
+                (withdrawal.client == client_a || withdrawal.client == client_b)
+                    && withdrawal.transaction_type == TransactionType::Withdrawal
+                    && withdrawal.dispute_status.is_none()
+            })
+            .filter_map(|withdrawal| {
+                let payee = if withdrawal.client == client_a {
+                    client_b
+                } else {
+                    client_a
+                };
+                let deposit = self.transactions.get(&(payee, withdrawal.tx))?;
+                let pays = deposit.transaction_type == TransactionType::Deposit
+                    && deposit.dispute_status.is_none()
+                    && deposit.amount == withdrawal.amount;
+                let amount = withdrawal.amount?;
+                pays.then_some((
+                    withdrawal.tx,
+                    if payee == client_b { amount } else { -amount },
+                ))
+            })
+            .collect();
+        if payments.len() < 2 {
+            return None;
+        }
+        for &(tx, _) in &payments {
+            self.transactions.shift_remove(&(client_a, tx));
+            self.transactions.shift_remove(&(client_b, tx));
+        }
+        let net = round_amount(
+            payments.iter().map(|(_, amount)| amount).sum(),
+            self.scale(),
+        );
+        let (net_direction, payer, payee) = match net {
+            net if net > 0_f64 => (NetDirection::AToB, client_a, client_b),
+            net if net < 0_f64 => (NetDirection::BToA, client_b, client_a),
+            _ => (NetDirection::Even, client_a, client_b),
+        };
+        if net_direction != NetDirection::Even {
+            let tx = payments[0].0;
+            for transaction in [
+                Transaction::new(TransactionType::Withdrawal, payer, tx, Some(net.abs())),
+                Transaction::new(TransactionType::Deposit, payee, tx, Some(net.abs())),
+            ] {
+                self.transactions.insert(transaction.key(), transaction);
+            }
+        }
+        Some(NettingResult {
+            net_direction,
+            net_amount: net.abs(),
+            transactions_netted: payments.len(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A payment is a withdrawal of the payer and a deposit of the payee with the same tx id
+    fn payment(payer: u16, payee: u16, tx: u32, amount: f64) -> [Transaction; 2] {
+        [
+            Transaction::new(TransactionType::Withdrawal, payer, tx, Some(amount)),
+            Transaction::new(TransactionType::Deposit, payee, tx, Some(amount)),
+        ]
+    }
+
+    fn funded_engine() -> PaymentEngine {
+        let mut engine = PaymentEngine::default();
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(100.0)),
+            Transaction::new(TransactionType::Deposit, 2, 2, Some(100.0)),
+        ];
+        assert!(engine.perform_transactions(txs).is_empty());
+        engine
+    }
+
+    /// The payments between clients 1 and 2 in the record, by the withdrawal of the payer
+    fn payments(engine: &PaymentEngine) -> Vec<(u16, u32, f64)> {
+        engine
+            .transactions
+            .values()
+            .filter(|t| t.transaction_type == TransactionType::Withdrawal)
+            .map(|t| (t.client, t.tx, t.amount.unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn net_offsetting_payments() {
+        let mut engine = funded_engine();
+        let txs = [
+            payment(1, 2, 10, 10.0),
+            payment(2, 1, 11, 7.0),
+            payment(1, 2, 12, 20.0),
+            payment(1, 2, 13, 5.5),
+            payment(2, 1, 14, 3.5),
+        ];
+        assert!(engine.perform_transactions(txs.concat()).is_empty());
+        let before: Vec<_> = engine.accounts_iter().map(|(_, a)| a.clone()).collect();
+
+        let result = engine.apply_netting(1, 2).unwrap();
+        assert_eq!(
+            result,
+            NettingResult {
+                net_direction: NetDirection::AToB,
+                net_amount: 25.0,
+                transactions_netted: 5,
+            }
+        );
+        // a single payment is left and the balances are the same
+        assert_eq!(payments(&engine), [(1, 10, 25.0)]);
+        assert_eq!(engine.transactions[&(2, 10)].amount, Some(25.0));
+        assert_eq!(engine.transactions.len(), 4);
+        engine.assert_accounts_eq(&before);
+        // the net payment has nothing to be netted with
+        assert_eq!(engine.apply_netting(2, 1), None);
+    }
+
+    #[test]
+    fn netting_direction_and_even() {
+        let mut engine = funded_engine();
+        let txs = [payment(1, 2, 10, 4.0), payment(2, 1, 11, 6.0)];
+        assert!(engine.perform_transactions(txs.concat()).is_empty());
+        let result = engine.apply_netting(1, 2).unwrap();
+        assert_eq!(result.net_direction, NetDirection::BToA);
+        assert_eq!(result.net_amount, 2.0);
+        assert_eq!(payments(&engine), [(2, 10, 2.0)]);
+
+        // payments which cancel out leave none
+        let mut engine = funded_engine();
+        let txs = [
+            payment(1, 2, 10, 2.5),
+            payment(2, 1, 11, 2.0),
+            payment(2, 1, 12, 0.5),
+        ];
+        assert!(engine.perform_transactions(txs.concat()).is_empty());
+        let result = engine.apply_netting(2, 1).unwrap();
+        assert_eq!(result.net_direction, NetDirection::Even);
+        assert_eq!(result.net_amount, 0.0);
+        assert!(payments(&engine).is_empty());
+        assert_eq!(engine.accounts[&1].available(), 100.0);
+        assert_eq!(engine.accounts[&2].available(), 100.0);
+    }
+
+    #[test]
+    fn netting_leaves_disputed_payments() {
+        let mut engine = funded_engine();
+        let txs = [
+            payment(1, 2, 10, 10.0),
+            payment(2, 1, 11, 7.0),
+            payment(1, 2, 12, 1.0),
+        ];
+        assert!(engine.perform_transactions(txs.concat()).is_empty());
+        // the payee disputes the deposit of the last payment
+        assert!(engine
+            .perform_transaction(Transaction::new(TransactionType::Dispute, 2, 12, None))
+            .is_ok());
+        let result = engine.apply_netting(1, 2).unwrap();
+        assert_eq!(result.transactions_netted, 2);
+        assert_eq!(result.net_amount, 3.0);
+        assert_eq!(payments(&engine), [(1, 12, 1.0), (1, 10, 3.0)]);
+
+        // a locked client's payments aren't netted
+        let txs = [payment(1, 2, 20, 1.0), payment(2, 1, 21, 1.0)];
+        assert!(engine.perform_transactions(txs.concat()).is_empty());
+        assert!(engine
+            .perform_transaction(Transaction::new(TransactionType::Chargeback, 2, 12, None))
+            .is_ok());
+        assert_eq!(engine.apply_netting(1, 2), None);
+        // neither are a client's payments to itself
+        assert_eq!(engine.apply_netting(1, 1), None);
+    }
+}
@@ -0,0 +1,193 @@
+use std::fmt::Write;
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+
+use crate::{
+    account::{format_amount, TrailingZeros},
+    transaction::{
+        engine::{mt940::MT940_CURRENCY, PaymentEngine},
+        TransactionType,
+    },
+};
+
+/// The currency of the exports, the same one the MT940 statements use
+pub const OFX_CURRENCY: &str = MT940_CURRENCY;
+/// The engine isn't a bank, so the routing number of the exports is a placeholder
+pub const OFX_BANK_ID: &str = "000000000";
+
+/// The header of an OFX 1.6 SGML document, followed by the blank line which ends it
+const OFX_HEADER: &str = "\
+OFXHEADER:100
+DATA:OFXSGML
+VERSION:160
+SECURITY:NONE
+ENCODING:USASCII
+CHARSET:1252
+COMPRESSION:NONE
+OLDFILEUID:NONE
+NEWFILEUID:NONE
+
+";
+
+impl PaymentEngine {
+    /// Export the history of `client` as an OFX 1.6 bank statement for personal finance software,
+    /// `None` if the client has no account. Every deposit and withdrawal gets a `STMTTRN` in the
+    /// order they were performed, with the tx id as its `FITID` and the amount signed, negative
+    /// for withdrawals. It's posted at its timestamp, or on `statement_date` without one. Chargebacks
+    /// and adjustments aren't transactions of the statement, the ledger balance is the total of
+    /// the account and the available balance its available funds. The account id is the client id.
+    pub fn generate_ofx_export(&self, client: u16, statement_date: NaiveDate) -> Option<String> {
+        let account = self.accounts.get(&client)?;
+        let scale = self.scale();
+        let amount = |amount: f64| format_amount(amount, scale, TrailingZeros::Pad);
+        let statement_time = statement_date.and_hms_opt(0, 0, 0).unwrap();
+        let posted_at = |timestamp: Option<u64>| {
+            timestamp
+                .and_then(|t| DateTime::from_timestamp(i64::try_from(t).ok()?, 0))
+                .map_or(statement_time, |time| time.naive_utc())
+        };
+        let ofx_time = |time: NaiveDateTime| time.format("%Y%m%d%H%M%S");
+        let history: Vec<_> = self
+            .transactions
+            .values()
+            .filter(|t| {
+                t.client == client
+                    && matches!(
+                        t.transaction_type,
+                        TransactionType::Deposit | TransactionType::Withdrawal
+                    )
+            })
+            .collect();
+        let start = history
+            .iter()
+            .map(|t| posted_at(t.timestamp))
+            .min()
+            .unwrap_or(statement_time);
+
+        let mut ofx = String::from(OFX_HEADER);
+        // writing to a String can't fail
+        writeln!(ofx, "<OFX>").unwrap();
+        writeln!(ofx, "<SIGNONMSGSRSV1>").unwrap();
+        writeln!(ofx, "<SONRS>").unwrap();
+        writeln!(ofx, "<STATUS>\n<CODE>0\n<SEVERITY>INFO\n</STATUS>").unwrap();
+        writeln!(ofx, "<DTSERVER>{}", ofx_time(statement_time)).unwrap();
+        writeln!(ofx, "<LANGUAGE>ENG").unwrap();
+        writeln!(ofx, "</SONRS>").unwrap();
+        writeln!(ofx, "</SIGNONMSGSRSV1>").unwrap();
+        writeln!(ofx, "<BANKMSGSRSV1>").unwrap();
+        writeln!(ofx, "<STMTTRNRS>").unwrap();
+        writeln!(ofx, "<TRNUID>0").unwrap();
+        writeln!(ofx, "<STATUS>\n<CODE>0\n<SEVERITY>INFO\n</STATUS>").unwrap();
+        writeln!(ofx, "<STMTRS>").unwrap();
+        writeln!(ofx, "<CURDEF>{}", OFX_CURRENCY).unwrap();
+        writeln!(ofx, "<BANKACCTFROM>").unwrap();
+        writeln!(ofx, "<BANKID>{}", OFX_BANK_ID).unwrap();
+        writeln!(ofx, "<ACCTID>{}", client).unwrap();
+        writeln!(ofx, "<ACCTTYPE>CHECKING").unwrap();
+        writeln!(ofx, "</BANKACCTFROM>").unwrap();
+        writeln!(ofx, "<BANKTRANLIST>").unwrap();
+        writeln!(ofx, "<DTSTART>{}", ofx_time(start)).unwrap();
+        writeln!(ofx, "<DTEND>{}", ofx_time(statement_time.max(start))).unwrap();
+        for t in history {
+            let (kind, sign) = match t.transaction_type {
+                TransactionType::Withdrawal => ("DEBIT", "-"),
+                _ => ("CREDIT", ""),
+            };
+            writeln!(ofx, "<STMTTRN>").unwrap();
+            writeln!(ofx, "<TRNTYPE>{}", kind).unwrap();
+            writeln!(ofx, "<DTPOSTED>{}", ofx_time(posted_at(t.timestamp))).unwrap();
+            writeln!(ofx, "<TRNAMT>{}{}", sign, amount(t.amount.unwrap())).unwrap();
+            writeln!(ofx, "<FITID>{}", t.tx).unwrap();
+            writeln!(ofx, "</STMTTRN>").unwrap();
+        }
+        writeln!(ofx, "</BANKTRANLIST>").unwrap();
+        for (aggregate, balance) in [
+            ("LEDGERBAL", account.total()),
+            ("AVAILBAL", account.available()),
+        ] {
+            writeln!(ofx, "<{}>", aggregate).unwrap();
+            writeln!(ofx, "<BALAMT>{}", amount(balance)).unwrap();
+            writeln!(ofx, "<DTASOF>{}", ofx_time(statement_time)).unwrap();
+            writeln!(ofx, "</{}>", aggregate).unwrap();
+        }
+        writeln!(ofx, "</STMTRS>").unwrap();
+        writeln!(ofx, "</STMTTRNRS>").unwrap();
+        writeln!(ofx, "</BANKMSGSRSV1>").unwrap();
+        writeln!(ofx, "</OFX>").unwrap();
+        Some(ofx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+
+    use super::*;
+    use crate::transaction::Transaction;
+
+    fn statement_date() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 3, 31).unwrap()
+    }
+
+    #[test]
+    fn ofx_structure() {
+        let mut engine = PaymentEngine::default();
+        let txs = [
+            // 2024-03-01 12:30:00
+            Transaction::new(TransactionType::Deposit, 7, 1, Some(100.0))
+                .with_timestamp(1709296200),
+            Transaction::new(TransactionType::Withdrawal, 7, 2, Some(25.5)),
+            Transaction::new(TransactionType::Deposit, 8, 3, Some(1.0)),
+            Transaction::new(TransactionType::Deposit, 7, 4, Some(40.0)),
+            Transaction::new(TransactionType::Dispute, 7, 4, None),
+        ];
+        assert!(engine.perform_transactions(txs).is_empty());
+        let ofx = engine.generate_ofx_export(7, statement_date()).unwrap();
+
+        // the header, a blank line, then one root element
+        let header =
+            Regex::new(r"^OFXHEADER:100\nDATA:OFXSGML\nVERSION:160\n(?:[A-Z]+:\w+\n)+\n<OFX>\n")
+                .unwrap();
+        assert!(header.is_match(&ofx));
+        assert!(ofx.ends_with("</OFX>\n"));
+        // every aggregate is closed, in the order it was opened
+        let tag = Regex::new(r"(?m)^<(/?)([A-Z0-9]+)>$").unwrap();
+        let mut open = Vec::new();
+        for captures in tag.captures_iter(&ofx) {
+            if captures[1].is_empty() {
+                open.push(captures[2].to_string());
+            } else {
+                assert_eq!(open.pop().as_deref(), Some(&captures[2]));
+            }
+        }
+        assert!(open.is_empty());
+
+        let transaction = Regex::new(
+            r"<STMTTRN>\n<TRNTYPE>(CREDIT|DEBIT)\n<DTPOSTED>(\d{14})\n<TRNAMT>(-?\d+\.\d{4})\n<FITID>(\d+)\n</STMTTRN>",
+        )
+        .unwrap();
+        let transactions: Vec<[&str; 4]> = transaction
+            .captures_iter(&ofx)
+            .map(|captures| captures.extract().1)
+            .collect();
+        assert_eq!(
+            transactions,
+            [
+                ["CREDIT", "20240301123000", "100.0000", "1"],
+                ["DEBIT", "20240331000000", "-25.5000", "2"],
+                ["CREDIT", "20240331000000", "40.0000", "4"],
+            ]
+        );
+        assert!(ofx.contains("<ACCTID>7\n"));
+        assert!(ofx.contains("<DTSTART>20240301123000\n<DTEND>20240331000000\n"));
+        assert!(ofx.contains("<LEDGERBAL>\n<BALAMT>114.5000\n"));
+        // the disputed deposit is held
+        assert!(ofx.contains("<AVAILBAL>\n<BALAMT>74.5000\n"));
+    }
+
+    #[test]
+    fn ofx_unknown_client() {
+        let engine = PaymentEngine::default();
+        assert_eq!(engine.generate_ofx_export(1, statement_date()), None);
+    }
+}
@@ -0,0 +1,430 @@
+use std::{collections::HashMap, io::Write, sync::Arc};
+
+use indexmap::IndexMap;
+
+use parquet::{
+    basic::Type as PhysicalType,
+    column::writer::ColumnWriter,
+    data_type::{BoolType, ByteArray, ByteArrayType, DoubleType, Int32Type, Int64Type},
+    errors::ParquetError,
+    file::{
+        properties::WriterProperties,
+        reader::{ChunkReader, FileReader, SerializedFileReader},
+        writer::{SerializedColumnWriter, SerializedFileWriter},
+    },
+    record::Field,
+    schema::parser::parse_message_type,
+};
+
+use crate::{
+    account::Account,
+    transaction::{engine::PaymentEngine, DisputeStatus, Transaction, TransactionType},
+};
+
+// One schema for both row groups: the first has a row per account with the columns of the CSV
+// output, total is written for consumers but ignored on import. The second has a row per recorded
+// transaction, the columns of the other row group are null in it.
+const ENGINE_SCHEMA: &str = "
+message engine {
+    REQUIRED INT32 client (INTEGER(16, false));
+    OPTIONAL DOUBLE available;
+    OPTIONAL DOUBLE held;
+    OPTIONAL DOUBLE total;
+    OPTIONAL BOOLEAN locked;
+    OPTIONAL INT64 first_tx;
+    OPTIONAL BYTE_ARRAY type (UTF8);
+    OPTIONAL INT64 tx;
+    OPTIONAL DOUBLE amount;
+    OPTIONAL INT64 timestamp;
+    OPTIONAL BYTE_ARRAY dispute_status (UTF8);
+    OPTIONAL BYTE_ARRAY dispute_reason (UTF8);
+    OPTIONAL INT64 disputed_at;
+    OPTIONAL INT64 resolved_at;
+    OPTIONAL INT64 chargeback_at;
+    OPTIONAL DOUBLE held_fee;
+}
+";
+
+/// Columns of the schema, the first ones are client and the account columns
+const COLUMNS: usize = 16;
+const ACCOUNT_COLUMNS: usize = 6;
+
+/// A cell of a row, in the physical type of its column
+enum Value {
+    Int32(i32),
+    Int64(i64),
+    Double(f64),
+    Bool(bool),
+    Text(String),
+    Null,
+}
+
+const TRANSACTION_TYPES: [TransactionType; 6] = [
+    TransactionType::Deposit,
+    TransactionType::Withdrawal,
+    TransactionType::Dispute,
+    TransactionType::Resolve,
+    TransactionType::Chargeback,
+    TransactionType::Adjustment,
+];
+
+const DISPUTE_STATUSES: [(DisputeStatus, &str); 4] = [
+    (DisputeStatus::Disputed, "disputed"),
+    (DisputeStatus::Resolved, "resolved"),
+    (DisputeStatus::Chargeback, "chargeback"),
+    (DisputeStatus::Rolledback, "rolledback"),
+];
+
+fn int64(value: Option<impl Into<i64>>) -> Value {
+    value.map_or(Value::Null, |value| Value::Int64(value.into()))
+}
+
+fn text(value: Option<&str>) -> Value {
+    value.map_or(Value::Null, |value| Value::Text(value.to_string()))
+}
+
+fn account_row(account: &Account) -> Vec<Value> {
+    let mut row = vec![
+        Value::Int32(i32::from(account.client())),
+        Value::Double(account.available()),
+        Value::Double(account.held()),
+        Value::Double(account.total()),
+        Value::Bool(account.locked()),
+        int64(account.first_tx()),
+    ];
+    row.resize_with(COLUMNS, || Value::Null);
+    row
+}
+
+fn transaction_row(transaction: &Transaction) -> Vec<Value> {
+    let status = transaction.dispute_status.map(|status| {
+        DISPUTE_STATUSES
+            .iter()
+            .find(|(s, _)| *s == status)
+            .unwrap()
+            .1
+    });
+    let timestamp = |t: Option<u64>| t.map_or(Value::Null, |t| Value::Int64(t as i64));
+    let mut row = vec![Value::Int32(i32::from(transaction.client))];
+    row.resize_with(ACCOUNT_COLUMNS, || Value::Null);
+    row.extend([
+        Value::Text(transaction.transaction_type.name().to_string()),
+        Value::Int64(i64::from(transaction.tx)),
+        transaction.amount.map_or(Value::Null, Value::Double),
+        timestamp(transaction.timestamp),
+        text(status),
+        text(transaction.dispute_reason.as_deref()),
+        timestamp(transaction.disputed_at),
+        timestamp(transaction.resolved_at),
+        timestamp(transaction.chargeback_at),
+        Value::Double(transaction.held_fee),
+    ]);
+    row
+}
+
+/// Write the cells of a column, the definition levels mark the nulls of an optional column
+fn write_column(
+    column: &mut SerializedColumnWriter<'_>,
+    cells: &[&Value],
+) -> Result<(), ParquetError> {
+    fn write<T: parquet::data_type::DataType>(
+        column: &mut SerializedColumnWriter<'_>,
+        cells: &[&Value],
+        value: impl Fn(&Value) -> Option<T::T>,
+    ) -> Result<(), ParquetError> {
+        let writer = column.typed::<T>();
+        let values: Vec<T::T> = cells.iter().filter_map(|cell| value(cell)).collect();
+        let levels: Vec<i16> = cells
+            .iter()
+            .map(|cell| i16::from(!matches!(cell, Value::Null)))
+            .collect();
+        let optional = writer.get_descriptor().max_def_level() > 0;
+        writer.write_batch(&values, optional.then_some(&levels[..]), None)?;
+        Ok(())
+    }
+    let physical_type = match column.untyped() {
+        ColumnWriter::Int32ColumnWriter(_) => PhysicalType::INT32,
+        ColumnWriter::Int64ColumnWriter(_) => PhysicalType::INT64,
+        ColumnWriter::DoubleColumnWriter(_) => PhysicalType::DOUBLE,
+        ColumnWriter::BoolColumnWriter(_) => PhysicalType::BOOLEAN,
+        ColumnWriter::ByteArrayColumnWriter(_) => PhysicalType::BYTE_ARRAY,
+        _ => unreachable!("the schema has no other column types"),
+    };
+    match physical_type {
+        PhysicalType::INT32 => write::<Int32Type>(column, cells, |cell| match cell {
+            Value::Int32(v) => Some(*v),
+            _ => None,
+        }),
+        PhysicalType::INT64 => write::<Int64Type>(column, cells, |cell| match cell {
+            Value::Int64(v) => Some(*v),
+            _ => None,
+        }),
+        PhysicalType::DOUBLE => write::<DoubleType>(column, cells, |cell| match cell {
+            Value::Double(v) => Some(*v),
+            _ => None,
+        }),
+        PhysicalType::BOOLEAN => write::<BoolType>(column, cells, |cell| match cell {
+            Value::Bool(v) => Some(*v),
+            _ => None,
+        }),
+        PhysicalType::BYTE_ARRAY => write::<ByteArrayType>(column, cells, |cell| match cell {
+            Value::Text(v) => Some(ByteArray::from(v.as_str())),
+            _ => None,
+        }),
+        other => unreachable!("the schema has no {} column", other),
+    }
+}
+
+fn malformed(what: &str) -> ParquetError {
+    ParquetError::General(format!("malformed {}", what))
+}
+
+/// The value of a column of a row read back by name, `None` if it's null
+fn field<'a>(row: &'a HashMap<String, Field>, name: &str) -> Option<&'a Field> {
+    row.get(name).filter(|field| !matches!(field, Field::Null))
+}
+
+fn read_f64(row: &HashMap<String, Field>, name: &str) -> Result<Option<f64>, ParquetError> {
+    match field(row, name) {
+        None => Ok(None),
+        Some(Field::Double(v)) => Ok(Some(*v)),
+        Some(_) => Err(malformed(name)),
+    }
+}
+
+fn read_u64(row: &HashMap<String, Field>, name: &str) -> Result<Option<u64>, ParquetError> {
+    match field(row, name) {
+        None => Ok(None),
+        Some(Field::Long(v)) => u64::try_from(*v).map(Some).map_err(|_| malformed(name)),
+        Some(_) => Err(malformed(name)),
+    }
+}
+
+fn read_u32(row: &HashMap<String, Field>, name: &str) -> Result<Option<u32>, ParquetError> {
+    read_u64(row, name)?
+        .map(|v| u32::try_from(v).map_err(|_| malformed(name)))
+        .transpose()
+}
+
+fn read_str<'a>(
+    row: &'a HashMap<String, Field>,
+    name: &str,
+) -> Result<Option<&'a str>, ParquetError> {
+    match field(row, name) {
+        None => Ok(None),
+        Some(Field::Str(v)) => Ok(Some(v)),
+        Some(_) => Err(malformed(name)),
+    }
+}
+
+fn read_account(client: u16, row: &HashMap<String, Field>) -> Result<Account, ParquetError> {
+    let locked = match field(row, "locked") {
+        Some(Field::Bool(locked)) => *locked,
+        _ => return Err(malformed("locked")),
+    };
+    let account = Account::restore(
+        client,
+        read_f64(row, "available")?.ok_or_else(|| malformed("available"))?,
+        read_f64(row, "held")?.ok_or_else(|| malformed("held"))?,
+        locked,
+    );
+    Ok(match read_u32(row, "first_tx")? {
+        Some(tx) => account.with_first_tx(tx),
+        None => account,
+    })
+}
+
+fn read_transaction(
+    client: u16,
+    row: &HashMap<String, Field>,
+) -> Result<Transaction, ParquetError> {
+    let name = read_str(row, "type")?;
+    let transaction_type = TRANSACTION_TYPES
+        .into_iter()
+        .find(|t| Some(t.name()) == name)
+        .ok_or_else(|| malformed("type"))?;
+    let dispute_status = read_str(row, "dispute_status")?
+        .map(|name| {
+            DISPUTE_STATUSES
+                .iter()
+                .find(|(_, n)| *n == name)
+                .map(|(status, _)| *status)
+                .ok_or_else(|| malformed("dispute_status"))
+        })
+        .transpose()?;
+    Ok(Transaction {
+        transaction_type,
+        client,
+        tx: read_u32(row, "tx")?.ok_or_else(|| malformed("tx"))?,
+        amount: read_f64(row, "amount")?,
+        timestamp: read_u64(row, "timestamp")?,
+        reason: None,
+        dispute_status,
+        disputed_at: read_u64(row, "disputed_at")?,
+        resolved_at: read_u64(row, "resolved_at")?,
+        chargeback_at: read_u64(row, "chargeback_at")?,
+        dispute_reason: read_str(row, "dispute_reason")?.map(str::to_string),
+        held_fee: read_f64(row, "held_fee")?.unwrap_or_default(),
+    })
+}
+
+/// Count the open disputes of each client in an imported transaction record
+fn count_open_disputes(transactions: &IndexMap<(u16, u32), Transaction>) -> HashMap<u16, usize> {
+    let mut open_disputes = HashMap::new();
+    for transaction in transactions.values() {
+        if transaction.dispute_status == Some(DisputeStatus::Disputed) {
+            *open_disputes.entry(transaction.client).or_default() += 1;
+        }
+    }
+    open_disputes
+}
+
+impl PaymentEngine {
+    /// Write the accounts ordered by client id as the first Parquet row group and the transaction
+    /// record in the order it was applied as the second, so an import can still resolve or charge
+    /// back the open disputes. Amounts are written unrounded so an import reproduces the exact
+    /// engine state.
+    pub fn export_accounts_parquet<W: Write + Send>(&self, writer: W) -> Result<(), ParquetError> {
+        let schema = Arc::new(parse_message_type(ENGINE_SCHEMA)?);
+        let props = Arc::new(WriterProperties::builder().build());
+        let mut file_writer = SerializedFileWriter::new(writer, schema, props)?;
+
+        let mut accounts: Vec<&Account> = self.accounts.values().collect();
+        accounts.sort_by_key(|acc| acc.client());
+        let row_groups = [
+            accounts.into_iter().map(account_row).collect::<Vec<_>>(),
+            self.transactions.values().map(transaction_row).collect(),
+        ];
+        for rows in row_groups {
+            let mut row_group = file_writer.next_row_group()?;
+            let mut column = 0;
+            while let Some(mut col_writer) = row_group.next_column()? {
+                let cells: Vec<&Value> = rows.iter().map(|row| &row[column]).collect();
+                write_column(&mut col_writer, &cells)?;
+                col_writer.close()?;
+                column += 1;
+            }
+            row_group.close()?;
+        }
+        file_writer.close()?;
+        Ok(())
+    }
+
+    /// Build an engine from the accounts and transactions written by `export_accounts_parquet`.
+    /// A row with a `type` is a transaction, the rest are accounts.
+    pub fn import_accounts_parquet<R: ChunkReader + 'static>(
+        reader: R,
+    ) -> Result<PaymentEngine, ParquetError> {
+        let file_reader = SerializedFileReader::new(reader)?;
+        let mut engine = PaymentEngine::default();
+        for row in file_reader.get_row_iter(None)? {
+            let row: HashMap<String, Field> = row?.into_columns().into_iter().collect();
+            let client = match field(&row, "client") {
+                Some(Field::UShort(client)) => *client,
+                _ => return Err(malformed("client")),
+            };
+            if field(&row, "type").is_some() {
+                let transaction = read_transaction(client, &row)?;
+                engine.transactions.insert(transaction.key(), transaction);
+            } else {
+                let account = read_account(client, &row)?;
+                engine.accounts.insert(client, account);
+            }
+        }
+        engine.open_disputes = count_open_disputes(&engine.transactions);
+        Ok(engine)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use parquet::record::RowAccessor;
+
+    use super::*;
+    use crate::transaction::engine::TransactionError;
+
+    #[test]
+    fn export_rows() {
+        let engine = PaymentEngine::example();
+        let mut buf = Vec::new();
+        engine.export_accounts_parquet(&mut buf).unwrap();
+
+        // read the file back with a plain parquet reader and check every row
+        let reader = SerializedFileReader::new(bytes::Bytes::from(buf)).unwrap();
+        // the accounts, then the transactions
+        let row_counts: Vec<i64> = reader
+            .metadata()
+            .row_groups()
+            .iter()
+            .map(|row_group| row_group.num_rows())
+            .collect();
+        assert_eq!(row_counts, [3, 3]);
+        let rows: Vec<_> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        let expected = [
+            (1, 100.0, 0.0, 100.0, false),
+            (2, 0.0, 50.0, 50.0, false),
+            (3, 0.0, 0.0, 0.0, true),
+        ];
+        for (row, (client, available, held, total, locked)) in rows.iter().zip(expected) {
+            assert_eq!(row.get_ushort(0).unwrap(), client);
+            assert_eq!(row.get_double(1).unwrap(), available);
+            assert_eq!(row.get_double(2).unwrap(), held);
+            assert_eq!(row.get_double(3).unwrap(), total);
+            assert_eq!(row.get_bool(4).unwrap(), locked);
+        }
+        let deposit = &rows[4];
+        assert_eq!(deposit.get_ushort(0).unwrap(), 2);
+        assert_eq!(deposit.get_string(6).unwrap(), "deposit");
+        assert_eq!(deposit.get_long(7).unwrap(), 2);
+        assert_eq!(deposit.get_double(8).unwrap(), 50.0);
+        assert_eq!(deposit.get_string(10).unwrap(), "disputed");
+        assert!(deposit.get_double(1).is_err());
+    }
+
+    #[test]
+    fn export_import_round_trip() {
+        let engine = PaymentEngine::example();
+        let mut buf = Vec::new();
+        engine.export_accounts_parquet(&mut buf).unwrap();
+
+        let imported = PaymentEngine::import_accounts_parquet(bytes::Bytes::from(buf)).unwrap();
+        assert_eq!(imported.accounts.len(), engine.accounts.len());
+        for (client, account) in &engine.accounts {
+            let imported_account = &imported.accounts[client];
+            assert_eq!(imported_account.available(), account.available());
+            assert_eq!(imported_account.held(), account.held());
+            assert_eq!(imported_account.locked(), account.locked());
+            assert_eq!(imported_account.first_tx(), account.first_tx());
+        }
+        assert_eq!(
+            imported.transactions.keys().collect::<Vec<_>>(),
+            engine.transactions.keys().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            imported.transactions[&(3, 3)].dispute_status,
+            Some(DisputeStatus::Chargeback)
+        );
+
+        assert_eq!(imported.open_disputes, engine.open_disputes);
+        // the open dispute can still be resolved
+        let mut imported = imported;
+        assert!(imported
+            .perform_transaction(Transaction::new(TransactionType::Resolve, 2, 2, None))
+            .is_ok());
+        assert_eq!(imported.accounts[&2].available(), 50.0);
+        // and the recorded tx ids are still taken
+        assert!(matches!(
+            imported.perform_transaction(Transaction::new(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(1.0)
+            )),
+            Err(TransactionError::DuplicateTransaction(1))
+        ));
+    }
+}
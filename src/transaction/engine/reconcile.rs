@@ -0,0 +1,593 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::Read,
+};
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    account::round_amount,
+    transaction::{engine::PaymentEngine, DisputeStatus, TransactionType},
+};
+
+/// A row of an external ledger
+#[derive(Debug, Deserialize)]
+struct ExternalAccount {
+    client: u16,
+    expected_available: f64,
+    expected_held: f64,
+    expected_locked: bool,
+}
+
+/// The columns of a transaction row needed to tell if it can be merged
+#[derive(Debug, Deserialize)]
+struct TransactionIds {
+    #[serde(rename = "type")]
+    transaction_type: TransactionType,
+    client: u16,
+    tx: u32,
+}
+
+/// Whether a transaction CSV can be merged into an engine without tx id collisions
+#[derive(Debug, Default, PartialEq)]
+pub struct CompatibilityReport {
+    pub collision_count: usize,
+    pub colliding_tx_ids: Vec<u32>,
+    pub new_client_ids: Vec<u16>,
+}
+
+impl CompatibilityReport {
+    pub fn is_compatible(&self) -> bool {
+        self.collision_count == 0
+    }
+}
+
+/// An account which differs between the engine and the external ledger.
+/// The differences are engine minus expected.
+#[derive(Debug, PartialEq)]
+pub struct BalanceMismatch {
+    pub client: u16,
+    pub available_diff: f64,
+    pub held_diff: f64,
+    pub expected_locked: bool,
+    pub locked: bool,
+}
+
+/// Outcome of comparing the engine against an external ledger, all lists are ordered by client id
+#[derive(Debug, Default, PartialEq)]
+pub struct ReconciliationReport {
+    pub matched_accounts: Vec<u16>,
+    pub missing_in_engine: Vec<u16>,
+    pub missing_in_external: Vec<u16>,
+    pub balance_mismatches: Vec<BalanceMismatch>,
+}
+
+impl ReconciliationReport {
+    /// Do the engine and the external ledger agree on every account?
+    pub fn is_consistent(&self) -> bool {
+        self.missing_in_engine.is_empty()
+            && self.missing_in_external.is_empty()
+            && self.balance_mismatches.is_empty()
+    }
+}
+
+/// An account whose held funds don't match its open disputes
+#[derive(Debug, PartialEq)]
+pub struct HeldInconsistency {
+    pub client: u16,
+    pub stored_held: f64,
+    pub computed_held: f64,
+    pub disputed_tx_ids: Vec<u32>,
+}
+
+/// Which way a statement entry moves money, a debit takes it out of the account
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DebitCredit {
+    Debit,
+    Credit,
+}
+
+/// A line of a bank statement, the amount is positive and the direction gives the sign
+#[derive(Clone, Debug, PartialEq)]
+pub struct StatementEntry {
+    pub amount: f64,
+    pub direction: DebitCredit,
+    pub reference: String,
+}
+
+impl StatementEntry {
+    /// The amount with credits positive and debits negative
+    fn signed_amount(&self) -> f64 {
+        match self.direction {
+            DebitCredit::Debit => -self.amount,
+            DebitCredit::Credit => self.amount,
+        }
+    }
+}
+
+/// The bank statement of a client for the whole history of its account
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AccountStatement {
+    pub opening_balance: f64,
+    pub entries: Vec<StatementEntry>,
+    pub closing_balance: f64,
+}
+
+/// The first place where the statement and the engine history disagree. Amounts are signed with
+/// credits positive, a side is `None` if it ran out of entries before the other.
+#[derive(Debug, PartialEq)]
+pub struct StatementDivergence {
+    pub index: usize,
+    pub reference: Option<String>,
+    pub statement_amount: Option<f64>,
+    pub engine_tx: Option<u32>,
+    pub engine_amount: Option<f64>,
+}
+
+/// Outcome of replaying the engine history of a client over the opening balance of a statement
+#[derive(Debug, PartialEq)]
+pub struct StatementReconciliation {
+    pub engine_closing_balance: f64, // the opening balance plus every movement in the engine
+    pub closing_balance_matches: bool,
+    pub first_divergence: Option<StatementDivergence>,
+}
+
+impl StatementReconciliation {
+    /// Does the statement agree with the engine entry by entry and in the closing balance?
+    pub fn is_reconciled(&self) -> bool {
+        self.closing_balance_matches && self.first_divergence.is_none()
+    }
+}
+
+impl PaymentEngine {
+    /// Check that the held funds of every account are the sum of its disputed transactions.
+    /// Amounts are compared at the output precision, the result is ordered by client id.
+    pub fn find_accounts_with_inconsistent_held(&self) -> Vec<HeldInconsistency> {
+        let mut disputes: HashMap<u16, (f64, Vec<u32>)> = HashMap::new();
+        for transaction in self.open_disputes() {
+            let (held, txs) = disputes.entry(transaction.client).or_default();
+            *held += transaction.held_amount();
+            txs.push(transaction.tx);
+        }
+        let mut inconsistencies: Vec<HeldInconsistency> = self
+            .accounts
+            .values()
+            .filter_map(|account| {
+                let (computed_held, disputed_tx_ids) =
+                    disputes.remove(&account.client()).unwrap_or_default();
+                let consistent = round_amount(account.held(), self.scale())
+                    == round_amount(computed_held, self.scale());
+                (!consistent).then(|| HeldInconsistency {
+                    client: account.client(),
+                    stored_held: account.held(),
+                    computed_held,
+                    disputed_tx_ids,
+                })
+            })
+            .collect();
+        inconsistencies.sort_by_key(|inconsistency| inconsistency.client);
+        inconsistencies
+    }
+
+    /// Scan a transaction CSV for deposits, withdrawals and adjustments whose client and tx id the
+    /// engine already has, before it's merged in. Disputes, resolves and chargebacks refer to an existing
+    /// tx id so they aren't collisions. Only the `type`, `client` and `tx` columns are read.
+    /// The clients which don't have an account yet are listed too, both lists are sorted.
+    pub fn validate_csv_schema_compatibility<R: Read>(
+        &self,
+        reader: R,
+    ) -> Result<CompatibilityReport, csv::Error> {
+        let rows = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All) // allow whitespace
+            .flexible(true) // avoid the extra comma after dispute, resolve and chargeback
+            .from_reader(reader)
+            .into_deserialize::<TransactionIds>();
+        let mut report = CompatibilityReport::default();
+        let mut new_clients = HashSet::new();
+        for row in rows {
+            let row = row?;
+            if row.transaction_type.is_new_transaction()
+                && self.transactions.contains_key(&(row.client, row.tx))
+            {
+                report.collision_count += 1;
+                report.colliding_tx_ids.push(row.tx);
+            }
+            if !self.accounts.contains_key(&row.client) {
+                new_clients.insert(row.client);
+            }
+        }
+        report.colliding_tx_ids.sort_unstable();
+        report.colliding_tx_ids.dedup();
+        report.new_client_ids = new_clients.into_iter().collect();
+        report.new_client_ids.sort_unstable();
+        Ok(report)
+    }
+
+    /// Compare the accounts against a `client,expected_available,expected_held,expected_locked` CSV.
+    /// Amounts are compared at the output precision so a ledger built from the engine output matches.
+    pub fn validate_consistency_with_external<R: Read>(
+        &self,
+        external_csv: R,
+    ) -> Result<ReconciliationReport, csv::Error> {
+        let externals = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All) // allow whitespace
+            .from_reader(external_csv)
+            .into_deserialize::<ExternalAccount>();
+        let mut report = ReconciliationReport::default();
+        let mut seen = HashSet::new();
+        for external in externals {
+            let external = external?;
+            seen.insert(external.client);
+            let account = match self.accounts.get(&external.client) {
+                Some(account) => account,
+                None => {
+                    report.missing_in_engine.push(external.client);
+                    continue;
+                }
+            };
+            let diff = |actual: f64, expected: f64| {
+                round_amount(actual, self.scale()) - round_amount(expected, self.scale())
+            };
+            let mismatch = BalanceMismatch {
+                client: external.client,
+                available_diff: diff(account.available(), external.expected_available),
+                held_diff: diff(account.held(), external.expected_held),
+                expected_locked: external.expected_locked,
+                locked: account.locked(),
+            };
+            if mismatch.available_diff == 0_f64
+                && mismatch.held_diff == 0_f64
+                && mismatch.expected_locked == mismatch.locked
+            {
+                report.matched_accounts.push(external.client);
+            } else {
+                report.balance_mismatches.push(mismatch);
+            }
+        }
+        report.missing_in_external = self
+            .accounts
+            .keys()
+            .filter(|client| !seen.contains(client))
+            .copied()
+            .collect();
+
+        report.matched_accounts.sort_unstable();
+        report.missing_in_engine.sort_unstable();
+        report.missing_in_external.sort_unstable();
+        report.balance_mismatches.sort_by_key(|m| m.client);
+        Ok(report)
+    }
+
+    /// Check a bank statement of `client` against the engine: the deposits, withdrawals and
+    /// adjustments of the client in the order they were performed, then a debit for every
+    /// chargeback and the opposite entry for every reversal, since the engine doesn't record when
+    /// those happened. Disputes and resolves only move funds to and from held so they aren't
+    /// entries. Amounts are compared at the output precision.
+    pub fn cross_validate_with_account_statement(
+        &self,
+        client: u16,
+        statement: &AccountStatement,
+    ) -> StatementReconciliation {
+        let history = || self.transactions.values().filter(|t| t.client == client);
+        let signed = |transaction_type, amount: f64| match transaction_type {
+            TransactionType::Withdrawal => -amount,
+            _ => amount,
+        };
+        let mut movements: Vec<(u32, f64)> = history()
+            .map(|t| (t.tx, signed(t.transaction_type, t.amount.unwrap())))
+            .collect();
+        for transaction in history() {
+            let amount = transaction.amount.unwrap();
+            match transaction.dispute_status {
+                Some(DisputeStatus::Chargeback) => movements.push((transaction.tx, -amount)),
+                Some(DisputeStatus::Rolledback) => movements.push((
+                    transaction.tx,
+                    -signed(transaction.transaction_type, amount),
+                )),
+                _ => {}
+            }
+        }
+
+        let same = |a: f64, b: f64| round_amount(a, self.scale()) == round_amount(b, self.scale());
+        let first_divergence =
+            (0..movements.len().max(statement.entries.len())).find_map(|index| {
+                let entry = statement.entries.get(index);
+                let movement = movements.get(index);
+                let agree = match (entry, movement) {
+                    (Some(entry), Some(&(_, amount))) => same(entry.signed_amount(), amount),
+                    _ => false,
+                };
+                (!agree).then(|| StatementDivergence {
+                    index,
+                    reference: entry.map(|entry| entry.reference.clone()),
+                    statement_amount: entry.map(StatementEntry::signed_amount),
+                    engine_tx: movement.map(|&(tx, _)| tx),
+                    engine_amount: movement.map(|&(_, amount)| amount),
+                })
+            });
+        let engine_closing_balance =
+            statement.opening_balance + movements.iter().map(|(_, amount)| amount).sum::<f64>();
+        StatementReconciliation {
+            engine_closing_balance,
+            closing_balance_matches: same(engine_closing_balance, statement.closing_balance),
+            first_divergence,
+        }
+    }
+
+    /// SHA-256 of the accounts sorted by client, so two systems can compare a single fingerprint
+    /// instead of their outputs. Each account is hashed as its client, its available and held
+    /// funds in units of the output precision and whether it's locked, all little endian, so
+    /// float error below the output precision doesn't change the digest.
+    pub fn state_digest(&self) -> [u8; 32] {
+        let precision = 10_f64.powi(self.scale() as i32);
+        let units = |amount: f64| (amount * precision).round() as i64;
+        let mut accounts: Vec<_> = self.accounts.values().collect();
+        accounts.sort_unstable_by_key(|account| account.client());
+        let mut hasher = Sha256::new();
+        for account in accounts {
+            hasher.update(account.client().to_le_bytes());
+            hasher.update(units(account.available()).to_le_bytes());
+            hasher.update(units(account.held()).to_le_bytes());
+            hasher.update([u8::from(account.locked())]);
+        }
+        hasher.finalize().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{Transaction, TransactionType};
+
+    #[test]
+    fn compatible_csv() {
+        let engine = PaymentEngine::example();
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,10,5.0\n\
+                   deposit,4,11,5.0\n\
+                   dispute,2,2,\n\
+                   resolve,2,2,\n";
+        let report = engine
+            .validate_csv_schema_compatibility(csv.as_bytes())
+            .unwrap();
+        assert!(report.is_compatible());
+        assert_eq!(
+            report,
+            CompatibilityReport {
+                collision_count: 0,
+                colliding_tx_ids: vec![],
+                new_client_ids: vec![4],
+            }
+        );
+    }
+
+    #[test]
+    fn incompatible_csv() {
+        let engine = PaymentEngine::example();
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,5.0\n\
+                   withdrawal,2,2,1.0\n\
+                   deposit,1,2,1.0\n\
+                   deposit,5,1,1.0\n\
+                   deposit,1,1,5.0\n";
+        let report = engine
+            .validate_csv_schema_compatibility(csv.as_bytes())
+            .unwrap();
+        assert!(!report.is_compatible());
+        assert_eq!(
+            report,
+            CompatibilityReport {
+                collision_count: 3,
+                colliding_tx_ids: vec![1, 2],
+                new_client_ids: vec![5],
+            }
+        );
+        // a row which can't be read fails the scan
+        let csv = "type,client,tx\ndeposit,1,x\n";
+        assert!(engine
+            .validate_csv_schema_compatibility(csv.as_bytes())
+            .is_err());
+    }
+
+    #[test]
+    fn inconsistent_held() {
+        let mut engine = PaymentEngine::example();
+        assert!(engine.find_accounts_with_inconsistent_held().is_empty());
+        engine.accounts.get_mut(&1).unwrap().set_held(5.0);
+        engine.accounts.get_mut(&2).unwrap().set_held(49.0);
+        assert_eq!(
+            engine.find_accounts_with_inconsistent_held(),
+            [
+                HeldInconsistency {
+                    client: 1,
+                    stored_held: 5.0,
+                    computed_held: 0.0,
+                    disputed_tx_ids: vec![],
+                },
+                HeldInconsistency {
+                    client: 2,
+                    stored_held: 49.0,
+                    computed_held: 50.0,
+                    disputed_tx_ids: vec![2],
+                },
+            ]
+        );
+    }
+
+    fn entry(amount: f64, direction: DebitCredit, reference: &str) -> StatementEntry {
+        StatementEntry {
+            amount,
+            direction,
+            reference: reference.to_string(),
+        }
+    }
+
+    #[test]
+    fn matching_statement() {
+        let mut engine = PaymentEngine::example();
+        let txs = [
+            Transaction::new(TransactionType::Withdrawal, 1, 4, Some(30.0)),
+            Transaction::new(TransactionType::Deposit, 1, 5, Some(12.5)),
+            Transaction::new(TransactionType::Dispute, 1, 5, None),
+            Transaction::new(TransactionType::Resolve, 1, 5, None),
+        ];
+        assert!(engine.perform_transactions(txs).is_empty());
+        let statement = AccountStatement {
+            opening_balance: 20.0,
+            entries: vec![
+                entry(100.0, DebitCredit::Credit, "a"),
+                entry(30.0, DebitCredit::Debit, "b"),
+                entry(12.5, DebitCredit::Credit, "c"),
+            ],
+            closing_balance: 102.5,
+        };
+        let reconciliation = engine.cross_validate_with_account_statement(1, &statement);
+        assert!(reconciliation.is_reconciled());
+        assert_eq!(reconciliation.engine_closing_balance, 102.5);
+
+        // the chargeback comes after the deposit it took back
+        let statement = AccountStatement {
+            opening_balance: 0.0,
+            entries: vec![
+                entry(7.5, DebitCredit::Credit, "deposit"),
+                entry(7.5, DebitCredit::Debit, "chargeback"),
+            ],
+            closing_balance: 0.0,
+        };
+        assert!(engine
+            .cross_validate_with_account_statement(3, &statement)
+            .is_reconciled());
+    }
+
+    #[test]
+    fn diverging_statement() {
+        let mut engine = PaymentEngine::example();
+        engine
+            .perform_transaction(Transaction::new(
+                TransactionType::Withdrawal,
+                1,
+                4,
+                Some(30.0),
+            ))
+            .unwrap();
+        // the bank has the withdrawal for a different amount
+        let statement = AccountStatement {
+            opening_balance: 0.0,
+            entries: vec![
+                entry(100.0, DebitCredit::Credit, "a"),
+                entry(35.0, DebitCredit::Debit, "b"),
+            ],
+            closing_balance: 65.0,
+        };
+        let reconciliation = engine.cross_validate_with_account_statement(1, &statement);
+        assert!(!reconciliation.is_reconciled());
+        assert!(!reconciliation.closing_balance_matches);
+        assert_eq!(reconciliation.engine_closing_balance, 70.0);
+        assert_eq!(
+            reconciliation.first_divergence,
+            Some(StatementDivergence {
+                index: 1,
+                reference: Some("b".to_string()),
+                statement_amount: Some(-35.0),
+                engine_tx: Some(4),
+                engine_amount: Some(-30.0),
+            })
+        );
+
+        // the statement is missing an entry, even though the closing balance agrees
+        let statement = AccountStatement {
+            opening_balance: 0.0,
+            entries: vec![entry(70.0, DebitCredit::Credit, "a")],
+            closing_balance: 70.0,
+        };
+        let reconciliation = engine.cross_validate_with_account_statement(1, &statement);
+        assert!(reconciliation.closing_balance_matches);
+        let divergence = reconciliation.first_divergence.unwrap();
+        assert_eq!(divergence.index, 0);
+        assert_eq!(divergence.engine_tx, Some(1));
+    }
+
+    #[test]
+    fn consistent_ledger() {
+        let engine = PaymentEngine::example();
+        let csv = r#"
+        client, expected_available, expected_held, expected_locked
+        1, 100.0, 0.0, false
+        2, 0.0, 50.0, false
+        3, 0.0, 0.0, true"#;
+        let report = engine
+            .validate_consistency_with_external(csv.as_bytes())
+            .unwrap();
+        assert!(report.is_consistent());
+        assert_eq!(report.matched_accounts, [1, 2, 3]);
+    }
+
+    #[test]
+    fn inconsistent_ledger() {
+        let engine = PaymentEngine::example();
+        // client 1 has a wrong balance, client 3 is missing and client 4 isn't in the engine
+        let csv = r#"
+        client, expected_available, expected_held, expected_locked
+        1, 90.0, 0.0, false
+        2, 0.0, 50.0, false
+        4, 1.0, 0.0, false"#;
+        let report = engine
+            .validate_consistency_with_external(csv.as_bytes())
+            .unwrap();
+        assert!(!report.is_consistent());
+        assert_eq!(
+            report,
+            ReconciliationReport {
+                matched_accounts: vec![2],
+                missing_in_engine: vec![4],
+                missing_in_external: vec![3],
+                balance_mismatches: vec![BalanceMismatch {
+                    client: 1,
+                    available_diff: 10.0,
+                    held_diff: 0.0,
+                    expected_locked: false,
+                    locked: false,
+                }],
+            }
+        );
+    }
+
+    #[test]
+    fn state_digest() {
+        let a = PaymentEngine::example();
+        let b = PaymentEngine::example();
+        assert_eq!(a.state_digest(), b.state_digest());
+        // the order the accounts were opened in doesn't matter
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 2, 1, Some(5.0)),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(5.0)),
+        ];
+        let (c, _) = PaymentEngine::from_transactions(txs.clone());
+        let (d, _) = PaymentEngine::from_transactions(txs.into_iter().rev());
+        assert_eq!(c.state_digest(), d.state_digest());
+        // neither does float error below the output precision
+        let (e, _) = PaymentEngine::from_transactions([
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(0.1)),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(0.2)),
+        ]);
+        let (f, _) = PaymentEngine::from_transactions([Transaction::new(
+            TransactionType::Deposit,
+            1,
+            1,
+            Some(0.3),
+        )]);
+        assert_eq!(e.state_digest(), f.state_digest());
+        // a single changed balance does
+        let mut changed = PaymentEngine::example();
+        assert!(changed
+            .perform_transaction(Transaction::new(
+                TransactionType::Deposit,
+                1,
+                50,
+                Some(0.0001)
+            ))
+            .is_ok());
+        assert_ne!(changed.state_digest(), a.state_digest());
+    }
+}
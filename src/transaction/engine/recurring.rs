@@ -0,0 +1,222 @@
+use std::time::{Duration, SystemTime};
+
+use crate::transaction::engine::{reconcile::DebitCredit, PaymentEngine};
+
+/// A payment which repeats every `interval` from `next_due`, a credit is a deposit and a debit a
+/// withdrawal. A zero interval is a payment which happens once.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecurringTransaction {
+    pub client: u16,
+    pub amount: f64,
+    pub direction: DebitCredit,
+    pub interval: Duration,
+    pub next_due: SystemTime,
+}
+
+impl RecurringTransaction {
+    /// When the payment first falls due at or after `as_of`, without stepping through the
+    /// occurrences before it. `None` if it never does, like a one-off payment in the past.
+    fn first_due_from(&self, as_of: SystemTime) -> Option<SystemTime> {
+        let Ok(behind) = as_of.duration_since(self.next_due) else {
+            return Some(self.next_due);
+        };
+        if behind.is_zero() {
+            return Some(self.next_due);
+        }
+        if self.interval.is_zero() {
+            return None;
+        }
+        let interval = self.interval.as_nanos();
+        let steps = behind.as_nanos().div_ceil(interval);
+        let offset = steps.checked_mul(interval)?;
+        let offset = Duration::new(
+            u64::try_from(offset / 1_000_000_000).ok()?,
+            (offset % 1_000_000_000) as u32,
+        );
+        self.next_due.checked_add(offset)
+    }
+}
+
+impl PaymentEngine {
+    /// Project the available funds of `client` over the `horizon` after `as_of`, with the recurring
+    /// transactions of the client which fall due in it. There is a point for every payment in the
+    /// order they fall due, with the available funds after it. Like the engine, a withdrawal above
+    /// the available funds or any payment to a locked account is left out, it still gets a point.
+    /// Payments due before `as_of` are taken as already performed, a client without an account
+    /// starts from nothing. A horizon past the latest time the platform can represent is shortened
+    /// until it fits.
+    pub fn project_future_balance(
+        &self,
+        client: u16,
+        recurring: &[RecurringTransaction],
+        as_of: SystemTime,
+        horizon: Duration,
+    ) -> Vec<(SystemTime, f64)> {
+        let end = std::iter::successors(Some(horizon), |horizon| Some(*horizon / 2))
+            .find_map(|horizon| as_of.checked_add(horizon))
+            .unwrap_or(as_of);
+        let mut payments: Vec<(SystemTime, &RecurringTransaction)> = Vec::new();
+        for payment in recurring.iter().filter(|r| r.client == client) {
+            let mut due = payment.first_due_from(as_of);
+            while let Some(at) = due.filter(|at| *at <= end) {
+                payments.push((at, payment));
+                if payment.interval.is_zero() {
+                    break;
+                }
+                due = at.checked_add(payment.interval);
+            }
+        }
+        // stable, so payments due together keep the order they were given in
+        payments.sort_by_key(|(due, _)| *due);
+
+        let account = self.accounts.get(&client);
+        let locked = account.is_some_and(|account| account.locked());
+        let mut available = account.map_or(0_f64, |account| account.available());
+        payments
+            .into_iter()
+            .map(|(due, payment)| {
+                match payment.direction {
+                    _ if locked => {}
+                    DebitCredit::Credit => available += payment.amount,
+                    DebitCredit::Debit if payment.amount <= available => {
+                        available -= payment.amount
+                    }
+                    DebitCredit::Debit => {}
+                }
+                (due, available)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+    use crate::transaction::{Transaction, TransactionType};
+
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    fn as_of() -> SystemTime {
+        UNIX_EPOCH + 1000 * DAY
+    }
+
+    fn engine_with(available: f64) -> PaymentEngine {
+        let mut engine = PaymentEngine::default();
+        engine
+            .perform_transaction(Transaction::new(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some(available),
+            ))
+            .unwrap();
+        engine
+    }
+
+    #[test]
+    fn monthly_deposit_weekly_withdrawal() {
+        let engine = engine_with(500.0);
+        let recurring = [
+            RecurringTransaction {
+                client: 1,
+                amount: 1000.0,
+                direction: DebitCredit::Credit,
+                interval: 30 * DAY,
+                next_due: as_of() + DAY,
+            },
+            RecurringTransaction {
+                client: 1,
+                amount: 100.0,
+                direction: DebitCredit::Debit,
+                interval: 7 * DAY,
+                // the payment of a week ago was performed already
+                next_due: as_of() - 5 * DAY,
+            },
+            // another client
+            RecurringTransaction {
+                client: 2,
+                amount: 1.0,
+                direction: DebitCredit::Credit,
+                interval: DAY,
+                next_due: as_of(),
+            },
+        ];
+        let projection = engine.project_future_balance(1, &recurring, as_of(), 90 * DAY);
+        // deposits on days 1, 31 and 61, withdrawals on days 2, 9, ..., 86
+        assert_eq!(projection.len(), 3 + 13);
+        assert_eq!(projection[0], (as_of() + DAY, 1500.0));
+        assert_eq!(projection[1], (as_of() + 2 * DAY, 1400.0));
+        assert_eq!(
+            projection.last(),
+            Some(&(as_of() + 86 * DAY, 500.0 + 3000.0 - 1300.0))
+        );
+        assert!(projection.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+    }
+
+    #[test]
+    fn rejected_withdrawals_and_one_off_payments() {
+        let engine = engine_with(150.0);
+        let recurring = [
+            RecurringTransaction {
+                client: 1,
+                amount: 100.0,
+                direction: DebitCredit::Debit,
+                interval: 7 * DAY,
+                next_due: as_of(),
+            },
+            RecurringTransaction {
+                client: 1,
+                amount: 60.0,
+                direction: DebitCredit::Credit,
+                interval: Duration::ZERO,
+                next_due: as_of() + 10 * DAY,
+            },
+        ];
+        let balances: Vec<f64> = engine
+            .project_future_balance(1, &recurring, as_of(), 21 * DAY)
+            .into_iter()
+            .map(|(_, available)| available)
+            .collect();
+        // the withdrawal of day 7 would be rejected
+        assert_eq!(balances, [50.0, 50.0, 110.0, 10.0, 10.0]);
+        assert!(engine
+            .project_future_balance(3, &recurring, as_of(), 21 * DAY)
+            .is_empty());
+    }
+
+    #[test]
+    fn far_past_and_far_future() {
+        let engine = PaymentEngine::default();
+        let recurring = [
+            // a second apart since the epoch, only the ones in the horizon are stepped through
+            RecurringTransaction {
+                client: 1,
+                amount: 1.0,
+                direction: DebitCredit::Credit,
+                interval: Duration::from_secs(1),
+                next_due: UNIX_EPOCH,
+            },
+        ];
+        let projection =
+            engine.project_future_balance(1, &recurring, as_of(), Duration::from_secs(2));
+        assert_eq!(
+            projection,
+            [
+                (as_of(), 1.0),
+                (as_of() + Duration::from_secs(1), 2.0),
+                (as_of() + Duration::from_secs(2), 3.0),
+            ]
+        );
+
+        // a horizon which doesn't fit doesn't panic
+        let one_off = [RecurringTransaction {
+            interval: Duration::ZERO,
+            next_due: as_of() + 400 * DAY,
+            ..recurring[0].clone()
+        }];
+        let projection = engine.project_future_balance(1, &one_off, as_of(), Duration::MAX);
+        assert_eq!(projection, [(as_of() + 400 * DAY, 1.0)]);
+    }
+}
@@ -0,0 +1,85 @@
+use crate::transaction::{
+    engine::{PaymentEngine, TransactionError},
+    Transaction,
+};
+
+impl PaymentEngine {
+    /// What-if analysis, run a transaction CSV through a fresh engine with every transaction passed
+    /// through `modifier` first. Returning `None` from `modifier` leaves the transaction out.
+    /// Rows which can't be read are skipped, the errors of the transactions which failed are returned.
+    pub fn replay_with_modifications<F>(
+        original_csv: &[u8],
+        modifier: F,
+    ) -> (PaymentEngine, Vec<TransactionError>)
+    where
+        F: Fn(Transaction) -> Option<Transaction>,
+    {
+        let mut engine = PaymentEngine::default();
+        let mut errors = Vec::new();
+        for transaction in Transaction::read_from_reader(original_csv)
+            .filter_map(Result::ok)
+            .filter_map(&modifier)
+        {
+            if let Err(e) = engine.perform_transaction(transaction) {
+                errors.push(e);
+            }
+        }
+        (engine, errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::TransactionType;
+
+    const CSV: &str = r#"
+        type, client, tx, amount
+        deposit, 1, 1, 10.0
+        deposit, 2, 2, 2.5
+        withdrawal, 1, 3, 4.0
+        deposit, 1, 4, 1.5"#;
+
+    #[test]
+    fn unmodified_replay() {
+        let (engine, errors) = PaymentEngine::replay_with_modifications(CSV.as_bytes(), Some);
+        assert!(errors.is_empty());
+        assert_eq!(engine.accounts[&1].available(), 7.5);
+        assert_eq!(engine.accounts[&2].available(), 2.5);
+    }
+
+    #[test]
+    fn double_deposits() {
+        let (engine, errors) =
+            PaymentEngine::replay_with_modifications(CSV.as_bytes(), |transaction| {
+                match (transaction.transaction_type(), transaction.amount()) {
+                    (TransactionType::Deposit, Some(amount)) => {
+                        Some(transaction.with_amount(amount * 2_f64))
+                    }
+                    // leave the withdrawals out so every balance is doubled
+                    (TransactionType::Withdrawal, _) => None,
+                    _ => Some(transaction),
+                }
+            });
+        assert!(errors.is_empty());
+        assert_eq!(engine.accounts[&1].available(), 23.0);
+        assert_eq!(engine.accounts[&2].available(), 5.0);
+    }
+
+    #[test]
+    fn modified_replay_errors() {
+        // withdrawing everything twice over fails
+        let (engine, errors) =
+            PaymentEngine::replay_with_modifications(CSV.as_bytes(), |transaction| {
+                match (transaction.transaction_type(), transaction.amount()) {
+                    (TransactionType::Withdrawal, Some(amount)) => {
+                        Some(transaction.with_amount(amount * 5_f64))
+                    }
+                    _ => Some(transaction),
+                }
+            });
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0], TransactionError::InsufficientFunds(1)));
+        assert_eq!(engine.accounts[&1].available(), 11.5);
+    }
+}
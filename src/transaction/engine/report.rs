@@ -0,0 +1,1092 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    io::Write,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::{
+    account::{round_amount, Account, OutputFormat},
+    serializer::{AccountSerializer, CsvAccountSerializer},
+    transaction::{engine::PaymentEngine, DisputeStatus, Transaction, TransactionType},
+};
+
+/// Summary of the money currently held in open disputes
+#[derive(Debug, Default, PartialEq)]
+pub struct DisputeExposure {
+    pub total_disputed_amount: f64,
+    pub dispute_count: usize,
+    pub max_single_dispute: f64,
+    pub accounts_affected: usize,
+}
+
+impl fmt::Display for DisputeExposure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} open disputes over {} accounts, {:.4} disputed in total, largest dispute {:.4}",
+            self.dispute_count,
+            self.accounts_affected,
+            self.total_disputed_amount,
+            self.max_single_dispute
+        )
+    }
+}
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// Upper bounds of the account age buckets in days, the last bucket has no upper bound
+const AGE_BUCKET_DAYS: [u64; 5] = [1, 7, 30, 90, 365];
+
+/// Number of accounts with an age in `age_range`, the lower bound is inclusive and the upper exclusive
+#[derive(Debug, PartialEq)]
+pub struct AgeBucket {
+    pub age_range: (Duration, Duration),
+    pub count: usize,
+}
+
+/// How long accounts have been active, from the timestamp of the transaction which opened them
+#[derive(Debug, PartialEq)]
+pub struct AgeDistribution {
+    pub buckets: Vec<AgeBucket>,
+    pub median_age: Duration,
+    pub mean_age: Duration,
+}
+
+/// An account as it would be if every open dispute ended in a chargeback
+#[derive(Debug, PartialEq)]
+pub struct AccountProjection {
+    pub client: u16,
+    pub current_available: f64,
+    pub projected_available: f64,
+    pub current_total: f64,
+    pub projected_total: f64,
+    pub projected_locked: bool,
+}
+
+/// The accounts of a group of clients added together
+#[derive(Debug, Default, PartialEq)]
+pub struct GroupPosition {
+    pub total_available: f64,
+    pub total_held: f64,
+    pub account_count: usize,
+    pub locked_count: usize,
+}
+
+/// Tax which should have been withheld from the deposits in the record
+#[derive(Debug, Default, PartialEq)]
+pub struct WithholdingReport {
+    pub total_taxable: f64,
+    pub total_withheld: f64,
+    pub per_client: Vec<(u16, f64)>, // client and the tax withheld from it, ordered by client id
+}
+
+/// Fee charged on each deposit and withdrawal, a flat part plus a share of the amount
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FeeSchedule {
+    pub flat: f64,
+    pub rate: f64, // 0.01 is 1% of the amount
+}
+
+impl FeeSchedule {
+    /// The fee the schedule charges on a transaction of `amount`
+    pub fn fee_for(&self, amount: f64) -> f64 {
+        self.flat + self.rate * amount
+    }
+}
+
+/// Fees a schedule would have charged on the transactions in the record
+#[derive(Debug, Default, PartialEq)]
+pub struct FeeImpactReport {
+    pub total_fee_revenue: f64,
+    pub accounts_affected: usize,
+    pub per_account: Vec<(u16, f64)>, // client and its fees, ordered by client id
+}
+
+/// What a client has brought in over the transaction record, for CRM systems. Reversed
+/// transactions don't count and the net is the deposits less the withdrawals and chargebacks.
+#[derive(Debug, Default, PartialEq)]
+pub struct LifetimeValue {
+    pub gross_deposits: f64,
+    pub gross_withdrawals: f64,
+    pub gross_chargebacks: f64,
+    pub net_ltv: f64,
+}
+
+impl LifetimeValue {
+    /// Count a transaction of the client towards the gross amounts, the net is left as it is
+    fn count(&mut self, transaction: &Transaction) {
+        if transaction.dispute_status == Some(DisputeStatus::Rolledback) {
+            return;
+        }
+        let amount = transaction.amount.unwrap_or_default();
+        match transaction.transaction_type {
+            TransactionType::Deposit => self.gross_deposits += amount,
+            TransactionType::Withdrawal => self.gross_withdrawals += amount,
+            _ => return,
+        }
+        if transaction.dispute_status == Some(DisputeStatus::Chargeback) {
+            self.gross_chargebacks += amount;
+        }
+    }
+
+    /// Work out the net from the gross amounts
+    fn with_net(mut self) -> Self {
+        self.net_ltv = self.gross_deposits - self.gross_withdrawals - self.gross_chargebacks;
+        self
+    }
+}
+
+/// A row of the lifetime value export
+#[derive(Serialize)]
+struct LifetimeValueRow {
+    client: u16,
+    gross_deposits: f64,
+    gross_withdrawals: f64,
+    gross_chargebacks: f64,
+    net_ltv: f64,
+}
+
+/// A row of the dispute timeline, the times are empty if the transaction had no timestamp
+#[derive(Serialize)]
+struct DisputeTimelineRow {
+    tx_id: u32,
+    client: u16,
+    original_amount: f64,
+    disputed_at: Option<u64>,
+    resolved_at: Option<u64>,
+    chargeback_at: Option<u64>,
+    final_status: &'static str,
+}
+
+/// Placeholder written instead of an amount in the redacted transaction export
+const REDACTED: &str = "*REDACTED*";
+
+/// A row of the redacted transaction export, in the input columns
+#[derive(Serialize)]
+struct RedactedTransactionRow {
+    #[serde(rename = "type")]
+    transaction_type: &'static str,
+    client: u16,
+    tx: u32,
+    amount: &'static str,
+}
+
+/// Layouts of account reports required by regulators and payment networks
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RegulatorySchema {
+    FinCENSAR,
+    /// Not implemented yet
+    VisaDispute,
+    /// Not implemented yet
+    ISO20022,
+}
+
+/// Column names of the FinCEN SAR account report, the fields of `FinCENSARRow`
+const FINCEN_SAR_COLUMNS: [&str; 5] = [
+    "AccountNumber",
+    "AvailableBalance",
+    "HeldBalance",
+    "TotalBalance",
+    "AccountFrozen",
+];
+
+/// A row of the FinCEN SAR account report
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct FinCENSARRow {
+    account_number: u16,
+    available_balance: f64,
+    held_balance: f64,
+    total_balance: f64,
+    account_frozen: bool,
+}
+
+impl FinCENSARRow {
+    /// The row of an account with the amounts rounded to `scale` decimal places
+    fn new(account: &Account, scale: u32) -> Self {
+        let view = account.to_view(scale);
+        FinCENSARRow {
+            account_number: view.client,
+            available_balance: view.available,
+            held_balance: view.held,
+            total_balance: view.total,
+            account_frozen: view.locked,
+        }
+    }
+}
+
+impl PaymentEngine {
+    /// Write every account as a CSV in the layout of a regulatory schema, ordered by client id.
+    /// Schemas which aren't implemented yet fail with an 'Unsupported' I/O error.
+    pub fn produce_regulatory_report<W: Write>(
+        &self,
+        writer: W,
+        schema: RegulatorySchema,
+    ) -> Result<(), csv::Error> {
+        let mut accounts: Vec<&Account> = self.accounts.values().collect();
+        accounts.sort_by_key(|acc| acc.client());
+        let mut wtr = csv::WriterBuilder::new()
+            // the header is written explicitly so it's there even without accounts
+            .has_headers(false)
+            .from_writer(writer);
+        match schema {
+            RegulatorySchema::FinCENSAR => {
+                wtr.write_record(FINCEN_SAR_COLUMNS)?;
+                for account in accounts {
+                    wtr.serialize(FinCENSARRow::new(account, self.scale()))?;
+                }
+            }
+            RegulatorySchema::VisaDispute | RegulatorySchema::ISO20022 => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    format!("regulatory schema {:?} is not implemented", schema),
+                )
+                .into());
+            }
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// Write the CSV account table with only the accounts whose available, held or locked
+    /// differ from `baseline`, or which the baseline doesn't have, for the output of an
+    /// incremental batch. Accounts only the baseline has aren't written. Returns the number of
+    /// accounts written.
+    pub fn write_account_changes_only<W: Write>(
+        &self,
+        baseline: &PaymentEngine,
+        writer: W,
+    ) -> Result<usize, csv::Error> {
+        let mut serializer = CsvAccountSerializer::new(writer, OutputFormat::default());
+        serializer.write_header()?;
+        let mut changed = 0;
+        for (client, account) in &self.accounts {
+            let unchanged = baseline.accounts.get(client).is_some_and(|before| {
+                before.available() == account.available()
+                    && before.held() == account.held()
+                    && before.locked() == account.locked()
+            });
+            if !unchanged {
+                serializer.write_account(account)?;
+                changed += 1;
+            }
+        }
+        serializer.finish()?;
+        Ok(changed)
+    }
+
+    /// Find the recorded transactions whose content is identical to an earlier one under another tx id.
+    /// Each entry is the client with the tx id of the first occurrence and of the later copy, in the
+    /// order they were applied.
+    pub fn detect_content_duplicates(&self) -> Vec<(u16, u32, u32)> {
+        let mut first_seen: HashMap<u64, u32> = HashMap::new();
+        let mut duplicates = Vec::new();
+        for transaction in self.transactions.values() {
+            let first = *first_seen
+                .entry(transaction.fingerprint())
+                .or_insert(transaction.tx);
+            if first != transaction.tx {
+                duplicates.push((transaction.client, first, transaction.tx));
+            }
+        }
+        duplicates
+    }
+
+    /// Bucket the accounts by the time between their opening transaction and `as_of`.
+    /// Accounts which weren't opened by a recorded transaction with a timestamp are left out,
+    /// and an account opened after `as_of` has an age of zero.
+    pub fn compute_account_age_distribution(&self, as_of: SystemTime) -> AgeDistribution {
+        let as_of = as_of
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .as_secs();
+        let mut ages: Vec<u64> = self
+            .accounts
+            .values()
+            .filter_map(|account| {
+                let first_tx = account.first_tx()?;
+                self.transactions
+                    .get(&(account.client(), first_tx))?
+                    .timestamp
+            })
+            .map(|opened| as_of.saturating_sub(opened))
+            .collect();
+        ages.sort_unstable();
+
+        let mut lower = 0;
+        let mut buckets = Vec::new();
+        for upper in AGE_BUCKET_DAYS
+            .map(|days| days * DAY)
+            .into_iter()
+            .chain([u64::MAX])
+        {
+            buckets.push(AgeBucket {
+                age_range: (Duration::from_secs(lower), Duration::from_secs(upper)),
+                count: ages
+                    .iter()
+                    .filter(|age| (lower..upper).contains(age))
+                    .count(),
+            });
+            lower = upper;
+        }
+
+        let median_age = match ages.len() {
+            0 => 0,
+            len if len % 2 == 0 => (ages[len / 2 - 1] + ages[len / 2]) / 2,
+            len => ages[len / 2],
+        };
+        let mean_age = match ages.len() {
+            0 => 0,
+            len => (ages.iter().map(|age| u128::from(*age)).sum::<u128>() / len as u128) as u64,
+        };
+        AgeDistribution {
+            buckets,
+            median_age: Duration::from_secs(median_age),
+            mean_age: Duration::from_secs(mean_age),
+        }
+    }
+
+    /// Write a CSV row for every disputed transaction with the time of each step of the dispute,
+    /// ordered by when the dispute started. Reversed transactions are only in the timeline if their
+    /// dispute had a timestamp, since a reversal of an undisputed transaction looks the same otherwise.
+    pub fn export_dispute_timeline<W: Write>(&self, writer: W) -> Result<(), csv::Error> {
+        let mut rows: Vec<DisputeTimelineRow> = self
+            .transactions
+            .values()
+            .filter_map(|t| {
+                let final_status = match t.dispute_status? {
+                    DisputeStatus::Disputed => "disputed",
+                    DisputeStatus::Resolved => "resolved",
+                    DisputeStatus::Chargeback => "chargeback",
+                    DisputeStatus::Rolledback if t.disputed_at.is_some() => "rolledback",
+                    DisputeStatus::Rolledback => return None,
+                };
+                Some(DisputeTimelineRow {
+                    tx_id: t.tx,
+                    client: t.client,
+                    original_amount: t.disputed_amount(),
+                    disputed_at: t.disputed_at,
+                    resolved_at: t.resolved_at,
+                    chargeback_at: t.chargeback_at,
+                    final_status,
+                })
+            })
+            .collect();
+        // a stable sort, disputes at the same time stay in the order of the transactions
+        rows.sort_by_key(|row| row.disputed_at);
+        let mut wtr = csv::Writer::from_writer(writer);
+        for row in rows {
+            wtr.serialize(row)?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// Write every transaction in the record as a `type,client,tx,amount` CSV with the amounts
+    /// replaced by `*REDACTED*`, for logs which mustn't show money. Reading it back fails on the
+    /// amounts, so the export can't be replayed.
+    pub fn export_redacted_transactions_csv<W: Write>(&self, writer: W) -> Result<(), csv::Error> {
+        let mut wtr = csv::Writer::from_writer(writer);
+        for transaction in self.transactions.values() {
+            wtr.serialize(RedactedTransactionRow {
+                transaction_type: transaction.transaction_type.name(),
+                client: transaction.client,
+                tx: transaction.tx,
+                amount: REDACTED,
+            })?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// Project every account as if all the open disputes ended in a chargeback: the held funds
+    /// are taken and the account is locked. A chargeback doesn't touch the available funds, so
+    /// the loss shows in the total. Nothing in the engine changes, the result is ordered by client id.
+    pub fn simulate_chargeback_scenario(&self) -> Vec<AccountProjection> {
+        let mut projected: HashMap<u16, Account> = HashMap::new();
+        for transaction in self.open_disputes() {
+            projected
+                .entry(transaction.client)
+                .or_insert_with(|| self.accounts[&transaction.client].clone())
+                .chargeback(transaction.held_amount());
+        }
+        let mut projections: Vec<AccountProjection> = self
+            .accounts
+            .values()
+            .map(|account| {
+                let after = projected.get(&account.client()).unwrap_or(account);
+                AccountProjection {
+                    client: account.client(),
+                    current_available: account.available(),
+                    projected_available: after.available(),
+                    current_total: account.total(),
+                    projected_total: after.total(),
+                    projected_locked: after.locked(),
+                }
+            })
+            .collect();
+        projections.sort_by_key(|projection| projection.client);
+        projections
+    }
+
+    /// Charge the schedule on every deposit and withdrawal in the transaction record, whatever
+    /// happened to them after, to price a fee schedule before it's used. Adjustments aren't
+    /// charged. Records pruned by `with_duplicate_window` are missed. Nothing in the engine changes.
+    pub fn simulate_fee_impact(&self, schedule: &FeeSchedule) -> FeeImpactReport {
+        let mut fees: HashMap<u16, f64> = HashMap::new();
+        for transaction in self.transactions.values().filter(|t| {
+            matches!(
+                t.transaction_type,
+                TransactionType::Deposit | TransactionType::Withdrawal
+            )
+        }) {
+            *fees.entry(transaction.client).or_default() +=
+                schedule.fee_for(transaction.amount.unwrap());
+        }
+        let mut per_account: Vec<(u16, f64)> = fees.into_iter().collect();
+        per_account.sort_by_key(|(client, _)| *client);
+        FeeImpactReport {
+            total_fee_revenue: per_account.iter().map(|(_, fee)| fee).sum(),
+            accounts_affected: per_account.len(),
+            per_account,
+        }
+    }
+
+    /// Add up the accounts of each group of clients, `grouper` gives the group of a client id.
+    /// Groups without accounts aren't in the result.
+    pub fn compute_net_position_by_client_group<F>(
+        &self,
+        grouper: F,
+    ) -> HashMap<String, GroupPosition>
+    where
+        F: Fn(u16) -> String,
+    {
+        let mut groups: HashMap<String, GroupPosition> = HashMap::new();
+        for account in self.accounts.values() {
+            let position = groups.entry(grouper(account.client())).or_default();
+            position.total_available += account.available();
+            position.total_held += account.held();
+            position.account_count += 1;
+            position.locked_count += usize::from(account.locked());
+        }
+        groups
+    }
+
+    /// Work out the tax a `rate` withheld at source would have taken from every completed deposit
+    /// over `min_threshold`, 0.2 is 20%. A deposit is completed unless it's under dispute, charged
+    /// back or reversed, a resolved dispute doesn't count. This only reports, nothing is deducted.
+    pub fn apply_withholding_tax(&self, rate: f64, min_threshold: f64) -> WithholdingReport {
+        let mut taxable: HashMap<u16, f64> = HashMap::new();
+        for transaction in self.transactions.values().filter(|t| {
+            t.transaction_type == TransactionType::Deposit
+                && matches!(t.dispute_status, None | Some(DisputeStatus::Resolved))
+                && t.amount.unwrap() > min_threshold
+        }) {
+            *taxable.entry(transaction.client).or_default() += transaction.amount.unwrap();
+        }
+        let mut per_client: Vec<(u16, f64)> = taxable
+            .iter()
+            .map(|(&client, &amount)| (client, amount * rate))
+            .collect();
+        per_client.sort_by_key(|(client, _)| *client);
+        WithholdingReport {
+            total_taxable: taxable.values().sum(),
+            total_withheld: per_client.iter().map(|(_, tax)| tax).sum(),
+            per_client,
+        }
+    }
+
+    /// The lifetime value of `client` from the transaction record, `None` if it has no account.
+    /// Records pruned by `with_duplicate_window` are missed and opening balances aren't counted.
+    pub fn compute_lifetime_value(&self, client: u16) -> Option<LifetimeValue> {
+        self.accounts.get(&client)?;
+        let mut value = LifetimeValue::default();
+        for transaction in self.transactions.values().filter(|t| t.client == client) {
+            value.count(transaction);
+        }
+        Some(value.with_net())
+    }
+
+    /// Write the lifetime value of every client as a CSV, ordered by client id. The amounts are
+    /// rounded to the output precision.
+    pub fn export_lifetime_values_csv<W: Write>(&self, writer: W) -> Result<(), csv::Error> {
+        let mut values: HashMap<u16, LifetimeValue> = self
+            .accounts
+            .keys()
+            .map(|&client| (client, LifetimeValue::default()))
+            .collect();
+        // one pass over the record for every client
+        for transaction in self.transactions.values() {
+            if let Some(value) = values.get_mut(&transaction.client) {
+                value.count(transaction);
+            }
+        }
+        let mut values: Vec<(u16, LifetimeValue)> = values.into_iter().collect();
+        values.sort_by_key(|(client, _)| *client);
+        let mut wtr = csv::Writer::from_writer(writer);
+        for (client, value) in values {
+            let value = value.with_net();
+            let round = |amount| round_amount(amount, self.scale());
+            wtr.serialize(LifetimeValueRow {
+                client,
+                gross_deposits: round(value.gross_deposits),
+                gross_withdrawals: round(value.gross_withdrawals),
+                gross_chargebacks: round(value.gross_chargebacks),
+                net_ltv: round(value.net_ltv),
+            })?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// Count the disputes by the reason they gave, whatever happened to them after.
+    /// Disputes without a reason aren't counted.
+    pub fn dispute_reasons(&self) -> HashMap<String, u32> {
+        let mut reasons: HashMap<String, u32> = HashMap::new();
+        for reason in self
+            .transactions
+            .values()
+            .filter_map(|t| t.dispute_reason())
+        {
+            *reasons.entry(reason.to_string()).or_default() += 1;
+        }
+        reasons
+    }
+
+    /// Total up what the transactions which are currently disputed hold, less the held fees charged
+    /// against them. Resolved and charged back ones are not at risk
+    pub fn compute_dispute_exposure(&self) -> DisputeExposure {
+        let mut exposure = DisputeExposure::default();
+        let mut clients = HashSet::new();
+        for transaction in self.open_disputes() {
+            let amount = transaction.held_amount();
+            exposure.total_disputed_amount += amount;
+            exposure.dispute_count += 1;
+            exposure.max_single_dispute = exposure.max_single_dispute.max(amount);
+            clients.insert(transaction.client);
+        }
+        exposure.accounts_affected = clients.len();
+        exposure
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::Transaction;
+
+    #[test]
+    fn fincen_sar_report() {
+        let mut engine = PaymentEngine::default();
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 2, 1, Some(10.0)),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(20.123456)),
+            Transaction::new(TransactionType::Dispute, 2, 1, None),
+            Transaction::new(TransactionType::Chargeback, 2, 1, None),
+        ];
+        for tx in txs {
+            assert!(engine.perform_transaction(tx).is_ok());
+        }
+        let mut buf = Vec::new();
+        assert!(engine
+            .produce_regulatory_report(&mut buf, RegulatorySchema::FinCENSAR)
+            .is_ok());
+        let report = String::from_utf8(buf).unwrap();
+        let mut lines = report.lines();
+        assert_eq!(
+            lines.next(),
+            Some("AccountNumber,AvailableBalance,HeldBalance,TotalBalance,AccountFrozen")
+        );
+        assert_eq!(lines.next(), Some("1,20.1235,0.0,20.1235,false"));
+        assert_eq!(lines.next(), Some("2,0.0,0.0,0.0,true"));
+        assert_eq!(lines.next(), None);
+
+        // without accounts there is still the header
+        let mut buf = Vec::new();
+        PaymentEngine::default()
+            .produce_regulatory_report(&mut buf, RegulatorySchema::FinCENSAR)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "AccountNumber,AvailableBalance,HeldBalance,TotalBalance,AccountFrozen\n"
+        );
+    }
+
+    #[test]
+    fn fincen_sar_report_scale() {
+        let mut engine = PaymentEngine::default().with_scale(0);
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(10.0)),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(5.0)),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+        ];
+        assert!(engine.perform_transactions(txs).is_empty());
+        // the fee leaves 9.9 held, which the currency can't show
+        engine.apply_held_fee(0.01).unwrap();
+        let mut buf = Vec::new();
+        engine
+            .produce_regulatory_report(&mut buf, RegulatorySchema::FinCENSAR)
+            .unwrap();
+        let report = String::from_utf8(buf).unwrap();
+        assert_eq!(report.lines().nth(1), Some("1,5.0,10.0,15.0,false"));
+    }
+
+    #[test]
+    fn unimplemented_regulatory_schema() {
+        let engine = PaymentEngine::default();
+        let res = engine.produce_regulatory_report(Vec::new(), RegulatorySchema::VisaDispute);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn dispute_exposure() {
+        let mut engine = PaymentEngine::default();
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(10.0)),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(30.0)),
+            Transaction::new(TransactionType::Deposit, 2, 3, Some(5.5)),
+            Transaction::new(TransactionType::Deposit, 3, 4, Some(100.0)),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+            Transaction::new(TransactionType::Dispute, 1, 2, None),
+            Transaction::new(TransactionType::Dispute, 2, 3, None),
+            // a resolved dispute isn't exposure
+            Transaction::new(TransactionType::Dispute, 3, 4, None),
+            Transaction::new(TransactionType::Resolve, 3, 4, None),
+        ];
+        for tx in txs {
+            assert!(engine.perform_transaction(tx).is_ok());
+        }
+        let exposure = engine.compute_dispute_exposure();
+        assert_eq!(
+            exposure,
+            DisputeExposure {
+                total_disputed_amount: 45.5,
+                dispute_count: 3,
+                max_single_dispute: 30.0,
+                accounts_affected: 2,
+            }
+        );
+        // everything disputed is held
+        let held: f64 = engine.accounts_iter().map(|(_, acc)| acc.held()).sum();
+        assert_eq!(exposure.total_disputed_amount, held);
+        assert_eq!(
+            exposure.to_string(),
+            "3 open disputes over 2 accounts, 45.5000 disputed in total, largest dispute 30.0000"
+        );
+    }
+
+    #[test]
+    fn fee_impact() {
+        let mut engine = PaymentEngine::default();
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 2, 1, Some(100.0)),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(250.0)),
+            Transaction::new(TransactionType::Withdrawal, 2, 3, Some(40.0)),
+            Transaction::new(TransactionType::Dispute, 1, 2, None),
+            Transaction::new(TransactionType::Adjustment, 3, 4, Some(1000.0)),
+        ];
+        assert!(engine.perform_transactions(txs).is_empty());
+        let before = engine.accounts[&1].clone();
+
+        let one_percent = FeeSchedule {
+            flat: 0.0,
+            rate: 0.01,
+        };
+        let report = engine.simulate_fee_impact(&one_percent);
+        assert!((report.total_fee_revenue - 0.01 * (100.0 + 250.0 + 40.0)).abs() < 1e-9);
+        // the adjustment isn't charged
+        assert_eq!(report.accounts_affected, 2);
+        assert_eq!(report.per_account[0].0, 1);
+        assert!((report.per_account[0].1 - 2.5).abs() < 1e-9);
+        assert_eq!(report.per_account[1].0, 2);
+        assert!((report.per_account[1].1 - 1.4).abs() < 1e-9);
+        assert_eq!(engine.accounts[&1], before);
+
+        let flat = FeeSchedule {
+            flat: 0.25,
+            rate: 0.0,
+        };
+        assert_eq!(engine.simulate_fee_impact(&flat).total_fee_revenue, 0.75);
+        assert_eq!(
+            PaymentEngine::default().simulate_fee_impact(&flat),
+            FeeImpactReport::default()
+        );
+    }
+
+    #[test]
+    fn net_position_by_parity() {
+        let mut engine = PaymentEngine::default();
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(10.0)),
+            Transaction::new(TransactionType::Deposit, 2, 2, Some(20.0)),
+            Transaction::new(TransactionType::Deposit, 3, 3, Some(5.5)),
+            Transaction::new(TransactionType::Deposit, 3, 4, Some(2.0)),
+            Transaction::new(TransactionType::Dispute, 3, 4, None),
+            Transaction::new(TransactionType::Deposit, 4, 5, Some(7.0)),
+            Transaction::new(TransactionType::Dispute, 4, 5, None),
+            Transaction::new(TransactionType::Chargeback, 4, 5, None),
+        ];
+        assert!(engine.perform_transactions(txs).is_empty());
+        let groups = engine.compute_net_position_by_client_group(|client| {
+            if client % 2 == 0 { "even" } else { "odd" }.to_string()
+        });
+        assert_eq!(groups.len(), 2);
+        assert_eq!(
+            groups["odd"],
+            GroupPosition {
+                total_available: 15.5,
+                total_held: 2.0,
+                account_count: 2,
+                locked_count: 0,
+            }
+        );
+        assert_eq!(
+            groups["even"],
+            GroupPosition {
+                total_available: 20.0,
+                total_held: 0.0,
+                account_count: 2,
+                locked_count: 1,
+            }
+        );
+        assert!(PaymentEngine::default()
+            .compute_net_position_by_client_group(|client| client.to_string())
+            .is_empty());
+    }
+
+    #[test]
+    fn withholding_tax() {
+        let mut engine = PaymentEngine::default();
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(1000.0)),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(50.0)),
+            Transaction::new(TransactionType::Deposit, 2, 3, Some(400.0)),
+            Transaction::new(TransactionType::Deposit, 2, 4, Some(300.0)),
+            Transaction::new(TransactionType::Dispute, 2, 4, None),
+            Transaction::new(TransactionType::Deposit, 3, 5, Some(200.0)),
+            Transaction::new(TransactionType::Dispute, 3, 5, None),
+            Transaction::new(TransactionType::Resolve, 3, 5, None),
+            Transaction::new(TransactionType::Withdrawal, 1, 6, Some(500.0)),
+            Transaction::new(TransactionType::Deposit, 4, 7, Some(100.0)),
+        ];
+        assert!(engine.perform_transactions(txs).is_empty());
+        // 50 and 100 aren't over the threshold, the disputed 300 isn't completed
+        let report = engine.apply_withholding_tax(0.2, 100.0);
+        assert_eq!(
+            report,
+            WithholdingReport {
+                total_taxable: 1600.0,
+                total_withheld: 320.0,
+                per_client: vec![(1, 200.0), (2, 80.0), (3, 40.0)],
+            }
+        );
+        // nothing was deducted
+        assert_eq!(engine.accounts[&1].available(), 550.0);
+
+        let report = engine.apply_withholding_tax(0.1, 0.0);
+        assert_eq!(report.total_taxable, 1750.0);
+        assert_eq!(report.total_withheld, 175.0);
+        assert_eq!(report.per_client.len(), 4);
+    }
+
+    #[test]
+    fn chargeback_scenario() {
+        let mut engine = PaymentEngine::default();
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(10.0)),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(30.0)),
+            Transaction::new(TransactionType::Deposit, 2, 3, Some(5.5)),
+            Transaction::new(TransactionType::Deposit, 3, 4, Some(100.0)),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+            Transaction::new(TransactionType::Dispute, 1, 2, None),
+            Transaction::new(TransactionType::Dispute, 2, 3, None),
+        ];
+        assert!(engine.perform_transactions(txs).is_empty());
+        let projections = engine.simulate_chargeback_scenario();
+        assert_eq!(
+            projections,
+            [
+                AccountProjection {
+                    client: 1,
+                    current_available: 0.0,
+                    projected_available: 0.0,
+                    current_total: 40.0,
+                    projected_total: 0.0,
+                    projected_locked: true,
+                },
+                AccountProjection {
+                    client: 2,
+                    current_available: 0.0,
+                    projected_available: 0.0,
+                    current_total: 5.5,
+                    projected_total: 0.0,
+                    projected_locked: true,
+                },
+                AccountProjection {
+                    client: 3,
+                    current_available: 100.0,
+                    projected_available: 100.0,
+                    current_total: 100.0,
+                    projected_total: 100.0,
+                    projected_locked: false,
+                },
+            ]
+        );
+        // the engine itself is untouched
+        assert_eq!(engine.accounts[&1].held(), 40.0);
+        assert!(!engine.accounts[&1].locked());
+    }
+
+    #[test]
+    fn content_duplicates() {
+        let mut engine = PaymentEngine::default();
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(10.0)),
+            Transaction::new(TransactionType::Deposit, 2, 2, Some(10.0)),
+            // same content as tx 1 under other ids
+            Transaction::new(TransactionType::Deposit, 1, 3, Some(10.0)),
+            Transaction::new(TransactionType::Withdrawal, 1, 4, Some(10.0)),
+            Transaction::new(TransactionType::Deposit, 1, 5, Some(10.0)),
+            // a copy of tx 2 under a tx id client 1 uses too
+            Transaction::new(TransactionType::Deposit, 2, 5, Some(10.0)),
+        ];
+        for tx in txs {
+            assert!(engine.perform_transaction(tx).is_ok());
+        }
+        assert_eq!(
+            engine.detect_content_duplicates(),
+            [(1, 1, 3), (1, 1, 5), (2, 2, 5)]
+        );
+    }
+
+    #[test]
+    fn account_age_distribution() {
+        let as_of = UNIX_EPOCH + Duration::from_secs(1000 * DAY);
+        let opened_days_ago = |days: u64| (1000 - days) * DAY;
+        let mut engine = PaymentEngine::default();
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(1.0))
+                .with_timestamp(opened_days_ago(0) - 60),
+            Transaction::new(TransactionType::Deposit, 2, 2, Some(1.0))
+                .with_timestamp(opened_days_ago(3)),
+            Transaction::new(TransactionType::Deposit, 3, 3, Some(1.0))
+                .with_timestamp(opened_days_ago(7)),
+            Transaction::new(TransactionType::Deposit, 4, 4, Some(1.0))
+                .with_timestamp(opened_days_ago(400)),
+            // no timestamp, so no known age
+            Transaction::new(TransactionType::Deposit, 5, 5, Some(1.0)),
+            // only the opening transaction counts
+            Transaction::new(TransactionType::Deposit, 1, 6, Some(1.0))
+                .with_timestamp(opened_days_ago(900)),
+        ];
+        for tx in txs {
+            assert!(engine.perform_transaction(tx).is_ok());
+        }
+        let distribution = engine.compute_account_age_distribution(as_of);
+        let counts: Vec<usize> = distribution.buckets.iter().map(|b| b.count).collect();
+        assert_eq!(counts, [1, 1, 1, 0, 0, 1]);
+        assert_eq!(
+            distribution.buckets[1].age_range,
+            (Duration::from_secs(DAY), Duration::from_secs(7 * DAY))
+        );
+        assert_eq!(
+            distribution.buckets[5].age_range.1,
+            Duration::from_secs(u64::MAX)
+        );
+        assert_eq!(distribution.median_age, Duration::from_secs(5 * DAY));
+        assert_eq!(
+            distribution.mean_age,
+            Duration::from_secs((60 + 410 * DAY) / 4)
+        );
+    }
+
+    #[test]
+    fn account_changes_only() {
+        let mut baseline = PaymentEngine::default();
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(10.5)),
+            Transaction::new(TransactionType::Deposit, 2, 2, Some(1.0)),
+            Transaction::new(TransactionType::Deposit, 3, 3, Some(7.0)),
+        ];
+        assert!(baseline.perform_transactions(txs).is_empty());
+        let mut engine = baseline.clone();
+        engine
+            .perform_transaction(Transaction::new(
+                TransactionType::Withdrawal,
+                2,
+                4,
+                Some(0.5),
+            ))
+            .unwrap();
+        let mut buf = Vec::new();
+        let changed = engine
+            .write_account_changes_only(&baseline, &mut buf)
+            .unwrap();
+        assert_eq!(changed, 1);
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "client,available,held,total,locked\n2,0.5,0.0,0.5,false\n"
+        );
+
+        // a dispute only moves funds to held, which is still a change
+        engine
+            .perform_transaction(Transaction::new(TransactionType::Dispute, 1, 1, None))
+            .unwrap();
+        let mut buf = Vec::new();
+        let changed = engine
+            .write_account_changes_only(&baseline, &mut buf)
+            .unwrap();
+        assert_eq!(changed, 2);
+
+        // a new account is a change, one only in the baseline isn't written
+        let mut buf = Vec::new();
+        let changed = baseline
+            .write_account_changes_only(&PaymentEngine::default(), &mut buf)
+            .unwrap();
+        assert_eq!(changed, 3);
+        let mut buf = Vec::new();
+        let changed = PaymentEngine::default()
+            .write_account_changes_only(&baseline, &mut buf)
+            .unwrap();
+        assert_eq!(changed, 0);
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "client,available,held,total,locked\n"
+        );
+    }
+
+    #[test]
+    fn redacted_transactions() {
+        let mut engine = PaymentEngine::default();
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(1234.5)),
+            Transaction::new(TransactionType::Withdrawal, 1, 2, Some(34.5)),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+        ];
+        assert!(engine.perform_transactions(txs).is_empty());
+        let mut buf = Vec::new();
+        engine.export_redacted_transactions_csv(&mut buf).unwrap();
+        let csv = String::from_utf8(buf).unwrap();
+        assert_eq!(
+            csv,
+            "type,client,tx,amount\n\
+             deposit,1,1,*REDACTED*\n\
+             withdrawal,1,2,*REDACTED*\n"
+        );
+        // the amounts can't be read back
+        let rows: Vec<_> = Transaction::read_from_reader(csv.as_bytes()).collect();
+        assert_eq!(rows.len(), 2);
+        assert!(rows.iter().all(Result::is_err));
+        assert!(!csv.contains("1234.5"));
+    }
+
+    #[test]
+    fn dispute_timeline() {
+        let mut engine = PaymentEngine::default();
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(10.0)).with_timestamp(100),
+            Transaction::new(TransactionType::Deposit, 2, 2, Some(20.5)).with_timestamp(110),
+            Transaction::new(TransactionType::Deposit, 3, 3, Some(1.0)).with_timestamp(120),
+            Transaction::new(TransactionType::Dispute, 2, 2, None).with_timestamp(200),
+            Transaction::new(TransactionType::Dispute, 1, 1, None).with_timestamp(300),
+            Transaction::new(TransactionType::Resolve, 1, 1, None).with_timestamp(400),
+            Transaction::new(TransactionType::Chargeback, 2, 2, None).with_timestamp(500),
+        ];
+        for tx in txs {
+            assert!(engine.perform_transaction(tx).is_ok());
+        }
+        let mut buf = Vec::new();
+        assert!(engine.export_dispute_timeline(&mut buf).is_ok());
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "tx_id,client,original_amount,disputed_at,resolved_at,chargeback_at,final_status\n\
+             2,2,20.5,200,,500,chargeback\n\
+             1,1,10.0,300,400,,resolved\n"
+        );
+    }
+
+    #[test]
+    fn lifetime_value() {
+        let mut engine = PaymentEngine::default();
+        engine
+            .seed_balances("client,available\n2,100.0\n".as_bytes())
+            .unwrap();
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(100.0)),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(40.0)),
+            Transaction::new(TransactionType::Withdrawal, 1, 3, Some(30.0)),
+            Transaction::new(TransactionType::Deposit, 1, 4, Some(5.0)),
+        ];
+        assert!(engine.perform_transactions(txs).is_empty());
+        // a reversed deposit never counted
+        let report = engine
+            .process_reversals("client,tx\n1,4\n".as_bytes())
+            .unwrap();
+        assert_eq!(report.reversed, [4]);
+        let txs = [
+            Transaction::new(TransactionType::Dispute, 1, 2, None),
+            Transaction::new(TransactionType::Chargeback, 1, 2, None),
+            // the only money client 2 moved is a withdrawal which was charged back
+            Transaction::new(TransactionType::Withdrawal, 2, 5, Some(50.0)),
+            Transaction::new(TransactionType::Dispute, 2, 5, None),
+            Transaction::new(TransactionType::Chargeback, 2, 5, None),
+        ];
+        assert!(engine.perform_transactions(txs).is_empty());
+
+        assert_eq!(
+            engine.compute_lifetime_value(1),
+            Some(LifetimeValue {
+                gross_deposits: 140.0,
+                gross_withdrawals: 30.0,
+                gross_chargebacks: 40.0,
+                net_ltv: 140.0 - 30.0 - 40.0,
+            })
+        );
+        let value = engine.compute_lifetime_value(2).unwrap();
+        assert_eq!(value.net_ltv, -100.0);
+        assert_eq!(engine.compute_lifetime_value(3), None);
+
+        let mut buf = Vec::new();
+        engine.export_lifetime_values_csv(&mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "client,gross_deposits,gross_withdrawals,gross_chargebacks,net_ltv\n\
+             1,140.0,30.0,40.0,70.0\n\
+             2,0.0,50.0,50.0,-100.0\n"
+        );
+    }
+
+    #[test]
+    fn dispute_reasons() {
+        let csv = "\
+type,client,tx,amount,reason
+deposit,1,1,10.0,
+deposit,1,2,10.0,
+deposit,2,3,10.0,
+deposit,2,4,10.0,
+deposit,3,5,10.0,
+dispute,1,1,,fraud
+dispute,1,2,,duplicate
+dispute,2,3,,fraud
+resolve,2,3,,not a reason
+dispute,2,4,,
+dispute,3,5,,product_not_received
+chargeback,3,5,,
+";
+        let mut engine = PaymentEngine::default();
+        for transaction in Transaction::read_from_reader(csv.as_bytes()) {
+            engine.perform_transaction(transaction.unwrap()).unwrap();
+        }
+        let reasons = engine.dispute_reasons();
+        assert_eq!(reasons.len(), 3);
+        assert_eq!(reasons["fraud"], 2);
+        assert_eq!(reasons["duplicate"], 1);
+        // charged back, still counted
+        assert_eq!(reasons["product_not_received"], 1);
+        assert_eq!(engine.transactions[&(2, 3)].dispute_reason(), Some("fraud"));
+        assert_eq!(engine.transactions[&(2, 4)].dispute_reason(), None);
+    }
+}
@@ -0,0 +1,158 @@
+use std::{fs::File, io::Read};
+
+use serde::Deserialize;
+
+use crate::transaction::{
+    engine::{PaymentEngine, TransactionError},
+    TransactionType,
+};
+
+/// A row of a reversals file
+#[derive(Debug, Deserialize)]
+struct Reversal {
+    client: u16,
+    tx: u32,
+}
+
+/// Outcome of a batch of reversals
+#[derive(Debug, Default)]
+pub struct ReversalReport {
+    pub reversed: Vec<u32>,
+    pub failures: Vec<TransactionError>,
+}
+
+impl PaymentEngine {
+    /// Reverse the transactions listed in a `client,tx` CSV file, see `process_reversals`.
+    pub fn process_reversals_file(&mut self, csv_path: &str) -> Result<ReversalReport, csv::Error> {
+        self.process_reversals(File::open(csv_path)?)
+    }
+
+    /// Reverse every transaction listed in a `client,tx` CSV, like undoing a payroll run.
+    /// A reversed deposit is taken out of the available funds and a reversed withdrawal is paid back.
+    /// Reversals which fail are reported and don't stop the batch, only a malformed CSV does.
+    /// Reversed transactions are marked as rolled back and can't be disputed anymore.
+    pub fn process_reversals<R: Read>(&mut self, reader: R) -> Result<ReversalReport, csv::Error> {
+        let reversals = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All) // allow whitespace
+            .from_reader(reader)
+            .into_deserialize::<Reversal>();
+        let mut report = ReversalReport::default();
+        for reversal in reversals {
+            let Reversal { client, tx } = reversal?;
+            match self.reverse_transaction(client, tx) {
+                Ok(()) => report.reversed.push(tx),
+                Err(e) => report.failures.push(e),
+            }
+        }
+        Ok(report)
+    }
+
+    /// Reverse a single deposit or withdrawal
+    pub(super) fn reverse_transaction(
+        &mut self,
+        client: u16,
+        tx: u32,
+    ) -> Result<(), TransactionError> {
+        let transaction = self
+            .transactions
+            .get_mut(&(client, tx))
+            .ok_or(TransactionError::NonExistingReversal(tx))?;
+        // every recorded transaction has an account
+        let account = self.accounts.get_mut(&transaction.client).unwrap();
+        if account.locked() {
+            return Err(TransactionError::AccountLocked(transaction.client));
+        }
+        // check the state first, but only mark it once the funds have moved
+        let mut reversed = transaction.clone();
+        if !reversed.rollback() {
+            return Err(TransactionError::InvalidReversal(transaction.client, tx));
+        }
+        let amount = transaction.amount.unwrap();
+        match transaction.transaction_type {
+            TransactionType::Deposit => {
+                if !account.reverse_deposit(amount) {
+                    return Err(TransactionError::InsufficientFunds(transaction.client));
+                }
+            }
+            TransactionType::Withdrawal => account.reverse_withdrawal(amount),
+            _ => unreachable!(),
+        }
+        *transaction = reversed;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{DisputeStatus, Transaction};
+
+    #[test]
+    fn reverse_deposit_and_withdrawal() {
+        let mut engine = PaymentEngine::default();
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(100.0)),
+            Transaction::new(TransactionType::Deposit, 1, 2, Some(2500.0)),
+            Transaction::new(TransactionType::Withdrawal, 1, 3, Some(40.0)),
+        ];
+        for tx in txs {
+            assert!(engine.perform_transaction(tx).is_ok());
+        }
+        let report = engine
+            .process_reversals_file("tests/reversals.csv")
+            .unwrap();
+        assert_eq!(report.reversed, [2, 3]);
+        assert!(report.failures.is_empty());
+        // back to the balance before the payroll deposit and the withdrawal
+        assert_eq!(engine.accounts[&1].available(), 100.0);
+        assert_eq!(
+            engine.transactions[&(1, 2)].dispute_status,
+            Some(DisputeStatus::Rolledback)
+        );
+        // a rolled back transaction can't be disputed
+        let res =
+            engine.perform_transaction(Transaction::new(TransactionType::Dispute, 1, 2, None));
+        assert!(matches!(
+            res.unwrap_err(),
+            TransactionError::InvalidDispute(1, 2)
+        ));
+    }
+
+    #[test]
+    fn reversal_failures() {
+        let mut engine = PaymentEngine::default();
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(100.0)),
+            Transaction::new(TransactionType::Withdrawal, 1, 2, Some(60.0)),
+            Transaction::new(TransactionType::Deposit, 2, 3, Some(10.0)),
+        ];
+        for tx in txs {
+            assert!(engine.perform_transaction(tx).is_ok());
+        }
+        let csv = r#"
+        client, tx
+        1, 1
+        1, 99
+        2, 3
+        2, 3"#;
+        let report = engine.process_reversals(csv.as_bytes()).unwrap();
+        // only 40 is left of the first deposit, the batch goes on past the failures
+        assert_eq!(report.reversed, [3]);
+        assert_eq!(report.failures.len(), 3);
+        assert!(matches!(
+            report.failures[0],
+            TransactionError::InsufficientFunds(1)
+        ));
+        assert!(matches!(
+            report.failures[1],
+            TransactionError::NonExistingReversal(99)
+        ));
+        assert!(matches!(
+            report.failures[2],
+            TransactionError::InvalidReversal(2, 3)
+        ));
+        assert_eq!(engine.accounts[&1].available(), 40.0);
+        assert_eq!(engine.transactions[&(1, 1)].dispute_status, None);
+        assert_eq!(engine.accounts[&2].available(), 0.0);
+    }
+}
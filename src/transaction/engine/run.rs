@@ -0,0 +1,152 @@
+use std::io::Read;
+
+use crate::transaction::{
+    engine::{PaymentEngine, TransactionError},
+    Transaction,
+};
+
+/// What went wrong in a run over a transaction CSV. Rows are counted from 0 after the header.
+#[derive(Debug)]
+pub enum EngineRunError {
+    /// The input couldn't be opened or read, the run stops
+    Io(std::io::Error),
+    /// The row couldn't be deserialized into a transaction
+    Deserialize { row: usize, error: csv::Error },
+    /// The engine rejected the transaction of the row
+    Transaction { row: usize, kind: TransactionError },
+}
+
+impl EngineRunError {
+    /// Sort a csv error of the row into a failure to read the input or a row which can't be deserialized
+    pub fn from_csv(row: usize, error: csv::Error) -> Self {
+        if error.is_io_error() {
+            match error.into_kind() {
+                csv::ErrorKind::Io(e) => EngineRunError::Io(e),
+                _ => unreachable!("is_io_error is only true for the Io kind"),
+            }
+        } else {
+            EngineRunError::Deserialize { row, error }
+        }
+    }
+}
+
+impl std::fmt::Display for EngineRunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EngineRunError::Io(e) => write!(f, "failed to read the input: {}", e),
+            EngineRunError::Deserialize { row, error } => {
+                write!(f, "deserialize of row {} failed: {}", row, error)
+            }
+            EngineRunError::Transaction { row, kind } => write!(f, "row {}: {}", row, kind),
+        }
+    }
+}
+
+impl std::error::Error for EngineRunError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EngineRunError::Io(e) => Some(e),
+            EngineRunError::Deserialize { error, .. } => Some(error),
+            EngineRunError::Transaction { kind, .. } => Some(kind),
+        }
+    }
+}
+
+impl From<std::io::Error> for EngineRunError {
+    fn from(e: std::io::Error) -> Self {
+        EngineRunError::Io(e)
+    }
+}
+
+impl PaymentEngine {
+    /// Perform every row of a transaction CSV and collect what went wrong, in the order of the rows.
+    /// A row which can't be deserialized or whose transaction is rejected, including rows which fail
+    /// `Transaction::validate`, doesn't stop the run. Failing to read the input does, then the last
+    /// error is `EngineRunError::Io`.
+    pub fn perform_csv<R: Read>(&mut self, reader: R) -> Vec<EngineRunError> {
+        let mut errors = Vec::new();
+        for (row, result) in Transaction::read_unvalidated_from_reader(reader).enumerate() {
+            let error = match result {
+                Ok(transaction) => match self.perform_transaction(transaction) {
+                    Ok(_) => continue,
+                    Err(kind) => EngineRunError::Transaction { row, kind },
+                },
+                Err(e) => EngineRunError::from_csv(row, e),
+            };
+            let stop = matches!(error, EngineRunError::Io(_));
+            errors.push(error);
+            if stop {
+                break;
+            }
+        }
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, ErrorKind};
+
+    use super::*;
+
+    /// Reads the bytes, then fails
+    struct Truncated<'a>(&'a [u8]);
+
+    impl Read for Truncated<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(io::Error::new(ErrorKind::ConnectionReset, "gone"));
+            }
+            self.0.read(buf)
+        }
+    }
+
+    #[test]
+    fn run_errors() {
+        let csv = "\
+type,client,tx,amount
+deposit,1,1,10.0
+withdrawal,1,2,50.0
+deposit,1,x,1.0
+deposit,2,3,
+dispute,1,9,
+";
+        let mut engine = PaymentEngine::default();
+        let errors = engine.perform_csv(csv.as_bytes());
+        assert!(matches!(
+            errors[..],
+            [
+                EngineRunError::Transaction {
+                    row: 1,
+                    kind: TransactionError::InsufficientFunds(1)
+                },
+                EngineRunError::Deserialize { row: 2, .. },
+                EngineRunError::Transaction {
+                    row: 3,
+                    kind: TransactionError::InvalidTransaction(3)
+                },
+                EngineRunError::Transaction {
+                    row: 4,
+                    kind: TransactionError::NonExistingDisputeResolveOrChargeback(1, 9)
+                },
+            ]
+        ));
+        assert_eq!(engine.accounts[&1].available(), 10.0);
+        assert_eq!(
+            errors[1].to_string().split(':').next(),
+            Some("deserialize of row 2 failed")
+        );
+    }
+
+    #[test]
+    fn run_stops_on_io_error() {
+        let csv = b"type,client,tx,amount\ndeposit,1,1,10.0\n";
+        let mut engine = PaymentEngine::default();
+        let errors = engine.perform_csv(Truncated(csv));
+        assert!(matches!(
+            &errors[..],
+            [EngineRunError::Io(e)] if e.kind() == ErrorKind::ConnectionReset
+        ));
+        assert_eq!(engine.accounts[&1].available(), 10.0);
+    }
+}
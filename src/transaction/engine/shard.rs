@@ -0,0 +1,236 @@
+use std::collections::HashSet;
+
+use crate::{
+    account::Account,
+    transaction::engine::{PaymentEngine, TransactionError},
+};
+
+impl PaymentEngine {
+    /// A new engine with the same configuration but without any accounts, transactions or corrections
+    pub(super) fn empty_like(&self) -> PaymentEngine {
+        PaymentEngine {
+            allowlist: self.allowlist.clone(),
+            max_accounts: self.max_accounts,
+            max_open_disputes: self.max_open_disputes,
+            require_registered: self.require_registered,
+            rules: self.rules.clone(),
+            round_trip_threshold: self.round_trip_threshold,
+            deduplicate: self.deduplicate,
+            duplicate_window: self.duplicate_window,
+            deposit_hook: self.deposit_hook.clone(),
+            lock_hook: self.lock_hook.clone(),
+            house_account: self.house_account,
+            ..PaymentEngine::default()
+        }
+    }
+
+    /// Partition the engine into `n` shards by `client % n`, the shards keep the configuration.
+    /// Each account goes to its shard together with its transactions and corrections.
+    /// Panics if `n` is zero.
+    pub fn split(self, n: usize) -> Vec<PaymentEngine> {
+        assert!(n > 0, "can't split an engine into zero shards");
+        self.split_by(n, |client| usize::from(client) % n)
+    }
+
+    /// Split the engine into the locked accounts and the unlocked ones, see `partition_by_predicate`
+    pub fn partition_by_locked_status(self) -> (PaymentEngine, PaymentEngine) {
+        self.partition_by_predicate(Account::locked)
+    }
+
+    /// Split the engine into the accounts `f` is true for and the rest, both keep the configuration.
+    /// Each account goes to its engine together with its transactions and corrections.
+    pub fn partition_by_predicate<F>(self, f: F) -> (PaymentEngine, PaymentEngine)
+    where
+        F: Fn(&Account) -> bool,
+    {
+        let matching: HashSet<u16> = self
+            .accounts
+            .iter()
+            .filter(|(_, account)| f(account))
+            .map(|(client, _)| *client)
+            .collect();
+        let mut engines = self.split_by(2, |client| usize::from(!matching.contains(&client)));
+        let rest = engines.pop().unwrap();
+        (engines.pop().unwrap(), rest)
+    }
+
+    /// Move everything of a client to the engine `shard_of` gives, out of `n` engines
+    fn split_by<F: Fn(u16) -> usize>(self, n: usize, shard_of: F) -> Vec<PaymentEngine> {
+        let mut shards: Vec<PaymentEngine> = (0..n).map(|_| self.empty_like()).collect();
+        for shard in &mut shards {
+            shard.withdrawal_day = self.withdrawal_day;
+        }
+        for (client, account) in self.accounts {
+            shards[shard_of(client)].accounts.insert(client, account);
+        }
+        for (client, total) in self.deposit_totals {
+            shards[shard_of(client)]
+                .deposit_totals
+                .insert(client, total);
+        }
+        for ((client, day), withdrawn) in self.daily_withdrawals {
+            shards[shard_of(client)]
+                .daily_withdrawals
+                .insert((client, day), withdrawn);
+        }
+        for ((client, tx), transaction) in self.transactions {
+            shards[shard_of(client)]
+                .transactions
+                .insert((client, tx), transaction);
+        }
+        for (client, open) in self.open_disputes {
+            shards[shard_of(client)].open_disputes.insert(client, open);
+        }
+        for correction in self.corrections {
+            shards[shard_of(correction.client)]
+                .corrections
+                .push(correction);
+        }
+        shards
+    }
+
+    /// Merge engines back into one, the inverse of `split`. The configuration of the first engine is kept.
+    /// Transactions are ordered engine by engine, so the order between shards is lost.
+    /// Fails if a transaction or an account is in more than one of the engines.
+    pub fn join(engines: Vec<PaymentEngine>) -> Result<PaymentEngine, TransactionError> {
+        let mut joined = match engines.first() {
+            Some(first) => first.empty_like(),
+            None => return Ok(PaymentEngine::default()),
+        };
+        for engine in engines {
+            for (client, account) in engine.accounts {
+                if joined.accounts.insert(client, account).is_some() {
+                    return Err(TransactionError::DuplicateAccount(client));
+                }
+            }
+            for ((client, tx), transaction) in engine.transactions {
+                if joined
+                    .transactions
+                    .insert((client, tx), transaction)
+                    .is_some()
+                {
+                    return Err(TransactionError::DuplicateTransaction(tx));
+                }
+            }
+            joined.corrections.extend(engine.corrections);
+            joined.deposit_totals.extend(engine.deposit_totals);
+            joined.daily_withdrawals.extend(engine.daily_withdrawals);
+            joined.withdrawal_day = joined.withdrawal_day.max(engine.withdrawal_day);
+            // the clients of the engines are distinct, the accounts were checked above
+            joined.open_disputes.extend(engine.open_disputes);
+        }
+        Ok(joined)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{Transaction, TransactionType};
+
+    /// Clients 1 to 10, each with a deposit and a disputed withdrawal
+    fn ten_clients() -> PaymentEngine {
+        let mut engine = PaymentEngine::default().with_max_accounts(100);
+        for client in 1..=10 {
+            let tx = u32::from(client) * 10;
+            let txs = [
+                Transaction::new(TransactionType::Deposit, client, tx, Some(100.0)),
+                Transaction::new(TransactionType::Withdrawal, client, tx + 1, Some(25.5)),
+                Transaction::new(TransactionType::Dispute, client, tx + 1, None),
+            ];
+            for tx in txs {
+                assert!(engine.perform_transaction(tx).is_ok());
+            }
+        }
+        engine
+    }
+
+    #[test]
+    fn split_by_client() {
+        let shards = ten_clients().split(2);
+        assert_eq!(shards.len(), 2);
+        for (i, shard) in shards.iter().enumerate() {
+            assert_eq!(shard.accounts.len(), 5);
+            assert_eq!(shard.transactions.len(), 10);
+            assert!(shard
+                .accounts
+                .keys()
+                .all(|client| *client as usize % 2 == i));
+            assert!(shard
+                .transactions
+                .values()
+                .all(|t| t.client as usize % 2 == i));
+            assert_eq!(shard.max_accounts, Some(100));
+        }
+    }
+
+    #[test]
+    fn split_join_round_trip() {
+        let engine = ten_clients();
+        let joined = PaymentEngine::join(ten_clients().split(2)).unwrap();
+        assert_eq!(joined.accounts, engine.accounts);
+        assert_eq!(joined.transactions.len(), engine.transactions.len());
+        for (tx, transaction) in &engine.transactions {
+            let joined_transaction = &joined.transactions[tx];
+            assert_eq!(joined_transaction.client, transaction.client);
+            assert_eq!(joined_transaction.amount, transaction.amount);
+            assert_eq!(
+                joined_transaction.dispute_status,
+                transaction.dispute_status
+            );
+        }
+        assert_eq!(joined.max_accounts, Some(100));
+        // every client has its disputed withdrawal open
+        assert_eq!(joined.open_disputes, engine.open_disputes);
+        assert_eq!(joined.open_disputes.len(), 10);
+    }
+
+    #[test]
+    fn partition_locked() {
+        let mut engine = ten_clients();
+        // charge back the disputed withdrawal of client '4', which locks the account
+        assert!(engine
+            .perform_transaction(Transaction::new(TransactionType::Chargeback, 4, 41, None))
+            .is_ok());
+        let (locked, unlocked) = engine.partition_by_locked_status();
+        assert_eq!(locked.accounts.len(), 1);
+        assert!(locked.accounts[&4].locked());
+        assert_eq!(locked.transactions.len(), 2);
+        assert!(locked.open_disputes.is_empty());
+        assert_eq!(unlocked.open_disputes.len(), 9);
+        assert!(locked.transactions.keys().all(|(client, _)| *client == 4));
+        assert_eq!(locked.max_accounts, Some(100));
+        assert_eq!(unlocked.accounts.len(), 9);
+        assert!(!unlocked.accounts.contains_key(&4));
+        assert!(unlocked.accounts.values().all(|account| !account.locked()));
+        assert_eq!(unlocked.transactions.len(), 18);
+    }
+
+    #[test]
+    fn partition_by_predicate() {
+        let (even, odd) = ten_clients().partition_by_predicate(|account| account.client() % 2 == 0);
+        let mut clients: Vec<u16> = even.accounts.keys().copied().collect();
+        clients.sort_unstable();
+        assert_eq!(clients, [2, 4, 6, 8, 10]);
+        assert_eq!(odd.accounts.len(), 5);
+        // nothing matches
+        let (none, all) = ten_clients().partition_by_predicate(|_| false);
+        assert!(none.accounts.is_empty() && none.transactions.is_empty());
+        assert_eq!(all.accounts.len(), 10);
+    }
+
+    #[test]
+    fn join_shared_tx_id() {
+        let mut a = PaymentEngine::default();
+        let mut b = PaymentEngine::default();
+        assert!(a
+            .perform_transaction(Transaction::new(TransactionType::Deposit, 1, 1, Some(1.0)))
+            .is_ok());
+        assert!(b
+            .perform_transaction(Transaction::new(TransactionType::Deposit, 2, 1, Some(1.0)))
+            .is_ok());
+        // tx ids only have to be unique per client
+        let joined = PaymentEngine::join(vec![a, b]).unwrap();
+        assert_eq!(joined.transactions.len(), 2);
+    }
+}
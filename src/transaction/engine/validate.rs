@@ -0,0 +1,246 @@
+use crate::transaction::{
+    engine::{
+        too_many_open_disputes, LimitAction, PaymentEngine, TransactionError, SECONDS_PER_DAY,
+    },
+    DisputeStatus, Transaction, TransactionType,
+};
+
+impl Transaction {
+    /// Run the checks `perform_transaction` makes against the state of the engine, without
+    /// changing it, so a batch can be validated before any of it is applied.
+    /// Every failed check is returned in the order `perform_transaction` makes them, the first
+    /// one is the error it would return. Checks which depend on a failed one are skipped, like the
+    /// client of a dispute when the disputed transaction doesn't exist. Empty if the transaction
+    /// would be performed, or if it's an identical replay the engine ignores.
+    pub fn is_valid_for_engine(&self, engine: &PaymentEngine) -> Vec<TransactionError> {
+        let mut errors = Vec::new();
+        if let Some(allowlist) = &engine.allowlist {
+            if !allowlist.contains(&self.client) {
+                errors.push(TransactionError::ClientNotAllowed(self.client));
+            }
+        }
+        let account = engine.accounts.get(&self.client);
+        if let Some(limit) = engine.max_accounts {
+            if account.is_none() && engine.accounts.len() >= limit {
+                errors.push(TransactionError::AccountLimitReached(self.client));
+            }
+        }
+        if engine.require_registered && account.is_none() {
+            errors.push(TransactionError::UnknownClient(self.client));
+        }
+        if account.is_some_and(|account| account.locked()) {
+            errors.push(TransactionError::AccountLocked(self.client));
+        }
+        if !self.validate() || self.in_dispute() {
+            errors.push(TransactionError::InvalidTransaction(self.tx));
+            // the remaining checks need a well formed transaction
+            return errors;
+        }
+
+        if self.transaction_type.is_new_transaction() {
+            let rules = &engine.rules;
+            if let Some(previous) = engine.transactions.get(&self.key()) {
+                let identical = previous.transaction_type == self.transaction_type
+                    && previous.amount == self.amount;
+                if !(rules.ignore_duplicates && identical) {
+                    errors.push(TransactionError::DuplicateTransaction(self.tx));
+                }
+                // a duplicate is never performed, so the rest doesn't apply
+                return errors;
+            }
+            let amount = self.amount.unwrap();
+            let is_adjustment = self.transaction_type == TransactionType::Adjustment;
+            if !is_adjustment && amount <= 0_f64 {
+                errors.push(TransactionError::NonPositiveAmount(
+                    self.client,
+                    self.tx,
+                    amount,
+                ));
+            }
+            if rules.scale.is_some_and(|scale| !self.fits_scale(scale)) {
+                errors.push(TransactionError::ExcessPrecision(
+                    self.client,
+                    self.tx,
+                    amount,
+                ));
+            }
+            if let Some(ceiling) = rules.max_transaction_amount {
+                if !is_adjustment && amount > ceiling {
+                    errors.push(TransactionError::AmountExceedsLimit(
+                        self.client,
+                        self.tx,
+                        amount,
+                        ceiling,
+                    ));
+                }
+            }
+            match self.transaction_type {
+                TransactionType::Deposit => {
+                    // with 'LimitAction::Warn' the deposit is performed, so it's not an error
+                    if let Some((limit, LimitAction::Reject)) = rules.aggregate_limit {
+                        let total = engine.deposit_totals.get(&self.client).copied();
+                        if total.unwrap_or_default() + amount > limit {
+                            errors.push(TransactionError::AggregateLimitExceeded(self.client));
+                        }
+                    }
+                }
+                TransactionType::Withdrawal => {
+                    if let Some(limit) = rules.daily_withdrawal_limit {
+                        let day = self.timestamp.map(|t| t / SECONDS_PER_DAY);
+                        let withdrawn = engine.daily_withdrawals.get(&(self.client, day)).copied();
+                        if withdrawn.unwrap_or_default() + amount > limit {
+                            errors.push(TransactionError::DailyWithdrawalLimitExceeded(
+                                self.client,
+                                limit,
+                            ));
+                        }
+                    }
+                    let available = account.map_or(0_f64, |account| account.available());
+                    // a partial withdrawal is performed, so it's not an error
+                    let partial = rules.partial_withdrawal && available > 0_f64;
+                    if available < amount && !partial {
+                        errors.push(TransactionError::InsufficientFunds(self.client));
+                    }
+                }
+                _ => {}
+            }
+        } else {
+            let Some(previous) = engine.transactions.get(&self.key()) else {
+                errors.push(TransactionError::NonExistingDisputeResolveOrChargeback(
+                    self.client,
+                    self.tx,
+                ));
+                return errors;
+            };
+            let releases_held = matches!(
+                self.transaction_type,
+                TransactionType::Resolve | TransactionType::Chargeback
+            ) && previous.dispute_status == Some(DisputeStatus::Disputed);
+            // a disputed transaction always has an amount, and its client an account
+            if releases_held
+                && !engine.accounts[&self.client].holds(previous.held_amount(), engine.scale())
+            {
+                errors.push(TransactionError::InsufficientHeld(self.client, self.tx));
+                return errors;
+            }
+            // try the state change on a copy of the disputed transaction
+            let already_resolved = previous.dispute_status == Some(DisputeStatus::Resolved);
+            let mut previous = previous.clone();
+            match self.transaction_type {
+                TransactionType::Resolve | TransactionType::Chargeback if already_resolved => {
+                    errors.push(TransactionError::DisputeAlreadyResolved(
+                        self.client,
+                        self.tx,
+                    ));
+                }
+                TransactionType::Dispute if !previous.dispute() => {
+                    errors.push(TransactionError::InvalidDispute(self.client, self.tx));
+                }
+                TransactionType::Resolve if !previous.resolve() => {
+                    errors.push(TransactionError::InvalidResolve(self.client, self.tx));
+                }
+                TransactionType::Chargeback if !previous.chargeback() => {
+                    errors.push(TransactionError::InvalidChargeback(self.client, self.tx));
+                }
+                _ if too_many_open_disputes(
+                    &engine.transactions,
+                    &engine.open_disputes,
+                    engine.max_open_disputes,
+                    self,
+                ) =>
+                {
+                    errors.push(TransactionError::TooManyOpenDisputes(self.client));
+                }
+                _ => {}
+            }
+        }
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An engine with every limit the checks look at and transactions in each dispute state
+    fn limited_engine() -> PaymentEngine {
+        let mut engine = PaymentEngine::default()
+            .with_max_transaction_amount(1000.0)
+            .with_scale(2)
+            .with_max_accounts(3);
+        let txs = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(100.0)),
+            Transaction::new(TransactionType::Deposit, 2, 2, Some(50.0)),
+            Transaction::new(TransactionType::Deposit, 2, 3, Some(20.0)),
+            Transaction::new(TransactionType::Dispute, 2, 3, None),
+            Transaction::new(TransactionType::Deposit, 3, 4, Some(10.0)),
+            Transaction::new(TransactionType::Deposit, 1, 5, Some(10.0)),
+            Transaction::new(TransactionType::Dispute, 1, 5, None),
+            Transaction::new(TransactionType::Resolve, 1, 5, None),
+            Transaction::new(TransactionType::Dispute, 3, 4, None),
+            Transaction::new(TransactionType::Chargeback, 3, 4, None),
+        ];
+        assert!(engine.perform_transactions(txs).is_empty());
+        engine
+    }
+
+    #[test]
+    fn same_errors_as_perform_transaction() {
+        let cases = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(5.0)),
+            Transaction::new(TransactionType::Deposit, 1, 10, Some(-5.0)),
+            Transaction::new(TransactionType::Deposit, 1, 11, Some(5.001)),
+            Transaction::new(TransactionType::Deposit, 1, 12, Some(5000.0)),
+            Transaction::new(TransactionType::Withdrawal, 1, 13, Some(500.0)),
+            Transaction::new(TransactionType::Deposit, 3, 14, Some(5.0)),
+            Transaction::new(TransactionType::Deposit, 4, 15, Some(5.0)),
+            Transaction::new(TransactionType::Dispute, 1, 99, None),
+            Transaction::new(TransactionType::Dispute, 1, 2, None),
+            Transaction::new(TransactionType::Dispute, 2, 3, None),
+            Transaction::new(TransactionType::Resolve, 2, 2, None),
+            Transaction::new(TransactionType::Chargeback, 1, 1, None),
+            Transaction::new(TransactionType::Resolve, 1, 5, None),
+            Transaction::new(TransactionType::Chargeback, 1, 5, None),
+            Transaction::new(TransactionType::Deposit, 1, 16, None),
+        ];
+        let engine = limited_engine();
+        for transaction in cases {
+            let errors = transaction.is_valid_for_engine(&engine);
+            let mut performed = engine.clone();
+            let expected = performed.perform_transaction(transaction).unwrap_err();
+            assert_eq!(errors[0].to_string(), expected.to_string());
+        }
+        // nothing was changed by the checks
+        assert_eq!(engine.transactions.len(), 5);
+    }
+
+    #[test]
+    fn valid_transactions() {
+        let engine = limited_engine();
+        let cases = [
+            Transaction::new(TransactionType::Deposit, 1, 20, Some(5.0)),
+            Transaction::new(TransactionType::Withdrawal, 1, 21, Some(100.0)),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+            Transaction::new(TransactionType::Resolve, 2, 3, None),
+            Transaction::new(TransactionType::Chargeback, 2, 3, None),
+        ];
+        for transaction in cases {
+            assert!(transaction.is_valid_for_engine(&engine).is_empty());
+        }
+    }
+
+    #[test]
+    fn every_failed_check() {
+        let engine = limited_engine();
+        let transaction = Transaction::new(TransactionType::Deposit, 4, 30, Some(5000.001));
+        let errors = transaction.is_valid_for_engine(&engine);
+        assert!(matches!(
+            errors[..],
+            [
+                TransactionError::AccountLimitReached(4),
+                TransactionError::ExcessPrecision(4, 30, _),
+                TransactionError::AmountExceedsLimit(4, 30, _, _),
+            ]
+        ));
+    }
+}
@@ -1,8 +1,16 @@
-use serde::Deserialize;
+use std::{
+    fs::File,
+    io::{self, BufReader, Read},
+};
 
-pub(crate) mod engine;
+use serde::{de::Error, Deserialize, Deserializer};
 
-#[derive(Copy, Clone, Debug, Deserialize)]
+use crate::account::{self, DEFAULT_SCALE};
+
+pub mod binary;
+pub mod engine;
+
+#[derive(Copy, Clone, Debug, Deserialize, Eq, Hash, PartialEq)]
 #[serde(rename_all(deserialize = "lowercase"))] // read the strings as lowercase
 pub enum TransactionType {
     Deposit,
@@ -10,6 +18,88 @@ pub enum TransactionType {
     Dispute,
     Resolve,
     Chargeback,
+    Adjustment, // signed correction of the available funds by an operator
+}
+
+/// Layout of a transaction CSV which doesn't use the default separators
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct InputFormat {
+    pub delimiter: u8,
+    /// Amounts are written with a comma as the decimal point, like `10,50`
+    pub decimal_comma: bool,
+}
+
+impl Default for InputFormat {
+    fn default() -> Self {
+        InputFormat {
+            delimiter: b',',
+            decimal_comma: false,
+        }
+    }
+}
+
+/// Why a row was deserialized but isn't a valid transaction
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ValidationFailure {
+    /// A dispute, resolve or chargeback has an amount, they take the amount of the transaction they refer to
+    AmountOnReferringTransaction,
+    /// A deposit, withdrawal or adjustment has no amount
+    MissingAmount,
+}
+
+/// Number of rows the reader dropped for each `ValidationFailure`
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DropCounts {
+    pub amount_on_referring_transaction: usize,
+    pub missing_amount: usize,
+}
+
+impl DropCounts {
+    pub fn count(&mut self, failure: ValidationFailure) {
+        match failure {
+            ValidationFailure::AmountOnReferringTransaction => {
+                self.amount_on_referring_transaction += 1
+            }
+            ValidationFailure::MissingAmount => self.missing_amount += 1,
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.amount_on_referring_transaction + self.missing_amount
+    }
+}
+
+/// Drops the transactions which fail `validate` from the rows of a reader and counts them by reason.
+/// Rows which can't be deserialized are kept as errors.
+pub struct ValidatedTransactions<I> {
+    rows: I,
+    dropped: DropCounts,
+}
+
+impl<I> ValidatedTransactions<I> {
+    /// The rows dropped so far, complete once the iterator is exhausted
+    pub fn dropped(&self) -> DropCounts {
+        self.dropped
+    }
+}
+
+impl<I> Iterator for ValidatedTransactions<I>
+where
+    I: Iterator<Item = Result<Transaction, csv::Error>>,
+{
+    type Item = Result<Transaction, csv::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.rows.next()? {
+                Ok(transaction) => match transaction.validation_failure() {
+                    Some(failure) => self.dropped.count(failure),
+                    None => return Some(Ok(transaction)),
+                },
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -17,21 +107,117 @@ pub enum DisputeStatus {
     Disputed,
     Resolved,
     Chargeback,
+    Rolledback, // reversed by an operator, can't be disputed anymore
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Transaction {
     #[serde(rename = "type")] // parse this field as 'type' not 'transaction_type'
     transaction_type: TransactionType,
+    #[serde(deserialize_with = "deserialize_client")]
     client: u16,
+    #[serde(deserialize_with = "deserialize_tx")]
     tx: u32,
     amount: Option<f64>, // only should be 'Some' if the type is Deposit or Withdrawal
+    #[serde(default)] // the column is optional
+    timestamp: Option<u64>, // seconds since the unix epoch
+    #[serde(default)] // the column is optional
+    reason: Option<String>, // why a dispute was opened, ignored for the other types
     #[serde(skip_deserializing)] // not serialized, internal use for disputes
     dispute_status: Option<DisputeStatus>,
+    // timestamps of the disputes, resolves and chargebacks which referred to this transaction
+    #[serde(skip_deserializing)]
+    disputed_at: Option<u64>,
+    #[serde(skip_deserializing)]
+    resolved_at: Option<u64>,
+    #[serde(skip_deserializing)]
+    chargeback_at: Option<u64>,
+    // reason of the dispute which referred to this transaction
+    #[serde(skip_deserializing)]
+    dispute_reason: Option<String>,
+    // held fees charged against the open dispute, see 'apply_held_fee'
+    #[serde(skip_deserializing)]
+    held_fee: f64,
+}
+
+/// The byte order mark some programs like Excel start a UTF-8 file with. It's left out before the
+/// csv crate reads the file, so the positions of its errors start after it.
+pub const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// Leave out a UTF-8 byte order mark at the start of the reader, the csv crate would read it as
+/// part of the first header. A read error is left for the next read to run into again.
+pub(crate) fn skip_bom<R: Read>(mut reader: R) -> impl Read {
+    let mut start = [0; 3];
+    let mut len = 0;
+    while len < start.len() {
+        match reader.read(&mut start[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) => break,
+        }
+    }
+    let start = if start[..len] == *UTF8_BOM {
+        Vec::new()
+    } else {
+        start[..len].to_vec()
+    };
+    io::Cursor::new(start).chain(reader)
+}
+
+/// Position of the `amount` column in the header
+fn amount_column(headers: &csv::StringRecord) -> Option<usize> {
+    headers.iter().position(|name| name == "amount")
+}
+
+/// The header of a reader, or its error as the only row since no row can be read without it
+fn read_headers<R: Read>(
+    reader: &mut csv::Reader<R>,
+) -> (Option<csv::StringRecord>, Option<csv::Error>) {
+    match reader.headers() {
+        Ok(headers) => (Some(headers.clone()), None),
+        Err(e) => (None, Some(e)),
+    }
+}
+
+/// A file of disputes, resolves and chargebacks can leave out the amount column, their rows read
+/// as `None`. A deposit, withdrawal or adjustment in a file without the column is an error instead
+/// of a row which is missing its amount, since the file has the wrong shape.
+fn check_amount_column(
+    row: Result<Transaction, csv::Error>,
+    has_amount_column: bool,
+) -> Result<Transaction, csv::Error> {
+    match row {
+        Ok(transaction)
+            if !has_amount_column && transaction.transaction_type.is_new_transaction() =>
+        {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} '{}' needs an amount but the file has no amount column",
+                    transaction.transaction_type.name(),
+                    transaction.tx
+                ),
+            )
+            .into())
+        }
+        row => row,
+    }
+}
+
+/// Reads the client id with an error naming the bound, instead of serde's generic integer error
+fn deserialize_client<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+    let id = u64::deserialize(deserializer)?;
+    u16::try_from(id).map_err(|_| D::Error::custom(format!("client id {} exceeds u16 max", id)))
+}
+
+/// Reads the transaction id with an error naming the bound, instead of serde's generic integer error
+fn deserialize_tx<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let id = u64::deserialize(deserializer)?;
+    u32::try_from(id).map_err(|_| D::Error::custom(format!("tx id {} exceeds u32 max", id)))
 }
 
 impl Transaction {
-    #[cfg(test)]
     pub fn new(
         transaction_type: TransactionType,
         client: u16,
@@ -43,49 +229,269 @@ impl Transaction {
             client,
             tx,
             amount,
+            timestamp: None,
+            reason: None,
             dispute_status: None,
+            disputed_at: None,
+            resolved_at: None,
+            chargeback_at: None,
+            dispute_reason: None,
+            held_fee: 0_f64,
         }
     }
 
+    pub fn transaction_type(&self) -> TransactionType {
+        self.transaction_type
+    }
+
+    pub fn client(&self) -> u16 {
+        self.client
+    }
+
+    pub fn tx(&self) -> u32 {
+        self.tx
+    }
+
+    pub fn amount(&self) -> Option<f64> {
+        self.amount
+    }
+
+    pub fn timestamp(&self) -> Option<u64> {
+        self.timestamp
+    }
+
+    /// Key of the transaction in the record of the engine, tx ids are only unique per client
+    pub(crate) fn key(&self) -> (u16, u32) {
+        (self.client, self.tx)
+    }
+
+    /// Where the transaction is in the dispute lifecycle, `None` if it was never disputed or reversed
+    pub fn dispute_status(&self) -> Option<DisputeStatus> {
+        self.dispute_status
+    }
+
+    /// The reason given by the dispute of this transaction, `None` if it was never disputed or the
+    /// dispute gave none
+    pub fn dispute_reason(&self) -> Option<&str> {
+        self.dispute_reason.as_deref()
+    }
+
+    /// The amount a dispute of the transaction is about. Only deposits and withdrawals can be
+    /// disputed and they always have an amount, so it panics for the other transactions.
+    pub fn disputed_amount(&self) -> f64 {
+        self.amount
+            .expect("only deposits and withdrawals can be disputed and they have an amount")
+    }
+
+    /// What a dispute of the transaction holds, its disputed amount less the held fees charged
+    /// against the dispute
+    pub fn held_amount(&self) -> f64 {
+        self.disputed_amount() - self.held_fee
+    }
+
+    /// Set the reason of a dispute, like `fraud` or `product_not_received`
+    pub fn with_reason(mut self, reason: &str) -> Self {
+        self.reason = Some(reason.to_string());
+        self
+    }
+
+    /// Set when the transaction happened, in seconds since the unix epoch
+    pub fn with_timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Replace the amount, the engine rejects it if the type of transaction has no amount
+    pub fn with_amount(mut self, amount: f64) -> Self {
+        self.amount = Some(amount);
+        self
+    }
+
+    /// Remove the amount so the transaction can be logged without it, a redacted deposit,
+    /// withdrawal or adjustment no longer passes `validate`
+    pub fn redact_amount(&mut self) {
+        self.amount = None;
+    }
+
+    /// A copy of the transaction with the amount removed
+    pub fn redacted(&self) -> Transaction {
+        let mut redacted = self.clone();
+        redacted.redact_amount();
+        redacted
+    }
+
+    /// A deterministic representation of the content of the transaction like `deposit|1|100.0000`.
+    /// The `tx` id is left out since different systems assign their own ids to the same transaction.
+    pub fn to_canonical_string(&self) -> String {
+        let amount = self
+            .amount
+            .map(|amount| format!("{:.*}", DEFAULT_SCALE as usize, amount))
+            .unwrap_or_default();
+        format!(
+            "{}|{}|{}",
+            self.transaction_type.name(),
+            self.client,
+            amount
+        )
+    }
+
+    /// Hash of the canonical string, seahash is used since its output is stable across platforms
+    pub fn fingerprint(&self) -> u64 {
+        seahash::hash(self.to_canonical_string().as_bytes())
+    }
+
+    /// Does the amount have no more than `scale` decimal places?
+    /// Transactions without an amount always fit.
+    fn fits_scale(&self, scale: u32) -> bool {
+        self.amount
+            .is_none_or(|amount| account::fits_scale(amount, scale))
+    }
+
     /// Ensure that only expected transaction types have amounts.
     /// Since serde can't guarantee the amount field is set according to type we enforce it manually.
-    fn validate(&self) -> bool {
-        self.amount.is_some() == self.transaction_type.should_have_amount()
+    pub fn validate(&self) -> bool {
+        self.validation_failure().is_none()
+    }
+
+    /// Same as `validate` but tells what is wrong with the transaction
+    pub fn validation_failure(&self) -> Option<ValidationFailure> {
+        match (self.transaction_type.should_have_amount(), self.amount) {
+            (true, None) => Some(ValidationFailure::MissingAmount),
+            (false, Some(_)) => Some(ValidationFailure::AmountOnReferringTransaction),
+            _ => None,
+        }
+    }
+
+    /// Same as `read_from_file` but the dropped rows are counted by reason,
+    /// see `ValidatedTransactions::dropped`
+    pub fn read_from_file_counting_drops(
+        file: &str,
+    ) -> Result<
+        ValidatedTransactions<impl Iterator<Item = Result<Transaction, csv::Error>>>,
+        csv::Error,
+    > {
+        Ok(Self::validated(Self::read_unvalidated_from_reader(
+            File::open(file)?,
+        )))
+    }
+
+    /// Drop the invalid transactions from the rows and count them, see `ValidatedTransactions`
+    pub fn validated<I>(rows: I) -> ValidatedTransactions<I>
+    where
+        I: Iterator<Item = Result<Transaction, csv::Error>>,
+    {
+        ValidatedTransactions {
+            rows,
+            dropped: DropCounts::default(),
+        }
     }
 
     /// Enforces additional restrictions when reading a 'Transaction'.
     /// Namely that some types must have amounts while others must not.
-    /// Filters out the transactions which are invalid.
+    /// Filters out the transactions which are invalid, rows which can't be read are kept as errors.
     pub fn read_from_file(
         file: &str,
-    ) -> Result<impl Iterator<Item = Result<Transaction, csv::Error>> + '_, csv::Error> {
-        Ok(csv::ReaderBuilder::new()
+    ) -> Result<impl Iterator<Item = Result<Transaction, csv::Error>>, csv::Error> {
+        Ok(Self::read_from_reader(File::open(file)?))
+    }
+
+    /// Same as `read_from_file` but the file is read through a buffer of `capacity` bytes,
+    /// to tune the throughput on slow storage
+    pub fn read_from_file_with_capacity(
+        file: &str,
+        capacity: usize,
+    ) -> Result<impl Iterator<Item = Result<Transaction, csv::Error>>, csv::Error> {
+        Ok(Self::read_from_reader(BufReader::with_capacity(
+            capacity,
+            File::open(file)?,
+        )))
+    }
+
+    /// Same as `read_from_file` but reads from any source, like an in-memory buffer.
+    pub fn read_from_reader<R: Read>(
+        reader: R,
+    ) -> impl Iterator<Item = Result<Transaction, csv::Error>> {
+        Self::validated(Self::read_unvalidated_from_reader(reader))
+    }
+
+    /// Same as `read_from_reader` but the transactions which fail `validate` are kept,
+    /// so the caller can tell the rows which were read from the rows which were valid
+    pub fn read_unvalidated_from_reader<R: Read>(
+        reader: R,
+    ) -> impl Iterator<Item = Result<Transaction, csv::Error>> {
+        let mut reader = csv::ReaderBuilder::new()
             .trim(csv::Trim::All) // allow whitespace
             .flexible(true) // avoid the extra comma after dispute, resolve and chargeback
-            .from_path(file)?
-            .into_deserialize::<Transaction>()
-            .filter(|res_transaction| {
-                res_transaction
-                    .as_ref()
-                    .map_or_else(|_| false, |t| t.validate())
-            }))
+            .from_reader(skip_bom(reader));
+        let (headers, header_error) = read_headers(&mut reader);
+        let has_amount_column = headers.as_ref().and_then(amount_column).is_some();
+        let rows = headers
+            .map(|_| reader.into_deserialize::<Transaction>())
+            .into_iter()
+            .flatten()
+            .map(move |row| check_amount_column(row, has_amount_column));
+        header_error.map(Err).into_iter().chain(rows)
     }
 
-    /// Enforces additional restrictions when reading a 'Transaction'.
-    /// Namely that some types must have amounts while others must not.
-    /// Filters out the transactions which are invalid.
-    #[cfg(test)]
-    fn read_from_bytes(bytes: &[u8]) -> impl Iterator<Item = Result<Transaction, csv::Error>> + '_ {
-        csv::ReaderBuilder::new()
+    /// Same as `read_from_reader` but for a CSV in another layout.
+    /// With a decimal comma the comma of the amount is read as a decimal point, so the delimiter
+    /// has to be something else.
+    pub fn read_from_reader_with_format<R: Read>(
+        reader: R,
+        format: InputFormat,
+    ) -> impl Iterator<Item = Result<Transaction, csv::Error>> {
+        Self::validated(Self::read_unvalidated_from_reader_with_format(
+            reader, format,
+        ))
+    }
+
+    /// Same as `read_from_reader_with_format` but the transactions which fail `validate` are kept
+    pub fn read_unvalidated_from_reader_with_format<R: Read>(
+        reader: R,
+        format: InputFormat,
+    ) -> impl Iterator<Item = Result<Transaction, csv::Error>> {
+        let mut reader = csv::ReaderBuilder::new()
             .trim(csv::Trim::All) // allow whitespace
             .flexible(true) // avoid the extra comma after dispute, resolve and chargeback
-            .from_reader(bytes)
-            .into_deserialize::<Transaction>()
-            .filter(|res_transaction| {
-                res_transaction
-                    .as_ref()
-                    .map_or_else(|_| false, |t| t.validate())
+            .delimiter(format.delimiter)
+            .from_reader(skip_bom(reader));
+        let (headers, header_error) = read_headers(&mut reader);
+        let amount_column = headers.as_ref().and_then(amount_column);
+        let rows = headers
+            .map(|headers| {
+                reader.into_records().map(move |record| {
+                    let mut record = record?;
+                    if let Some(amount) = amount_column.filter(|_| format.decimal_comma) {
+                        // only the amount is a decimal, the other fields keep their commas
+                        record = record
+                            .iter()
+                            .enumerate()
+                            .map(|(i, field)| {
+                                if i == amount {
+                                    field.replace(',', ".")
+                                } else {
+                                    field.to_owned()
+                                }
+                            })
+                            .collect();
+                    }
+                    check_amount_column(
+                        record.deserialize::<Transaction>(Some(&headers)),
+                        amount_column.is_some(),
+                    )
+                })
             })
+            .into_iter()
+            .flatten();
+        header_error.map(Err).into_iter().chain(rows)
+    }
+
+    /// Used to deserialize byte strings in tests
+    #[cfg(test)]
+    pub(crate) fn read_from_bytes(
+        bytes: &[u8],
+    ) -> impl Iterator<Item = Result<Transaction, csv::Error>> + '_ {
+        Self::read_from_reader(bytes)
     }
 
     // Disputes work like a state machine:
@@ -133,17 +539,42 @@ impl Transaction {
         }
         can_chargeback
     }
+
+    /// Roll back the transaction if possible, it can't be under an open dispute or charged back
+    pub fn rollback(&mut self) -> bool {
+        let can_rollback = self.dispute_possible()
+            && matches!(self.dispute_status, None | Some(DisputeStatus::Resolved));
+        if can_rollback {
+            self.dispute_status = Some(DisputeStatus::Rolledback);
+        }
+        can_rollback
+    }
 }
 
 impl TransactionType {
     /// Used to ensure correctness of transaction type, only some transactions have an amount field
     const fn should_have_amount(self) -> bool {
-        matches!(self, TransactionType::Deposit | TransactionType::Withdrawal)
+        matches!(
+            self,
+            TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Adjustment
+        )
     }
 
-    /// Is the transaction either a deposit or a withdrawal?
+    /// The name of the type as it's written in the CSV
+    pub const fn name(self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Adjustment => "adjustment",
+        }
+    }
+
+    /// Is the transaction a deposit, a withdrawal or an adjustment?
     /// If so it's going to be a new transaction record we have to keep
-    const fn is_new_transaction(self) -> bool {
+    pub const fn is_new_transaction(self) -> bool {
         // The duplication here is for clarity
         self.should_have_amount()
     }
@@ -152,6 +583,83 @@ impl TransactionType {
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[test]
+    fn drop_counts_by_reason() {
+        let csv = r#"
+        type, client, tx, amount
+        deposit, 1, 1, 10.0
+        dispute, 1, 1, 10.0
+        deposit, 1, 2,
+        resolve, 1, 1, 5.0
+        withdrawal, 1, 3,
+        chargeback, 1, 1, 1.0
+        dispute, 1, 1,
+        deposit, x, 4, 1.0"#;
+        let mut iter =
+            Transaction::validated(Transaction::read_unvalidated_from_reader(csv.as_bytes()));
+        let results: Vec<_> = iter.by_ref().collect();
+        // the valid deposit and dispute, and the row which couldn't be read
+        assert_eq!(results.len(), 3);
+        assert!(results[2].is_err());
+        assert_eq!(
+            iter.dropped(),
+            DropCounts {
+                amount_on_referring_transaction: 3,
+                missing_amount: 2,
+            }
+        );
+        assert_eq!(iter.dropped().total(), 5);
+    }
+
+    #[test]
+    fn drop_counts_from_file() {
+        let mut iter =
+            Transaction::read_from_file_counting_drops("tests/error_all_invalid.csv").unwrap();
+        assert_eq!(iter.by_ref().count(), 0);
+        assert_eq!(
+            iter.dropped(),
+            DropCounts {
+                amount_on_referring_transaction: 1,
+                missing_amount: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn parse_with_bom() {
+        // exported from Excel, with a byte order mark, CRLF line endings and a trailing empty line
+        let transactions: Vec<Transaction> = Transaction::read_from_file("tests/bom.csv")
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[0].transaction_type, TransactionType::Deposit);
+        assert_eq!(transactions[1].amount, Some(1.5));
+        let format = InputFormat {
+            delimiter: b';',
+            decimal_comma: true,
+        };
+        let csv = b"\xEF\xBB\xBFtype;client;tx;amount\ndeposit;1;1;2,5\n";
+        let mut rows = Transaction::read_from_reader_with_format(&csv[..], format);
+        assert_eq!(rows.next().unwrap().unwrap().amount, Some(2.5));
+        // only a mark at the very start is left out
+        let read = |bytes: &[u8]| {
+            let mut read = String::new();
+            skip_bom(bytes).read_to_string(&mut read).unwrap();
+            read
+        };
+        assert_eq!(read(b"ab"), "ab");
+        assert_eq!(read(b"type\xEF\xBB\xBF"), "type\u{feff}");
+    }
+
+    #[test]
+    fn parse_header_only() {
+        // a header without rows and a file without even a header both have no transactions
+        for file in ["tests/header_only.csv", "tests/empty.csv"] {
+            assert_eq!(Transaction::read_from_file(file).unwrap().count(), 0);
+        }
+    }
+
     #[test]
     fn parse_many_errors() {
         let csv = r#"
@@ -160,12 +668,37 @@ mod tests {
         withdrawal, 1.25, 1, 2
         , 25, 1, hello
         "#;
-        for _ in Transaction::read_from_bytes(csv.as_bytes()) {
-            // all the lines are wrong and should be filtered out, so we never reach the inner loop
-            assert!(false);
+        for res in Transaction::read_from_bytes(csv.as_bytes()) {
+            // all the lines are wrong and should be errors, so we never read a transaction
+            assert!(res.is_err());
         }
     }
 
+    #[test]
+    fn parse_id_bounds() {
+        let csv = r#"
+        type, client, tx, amount
+        deposit, 70000, 1, 1.0
+        deposit, 1, 4294967296, 1.0
+        deposit, 65535, 4294967295, 1.0"#;
+        let mut results = Transaction::read_from_bytes(csv.as_bytes());
+        let error = results.next().unwrap().unwrap_err().to_string();
+        assert!(
+            error.contains("client id 70000 exceeds u16 max"),
+            "{}",
+            error
+        );
+        let error = results.next().unwrap().unwrap_err().to_string();
+        assert!(
+            error.contains("tx id 4294967296 exceeds u32 max"),
+            "{}",
+            error
+        );
+        // the maximums themselves are fine
+        let transaction = results.next().unwrap().unwrap();
+        assert_eq!((transaction.client, transaction.tx), (u16::MAX, u32::MAX));
+    }
+
     #[test]
     fn parse_amount_errors() {
         // check to see if the 'amount' field is where it should be
@@ -188,6 +721,184 @@ mod tests {
         }
     }
 
+    #[test]
+    fn parse_without_amount_column() {
+        // disputes, resolves and chargebacks don't need the column at all
+        let csv = r#"
+        type, client, tx
+        dispute, 1, 1
+        resolve, 1, 1
+        chargeback, 2, 3"#;
+        let transactions: Vec<Transaction> = Transaction::read_from_bytes(csv.as_bytes())
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(transactions.len(), 3);
+        assert!(transactions
+            .iter()
+            .all(|t| t.amount.is_none() && t.validate()));
+        assert_eq!(
+            transactions[2].transaction_type,
+            TransactionType::Chargeback
+        );
+
+        // a deposit or withdrawal can't be read without it
+        let csv = r#"
+        type, client, tx
+        deposit, 1, 1
+        dispute, 1, 1
+        withdrawal, 1, 2"#;
+        let results: Vec<_> = Transaction::read_from_bytes(csv.as_bytes()).collect();
+        assert_eq!(results.len(), 3);
+        let error = results[0].as_ref().unwrap_err().to_string();
+        assert!(
+            error.contains("deposit '1' needs an amount but the file has no amount column"),
+            "{}",
+            error
+        );
+        assert!(results[1].is_ok());
+        assert!(results[2].is_err());
+
+        // the same with another layout
+        let csv = "type;client;tx\nwithdrawal;1;2\nresolve;1;1";
+        let format = InputFormat {
+            delimiter: b';',
+            decimal_comma: true,
+        };
+        let results: Vec<_> =
+            Transaction::read_from_reader_with_format(csv.as_bytes(), format).collect();
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_err());
+        assert!(results[1].is_ok());
+    }
+
+    #[test]
+    fn parse_bad_header() {
+        // a header which isn't UTF-8 is the error of the file instead of rows read without it
+        let csv = b"type,client,tx,am\xffount\ndeposit,1,1,1.0";
+        let results: Vec<_> = Transaction::read_from_bytes(csv).collect();
+        assert_eq!(results.len(), 1);
+        assert!(results[0]
+            .as_ref()
+            .unwrap_err()
+            .to_string()
+            .contains("UTF-8"));
+        let results: Vec<_> =
+            Transaction::read_from_reader_with_format(&csv[..], InputFormat::default()).collect();
+        assert_eq!(results.len(), 1);
+        assert!(results[0].is_err());
+    }
+
+    #[test]
+    fn redact_amount() {
+        let deposit = Transaction::new(TransactionType::Deposit, 1, 1, Some(10.0));
+        let redacted = deposit.redacted();
+        assert_eq!(redacted.amount, None);
+        assert_eq!((redacted.client, redacted.tx), (1, 1));
+        assert!(!redacted.validate());
+        // the original is untouched
+        assert_eq!(deposit.amount, Some(10.0));
+
+        let mut withdrawal = Transaction::new(TransactionType::Withdrawal, 1, 2, Some(5.0));
+        withdrawal.redact_amount();
+        assert!(!withdrawal.validate());
+        // a dispute never had an amount
+        assert!(Transaction::new(TransactionType::Dispute, 1, 1, None)
+            .redacted()
+            .validate());
+    }
+
+    #[test]
+    fn read_with_capacity() {
+        let amounts = |iter: &mut dyn Iterator<Item = Result<Transaction, csv::Error>>| {
+            iter.map(|t| t.unwrap().amount).collect::<Vec<_>>()
+        };
+        let expected = amounts(&mut Transaction::read_from_file("tests/a1.csv").unwrap());
+        assert!(!expected.is_empty());
+        // a buffer smaller than a single row still reads the same transactions
+        for capacity in [1, 7, 64 * 1024] {
+            let mut iter =
+                Transaction::read_from_file_with_capacity("tests/a1.csv", capacity).unwrap();
+            assert_eq!(amounts(&mut iter), expected);
+        }
+    }
+
+    #[test]
+    fn parse_decimal_comma() {
+        let csv = r#"
+        type; client; tx; amount
+        deposit; 1; 1; 10,50
+        withdrawal; 1; 2; 3
+        dispute; 1; 1;"#;
+        let format = InputFormat {
+            delimiter: b';',
+            decimal_comma: true,
+        };
+        let transactions: Vec<Transaction> =
+            Transaction::read_from_reader_with_format(csv.as_bytes(), format)
+                .map(|t| t.unwrap())
+                .collect();
+        assert_eq!(transactions.len(), 3);
+        assert_eq!(transactions[0].amount, Some(10.5));
+        assert_eq!(transactions[1].amount, Some(3.0));
+        assert_eq!(transactions[2].amount, None);
+
+        // the other fields keep their commas
+        let csv = "type;client;tx;amount;reason\ndeposit;1;1;10,50;salary, march";
+        let transaction = Transaction::read_from_reader_with_format(csv.as_bytes(), format)
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(transaction.amount, Some(10.5));
+        assert_eq!(transaction.reason.as_deref(), Some("salary, march"));
+
+        // without the decimal comma the amount isn't a number
+        let format = InputFormat {
+            delimiter: b';',
+            decimal_comma: false,
+        };
+        let mut results = Transaction::read_from_reader_with_format(csv.as_bytes(), format);
+        assert!(results.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn parse_timestamp() {
+        let csv = r#"
+        type, client, tx, amount, timestamp
+        deposit, 1, 1, 1.0, 1700000000
+        dispute, 1, 1, ,"#;
+        let transactions: Vec<Transaction> = Transaction::read_from_bytes(csv.as_bytes())
+            .map(|t| t.unwrap())
+            .collect();
+        assert_eq!(transactions[0].timestamp, Some(1700000000));
+        assert_eq!(transactions[1].timestamp, None);
+    }
+
+    #[test]
+    fn canonical_string() {
+        let deposit = Transaction::new(TransactionType::Deposit, 1, 42, Some(100.0));
+        assert_eq!(deposit.to_canonical_string(), "deposit|1|100.0000");
+        let dispute = Transaction::new(TransactionType::Dispute, 1, 42, None);
+        assert_eq!(dispute.to_canonical_string(), "dispute|1|");
+        // the tx id isn't part of the content
+        let same = Transaction::new(TransactionType::Deposit, 1, 43, Some(100.0));
+        assert_eq!(deposit.fingerprint(), same.fingerprint());
+        let other = Transaction::new(TransactionType::Withdrawal, 1, 42, Some(100.0));
+        assert_ne!(deposit.fingerprint(), other.fingerprint());
+    }
+
+    #[test]
+    fn fits_scale() {
+        let amount = |amount| Transaction::new(TransactionType::Deposit, 1, 1, Some(amount));
+        assert!(amount(100.0).fits_scale(0));
+        assert!(!amount(1.5).fits_scale(0));
+        assert!(amount(1.05).fits_scale(2));
+        assert!(!amount(1.005).fits_scale(2));
+        assert!(amount(123456789.12).fits_scale(2));
+        assert!(amount(0.0001).fits_scale(4));
+        assert!(!amount(0.00001).fits_scale(4));
+        assert!(Transaction::new(TransactionType::Dispute, 1, 1, None).fits_scale(0));
+    }
+
     #[test]
     fn dispute_states() {
         // make sure the state transitions for disputes functions properly
@@ -201,5 +912,9 @@ mod tests {
         assert!(transaction.resolve());
         assert!(!transaction.chargeback());
         assert!(!transaction.dispute());
+        // a resolved transaction can still be rolled back, but only once
+        assert!(transaction.rollback());
+        assert!(!transaction.rollback());
+        assert!(!transaction.dispute());
     }
 }
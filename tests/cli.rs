@@ -0,0 +1,216 @@
+use std::process::Command;
+
+fn run(args: &[&str]) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_payment_engine"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn errors_exit_zero_by_default() {
+    // the withdrawal of client 1 fails for insufficient funds
+    let output = run(&["tests/error_some.csv"]);
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn fail_on_error_exit_code() {
+    let output = run(&["--fail-on-error", "tests/error_some.csv"]);
+    assert_eq!(output.status.code(), Some(1));
+    // the balances are still written
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("client,available,held,total,locked\n"));
+    assert_eq!(stdout.lines().count(), 3);
+
+    let output = run(&["--fail-on-error", "tests/a1.csv"]);
+    assert_eq!(output.status.code(), Some(0));
+
+    // rows dropped for an amount which doesn't fit their type are errors too
+    let output = run(&["--fail-on-error", "tests/error_all_invalid.csv"]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[cfg(feature = "printerrors")]
+#[test]
+fn empty_table_diagnostics() {
+    let stderr = |file| String::from_utf8(run(&[file]).stderr).unwrap();
+    assert!(stderr("tests/header_only.csv")
+        .contains("csv error: table is empty, file has no data rows"));
+    assert!(stderr("tests/empty.csv").contains("csv error: table is empty, file is empty"));
+    // a byte order mark doesn't hide the header
+    let bom = run(&["tests/bom.csv"]);
+    assert!(String::from_utf8(bom.stderr).unwrap().is_empty());
+    assert_eq!(
+        String::from_utf8(bom.stdout).unwrap(),
+        "client,available,held,total,locked\n1,3.5,0.0,3.5,false\n"
+    );
+    let all_invalid = stderr("tests/error_all_invalid.csv");
+    assert!(
+        all_invalid.contains("csv error: table is empty, 2 rows present but all failed validation")
+    );
+    assert!(all_invalid.contains("warning: dropped 2 invalid rows, 1 disputes, resolves or chargebacks with an amount and 1 deposits, withdrawals or adjustments without one"));
+    assert!(stderr("tests/error_bad_table.csv")
+        .contains("csv error: table is empty, header columns unexpected"));
+}
+
+#[cfg(feature = "printerrors")]
+#[test]
+fn bad_row_after_bom() {
+    let output = run(&["tests/error_bom_bad_row.csv"]);
+    assert_eq!(output.status.code(), Some(0));
+    // the row is read from where it starts, the byte order mark doesn't shift it
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains(", the row was 'deposit,1,x,2.0'"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn opening_balance_over_scale() {
+    let output = run(&[
+        "--scale",
+        "0",
+        "--opening",
+        "tests/opening_fractional.csv",
+        "tests/a1.csv",
+    ]);
+    assert_ne!(output.status.code(), Some(0));
+    assert!(output.stdout.is_empty());
+    // the balance fits the default scale
+    let output = run(&["--opening", "tests/opening_fractional.csv", "tests/a1.csv"]);
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn open_disputes_report_scale() {
+    // only the deposit of 10 fits a scale of 0, so its dispute is the only one
+    let output = run(&[
+        "--scale",
+        "0",
+        "--report-open-disputes",
+        "tests/open_disputes.csv",
+    ]);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("open disputes: 1 holding 10\n"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn per_client_dir() {
+    let dir = std::env::temp_dir().join(format!("payment_engine_clients_{}", std::process::id()));
+    // the directory doesn't exist yet and is created
+    let _ = std::fs::remove_dir_all(&dir);
+    let output = run(&[
+        "--per-client-dir",
+        dir.to_str().unwrap(),
+        "tests/open_disputes.csv",
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(output.stdout.is_empty());
+    let read = |file: &str| std::fs::read_to_string(dir.join(file)).unwrap();
+    assert_eq!(
+        read("1.csv"),
+        "client,available,held,total,locked\n1,2.5,10.0,12.5,false\n"
+    );
+    assert_eq!(
+        read("2.csv"),
+        "client,available,held,total,locked\n2,0.0,4.25,4.25,false\n"
+    );
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn hide_zero() {
+    // client 1 withdrew everything it deposited
+    let stdout = |args: &[&str]| String::from_utf8(run(args).stdout).unwrap();
+    assert_eq!(
+        stdout(&["--hide-zero", "tests/zero_balance.csv"]),
+        "client,available,held,total,locked\n2,5.0,0.0,5.0,false\n"
+    );
+    assert_eq!(stdout(&["tests/zero_balance.csv"]).lines().count(), 3);
+}
+
+#[test]
+fn rejected_output() {
+    let path = std::env::temp_dir().join(format!(
+        "payment_engine_rejected_{}.csv",
+        std::process::id()
+    ));
+    let output = run(&["--rejected", path.to_str().unwrap(), "tests/rejected.csv"]);
+    assert_eq!(output.status.code(), Some(0));
+    // the withdrawal over the balance and the deposit to the account locked by the chargeback
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "type,client,tx,amount,timestamp,error\n\
+         withdrawal,1,2,7.5,,client '1' has insufficient funds\n\
+         deposit,2,4,1.0,,account '2' is locked\n"
+    );
+    // the rejected file reads as input again, in a fresh engine the deposit goes through
+    let stdout = String::from_utf8(run(&[path.to_str().unwrap()]).stdout).unwrap();
+    assert_eq!(stdout.lines().count(), 3);
+    assert!(stdout.contains("\n2,1.0,0.0,1.0,false\n"));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn quote_style() {
+    let stdout = |args: &[&str]| run(args).stdout;
+    assert_eq!(
+        stdout(&["--quote-all", "tests/a1.csv"]),
+        b"\"client\",\"available\",\"held\",\"total\",\"locked\"\n\"1\",\"3000.0\",\"0.0\",\"3000.0\",\"false\"\n"
+    );
+    let never = stdout(&["--quote-never", "tests/a1.csv"]);
+    assert!(!never.contains(&b'"'));
+    assert_eq!(never, stdout(&["tests/a1.csv"]));
+    let output = run(&["--quote-all", "--quote-never", "tests/a1.csv"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn output_to_file() {
+    let path =
+        std::env::temp_dir().join(format!("payment_engine_output_{}.csv", std::process::id()));
+    // an existing file is truncated
+    std::fs::write(
+        &path,
+        "stale contents that are longer than the output\n".repeat(10),
+    )
+    .unwrap();
+    let output = run(&["--output", path.to_str().unwrap(), "tests/a1.csv"]);
+    assert_eq!(output.status.code(), Some(0));
+    // nothing is written to stdout instead
+    assert!(output.stdout.is_empty());
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "client,available,held,total,locked\n1,3000.0,0.0,3000.0,false\n"
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn partial_withdrawal_is_not_rejected() {
+    let path =
+        std::env::temp_dir().join(format!("payment_engine_partial_{}.csv", std::process::id()));
+    // client 1 withdraws 1.5 with 1.0 available
+    let output = run(&[
+        "--partial-withdrawal",
+        "--rejected",
+        path.to_str().unwrap(),
+        "tests/error_some.csv",
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .contains("\n1,0.0,0.0,0.0,false\n"));
+    // the withdrawal was performed, so a replay of the rejected file mustn't withdraw it again
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+    std::fs::remove_file(&path).unwrap();
+}